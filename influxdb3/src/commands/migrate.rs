@@ -0,0 +1,169 @@
+//! Entrypoint for migrating data from other versions of InfluxDB
+
+use clap_blocks::object_store::{make_object_store, ObjectStoreConfig};
+use influxdb3_write::migrate::{InfluxDbVersion, TsmMigrationConfig, TsmMigrator};
+use influxdb3_write::persister::PersisterImpl;
+use influxdb3_write::wal::WalImpl;
+use object_store::DynObjectStore;
+use observability_deps::tracing::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+use trogging::cli::LoggingConfig;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot parse object store config: {0}")]
+    ObjectStoreParsing(#[from] clap_blocks::object_store::ParseError),
+
+    #[error("Wal error: {0}")]
+    Wal(#[from] influxdb3_write::wal::Error),
+
+    #[error("Migration error: {0}")]
+    Migrate(#[from] influxdb3_write::migrate::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, clap::Parser)]
+pub struct Config {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+impl Config {
+    pub(crate) fn logging_config(&self) -> &LoggingConfig {
+        match &self.command {
+            Command::Tsm(config) => &config.logging_config,
+        }
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+enum Command {
+    /// Migrate the TSM files of InfluxDB 1.x or 2.x into the object store of InfluxDB 3.0. The
+    /// server must not be running while the data is migrated.
+    Tsm(TsmConfig),
+}
+
+/// The version of InfluxDB that wrote the TSM files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SourceVersion {
+    V1,
+    V2,
+}
+
+impl From<SourceVersion> for InfluxDbVersion {
+    fn from(version: SourceVersion) -> Self {
+        match version {
+            SourceVersion::V1 => Self::V1,
+            SourceVersion::V2 => Self::V2,
+        }
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+struct TsmConfig {
+    /// The data directory of the storage engine, e.g. ~/.influxdbv2/engine/data for InfluxDB
+    /// 2.x or ~/.influxdb/data for InfluxDB 1.x. Every directory under it that has TSM files is
+    /// migrated as a shard.
+    #[clap(long = "tsm-dir", action)]
+    tsm_dir: PathBuf,
+
+    /// The version of InfluxDB that wrote the data directory. The data of an InfluxDB 1.x
+    /// database is migrated into a database of the same name for its `autogen` retention
+    /// policy, and into `<database>/<retention policy>` for its other retention policies.
+    #[clap(long = "source-version", value_enum, default_value = "v2", action)]
+    source_version: SourceVersion,
+
+    /// The database to migrate the data of an InfluxDB 2.x bucket into, as
+    /// `<bucket id>=<database>`. Can be given more than once. Buckets that aren't given are
+    /// migrated into a database named after the bucket ID.
+    #[clap(
+        long = "bucket-database",
+        value_parser = parse_bucket_database,
+        action = clap::ArgAction::Append,
+    )]
+    bucket_databases: Vec<(String, String)>,
+
+    /// The file that the progress of the migration is recorded in. A migration that is
+    /// interrupted resumes from it when it is run again.
+    #[clap(
+        long = "checkpoint-file",
+        default_value = "tsm_migration_checkpoint.json",
+        action
+    )]
+    checkpoint_file: PathBuf,
+
+    /// The directory of the write ahead log of the server, so that migrated segments don't
+    /// reuse the ids of segments in it that have yet to be persisted.
+    #[clap(long = "wal-directory", env = "INFLUXDB3_WAL_DIRECTORY", action)]
+    wal_directory: Option<PathBuf>,
+
+    #[clap(flatten)]
+    object_store_config: ObjectStoreConfig,
+
+    /// logging options
+    #[clap(flatten)]
+    logging_config: LoggingConfig,
+}
+
+pub async fn command(config: Config) -> Result<()> {
+    match config.command {
+        Command::Tsm(config) => migrate_tsm(config).await,
+    }
+}
+
+async fn migrate_tsm(config: TsmConfig) -> Result<()> {
+    let object_store: Arc<DynObjectStore> =
+        make_object_store(&config.object_store_config).map_err(Error::ObjectStoreParsing)?;
+    let persister = Arc::new(PersisterImpl::new(object_store));
+    let wal = config.wal_directory.map(WalImpl::new).transpose()?;
+
+    let migration_config = TsmMigrationConfig {
+        data_dir: config.tsm_dir,
+        version: config.source_version.into(),
+        bucket_databases: config
+            .bucket_databases
+            .into_iter()
+            .collect::<HashMap<_, _>>(),
+        checkpoint_path: config.checkpoint_file,
+    };
+    let mut migrator = TsmMigrator::new(persister, wal.as_ref(), migration_config).await?;
+    let summary = migrator.run().await?;
+
+    info!(
+        shards_migrated = summary.shards_migrated,
+        shards_skipped = summary.shards_skipped,
+        parquet_files = summary.parquet_files,
+        rows = summary.rows,
+        "migration complete"
+    );
+    println!(
+        "Migrated {} shards ({} skipped) into {} parquet files with {} rows",
+        summary.shards_migrated, summary.shards_skipped, summary.parquet_files, summary.rows
+    );
+
+    Ok(())
+}
+
+/// Parses a `<bucket id>=<database>` pair, normalising the bucket ID to the 16 lowercase hex
+/// digits that bucket IDs are displayed with.
+fn parse_bucket_database(
+    s: &str,
+) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let Some((bucket_id, database)) = s.split_once('=') else {
+        return Err(
+            format!("Invalid bucket database - expected 'BUCKET_ID=DATABASE' got '{s}'").into(),
+        );
+    };
+    let bucket_id = u64::from_str_radix(bucket_id.trim(), 16)
+        .map_err(|e| format!("Invalid bucket ID '{bucket_id}': {e}"))?;
+    let database = database.trim();
+    if database.is_empty() {
+        return Err(format!("Missing database for bucket ID '{bucket_id:016x}'").into());
+    }
+
+    Ok((format!("{bucket_id:016x}"), database.to_string()))
+}
@@ -7,11 +7,15 @@ use clap_blocks::{
     object_store::{make_object_store, ObjectStoreConfig},
     socket_addr::SocketAddr,
 };
-use influxdb3_server::{query_executor::QueryExecutorImpl, serve, CommonServerState, Server};
-use influxdb3_write::persister::PersisterImpl;
+use influxdb3_server::{
+    query_executor::QueryExecutorImpl, serve, CommonServerState, Server, TokenAuthorizer,
+};
+use influxdb3_write::persister::{PersisterImpl, PARQUET_STORAGE_ID};
 use influxdb3_write::wal::WalImpl;
-use influxdb3_write::write_buffer::WriteBufferImpl;
+use influxdb3_write::write_buffer::{CompactionConfig, SegmentConfig, WriteBufferImpl};
+use influxdb3_write::Bufferer;
 use iox_query::exec::{Executor, ExecutorConfig};
+use iox_time::SystemProvider;
 use ioxd_common::reexport::trace_http::ctx::TraceHeaderParser;
 use object_store::DynObjectStore;
 use observability_deps::tracing::*;
@@ -22,6 +26,7 @@ use std::{
     num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio_util::sync::CancellationToken;
@@ -35,6 +40,18 @@ pub const DEFAULT_DATA_DIRECTORY_NAME: &str = ".influxdb3";
 /// The default bind address for the HTTP API.
 pub const DEFAULT_HTTP_BIND_ADDR: &str = "127.0.0.1:8181";
 
+/// The default bind address for the Arrow Flight gRPC API.
+pub const DEFAULT_GRPC_BIND_ADDR: &str = "127.0.0.1:8182";
+
+/// How often the write buffer checks whether the open segment is old enough to be closed.
+const SEGMENT_ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the write buffer deletes data that is past the retention period of its database.
+const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the write buffer looks for table partitions with enough parquet files to compact.
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Cannot parse object store config: {0}")]
@@ -48,6 +65,9 @@ pub enum Error {
 
     #[error("Wal error: {0}")]
     Wal(#[from] influxdb3_write::wal::Error),
+
+    #[error("Write buffer error: {0}")]
+    WriteBuffer(#[from] influxdb3_write::Error),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -72,6 +92,50 @@ pub struct Config {
     #[clap(long = "wal-directory", env = "INFLUXDB3_WAL_DIRECTORY", action)]
     pub wal_directory: Option<PathBuf>,
 
+    /// The size in bytes of line protocol written into a segment before it is closed and
+    /// persisted to object storage.
+    #[clap(
+    long = "segment-max-size",
+    env = "INFLUXDB3_SEGMENT_MAX_SIZE",
+    default_value = "268435456", // 256 MiB
+    action,
+    )]
+    pub segment_max_size: usize,
+
+    /// The amount of time after the first write into a segment before it is closed and
+    /// persisted to object storage.
+    #[clap(
+    long = "segment-duration",
+    env = "INFLUXDB3_SEGMENT_DURATION",
+    default_value = "1h",
+    value_parser = humantime::parse_duration,
+    action,
+    )]
+    pub segment_duration: Duration,
+
+    /// The limit on the memory used by data buffered in the write buffer, in bytes. Segments are
+    /// persisted early as the limit is approached, and writes are rejected with a 503 once it has
+    /// been reached.
+    ///
+    /// Can be given as absolute value or in percentage of the total available memory (e.g. `10%`).
+    #[clap(
+        long = "buffer-mem-limit",
+        env = "INFLUXDB3_BUFFER_MEM_LIMIT",
+        default_value = "50%",
+        action
+    )]
+    pub buffer_mem_limit: MemorySize,
+
+    /// The number of parquet files persisted for a table partition at which they are compacted
+    /// into a single file.
+    #[clap(
+        long = "compaction-min-files",
+        env = "INFLUXDB3_COMPACTION_MIN_FILES",
+        default_value = "10",
+        action
+    )]
+    pub compaction_min_files: usize,
+
     /// The address on which InfluxDB will serve HTTP API requests
     #[clap(
     long = "http-bind",
@@ -81,6 +145,22 @@ pub struct Config {
     )]
     pub http_bind_address: SocketAddr,
 
+    /// The address on which InfluxDB will serve Arrow Flight and Flight SQL gRPC requests
+    #[clap(
+    long = "grpc-bind",
+    env = "INFLUXDB3_GRPC_BIND_ADDR",
+    default_value = DEFAULT_GRPC_BIND_ADDR,
+    action,
+    )]
+    pub grpc_bind_address: SocketAddr,
+
+    /// The admin token, which is allowed to do anything, including creating and revoking the
+    /// tokens that are scoped to databases.
+    ///
+    /// If not specified, requests are not authorized.
+    #[clap(long = "admin-token", env = "INFLUXDB3_ADMIN_TOKEN", action)]
+    pub admin_token: Option<String>,
+
     /// Size of the RAM cache used to store data in bytes.
     ///
     /// Can be given as absolute value or in percentage of the total available memory (e.g. `10%`).
@@ -205,8 +285,10 @@ pub async fn command(config: Config) -> Result<()> {
         NonZeroUsize::new(num_cpus::get()).unwrap_or_else(|| NonZeroUsize::new(1).unwrap());
 
     info!(%num_threads, "Creating shared query executor");
-    let parquet_store =
-        ParquetStorage::new(Arc::clone(&object_store), StorageId::from("influxdb3"));
+    let parquet_store = ParquetStorage::new(
+        Arc::clone(&object_store),
+        StorageId::from(PARQUET_STORAGE_ID),
+    );
     let exec = Arc::new(Executor::new_with_config(ExecutorConfig {
         num_threads,
         target_query_partitions: num_threads,
@@ -231,15 +313,38 @@ pub async fn command(config: Config) -> Result<()> {
         trace_exporter,
         trace_header_parser,
         *config.http_bind_address,
+        *config.grpc_bind_address,
     );
-    let catalog = Arc::new(influxdb3_write::catalog::Catalog::new());
     let wal: Option<Arc<WalImpl>> = config
         .wal_directory
         .map(|dir| WalImpl::new(dir).map(Arc::new))
         .transpose()?;
-    let write_buffer = Arc::new(WriteBufferImpl::new(Arc::clone(&catalog), wal));
+    let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+    let segment_config = SegmentConfig {
+        max_size_bytes: config.segment_max_size,
+        max_duration: config.segment_duration,
+        max_buffer_memory_bytes: config.buffer_mem_limit.bytes(),
+    };
+    let write_buffer = Arc::new(
+        WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            wal,
+            Arc::new(SystemProvider::new()),
+            segment_config,
+        )
+        .await?,
+    );
+    write_buffer.start_segment_rotation_task(SEGMENT_ROTATION_CHECK_INTERVAL);
+    write_buffer.start_retention_task(RETENTION_CHECK_INTERVAL);
+    write_buffer.start_compaction_task(
+        Arc::clone(&exec),
+        CompactionConfig {
+            min_files: config.compaction_min_files,
+        },
+        COMPACTION_CHECK_INTERVAL,
+    );
     let query_executor = QueryExecutorImpl::new(
-        catalog,
+        write_buffer.catalog(),
         Arc::clone(&write_buffer),
         Arc::clone(&exec),
         Arc::clone(&metrics),
@@ -247,13 +352,20 @@ pub async fn command(config: Config) -> Result<()> {
         10,
     );
 
-    let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+    let authorizer = config
+        .admin_token
+        .map(|token| Arc::new(TokenAuthorizer::new(write_buffer.catalog(), &token)));
+    if authorizer.is_none() {
+        warn!("no admin token is set, requests are not authorized");
+    }
+
     let server = Server::new(
         common_state,
         persister,
         Arc::clone(&write_buffer),
         Arc::new(query_executor),
         config.max_http_request_size,
+        authorizer,
     );
     serve(server, frontend_shutdown).await?;
 
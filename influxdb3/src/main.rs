@@ -25,6 +25,7 @@ use trogging::{
 };
 
 mod commands {
+    pub mod migrate;
     pub mod serve;
 }
 
@@ -71,6 +72,12 @@ Examples:
 
     # Run InfluxDB 3.0 Edge with full debug logging specified with LOG_FILTER
     LOG_FILTER=debug influxdb3 serve
+
+    # Migrate the TSM files of InfluxDB 2.x into the object store of InfluxDB 3.0 Edge
+    influxdb3 migrate tsm --tsm-dir ~/.influxdbv2/engine/data --object-store file --data-dir ~/.influxdb3
+
+    # Migrate the TSM files of InfluxDB 1.x into the object store of InfluxDB 3.0 Edge
+    influxdb3 migrate tsm --source-version v1 --tsm-dir ~/.influxdb/data --object-store file --data-dir ~/.influxdb3
 "#
 )]
 struct Config {
@@ -82,6 +89,9 @@ struct Config {
 enum Command {
     /// Run the InfluxDB 3.0 server
     Serve(commands::serve::Config),
+
+    /// Migrate data from other versions of InfluxDB
+    Migrate(commands::migrate::Config),
 }
 
 fn main() -> Result<(), std::io::Error> {
@@ -114,6 +124,14 @@ fn main() -> Result<(), std::io::Error> {
                     std::process::exit(ReturnCode::Failure as _)
                 }
             }
+            Some(Command::Migrate(config)) => {
+                let _tracing_guard =
+                    handle_init_logs(init_logs_and_tracing(config.logging_config()));
+                if let Err(e) = commands::migrate::command(config).await {
+                    eprintln!("Migrate command failed: {e}");
+                    std::process::exit(ReturnCode::Failure as _)
+                }
+            }
         }
     });
 
@@ -0,0 +1,203 @@
+//! Authorization of requests with API tokens. The server is started with an admin token that
+//! can do anything, including managing the other tokens, which are stored as hashes in the
+//! catalog and grant read and write access to specific databases.
+
+use async_trait::async_trait;
+use authz::{Action, Authorizer, Error, Permission, Resource};
+use influxdb3_write::catalog::{Catalog, TokenAction, TokenInfo, TokenPermission};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// The prefix of tokens created by the server, so that they are easy to recognize.
+const TOKEN_PREFIX: &str = "apiv3_";
+
+/// The number of random bytes in a token.
+const TOKEN_BYTES: usize = 32;
+
+/// The number of random bytes in the id of a token.
+const TOKEN_ID_BYTES: usize = 8;
+
+/// An [`Authorizer`] that checks the tokens of requests against the admin token and the tokens
+/// in the catalog.
+#[derive(Debug)]
+pub struct TokenAuthorizer {
+    catalog: Arc<Catalog>,
+    admin_token_hash: String,
+}
+
+/// Who a request was made by.
+#[derive(Debug)]
+enum Principal {
+    Admin,
+    Token(TokenInfo),
+}
+
+impl TokenAuthorizer {
+    pub fn new(catalog: Arc<Catalog>, admin_token: &str) -> Self {
+        Self {
+            catalog,
+            admin_token_hash: hash_token(admin_token.as_bytes()),
+        }
+    }
+
+    fn authenticate(&self, token: Option<Vec<u8>>) -> Result<Principal, Error> {
+        let hash = hash_token(&token.ok_or(Error::NoToken)?);
+        if hash == self.admin_token_hash {
+            return Ok(Principal::Admin);
+        }
+
+        self.catalog
+            .token_by_hash(&hash)
+            .map(Principal::Token)
+            .ok_or(Error::InvalidToken)
+    }
+
+    /// Checks that the token is valid, regardless of what it is allowed to do.
+    pub(crate) fn authorize_any(&self, token: Option<Vec<u8>>) -> Result<(), Error> {
+        self.authenticate(token).map(|_| ())
+    }
+
+    /// Checks that the token is the admin token.
+    pub(crate) fn authorize_admin(&self, token: Option<Vec<u8>>) -> Result<(), Error> {
+        match self.authenticate(token)? {
+            Principal::Admin => Ok(()),
+            Principal::Token(_) => Err(Error::Forbidden),
+        }
+    }
+}
+
+#[async_trait]
+impl Authorizer for TokenAuthorizer {
+    async fn permissions(
+        &self,
+        token: Option<Vec<u8>>,
+        perms: &[Permission],
+    ) -> Result<Vec<Permission>, Error> {
+        let granted: Vec<_> = match self.authenticate(token)? {
+            Principal::Admin => perms.to_vec(),
+            Principal::Token(token) => perms
+                .iter()
+                .filter(|perm| token_grants(&token, perm))
+                .cloned()
+                .collect(),
+        };
+
+        if granted.is_empty() {
+            return Err(Error::Forbidden);
+        }
+        Ok(granted)
+    }
+}
+
+/// Returns true if the token has the permission. Reading the schema of a database only needs
+/// read access, and tokens can't create or delete anything.
+fn token_grants(token: &TokenInfo, perm: &Permission) -> bool {
+    let Permission::ResourceAction(Resource::Database(database), action) = perm;
+    let action = match action {
+        Action::Read | Action::ReadSchema => TokenAction::Read,
+        Action::Write => TokenAction::Write,
+        Action::Create | Action::Delete => return false,
+    };
+
+    token
+        .permissions
+        .iter()
+        .any(|perm| &perm.database == database && perm.actions.contains(&action))
+}
+
+/// Creates a new token with the given permissions. The token is returned along with the info
+/// to store in the catalog, which only has its hash.
+pub(crate) fn new_token(
+    description: Option<String>,
+    permissions: Vec<TokenPermission>,
+) -> (String, TokenInfo) {
+    let mut rng = rand::thread_rng();
+    let mut token = [0; TOKEN_BYTES];
+    rng.fill_bytes(&mut token);
+    let token = format!("{TOKEN_PREFIX}{}", hex::encode(token));
+    let mut id = [0; TOKEN_ID_BYTES];
+    rng.fill_bytes(&mut id);
+
+    let info = TokenInfo {
+        id: hex::encode(id),
+        description,
+        hash: hash_token(token.as_bytes()),
+        permissions,
+    };
+    (token, info)
+}
+
+fn hash_token(token: &[u8]) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(database: &str) -> Permission {
+        Permission::ResourceAction(Resource::Database(database.to_string()), Action::Read)
+    }
+
+    fn write(database: &str) -> Permission {
+        Permission::ResourceAction(Resource::Database(database.to_string()), Action::Write)
+    }
+
+    #[tokio::test]
+    async fn tokens_are_scoped_to_databases() {
+        let catalog = Arc::new(Catalog::new());
+        let authorizer = TokenAuthorizer::new(Arc::clone(&catalog), "admin");
+        let (token, info) = new_token(
+            None,
+            vec![TokenPermission {
+                database: "foo".to_string(),
+                actions: vec![TokenAction::Read],
+            }],
+        );
+        catalog.insert_token(info);
+        let token = Some(token.into_bytes());
+
+        assert_eq!(
+            authorizer
+                .permissions(token.clone(), &[read("foo")])
+                .await
+                .unwrap(),
+            vec![read("foo")]
+        );
+        assert!(matches!(
+            authorizer.permissions(token.clone(), &[write("foo")]).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            authorizer.permissions(token.clone(), &[read("bar")]).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            authorizer.authorize_admin(token.clone()),
+            Err(Error::Forbidden)
+        ));
+        authorizer.authorize_any(token).unwrap();
+
+        let admin = Some(b"admin".to_vec());
+        assert_eq!(
+            authorizer
+                .permissions(admin.clone(), &[write("bar")])
+                .await
+                .unwrap(),
+            vec![write("bar")]
+        );
+        authorizer.authorize_admin(admin).unwrap();
+
+        assert!(matches!(
+            authorizer
+                .permissions(Some(b"apiv3_unknown".to_vec()), &[read("foo")])
+                .await,
+            Err(Error::InvalidToken)
+        ));
+        assert!(matches!(
+            authorizer.permissions(None, &[read("foo")]).await,
+            Err(Error::NoToken)
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+//! The gRPC API, which serves the Arrow Flight and Flight SQL services so that clients can
+//! fetch query results as Arrow record batches.
+
+use crate::{CommonServerState, QueryExecutor, TokenAuthorizer};
+use authz::Authorizer;
+use observability_deps::tracing::info;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::server::TcpIncoming;
+use trace_http::metrics::{MetricFamily, RequestMetrics};
+use trace_http::tower::TraceLayer;
+
+const TRACE_SERVER_NAME: &str = "grpc_api";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to bind gRPC listener: {0}")]
+    Bind(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("error serving gRPC: {0}")]
+    Serving(#[from] tonic::transport::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+pub(crate) async fn serve<Q: QueryExecutor>(
+    common_state: CommonServerState,
+    query_executor: Arc<Q>,
+    authorizer: Option<Arc<TokenAuthorizer>>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let listener = TcpIncoming::new(common_state.grpc_addr, true, None).map_err(Error::Bind)?;
+    info!(bind_addr=%common_state.grpc_addr, "bound gRPC listener");
+
+    let req_metrics =
+        RequestMetrics::new(Arc::clone(&common_state.metrics), MetricFamily::GrpcServer);
+    let trace_layer = TraceLayer::new(
+        common_state.trace_header_parser.clone(),
+        Arc::new(req_metrics),
+        common_state.trace_collector(),
+        TRACE_SERVER_NAME,
+    );
+
+    // the same service handles both Flight and Flight SQL requests
+    let authorizer = authorizer.map(|authorizer| authorizer as Arc<dyn Authorizer>);
+    tonic::transport::Server::builder()
+        .layer(trace_layer)
+        .layer(tower_trailer::TrailerLayer::default())
+        .add_service(service_grpc_flight::make_server(query_executor, authorizer))
+        .serve_with_incoming_shutdown(listener, shutdown.cancelled())
+        .await?;
+
+    Ok(())
+}
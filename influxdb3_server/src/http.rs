@@ -1,25 +1,42 @@
 //! HTTP API service implementations for `server`
 
-use crate::{CommonServerState, QueryExecutor};
-use arrow::record_batch::RecordBatch;
-use arrow::util::pretty;
+mod format;
+mod v1;
+
+use crate::auth::new_token;
+use crate::http::format::QueryFormat;
+use crate::http::v1::{V1QueryForm, V1QueryParams};
+use crate::{CommonServerState, QueryExecutor, QueryExecutorError, QueryKind, TokenAuthorizer};
 use authz::http::AuthorizationHeaderExtension;
+use authz::{extract_token, Action, Authorizer, Permission, Resource};
 use bytes::{Bytes, BytesMut};
-use data_types::NamespaceName;
+use data_types::{ColumnType, NamespaceName};
 use futures::StreamExt;
-use hyper::header::CONTENT_ENCODING;
+use generated_types::influxdata::iox::partition_template::v1::PartitionTemplate;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER};
 use hyper::http::HeaderValue;
 use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use influxdb3_write::WriteBuffer;
+use influxdb3_write::catalog::{
+    DatabaseSchema, DistinctCacheDefinition, Error as CatalogError, LastCacheDefinition,
+    TableDefinition, TokenInfo, TokenPermission,
+};
+use influxdb3_write::write_buffer::Error as WriteBufferError;
+use influxdb3_write::{BufferedWriteRequest, Precision, WriteBuffer, WriteLineError};
+use influxdb_line_protocol::lint::{lint_lines, Diagnostic, LintOptions};
+use influxdb_line_protocol::stream::Lines;
+use influxdb_line_protocol::{SpannedError, StreamingParser};
 use iox_time::{SystemProvider, TimeProvider};
 use observability_deps::tracing::{debug, error, info};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::fmt::Debug;
+use std::io::Write;
 use std::num::NonZeroI32;
 use std::str::Utf8Error;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio_util::sync::CancellationToken;
 use tower::Layer;
@@ -114,21 +131,283 @@ pub enum Error {
     #[error("hyper http error: {0}")]
     Hyper(#[from] hyper::http::Error),
 
+    /// The query could not be planned or executed
+    #[error(transparent)]
+    Query(#[from] QueryExecutorError),
+
+    /// DataFusion error
+    #[error("datafusion error: {0}")]
+    DataFusion(#[from] datafusion::error::DataFusionError),
+
     /// WriteBuffer error
     #[error("write buffer error: {0}")]
     WriteBuffer(#[from] influxdb3_write::write_buffer::Error),
+
+    /// The token could not be verified.
+    #[error("error verifying token: {0}")]
+    Authz(authz::Error),
+
+    /// The request body is not valid JSON for the endpoint.
+    #[error("invalid json in request body: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// There is no token with the id to revoke.
+    #[error("token not found: {0}")]
+    TokenNotFound(String),
+
+    /// A column of a table to create is invalid.
+    #[error("invalid column {0}: every column needs a unique name other than time")]
+    InvalidColumn(String),
+
+    /// The retention period of a database to create is invalid.
+    #[error("invalid retention period {0}: it must be a positive duration like 30d or 12h")]
+    InvalidRetentionPeriod(String),
+
+    /// A database or table couldn't be created or deleted.
+    #[error("{0}")]
+    Configure(#[source] influxdb3_write::Error),
+
+    /// The catalog couldn't be persisted after a change to the tokens.
+    #[error("error persisting the catalog: {0}")]
+    PersistCatalog(#[source] influxdb3_write::Error),
+
+    /// A write that was linted has problems, so none of it was written
+    #[error("linting failed for write_lp endpoint:\n{}", format_diagnostics(.0))]
+    Lint(Vec<Diagnostic>),
+
+    /// Some lines of a write to a v1 or v2 compatible endpoint were not written
+    #[error("partial write: {}", format_invalid_lines(.0))]
+    PartialWrite(Vec<WriteLineError>),
+
+    /// An error from a v1 or v2 compatible write endpoint, which is returned in the format
+    /// that clients of that API expect
+    #[error("{source}")]
+    LegacyWrite {
+        api: LegacyWriteApi,
+        source: Box<Error>,
+    },
+}
+
+impl From<authz::Error> for Error {
+    fn from(source: authz::Error) -> Self {
+        match source {
+            authz::Error::NoToken | authz::Error::InvalidToken => Self::Unauthenticated,
+            authz::Error::Forbidden => Self::Forbidden,
+            authz::Error::Verification { .. } => Self::Authz(source),
+        }
+    }
 }
 
 impl Error {
     fn response(&self) -> Response<Body> {
-        let body = Body::from(self.to_string());
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(body)
-            .unwrap()
+        match self {
+            Self::WriteBuffer(WriteBufferError::ParseError(errors)) => {
+                let err = ErrorMessage {
+                    error: "parsing failed for write_lp endpoint".to_string(),
+                    data: Some(errors),
+                };
+                json_response(StatusCode::BAD_REQUEST, &err)
+            }
+            Self::Lint(diagnostics) => {
+                let err = ErrorMessage {
+                    error: self.to_string(),
+                    data: Some(
+                        diagnostics
+                            .iter()
+                            .map(WriteLineError::from)
+                            .collect::<Vec<_>>(),
+                    ),
+                };
+                json_response(StatusCode::BAD_REQUEST, &err)
+            }
+            Self::WriteBuffer(WriteBufferError::BufferFull { .. }) => {
+                with_retry_after(json_error_response(StatusCode::SERVICE_UNAVAILABLE, self))
+            }
+            Self::LegacyWrite { api, source } => source.legacy_write_response(*api),
+            Self::Query(e) => {
+                let status = match e {
+                    QueryExecutorError::DatabaseNotFound { .. } => StatusCode::NOT_FOUND,
+                    QueryExecutorError::QueryPlanning(_) => StatusCode::BAD_REQUEST,
+                    QueryExecutorError::ExecuteStream(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                json_error_response(status, e)
+            }
+            Self::DataFusion(_) => json_error_response(StatusCode::INTERNAL_SERVER_ERROR, self),
+            Self::MissingQueryParams
+            | Self::MissingWriteParams
+            | Self::Serde(_)
+            | Self::Json(_) => json_error_response(StatusCode::BAD_REQUEST, self),
+            Self::Unauthenticated => json_error_response(StatusCode::UNAUTHORIZED, self),
+            Self::Forbidden => json_error_response(StatusCode::FORBIDDEN, self),
+            Self::TokenNotFound(_) => json_error_response(StatusCode::NOT_FOUND, self),
+            Self::InvalidNamespaceName(_)
+            | Self::InvalidColumn(_)
+            | Self::InvalidRetentionPeriod(_) => json_error_response(StatusCode::BAD_REQUEST, self),
+            Self::Configure(e) => {
+                let status = match e {
+                    influxdb3_write::Error::WriteBuffer(WriteBufferError::Catalog(e)) => match e {
+                        CatalogError::DatabaseNotFound(_)
+                        | CatalogError::TableNotFound { .. }
+                        | CatalogError::LastCacheNotFound { .. }
+                        | CatalogError::DistinctCacheNotFound { .. } => StatusCode::NOT_FOUND,
+                        CatalogError::DatabaseExists(_)
+                        | CatalogError::TableExists { .. }
+                        | CatalogError::LastCacheExists { .. }
+                        | CatalogError::DistinctCacheExists { .. } => StatusCode::CONFLICT,
+                        CatalogError::InvalidPartitionTemplate(_)
+                        | CatalogError::InvalidRetentionPeriod(_)
+                        | CatalogError::InvalidLastCache(_)
+                        | CatalogError::InvalidDistinctCache(_) => StatusCode::BAD_REQUEST,
+                        _ => StatusCode::INTERNAL_SERVER_ERROR,
+                    },
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                json_error_response(status, self)
+            }
+            _ => {
+                let body = Body::from(self.to_string());
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(body)
+                    .unwrap()
+            }
+        }
+    }
+
+    fn into_legacy_write(self, api: LegacyWriteApi) -> Self {
+        Self::LegacyWrite {
+            api,
+            source: Box::new(self),
+        }
+    }
+
+    /// The response for an error from a v1 or v2 compatible write endpoint. The status codes
+    /// and JSON bodies match the ones returned by InfluxDB 1.x and 2.x.
+    fn legacy_write_response(&self, api: LegacyWriteApi) -> Response<Body> {
+        let status = match self {
+            Self::PartialWrite(_)
+            | Self::Lint(_)
+            | Self::WriteBuffer(WriteBufferError::ParseError(_))
+            | Self::MissingWriteParams
+            | Self::Serde(_)
+            | Self::InvalidNamespaceName(_)
+            | Self::NonUtf8Body(_)
+            | Self::NonUtf8ContentHeader(_)
+            | Self::InvalidContentEncoding(_)
+            | Self::InvalidGzip(_) => StatusCode::BAD_REQUEST,
+            Self::RequestSizeExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::RequestLimit | Self::WriteBuffer(WriteBufferError::BufferFull { .. }) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let message = self.to_string();
+
+        let response = match api {
+            LegacyWriteApi::V1 => json_response(status, &V1ErrorMessage { error: message }),
+            LegacyWriteApi::V2 => {
+                let code = match status {
+                    StatusCode::BAD_REQUEST => "invalid",
+                    StatusCode::PAYLOAD_TOO_LARGE => "request too large",
+                    StatusCode::UNAUTHORIZED => "unauthorized",
+                    StatusCode::FORBIDDEN => "forbidden",
+                    StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+                    _ => "internal error",
+                };
+                json_response(status, &V2ErrorMessage { code, message })
+            }
+        };
+        if matches!(self, Self::WriteBuffer(WriteBufferError::BufferFull { .. })) {
+            with_retry_after(response)
+        } else {
+            response
+        }
     }
 }
 
+/// The number of seconds clients are asked to wait before retrying a write that was shed because
+/// the write buffer is full, which gives the buffer time to persist data and free memory.
+const BUFFER_FULL_RETRY_AFTER_SECS: u64 = 5;
+
+fn with_retry_after(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, BUFFER_FULL_RETRY_AFTER_SECS.into());
+    response
+}
+
+/// Formats the invalid lines of a write the way InfluxDB 1.x reports lines it couldn't parse.
+fn format_invalid_lines(lines: &[WriteLineError]) -> String {
+    lines
+        .iter()
+        .map(|line| {
+            format!(
+                "unable to parse '{}': {}",
+                line.original_line, line.error_message
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Formats the problems of a linted write with the part of each line they are in.
+fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The InfluxDB API version of a write compatibility endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyWriteApi {
+    /// The InfluxDB 1.x `/write` endpoint
+    V1,
+    /// The InfluxDB 2.x `/api/v2/write` endpoint
+    V2,
+}
+
+/// The JSON body returned for errors, with any data about what went wrong.
+#[derive(Debug, Serialize)]
+struct ErrorMessage<T: Serialize> {
+    error: String,
+    data: Option<T>,
+}
+
+/// The JSON body returned for errors from the v1 write endpoint.
+#[derive(Debug, Serialize)]
+struct V1ErrorMessage {
+    error: String,
+}
+
+/// The JSON body returned for errors from the v2 write endpoint.
+#[derive(Debug, Serialize)]
+struct V2ErrorMessage {
+    code: &'static str,
+    message: String,
+}
+
+/// Returns a JSON body for an error that has no data about what went wrong.
+fn json_error_response(status: StatusCode, error: &impl std::error::Error) -> Response<Body> {
+    let err = ErrorMessage::<()> {
+        error: error.to_string(),
+        data: None,
+    };
+    json_response(status, &err)
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let body = serde_json::to_string(body).expect("error message should serialize to JSON");
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 const TRACE_SERVER_NAME: &str = "http_api";
@@ -139,6 +418,8 @@ pub(crate) struct HttpApi<W, Q> {
     write_buffer: Arc<W>,
     query_executor: Arc<Q>,
     max_request_bytes: usize,
+    /// Checks the tokens of requests. Every request is allowed if this isn't set.
+    authorizer: Option<Arc<TokenAuthorizer>>,
 }
 
 impl<W, Q> HttpApi<W, Q> {
@@ -147,12 +428,14 @@ impl<W, Q> HttpApi<W, Q> {
         write_buffer: Arc<W>,
         query_executor: Arc<Q>,
         max_request_bytes: usize,
+        authorizer: Option<Arc<TokenAuthorizer>>,
     ) -> Self {
         Self {
             common_state,
             write_buffer,
             query_executor,
             max_request_bytes,
+            authorizer,
         }
     }
 }
@@ -166,47 +449,191 @@ where
         let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
         let params: WriteParams = serde_urlencoded::from_str(query)?;
         info!("write_lp to {}", params.db);
+        self.authorize_request(&req, &params.db, Action::Write)
+            .await?;
 
-        let body = self.read_body(req).await?;
-        let body = std::str::from_utf8(&body).map_err(Error::NonUtf8Body)?;
+        let result = self.write_body_to_buffer(params, req).await?;
+
+        if result.invalid_lines.is_empty() {
+            return Ok(Response::new(Body::from("{}")));
+        }
+
+        // the write only fails if none of the lines could be written
+        let (status, error) = if result.line_count == 0 {
+            (StatusCode::BAD_REQUEST, "no lines were written")
+        } else {
+            (StatusCode::OK, "partial write of line protocol occurred")
+        };
+        let err = ErrorMessage {
+            error: error.to_string(),
+            data: Some(result.invalid_lines),
+        };
+
+        Ok(json_response(status, &err))
+    }
 
-        let database = NamespaceName::new(params.db)?;
+    /// Handles writes to the InfluxDB 1.x compatible `/write` endpoint. A retention policy is
+    /// written to the database `{db}/{rp}`.
+    async fn write_v1(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().unwrap_or_default();
+        let params: V1WriteParams = serde_urlencoded::from_str(query)?;
+        info!(db = %params.db, rp = ?params.rp, "write to v1 endpoint");
+        add_v1_credentials(&mut req, params.p.as_deref());
+
+        let params = WriteParams {
+            db: v1_database(params.db, params.rp),
+            accept_partial: true,
+            precision: params.precision,
+            lint: false,
+        };
 
+        self.write_legacy(params, req).await
+    }
+
+    /// Handles writes to the InfluxDB 2.x compatible `/api/v2/write` endpoint. The bucket is
+    /// the database that is written to and the org is ignored.
+    async fn write_v2(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().unwrap_or_default();
+        let params: V2WriteParams = serde_urlencoded::from_str(query)?;
+        info!(bucket = %params.bucket, org = ?params.org, "write to v2 endpoint");
+
+        let params = WriteParams {
+            db: params.bucket,
+            accept_partial: true,
+            precision: params.precision,
+            lint: false,
+        };
+
+        self.write_legacy(params, req).await
+    }
+
+    /// Writes the body of a request to a v1 or v2 endpoint. Like those APIs, the valid lines
+    /// are written even if some lines are invalid, but the write still fails.
+    async fn write_legacy(
+        &self,
+        params: WriteParams,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        self.authorize_request(&req, &params.db, Action::Write)
+            .await?;
+        let result = self.write_body_to_buffer(params, req).await?;
+
+        if !result.invalid_lines.is_empty() {
+            return Err(Error::PartialWrite(result.invalid_lines));
+        }
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    /// Reads the line protocol in the body of the request and writes it into the buffer.
+    async fn write_body_to_buffer(
+        &self,
+        params: WriteParams,
+        req: Request<Body>,
+    ) -> Result<BufferedWriteRequest> {
+        let mut body = self.read_lp_body(req).await?;
+
+        let database = NamespaceName::new(params.db.clone())?;
+
+        if params.lint {
+            let options =
+                LintOptions::default().with_timestamp_range(params.precision.timestamp_range());
+            let mut diagnostics = std::mem::take(&mut body.parse_errors);
+            diagnostics.extend(lint_lines(&body.lp, options));
+            if !diagnostics.is_empty() {
+                diagnostics.sort_by_key(|diagnostic| diagnostic.line_number);
+                return Err(Error::Lint(diagnostics));
+            }
+        }
+
+        let parse_errors: Vec<_> = body.parse_errors.iter().map(WriteLineError::from).collect();
+        if !params.accept_partial && !parse_errors.is_empty() {
+            return Err(WriteBufferError::ParseError(parse_errors).into());
+        }
+        self.write_lp_body(database, &body.lp, parse_errors, &params)
+            .await
+    }
+
+    /// Writes the lines of a body that parsed into the buffer, returning them with the errors of
+    /// the lines that didn't parse.
+    async fn write_lp_body(
+        &self,
+        database: NamespaceName<'static>,
+        lp: &str,
+        parse_errors: Vec<WriteLineError>,
+        params: &WriteParams,
+    ) -> Result<BufferedWriteRequest> {
         // TODO: use the time provider
         let default_time = SystemProvider::new().now().timestamp_nanos();
 
-        self.write_buffer
-            .write_lp(database, body, default_time)
+        let mut result = self
+            .write_buffer
+            .write_lp(
+                database,
+                lp,
+                default_time,
+                params.accept_partial,
+                params.precision,
+            )
             .await?;
-
-        Ok(Response::new(Body::from("{}")))
+        result.invalid_lines.extend(parse_errors);
+        result.invalid_lines.sort_by_key(|error| error.line_number);
+        Ok(result)
     }
 
-    async fn query_sql(&self, req: Request<Body>) -> Result<Response<Body>> {
+    async fn query(&self, req: Request<Body>, kind: QueryKind) -> Result<Response<Body>> {
         let query = req.uri().query().ok_or(Error::MissingQueryParams)?;
-        let params: QuerySqlParams = serde_urlencoded::from_str(query)?;
-
-        println!("query_sql {:?}", params);
+        let params: QueryParams = serde_urlencoded::from_str(query)?;
+        // the format parameter takes precedence over the accept header
+        let format = params
+            .format
+            .or_else(|| QueryFormat::from_accept_header(&req))
+            .unwrap_or_default();
+
+        info!(db = %params.db, q = %params.q, ?kind, ?format, "query");
+        self.authorize_request(&req, &params.db, Action::Read)
+            .await?;
 
-        let result = self
+        let stream = self
             .query_executor
-            .query(&params.db, &params.q, None, None)
-            .await
-            .unwrap();
+            .query(&params.db, &params.q, kind, None, None)
+            .await?;
 
-        let batches: Vec<RecordBatch> = result
-            .collect::<Vec<datafusion::common::Result<RecordBatch>>>()
-            .await
-            .into_iter()
-            .map(|b| b.unwrap())
-            .collect();
-        let pretty_string = format!("{}", pretty::pretty_format_batches(&batches)?);
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, format.content_type())
+            .body(format.stream_body(stream).await?)?)
+    }
+
+    /// Handles InfluxQL queries to the InfluxDB 1.x compatible `/query` endpoint, returning the
+    /// results as JSON series. The query can be sent in the URL or in a form encoded body.
+    async fn query_v1(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().unwrap_or_default();
+        let params: V1QueryParams = serde_urlencoded::from_str(query)?;
+        add_v1_credentials(&mut req, params.p.as_deref());
+        let db = v1_database(params.db.clone(), params.rp.clone());
+        self.authorize_request(&req, &db, Action::Read).await?;
+        let q = match &params.q {
+            Some(q) => q.clone(),
+            None => {
+                let body = self.read_body(req).await?;
+                serde_urlencoded::from_bytes::<V1QueryForm>(&body)?.q
+            }
+        };
+        info!(%db, %q, "query to v1 endpoint");
+
+        let stream = self
+            .query_executor
+            .query(&db, &q, QueryKind::InfluxQl, None, None)
+            .await?;
+        let body = v1::stream_body(stream, params.epoch, params.chunk_size()).await?;
 
-        // Create a response with the pretty-printed string as the body.
         Ok(Response::builder()
             .status(StatusCode::OK)
-            .header("Content-Type", "text/plain; charset=utf-8")
-            .body(Body::from(pretty_string))?) // Handle this unwrap in production.
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)?)
     }
 
     fn health(&self) -> Result<Response<Body>> {
@@ -214,7 +641,11 @@ where
         Ok(Response::new(Body::from(response_body.to_string())))
     }
 
-    fn handle_metrics(&self) -> Result<Response<Body>> {
+    fn handle_metrics(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        if let Some(authorizer) = &self.authorizer {
+            authorizer.authorize_any(request_token(req))?;
+        }
+
         let mut body: Vec<u8> = Default::default();
         let mut reporter = metric_exporters::PrometheusTextEncoder::new(&mut body);
         self.common_state.metrics.report(&mut reporter);
@@ -222,19 +653,349 @@ where
         Ok(Response::new(Body::from(body)))
     }
 
+    /// Creates an API token with the permissions in the request body. The response has the
+    /// token, which can't be retrieved later as only its hash is stored.
+    async fn create_token(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let body = self.read_body(req).await?;
+        let request: CreateTokenRequest = serde_json::from_slice(&body)?;
+
+        let (token, info) = new_token(request.description, request.permissions);
+        info!(id = %info.id, "creating token");
+        let response = CreatedToken {
+            token,
+            info: TokenSummary::from(&info),
+        };
+        let catalog = self.write_buffer.catalog();
+        catalog.insert_token(info);
+        self.write_buffer
+            .persist_catalog()
+            .await
+            .map_err(Error::PersistCatalog)?;
+
+        Ok(json_response(StatusCode::CREATED, &response))
+    }
+
+    fn list_tokens(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(req)?;
+        let tokens: Vec<_> = self
+            .write_buffer
+            .catalog()
+            .tokens()
+            .iter()
+            .map(TokenSummary::from)
+            .collect();
+
+        Ok(json_response(StatusCode::OK, &tokens))
+    }
+
+    async fn revoke_token(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let query = req.uri().query().unwrap_or_default();
+        let params: RevokeTokenParams = serde_urlencoded::from_str(query)?;
+        info!(id = %params.id, "revoking token");
+
+        self.write_buffer
+            .catalog()
+            .revoke_token(&params.id)
+            .ok_or(Error::TokenNotFound(params.id))?;
+        self.write_buffer
+            .persist_catalog()
+            .await
+            .map_err(Error::PersistCatalog)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    async fn create_database(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let body = self.read_body(req).await?;
+        let request: CreateDatabaseRequest = serde_json::from_slice(&body)?;
+        let db = NamespaceName::new(request.db)?;
+        let retention_period_ns = request
+            .retention_period
+            .map(|period| parse_retention_period(&period))
+            .transpose()?;
+        info!(%db, ?retention_period_ns, "creating database");
+
+        self.write_buffer
+            .create_database(db.as_str(), request.partition_template, retention_period_ns)
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .body(Body::empty())?)
+    }
+
+    fn list_databases(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(req)?;
+        let databases: Vec<_> = self
+            .write_buffer
+            .catalog()
+            .databases()
+            .iter()
+            .map(|db| DatabaseSummary::from(db.as_ref()))
+            .collect();
+
+        Ok(json_response(StatusCode::OK, &databases))
+    }
+
+    /// Deletes a database along with all of its data.
+    async fn delete_database(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let query = req.uri().query().unwrap_or_default();
+        let params: DatabaseParams = serde_urlencoded::from_str(query)?;
+        info!(db = %params.db, "deleting database");
+
+        self.write_buffer
+            .delete_database(&params.db)
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    /// Creates a table in an existing database, so that its schema is set before anything is
+    /// written to it.
+    async fn create_table(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let body = self.read_body(req).await?;
+        let request: CreateTableRequest = serde_json::from_slice(&body)?;
+        info!(db = %request.db, table = %request.table, "creating table");
+
+        let tags = request.tags.into_iter().map(|name| (name, ColumnType::Tag));
+        let fields = request
+            .fields
+            .into_iter()
+            .map(|field| (field.name, field.field_type.into()));
+        let mut columns = BTreeMap::new();
+        for (name, column_type) in tags.chain(fields) {
+            if name == "time" || columns.contains_key(&name) {
+                return Err(Error::InvalidColumn(name));
+            }
+            columns.insert(name, column_type);
+        }
+
+        self.write_buffer
+            .create_table(
+                &request.db,
+                &request.table,
+                columns,
+                request.partition_template,
+            )
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .body(Body::empty())?)
+    }
+
+    fn list_tables(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(req)?;
+        let query = req.uri().query().unwrap_or_default();
+        let params: DatabaseParams = serde_urlencoded::from_str(query)?;
+
+        let db = self
+            .write_buffer
+            .catalog()
+            .db_schema(&params.db)
+            .ok_or(Error::Configure(influxdb3_write::Error::WriteBuffer(
+                WriteBufferError::Catalog(CatalogError::DatabaseNotFound(params.db)),
+            )))?;
+        let tables: Vec<_> = db.tables().map(TableSummary::from).collect();
+
+        Ok(json_response(StatusCode::OK, &tables))
+    }
+
+    /// Deletes a table along with all of its data.
+    async fn delete_table(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let query = req.uri().query().unwrap_or_default();
+        let params: TableParams = serde_urlencoded::from_str(query)?;
+        info!(db = %params.db, table = %params.table, "deleting table");
+
+        self.write_buffer
+            .delete_table(&params.db, &params.table)
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    /// Creates a last cache for a table. The response has the definition of the cache, which
+    /// includes the name it was given if the request didn't have one.
+    async fn create_last_cache(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let body = self.read_body(req).await?;
+        let request: CreateLastCacheRequest = serde_json::from_slice(&body)?;
+        info!(
+            db = %request.db,
+            table = %request.table,
+            name = ?request.name,
+            "creating last cache"
+        );
+
+        let definition = self
+            .write_buffer
+            .create_last_cache(
+                &request.db,
+                &request.table,
+                request.name.as_deref(),
+                request.key_columns,
+                request.value_columns,
+                request.count,
+            )
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(json_response(StatusCode::CREATED, &definition))
+    }
+
+    async fn delete_last_cache(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let query = req.uri().query().unwrap_or_default();
+        let params: CacheParams = serde_urlencoded::from_str(query)?;
+        info!(
+            db = %params.db,
+            table = %params.table,
+            name = %params.name,
+            "deleting last cache"
+        );
+
+        self.write_buffer
+            .delete_last_cache(&params.db, &params.table, &params.name)
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    /// Creates a distinct value cache for a table, which is seeded with the values already
+    /// written within its max age. The response has the definition of the cache.
+    async fn create_distinct_cache(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let body = self.read_body(req).await?;
+        let request: CreateDistinctCacheRequest = serde_json::from_slice(&body)?;
+        info!(
+            db = %request.db,
+            table = %request.table,
+            name = ?request.name,
+            "creating distinct cache"
+        );
+
+        let definition = self
+            .write_buffer
+            .create_distinct_cache(
+                &request.db,
+                &request.table,
+                request.name.as_deref(),
+                request.columns,
+                request.max_cardinality,
+                request.max_age_seconds,
+            )
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(json_response(StatusCode::CREATED, &definition))
+    }
+
+    async fn delete_distinct_cache(&self, req: Request<Body>) -> Result<Response<Body>> {
+        self.authorize_admin(&req)?;
+        let query = req.uri().query().unwrap_or_default();
+        let params: CacheParams = serde_urlencoded::from_str(query)?;
+        info!(
+            db = %params.db,
+            table = %params.table,
+            name = %params.name,
+            "deleting distinct cache"
+        );
+
+        self.write_buffer
+            .delete_distinct_cache(&params.db, &params.table, &params.name)
+            .await
+            .map_err(Error::Configure)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    /// Checks that the token of the request allows the action on the database.
+    async fn authorize_request(
+        &self,
+        req: &Request<Body>,
+        database: &str,
+        action: Action,
+    ) -> Result<()> {
+        let Some(authorizer) = &self.authorizer else {
+            return Ok(());
+        };
+
+        let perms = [Permission::ResourceAction(
+            Resource::Database(database.to_string()),
+            action,
+        )];
+        authorizer.permissions(request_token(req), &perms).await?;
+        Ok(())
+    }
+
+    fn authorize_admin(&self, req: &Request<Body>) -> Result<()> {
+        match &self.authorizer {
+            Some(authorizer) => Ok(authorizer.authorize_admin(request_token(req))?),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the line protocol in the body of a write as it is received, parsing each line as
+    /// soon as the chunk that completes it arrives. The size limit applies to the decoded body.
+    async fn read_lp_body(&self, req: hyper::Request<Body>) -> Result<LpBody> {
+        let mut decoder = is_gzip(&req)?.then(|| flate2::write::GzDecoder::new(Vec::new()));
+        let mut parser = StreamingParser::new();
+        let mut body = LpBody::default();
+        let mut body_len = 0;
+
+        let mut payload = req.into_body();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(Error::ClientHangup)?;
+            let chunk = match &mut decoder {
+                Some(decoder) => {
+                    decoder.write_all(&chunk).map_err(Error::InvalidGzip)?;
+                    Bytes::from(std::mem::take(decoder.get_mut()))
+                }
+                None => chunk,
+            };
+            body_len += chunk.len();
+            if body_len > self.max_request_bytes {
+                return Err(Error::RequestSizeExceeded(self.max_request_bytes));
+            }
+            body.add_lines(parser.push(&chunk));
+        }
+        if let Some(decoder) = decoder {
+            let rest = decoder.finish().map_err(Error::InvalidGzip)?;
+            if body_len + rest.len() > self.max_request_bytes {
+                return Err(Error::RequestSizeExceeded(self.max_request_bytes));
+            }
+            body.add_lines(parser.push(&rest));
+        }
+        body.add_lines(parser.finish());
+
+        Ok(body)
+    }
+
     /// Parse the request's body into raw bytes, applying the configured size
     /// limits and decoding any content encoding.
     async fn read_body(&self, req: hyper::Request<Body>) -> Result<Bytes> {
-        let encoding = req
-            .headers()
-            .get(&CONTENT_ENCODING)
-            .map(|v| v.to_str().map_err(Error::NonUtf8ContentHeader))
-            .transpose()?;
-        let ungzip = match encoding {
-            None | Some("identity") => false,
-            Some("gzip") => true,
-            Some(v) => return Err(Error::InvalidContentEncoding(v.to_string())),
-        };
+        let ungzip = is_gzip(&req)?;
 
         let mut payload = req.into_body();
 
@@ -280,15 +1041,370 @@ where
     }
 }
 
+/// Returns whether the body of a request is gzip encoded, failing for other encodings.
+fn is_gzip(req: &Request<Body>) -> Result<bool> {
+    let encoding = req
+        .headers()
+        .get(&CONTENT_ENCODING)
+        .map(|v| v.to_str().map_err(Error::NonUtf8ContentHeader))
+        .transpose()?;
+    match encoding {
+        None | Some("identity") => Ok(false),
+        Some("gzip") => Ok(true),
+        Some(v) => Err(Error::InvalidContentEncoding(v.to_string())),
+    }
+}
+
+/// The line protocol in the body of a write.
+#[derive(Debug, Default)]
+struct LpBody {
+    /// The lines that parsed. The other lines are left blank so that every line keeps its number.
+    lp: String,
+    /// The number of lines in `lp`
+    line_count: usize,
+    /// The errors of the lines that didn't parse, in line order
+    parse_errors: Vec<Diagnostic>,
+}
+
+impl LpBody {
+    fn add_lines(&mut self, mut lines: Lines<'_>) {
+        while let Some((line_number, line, parsed)) = lines.next_line() {
+            match parsed {
+                Ok(_) => {
+                    // blank lines, comments and lines that didn't parse aren't returned
+                    while self.line_count + 1 < line_number {
+                        self.lp.push('\n');
+                        self.line_count += 1;
+                    }
+                    self.lp
+                        .push_str(std::str::from_utf8(line).expect("lines that parse are UTF-8"));
+                    self.lp.push('\n');
+                    self.line_count += 1;
+                }
+                Err(e) => {
+                    let error = SpannedError {
+                        source: e.source,
+                        span: e.span,
+                    };
+                    self.parse_errors.push(Diagnostic::from_parse_error(
+                        e.line_number,
+                        &e.line,
+                        error,
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Returns the token from the authorization header that `route_request` moved into the
+/// extensions of the request.
+fn request_token(req: &Request<Body>) -> Option<Vec<u8>> {
+    req.extensions()
+        .get::<AuthorizationHeaderExtension>()
+        .and_then(|header| extract_token(header.as_ref()))
+}
+
+/// Uses the password of the InfluxDB 1.x `u` and `p` query parameters as the token of a request
+/// that has no authorization header. The user name is ignored.
+fn add_v1_credentials(req: &mut Request<Body>, password: Option<&str>) {
+    let has_header = req
+        .extensions()
+        .get::<AuthorizationHeaderExtension>()
+        .is_some_and(|header| header.is_some());
+    let Some(header) = password
+        .filter(|password| !has_header && !password.is_empty())
+        .and_then(|password| HeaderValue::from_str(&format!("Token {password}")).ok())
+    else {
+        return;
+    };
+    req.extensions_mut()
+        .insert(AuthorizationHeaderExtension::new(Some(header)));
+}
+
+/// The body of a request to create a token.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateTokenRequest {
+    pub(crate) description: Option<String>,
+    pub(crate) permissions: Vec<TokenPermission>,
+}
+
+/// A token as it is listed by the API, without its hash.
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenSummary {
+    pub(crate) id: String,
+    pub(crate) description: Option<String>,
+    pub(crate) permissions: Vec<TokenPermission>,
+}
+
+impl From<&TokenInfo> for TokenSummary {
+    fn from(info: &TokenInfo) -> Self {
+        Self {
+            id: info.id.clone(),
+            description: info.description.clone(),
+            permissions: info.permissions.clone(),
+        }
+    }
+}
+
+/// The response to creating a token, which is the only time the token is returned.
+#[derive(Debug, Serialize)]
+pub(crate) struct CreatedToken {
+    pub(crate) token: String,
+    #[serde(flatten)]
+    pub(crate) info: TokenSummary,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RevokeTokenParams {
+    pub(crate) id: String,
+}
+
+/// The body of a request to create a database.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateDatabaseRequest {
+    pub(crate) db: String,
+    /// How tables in the database are partitioned, which is by day if it isn't set
+    pub(crate) partition_template: Option<PartitionTemplate>,
+    /// How long data is kept for, like `30d` or `12h`, which is forever if it isn't set
+    pub(crate) retention_period: Option<String>,
+}
+
+/// Parses a retention period like `30d` into nanoseconds.
+fn parse_retention_period(period: &str) -> Result<i64> {
+    let invalid = || Error::InvalidRetentionPeriod(period.to_string());
+    let duration = humantime::parse_duration(period).map_err(|_| invalid())?;
+    i64::try_from(duration.as_nanos())
+        .ok()
+        .filter(|nanos| *nanos > 0)
+        .ok_or_else(invalid)
+}
+
+/// The body of a request to create a table. The table gets a time column along with the tag
+/// and field columns.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateTableRequest {
+    pub(crate) db: String,
+    pub(crate) table: String,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    #[serde(default)]
+    pub(crate) fields: Vec<FieldDefinition>,
+    /// How the table is partitioned, which is the same as its database if it isn't set
+    pub(crate) partition_template: Option<PartitionTemplate>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct FieldDefinition {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) field_type: FieldType,
+}
+
+/// The type of a field column, named the same as the column types listed by the API.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FieldType {
+    I64,
+    U64,
+    F64,
+    Bool,
+    String,
+}
+
+impl From<FieldType> for ColumnType {
+    fn from(field_type: FieldType) -> Self {
+        match field_type {
+            FieldType::I64 => Self::I64,
+            FieldType::U64 => Self::U64,
+            FieldType::F64 => Self::F64,
+            FieldType::Bool => Self::Bool,
+            FieldType::String => Self::String,
+        }
+    }
+}
+
+/// The body of a request to create a last cache for a table.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateLastCacheRequest {
+    pub(crate) db: String,
+    pub(crate) table: String,
+    /// The name of the cache, which is made from the table and key columns if it isn't set
+    pub(crate) name: Option<String>,
+    /// The tags that the last values are kept for each combination of, which are all the tags
+    /// of the table if it isn't set
+    pub(crate) key_columns: Option<Vec<String>>,
+    /// The columns that are cached, which are all the columns that aren't keys if it isn't set
+    pub(crate) value_columns: Option<Vec<String>>,
+    /// How many of the last values are kept for each combination of keys, which is 1 if it
+    /// isn't set
+    pub(crate) count: Option<usize>,
+}
+
+/// The body of a request to create a distinct value cache for a table.
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateDistinctCacheRequest {
+    pub(crate) db: String,
+    pub(crate) table: String,
+    /// The name of the cache, which is made from the table and columns if it isn't set
+    pub(crate) name: Option<String>,
+    /// The tags that are cached, from the top of the hierarchy down
+    pub(crate) columns: Vec<String>,
+    /// The most combinations of values that are kept
+    pub(crate) max_cardinality: Option<usize>,
+    /// How long combinations of values are kept after they were last written
+    pub(crate) max_age_seconds: Option<u64>,
+}
+
+/// Identifies a cache of a table to delete.
 #[derive(Debug, Deserialize)]
-pub(crate) struct QuerySqlParams {
+pub(crate) struct CacheParams {
+    pub(crate) db: String,
+    pub(crate) table: String,
+    pub(crate) name: String,
+}
+
+/// A database as it is listed by the API.
+#[derive(Debug, Serialize)]
+pub(crate) struct DatabaseSummary {
+    pub(crate) name: String,
+    pub(crate) tables: Vec<String>,
+    pub(crate) partition_template: Option<PartitionTemplate>,
+    pub(crate) retention_period: Option<String>,
+}
+
+impl From<&DatabaseSchema> for DatabaseSummary {
+    fn from(db: &DatabaseSchema) -> Self {
+        Self {
+            name: db.name.clone(),
+            tables: db.table_names(),
+            partition_template: db.partition_template().as_proto().cloned(),
+            retention_period: db.retention_period_ns().map(|period| {
+                humantime::format_duration(Duration::from_nanos(period as u64)).to_string()
+            }),
+        }
+    }
+}
+
+/// A table as it is listed by the API.
+#[derive(Debug, Serialize)]
+pub(crate) struct TableSummary {
+    pub(crate) name: String,
+    pub(crate) columns: Vec<ColumnSummary>,
+    pub(crate) partition_template: Option<PartitionTemplate>,
+    pub(crate) last_caches: Vec<LastCacheDefinition>,
+    pub(crate) distinct_caches: Vec<DistinctCacheDefinition>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ColumnSummary {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) column_type: &'static str,
+}
+
+impl From<&TableDefinition> for TableSummary {
+    fn from(table: &TableDefinition) -> Self {
+        Self {
+            name: table.name.clone(),
+            columns: table
+                .column_types()
+                .map(|(name, column_type)| ColumnSummary {
+                    name: name.to_string(),
+                    column_type: column_type.as_str(),
+                })
+                .collect(),
+            partition_template: table.partition_template().as_proto().cloned(),
+            last_caches: table.last_caches().cloned().collect(),
+            distinct_caches: table.distinct_caches().cloned().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DatabaseParams {
+    pub(crate) db: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TableParams {
+    pub(crate) db: String,
+    pub(crate) table: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct QueryParams {
     pub(crate) db: String,
     pub(crate) q: String,
+    /// The format to return results in, which is otherwise chosen from the `Accept` header
+    pub(crate) format: Option<QueryFormat>,
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct WriteParams {
     pub(crate) db: String,
+    /// Whether the valid lines of a write are accepted when other lines have errors. If
+    /// false, nothing is written if any line has an error.
+    #[serde(default = "WriteParams::default_accept_partial")]
+    pub(crate) accept_partial: bool,
+    /// The precision of the timestamps in the line protocol, which defaults to nanoseconds.
+    #[serde(default, deserialize_with = "deserialize_precision")]
+    pub(crate) precision: Precision,
+    /// Whether the write is checked for problems beyond lines that don't parse, like columns with
+    /// conflicting types, before it is written. If any line has a problem, nothing is written and
+    /// the problems of every line are returned.
+    #[serde(default)]
+    pub(crate) lint: bool,
+}
+
+impl WriteParams {
+    fn default_accept_partial() -> bool {
+        true
+    }
+}
+
+/// Returns the database for a v1 database and retention policy, which is `{db}/{rp}` if there
+/// is a retention policy.
+fn v1_database(db: String, rp: Option<String>) -> String {
+    match rp {
+        Some(rp) if !rp.is_empty() => format!("{db}/{rp}"),
+        _ => db,
+    }
+}
+
+/// The parameters of a write to the InfluxDB 1.x compatible `/write` endpoint.
+#[derive(Debug, Deserialize)]
+pub(crate) struct V1WriteParams {
+    pub(crate) db: String,
+    pub(crate) rp: Option<String>,
+    /// Unlike the other write APIs, minute and hour precisions are accepted.
+    #[serde(default)]
+    pub(crate) precision: Precision,
+    /// The password of the 1.x credentials, which is the token of the write
+    pub(crate) p: Option<String>,
+}
+
+/// The parameters of a write to the InfluxDB 2.x compatible `/api/v2/write` endpoint.
+#[derive(Debug, Deserialize)]
+pub(crate) struct V2WriteParams {
+    pub(crate) bucket: String,
+    pub(crate) org: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_precision")]
+    pub(crate) precision: Precision,
+}
+
+/// Deserializes the precision of a write to an endpoint other than the InfluxDB 1.x `/write`,
+/// which doesn't accept minute and hour precisions.
+fn deserialize_precision<'de, D>(deserializer: D) -> Result<Precision, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Precision::deserialize(deserializer)? {
+        precision @ (Precision::Minute | Precision::Hour) => Err(serde::de::Error::custom(
+            format!("precision {precision:?} is only supported by the v1 write API"),
+        )),
+        precision => Ok(precision),
+    }
 }
 
 pub(crate) async fn serve<W: WriteBuffer, Q: QueryExecutor>(
@@ -344,9 +1460,42 @@ async fn route_request<W: WriteBuffer, Q: QueryExecutor>(
 
     let response = match (method.clone(), uri.path()) {
         (Method::POST, "/api/v3/write_lp") => http_server.write_lp(req).await,
-        (Method::GET | Method::POST, "/api/v3/query_sql") => http_server.query_sql(req).await,
+        (Method::POST, "/write") => http_server
+            .write_v1(req)
+            .await
+            .map_err(|e| e.into_legacy_write(LegacyWriteApi::V1)),
+        (Method::POST, "/api/v2/write") => http_server
+            .write_v2(req)
+            .await
+            .map_err(|e| e.into_legacy_write(LegacyWriteApi::V2)),
+        (Method::GET | Method::POST, "/api/v3/query_sql") => {
+            http_server.query(req, QueryKind::Sql).await
+        }
+        (Method::GET | Method::POST, "/api/v3/query_influxql") => {
+            http_server.query(req, QueryKind::InfluxQl).await
+        }
+        (Method::GET | Method::POST, "/query") => http_server.query_v1(req).await,
         (Method::GET, "/health") => http_server.health(),
-        (Method::GET, "/metrics") => http_server.handle_metrics(),
+        (Method::GET, "/metrics") => http_server.handle_metrics(&req),
+        (Method::POST, "/api/v3/configure/token") => http_server.create_token(req).await,
+        (Method::GET, "/api/v3/configure/token") => http_server.list_tokens(&req),
+        (Method::DELETE, "/api/v3/configure/token") => http_server.revoke_token(req).await,
+        (Method::POST, "/api/v3/configure/database") => http_server.create_database(req).await,
+        (Method::GET, "/api/v3/configure/database") => http_server.list_databases(&req),
+        (Method::DELETE, "/api/v3/configure/database") => http_server.delete_database(req).await,
+        (Method::POST, "/api/v3/configure/table") => http_server.create_table(req).await,
+        (Method::GET, "/api/v3/configure/table") => http_server.list_tables(&req),
+        (Method::DELETE, "/api/v3/configure/table") => http_server.delete_table(req).await,
+        (Method::POST, "/api/v3/configure/last_cache") => http_server.create_last_cache(req).await,
+        (Method::DELETE, "/api/v3/configure/last_cache") => {
+            http_server.delete_last_cache(req).await
+        }
+        (Method::POST, "/api/v3/configure/distinct_cache") => {
+            http_server.create_distinct_cache(req).await
+        }
+        (Method::DELETE, "/api/v3/configure/distinct_cache") => {
+            http_server.delete_distinct_cache(req).await
+        }
         (Method::GET, "/debug/pprof") => pprof_home(req).await,
         (Method::GET, "/debug/pprof/profile") => pprof_profile(req).await,
         (Method::GET, "/debug/pprof/allocs") => pprof_heappy_profile(req).await,
@@ -0,0 +1,246 @@
+//! Output formats for the results of queries. Results are written out as each record batch
+//! arrives from the query, except for the pretty format, which needs every batch to size the
+//! columns of the table.
+//!
+//! The first record batch is read before the response is sent, so errors from running the query
+//! are usually returned with an error status. Errors after that are logged and, for the text
+//! formats, written as a JSON error object at the end of the body.
+
+use arrow::csv;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use arrow::util::pretty;
+use arrow_json::{ArrayWriter, LineDelimitedWriter};
+use arrow_schema::SchemaRef;
+use bytes::Bytes;
+use datafusion::error::DataFusionError;
+use datafusion::execution::SendableRecordBatchStream;
+use futures::StreamExt;
+use hyper::header::ACCEPT;
+use hyper::{Body, Request};
+use observability_deps::tracing::error;
+use parking_lot::Mutex;
+use parquet::arrow::ArrowWriter;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::io::Write;
+use std::sync::Arc;
+
+/// The format that query results are returned in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QueryFormat {
+    /// An ASCII table for humans to read
+    #[default]
+    Pretty,
+    /// A JSON array with an object for each row
+    Json,
+    /// A JSON object for each row, separated by newlines
+    Jsonl,
+    /// Comma separated values with a header row
+    Csv,
+    /// A parquet file
+    Parquet,
+    /// The Arrow IPC streaming format
+    Arrow,
+}
+
+impl QueryFormat {
+    /// Returns the first format in the `Accept` header of the request that results can be
+    /// returned in, or `None` if there isn't one.
+    pub(crate) fn from_accept_header(req: &Request<Body>) -> Option<Self> {
+        let accept = req.headers().get(ACCEPT)?.to_str().ok()?;
+        accept.split(',').find_map(|media_range| {
+            // parameters like the quality are ignored, the formats are tried in order
+            let media_type = media_range.split(';').next()?.trim();
+            match media_type {
+                "text/plain" => Some(Self::Pretty),
+                "application/json" => Some(Self::Json),
+                "application/jsonl" | "application/x-ndjson" => Some(Self::Jsonl),
+                "text/csv" => Some(Self::Csv),
+                "application/vnd.apache.parquet" => Some(Self::Parquet),
+                "application/vnd.apache.arrow.stream" => Some(Self::Arrow),
+                _ => None,
+            }
+        })
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            Self::Pretty => "text/plain; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Jsonl => "application/jsonl",
+            Self::Csv => "text/csv",
+            Self::Parquet => "application/vnd.apache.parquet",
+            Self::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+
+    /// Returns a body that writes out the record batches of the stream in this format as they
+    /// are produced by the query. Returns an error if the query fails before producing its
+    /// first record batch.
+    pub(crate) async fn stream_body(
+        self,
+        mut stream: SendableRecordBatchStream,
+    ) -> Result<Body, DataFusionError> {
+        let buffer = SharedBuffer::default();
+        let writer = BatchWriter::try_new(self, stream.schema(), buffer.clone())?;
+
+        let first_batch = stream.next().await.transpose()?;
+        let stream = futures::stream::iter(first_batch.map(Ok)).chain(stream);
+
+        let body =
+            futures::stream::unfold(Some((stream, writer, buffer)), move |state| async move {
+                let (mut stream, mut writer, buffer) = state?;
+                let error = match stream.next().await {
+                    Some(Ok(batch)) => match writer.write(batch) {
+                        Ok(()) => return Some((buffer.take(), Some((stream, writer, buffer)))),
+                        Err(e) => e,
+                    },
+                    Some(Err(e)) => e,
+                    None => match writer.finish() {
+                        Ok(()) => return Some((buffer.take(), None)),
+                        Err(e) => e,
+                    },
+                };
+
+                // the status has already been sent, so the error is reported in the body
+                error!(%error, "error while streaming query results");
+                Some((self.error_chunk(&error), None))
+            })
+            .filter(|bytes| futures::future::ready(!bytes.is_empty()))
+            .map(Ok::<_, Infallible>);
+
+        Ok(Body::wrap_stream(body))
+    }
+
+    /// The end of the body for an error that happens after some of the results were sent. The
+    /// binary formats are left truncated, which their readers detect.
+    fn error_chunk(&self, error: &DataFusionError) -> Bytes {
+        match self {
+            Self::Parquet | Self::Arrow => Bytes::new(),
+            Self::Pretty | Self::Json | Self::Jsonl | Self::Csv => {
+                let error = serde_json::json!({ "error": error.to_string() });
+                format!("\n{error}\n").into()
+            }
+        }
+    }
+}
+
+/// Writes record batches in one of the query formats into a shared buffer.
+enum BatchWriter {
+    Pretty(Vec<RecordBatch>, SharedBuffer),
+    Json(ArrayWriter<SharedBuffer>),
+    Jsonl(LineDelimitedWriter<SharedBuffer>),
+    Csv(csv::Writer<SharedBuffer>),
+    Parquet(Box<ArrowWriter<SharedBuffer>>),
+    Arrow(StreamWriter<SharedBuffer>),
+}
+
+impl BatchWriter {
+    fn try_new(
+        format: QueryFormat,
+        schema: SchemaRef,
+        buffer: SharedBuffer,
+    ) -> Result<Self, DataFusionError> {
+        Ok(match format {
+            QueryFormat::Pretty => Self::Pretty(vec![], buffer),
+            QueryFormat::Json => Self::Json(ArrayWriter::new(buffer)),
+            QueryFormat::Jsonl => Self::Jsonl(LineDelimitedWriter::new(buffer)),
+            QueryFormat::Csv => Self::Csv(csv::Writer::new(buffer)),
+            QueryFormat::Parquet => {
+                Self::Parquet(Box::new(ArrowWriter::try_new(buffer, schema, None)?))
+            }
+            QueryFormat::Arrow => Self::Arrow(StreamWriter::try_new(buffer, &schema)?),
+        })
+    }
+
+    fn write(&mut self, batch: RecordBatch) -> Result<(), DataFusionError> {
+        match self {
+            Self::Pretty(batches, _) => batches.push(batch),
+            Self::Json(writer) => writer.write(&batch)?,
+            Self::Jsonl(writer) => writer.write(&batch)?,
+            Self::Csv(writer) => writer.write(&batch)?,
+            Self::Parquet(writer) => {
+                // write each batch as a row group so it can be sent right away
+                writer.write(&batch)?;
+                writer.flush()?;
+            }
+            Self::Arrow(writer) => writer.write(&batch)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), DataFusionError> {
+        match self {
+            Self::Pretty(batches, mut buffer) => {
+                let table = pretty::pretty_format_batches(&batches)?;
+                write!(buffer, "{table}")?;
+            }
+            Self::Json(mut writer) => writer.finish()?,
+            Self::Jsonl(mut writer) => writer.finish()?,
+            Self::Csv(_) => {}
+            Self::Parquet(writer) => {
+                writer.close()?;
+            }
+            Self::Arrow(mut writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// A buffer that the format writers write into and that is emptied into the response body
+/// after every record batch.
+#[derive(Debug, Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Bytes {
+        std::mem::take(&mut *self.0.lock()).into()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_accepting(accept: &str) -> Request<Body> {
+        Request::builder()
+            .header(ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn format_from_accept_header() {
+        assert_eq!(
+            QueryFormat::from_accept_header(&request_accepting("text/csv")),
+            Some(QueryFormat::Csv)
+        );
+        assert_eq!(
+            QueryFormat::from_accept_header(&request_accepting(
+                "text/html, application/json;q=0.9, */*;q=0.8"
+            )),
+            Some(QueryFormat::Json)
+        );
+        assert_eq!(
+            QueryFormat::from_accept_header(&request_accepting("*/*")),
+            None
+        );
+        assert_eq!(
+            QueryFormat::from_accept_header(&Request::new(Body::empty())),
+            None
+        );
+    }
+}
@@ -0,0 +1,484 @@
+//! The InfluxDB 1.x `/query` API, which returns the results of InfluxQL queries as JSON series.
+//!
+//! Rows are grouped into a series for each measurement and set of `GROUP BY` tag values, using
+//! the InfluxQL metadata that the planner adds to the schema of the results. When `chunked` is
+//! set, each series is written as its own JSON object, split into chunks of at most
+//! `chunk_size` rows, as it is read from the query.
+
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, TimestampNanosecondArray,
+    UInt64Array,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use arrow_schema::SchemaRef;
+use bytes::Bytes;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use datafusion::error::DataFusionError;
+use datafusion::execution::SendableRecordBatchStream;
+use futures::StreamExt;
+use generated_types::influxdata::iox::querier::v1::InfluxQlMetadata;
+use hyper::Body;
+use observability_deps::tracing::error;
+use schema::{INFLUXQL_METADATA_KEY, TIME_COLUMN_NAME};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+/// The number of rows in each chunk of a chunked response, if `chunk_size` isn't set.
+const DEFAULT_CHUNK_SIZE: usize = 10_000;
+
+/// The parameters of a query to the InfluxDB 1.x compatible `/query` endpoint.
+#[derive(Debug, Deserialize)]
+pub(crate) struct V1QueryParams {
+    pub(crate) db: String,
+    pub(crate) rp: Option<String>,
+    /// The query, which can also be sent in a form encoded body
+    pub(crate) q: Option<String>,
+    /// Return times as integers in this precision, rather than RFC3339 strings
+    pub(crate) epoch: Option<Epoch>,
+    #[serde(default)]
+    pub(crate) chunked: bool,
+    pub(crate) chunk_size: Option<usize>,
+    /// The password of the 1.x credentials, which is the token of the query
+    pub(crate) p: Option<String>,
+}
+
+impl V1QueryParams {
+    /// The maximum number of rows in each chunk of the response, or `None` if it isn't chunked.
+    pub(crate) fn chunk_size(&self) -> Option<usize> {
+        self.chunked
+            .then(|| self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1))
+    }
+}
+
+/// The body of a `POST` to `/query` that sends the query as a form.
+#[derive(Debug, Deserialize)]
+pub(crate) struct V1QueryForm {
+    pub(crate) q: String,
+}
+
+/// The precision of the times in the results of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub(crate) enum Epoch {
+    #[serde(rename = "h")]
+    Hour,
+    #[serde(rename = "m")]
+    Minute,
+    #[serde(rename = "s")]
+    Second,
+    #[serde(rename = "ms")]
+    Millisecond,
+    #[serde(rename = "u", alias = "µ")]
+    Microsecond,
+    #[serde(rename = "ns", alias = "n")]
+    Nanosecond,
+}
+
+impl Epoch {
+    fn nanos_per_unit(&self) -> i64 {
+        match self {
+            Self::Hour => 3_600_000_000_000,
+            Self::Minute => 60_000_000_000,
+            Self::Second => 1_000_000_000,
+            Self::Millisecond => 1_000_000,
+            Self::Microsecond => 1_000,
+            Self::Nanosecond => 1,
+        }
+    }
+}
+
+/// Returns a body with the results of the query in the 1.x JSON format. Returns an error if
+/// the query fails before producing its first record batch.
+pub(crate) async fn stream_body(
+    mut stream: SendableRecordBatchStream,
+    epoch: Option<Epoch>,
+    chunk_size: Option<usize>,
+) -> Result<Body, DataFusionError> {
+    let builder = ResponseBuilder::try_new(stream.schema(), epoch, chunk_size)?;
+
+    let first_batch = stream.next().await.transpose()?;
+    let stream = futures::stream::iter(first_batch.map(Ok)).chain(stream);
+
+    let body = futures::stream::unfold(Some((stream, builder)), |state| async move {
+        let (mut stream, mut builder) = state?;
+        let result = match stream.next().await {
+            Some(Ok(batch)) => match builder.push_batch(&batch) {
+                Ok(()) => return Some((builder.take_chunks(), Some((stream, builder)))),
+                Err(e) => e,
+            },
+            Some(Err(e)) => e,
+            None => return Some((builder.finish(), None)),
+        };
+
+        // the status has already been sent, so the error is reported as a statement error
+        error!(error = %result, "error while streaming influxql query results");
+        Some((builder.error(&result), None))
+    })
+    .filter(|bytes| futures::future::ready(!bytes.is_empty()))
+    .map(Ok::<_, Infallible>);
+
+    Ok(Body::wrap_stream(body))
+}
+
+#[derive(Debug, Serialize)]
+struct QueryResponse<'a> {
+    results: [StatementResult<'a>; 1],
+}
+
+#[derive(Debug, Serialize)]
+struct StatementResult<'a> {
+    statement_id: usize,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    series: &'a [Series],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Series {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<BTreeMap<String, String>>,
+    columns: Vec<String>,
+    values: Vec<Vec<Value>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+}
+
+/// Groups the rows of record batches into series and writes them out as JSON.
+#[derive(Debug)]
+struct ResponseBuilder {
+    epoch: Option<Epoch>,
+    /// The maximum rows in each chunk, or `None` if the response isn't chunked
+    chunk_size: Option<usize>,
+    measurement_index: Option<usize>,
+    /// The names and column indexes of the `GROUP BY` tags
+    group_by_tags: Vec<(String, usize)>,
+    /// The indexes of the columns included in the values of each series
+    value_indexes: Vec<usize>,
+    columns: Vec<String>,
+    /// The series that are complete and haven't been written yet
+    series: Vec<Series>,
+    current: Option<Series>,
+    /// Chunks that are ready to be sent
+    output: Vec<u8>,
+}
+
+impl ResponseBuilder {
+    fn try_new(
+        schema: SchemaRef,
+        epoch: Option<Epoch>,
+        chunk_size: Option<usize>,
+    ) -> Result<Self, DataFusionError> {
+        // statements that aren't grouped into series don't have the metadata
+        let metadata = schema
+            .metadata()
+            .get(INFLUXQL_METADATA_KEY)
+            .map(|md| serde_json::from_str::<InfluxQlMetadata>(md))
+            .transpose()
+            .map_err(|e| DataFusionError::External(Box::new(e)))?;
+
+        let measurement_index = metadata
+            .as_ref()
+            .map(|md| md.measurement_column_index as usize);
+        let tag_key_columns = metadata.map(|md| md.tag_key_columns).unwrap_or_default();
+        let group_by_tags = tag_key_columns
+            .iter()
+            .map(|tk| (tk.tag_key.clone(), tk.column_index as usize))
+            .collect();
+
+        // the measurement and tags that are only in the GROUP BY are in the series, not the values
+        let value_indexes: Vec<_> = (0..schema.fields().len())
+            .filter(|i| {
+                Some(*i) != measurement_index
+                    && !tag_key_columns
+                        .iter()
+                        .any(|tk| tk.column_index as usize == *i && !tk.is_projected)
+            })
+            .collect();
+        let columns = value_indexes
+            .iter()
+            .map(|i| schema.field(*i).name().clone())
+            .collect();
+
+        Ok(Self {
+            epoch,
+            chunk_size,
+            measurement_index,
+            group_by_tags,
+            value_indexes,
+            columns,
+            series: vec![],
+            current: None,
+            output: vec![],
+        })
+    }
+
+    fn push_batch(&mut self, batch: &RecordBatch) -> Result<(), DataFusionError> {
+        let measurements = self
+            .measurement_index
+            .map(|i| string_values(batch.column(i)))
+            .transpose()?;
+        let tag_values = self
+            .group_by_tags
+            .iter()
+            .map(|(_, i)| string_values(batch.column(*i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let values = self
+            .value_indexes
+            .iter()
+            .map(|i| {
+                json_values(
+                    batch.schema().field(*i).name(),
+                    batch.column(*i),
+                    self.epoch,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for row in 0..batch.num_rows() {
+            let name = measurements
+                .as_ref()
+                .and_then(|m| m[row].clone())
+                .unwrap_or_default();
+            let tags = (!self.group_by_tags.is_empty()).then(|| {
+                self.group_by_tags
+                    .iter()
+                    .zip(&tag_values)
+                    .map(|((key, _), values)| {
+                        (key.clone(), values[row].clone().unwrap_or_default())
+                    })
+                    .collect()
+            });
+
+            let same_series = self
+                .current
+                .as_ref()
+                .is_some_and(|s| s.name == name && s.tags == tags);
+            if !same_series {
+                self.start_series(name, tags);
+            } else if let Some(chunk) = self.take_full_chunk() {
+                self.write_chunk(&[chunk], None, true);
+            }
+
+            let series = self.current.as_mut().expect("series was started");
+            series
+                .values
+                .push(values.iter().map(|column| column[row].clone()).collect());
+        }
+
+        Ok(())
+    }
+
+    /// Takes the rows of the current series if they fill a chunk, so the series continues in the
+    /// next chunk.
+    fn take_full_chunk(&mut self) -> Option<Series> {
+        let chunk_size = self.chunk_size?;
+        let series = self.current.as_mut()?;
+        (series.values.len() >= chunk_size).then(|| Series {
+            name: series.name.clone(),
+            tags: series.tags.clone(),
+            columns: series.columns.clone(),
+            values: std::mem::take(&mut series.values),
+            partial: true,
+        })
+    }
+
+    fn start_series(&mut self, name: String, tags: Option<BTreeMap<String, String>>) {
+        let previous = self.current.replace(Series {
+            name,
+            tags,
+            columns: self.columns.clone(),
+            values: vec![],
+            partial: false,
+        });
+
+        match previous {
+            Some(series) if self.chunk_size.is_some() => self.write_chunk(&[series], None, true),
+            Some(series) => self.series.push(series),
+            None => {}
+        }
+    }
+
+    /// Returns the chunks that are ready to be sent.
+    fn take_chunks(&mut self) -> Bytes {
+        std::mem::take(&mut self.output).into()
+    }
+
+    /// Returns the rest of the response once every batch has been pushed.
+    fn finish(mut self) -> Bytes {
+        let mut series = std::mem::take(&mut self.series);
+        series.extend(self.current.take());
+        self.write_chunk(&series, None, false);
+        self.take_chunks()
+    }
+
+    /// Returns the rest of the response when the query fails part way through. In a chunked
+    /// response the error follows the chunks already sent, otherwise only the error is returned.
+    fn error(mut self, error: &DataFusionError) -> Bytes {
+        self.write_chunk(&[], Some(error.to_string()), false);
+        self.take_chunks()
+    }
+
+    fn write_chunk(&mut self, series: &[Series], error: Option<String>, partial: bool) {
+        let response = QueryResponse {
+            results: [StatementResult {
+                statement_id: 0,
+                series,
+                error,
+                partial,
+            }],
+        };
+        serde_json::to_writer(&mut self.output, &response).expect("response should serialize");
+        self.output.push(b'\n');
+    }
+}
+
+/// Returns the values of a tag or measurement column as strings.
+fn string_values(array: &ArrayRef) -> Result<Vec<Option<String>>, DataFusionError> {
+    let array = cast(array, &DataType::Utf8)?;
+    let array = array
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .expect("cast to utf8");
+    Ok(array.iter().map(|v| v.map(ToString::to_string)).collect())
+}
+
+/// Converts the values of a column to JSON. Times are RFC3339 strings, or integers in the
+/// precision of the epoch if it is set.
+fn json_values(
+    name: &str,
+    array: &ArrayRef,
+    epoch: Option<Epoch>,
+) -> Result<Vec<Value>, DataFusionError> {
+    macro_rules! values {
+        ($array_type:ty, $to_value:expr) => {{
+            let array = array
+                .as_any()
+                .downcast_ref::<$array_type>()
+                .expect("array type matches data type");
+            array
+                .iter()
+                .map(|v| v.map($to_value).unwrap_or(Value::Null))
+                .collect()
+        }};
+    }
+
+    Ok(match array.data_type() {
+        DataType::Timestamp(TimeUnit::Nanosecond, _) if name == TIME_COLUMN_NAME => {
+            values!(TimestampNanosecondArray, |t| match epoch {
+                Some(epoch) => Value::from(t / epoch.nanos_per_unit()),
+                None => Value::from(
+                    Utc.timestamp_nanos(t)
+                        .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+                ),
+            })
+        }
+        DataType::Int64 => values!(Int64Array, Value::from),
+        DataType::UInt64 => values!(UInt64Array, Value::from),
+        // NaN and infinite floats become null, like in 1.x
+        DataType::Float64 => values!(Float64Array, Value::from),
+        DataType::Boolean => values!(BooleanArray, Value::from),
+        _ => string_values(array)?
+            .into_iter()
+            .map(|v| v.map(Value::from).unwrap_or(Value::Null))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::DictionaryArray;
+    use arrow::datatypes::{Field, Int32Type, Schema};
+    use generated_types::influxdata::iox::querier::v1::influx_ql_metadata::TagKeyColumn;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn batch(group_by_host: bool) -> RecordBatch {
+        let metadata = InfluxQlMetadata {
+            measurement_column_index: 0,
+            tag_key_columns: group_by_host
+                .then(|| TagKeyColumn {
+                    tag_key: "host".to_string(),
+                    column_index: 2,
+                    is_projected: false,
+                })
+                .into_iter()
+                .collect(),
+        };
+        let tag_type = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        let schema = Schema::new(vec![
+            Field::new("iox::measurement", tag_type.clone(), false),
+            Field::new(
+                "time",
+                DataType::Timestamp(TimeUnit::Nanosecond, None),
+                false,
+            ),
+            Field::new("host", tag_type, true),
+            Field::new("val", DataType::Float64, true),
+        ])
+        .with_metadata(HashMap::from([(
+            INFLUXQL_METADATA_KEY.to_string(),
+            serde_json::to_string(&metadata).unwrap(),
+        )]));
+
+        let tags = |values: Vec<&'static str>| -> ArrayRef {
+            Arc::new(values.into_iter().collect::<DictionaryArray<Int32Type>>())
+        };
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                tags(vec!["cpu", "cpu", "cpu"]),
+                Arc::new(TimestampNanosecondArray::from(vec![
+                    1_000_000_000,
+                    2_000_000_000,
+                    1_500_000_000,
+                ])),
+                tags(vec!["a", "a", "b"]),
+                Arc::new(Float64Array::from(vec![Some(1.0), None, Some(f64::NAN)])),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn response(batch: &RecordBatch, epoch: Option<Epoch>, chunk_size: Option<usize>) -> String {
+        let mut builder = ResponseBuilder::try_new(batch.schema(), epoch, chunk_size).unwrap();
+        builder.push_batch(batch).unwrap();
+        let mut output = builder.take_chunks().to_vec();
+        output.extend_from_slice(&builder.finish());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn rows_in_one_series() {
+        assert_eq!(
+            response(&batch(false), None, None),
+            concat!(
+                r#"{"results":[{"statement_id":0,"series":[{"name":"cpu","columns":["time","host","val"],"values":["#,
+                r#"["1970-01-01T00:00:01Z","a",1.0],["1970-01-01T00:00:02Z","a",null],["1970-01-01T00:00:01.500Z","b",null]]}]}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn chunked_series_grouped_by_tag() {
+        assert_eq!(
+            response(&batch(true), Some(Epoch::Second), Some(1)),
+            concat!(
+                r#"{"results":[{"statement_id":0,"series":[{"name":"cpu","tags":{"host":"a"},"columns":["time","val"],"values":[[1,1.0]],"partial":true}],"partial":true}]}"#,
+                "\n",
+                r#"{"results":[{"statement_id":0,"series":[{"name":"cpu","tags":{"host":"a"},"columns":["time","val"],"values":[[2,null]]}],"partial":true}]}"#,
+                "\n",
+                r#"{"results":[{"statement_id":0,"series":[{"name":"cpu","tags":{"host":"b"},"columns":["time","val"],"values":[[1,null]]}]}]}"#,
+                "\n"
+            )
+        );
+    }
+}
@@ -1,6 +1,6 @@
 //! InfluxDB 3.0 Edge server implementation
 //!
-//! The server is responsible for handling the HTTP API
+//! The server is responsible for handling the HTTP API and the Arrow Flight gRPC API
 #![deny(rustdoc::broken_intra_doc_links, rustdoc::bare_urls, rust_2018_idioms)]
 #![warn(
 missing_debug_implementations,
@@ -11,13 +11,17 @@ clippy::clone_on_ref_ptr,
 clippy::future_not_send
 )]
 
+mod auth;
+mod grpc;
 mod http;
 pub mod query_executor;
 
+pub use crate::auth::TokenAuthorizer;
 use crate::http::HttpApi;
 use async_trait::async_trait;
 use datafusion::execution::SendableRecordBatchStream;
 use influxdb3_write::{Persister, WriteBuffer};
+use iox_query::QueryNamespaceProvider;
 use observability_deps::tracing::info;
 use std::fmt::Debug;
 use std::net::SocketAddr;
@@ -34,8 +38,8 @@ pub enum Error {
     #[error("http error: {0}")]
     Http(#[from] http::Error),
 
-    #[error("database not found {db_name}")]
-    DatabaseNotFound { db_name: String },
+    #[error("grpc error: {0}")]
+    Grpc(#[from] grpc::Error),
 
     #[error("datafusion error: {0}")]
     DataFusion(#[from] datafusion::error::DataFusionError),
@@ -43,12 +47,26 @@ pub enum Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Errors from planning or starting the execution of a query.
+#[derive(Debug, Error)]
+pub enum QueryExecutorError {
+    #[error("database not found: {db_name}")]
+    DatabaseNotFound { db_name: String },
+
+    #[error("error while planning query: {0}")]
+    QueryPlanning(#[source] datafusion::error::DataFusionError),
+
+    #[error("error while executing plan: {0}")]
+    ExecuteStream(#[source] datafusion::error::DataFusionError),
+}
+
 #[derive(Debug, Clone)]
 pub struct CommonServerState {
     metrics: Arc<metric::Registry>,
     trace_exporter: Option<Arc<trace_exporters::export::AsyncExporter>>,
     trace_header_parser: TraceHeaderParser,
     http_addr: SocketAddr,
+    grpc_addr: SocketAddr,
 }
 
 impl CommonServerState {
@@ -57,12 +75,14 @@ impl CommonServerState {
         trace_exporter: Option<Arc<trace_exporters::export::AsyncExporter>>,
         trace_header_parser: TraceHeaderParser,
         http_addr: SocketAddr,
+        grpc_addr: SocketAddr,
     ) -> Self {
         Self {
             metrics,
             trace_exporter,
             trace_header_parser,
             http_addr,
+            grpc_addr,
         }
     }
 
@@ -87,18 +107,38 @@ impl CommonServerState {
 
 #[derive(Debug)]
 pub struct Server<W, Q> {
+    common_state: CommonServerState,
     http: Arc<HttpApi<W, Q>>,
+    query_executor: Arc<Q>,
+    authorizer: Option<Arc<TokenAuthorizer>>,
+}
+
+/// The language a query is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryKind {
+    Sql,
+    InfluxQl,
+}
+
+impl QueryKind {
+    pub(crate) fn query_type(&self) -> &'static str {
+        match self {
+            Self::Sql => "sql",
+            Self::InfluxQl => "influxql",
+        }
+    }
 }
 
 #[async_trait]
-pub trait QueryExecutor: Debug + Send + Sync + 'static {
+pub trait QueryExecutor: QueryNamespaceProvider + Debug + Send + Sync + 'static {
     async fn query(
         &self,
         database: &str,
         q: &str,
+        kind: QueryKind,
         span_ctx: Option<SpanContext>,
         external_span_ctx: Option<RequestLogContext>,
-    ) -> Result<SendableRecordBatchStream>;
+    ) -> Result<SendableRecordBatchStream, QueryExecutorError>;
 }
 
 impl<W, Q> Server<W, Q> {
@@ -108,15 +148,22 @@ impl<W, Q> Server<W, Q> {
         write_buffer: Arc<W>,
         query_executor: Arc<Q>,
         max_http_request_size: usize,
+        authorizer: Option<Arc<TokenAuthorizer>>,
     ) -> Self {
         let http = Arc::new(HttpApi::new(
             common_state.clone(),
             Arc::<W>::clone(&write_buffer),
             Arc::<Q>::clone(&query_executor),
             max_http_request_size,
+            authorizer.clone(),
         ));
 
-        Self { http }
+        Self {
+            common_state,
+            http,
+            query_executor,
+            authorizer,
+        }
     }
 }
 
@@ -124,13 +171,25 @@ pub async fn serve<W: WriteBuffer, Q: QueryExecutor>(
     server: Server<W, Q>,
     shutdown: CancellationToken,
 ) -> Result<()> {
-    // TODO:
-    //  1. load the persisted catalog and segments from the persister
-    //  2. load semgments into the buffer
-    //  3. persist any segments from the buffer that are closed and haven't yet been persisted
-    //  4. start serving
+    let http = async {
+        http::serve(Arc::clone(&server.http), shutdown.clone()).await?;
+        Ok::<_, Error>(())
+    };
+    let grpc = async {
+        grpc::serve(
+            server.common_state.clone(),
+            Arc::clone(&server.query_executor),
+            server.authorizer.clone(),
+            shutdown.clone(),
+        )
+        .await?;
+        Ok::<_, Error>(())
+    };
 
-    http::serve(Arc::clone(&server.http), shutdown).await?;
+    // if either listener fails the other is shut down too
+    let result = tokio::try_join!(http, grpc);
+    shutdown.cancel();
+    result?;
 
     Ok(())
 }
@@ -158,33 +217,1125 @@ pub async fn wait_for_signal() {
 
 #[cfg(test)]
 mod tests {
-    use crate::serve;
+    use crate::{serve, TokenAuthorizer};
+    use datafusion::assert_batches_eq;
     use datafusion::parquet::data_type::AsBytes;
-    use hyper::{body, Body, Client, Request, Response};
-    use influxdb3_write::persister::PersisterImpl;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use futures::TryStreamExt;
+    use hyper::{body, Body, Client, Request, Response, StatusCode};
+    use influxdb3_write::catalog::Catalog;
+    use influxdb3_write::persister::{PersisterImpl, PARQUET_STORAGE_ID};
+    use influxdb3_write::wal::WalImpl;
+    use influxdb3_write::write_buffer::{SegmentConfig, WriteBufferImpl};
+    use influxdb3_write::{Bufferer, Persister};
+    use influxdb_iox_client::flightsql::FlightSqlClient;
     use iox_query::exec::{Executor, ExecutorConfig};
+    use iox_time::TimeProvider;
+    use object_store::memory::InMemory;
     use object_store::DynObjectStore;
     use parquet_file::storage::{ParquetStorage, StorageId};
     use std::collections::HashMap;
+    use std::io::Write;
     use std::net::{SocketAddr, SocketAddrV4};
     use std::num::NonZeroUsize;
     use std::sync::atomic::{AtomicU16, Ordering};
     use std::sync::Arc;
+    use std::time::Duration;
     use tokio_util::sync::CancellationToken;
 
     static NEXT_PORT: AtomicU16 = AtomicU16::new(8090);
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn write_and_query() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+
+        write_lp(&server, "foo", "cpu,host=a val=1i 123", None).await;
+        let res = query(server, "foo", "select * from cpu", None).await;
+
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let expected = vec![
+            "+------+-------------------------------+-----+",
+            "| host | time                          | val |",
+            "+------+-------------------------------+-----+",
+            "| a    | 1970-01-01T00:00:00.000000123 | 1   |",
+            "+------+-------------------------------+-----+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(
+            expected, actual,
+            "\n\nexpected:\n\n{:#?}\nactual:\n\n{:#?}\n\n",
+            expected, actual
+        );
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn query_in_each_format() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+
+        write_lp(
+            &server,
+            "foo",
+            "cpu,host=a val=1i 1\ncpu,host=b val=2i 2",
+            None,
+        )
+        .await;
+        let query = urlencoding::encode("select host, val from cpu order by host");
+
+        let get = |params: String, accept: Option<&'static str>| {
+            let url = format!("{server}/api/v3/query_sql?db=foo&q={query}{params}");
+            async move {
+                let mut builder = Request::builder().uri(url).method("GET");
+                if let Some(accept) = accept {
+                    builder = builder.header(hyper::header::ACCEPT, accept);
+                }
+                let res = Client::new()
+                    .request(builder.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                let content_type = res.headers()[hyper::header::CONTENT_TYPE].clone();
+                let body = body::to_bytes(res.into_body()).await.unwrap();
+                (content_type, body)
+            }
+        };
+
+        let (content_type, body) = get("&format=json".to_string(), None).await;
+        assert_eq!(content_type, "application/json");
+        assert_eq!(
+            body.as_bytes(),
+            br#"[{"host":"a","val":1},{"host":"b","val":2}]"#
+        );
+
+        let (content_type, body) = get("&format=jsonl".to_string(), None).await;
+        assert_eq!(content_type, "application/jsonl");
+        assert_eq!(
+            body.as_bytes(),
+            b"{\"host\":\"a\",\"val\":1}\n{\"host\":\"b\",\"val\":2}\n"
+        );
+
+        // the format is negotiated from the accept header without a format parameter
+        let (content_type, body) = get(String::new(), Some("text/csv")).await;
+        assert_eq!(content_type, "text/csv");
+        assert_eq!(body.as_bytes(), b"host,val\na,1\nb,2\n");
+
+        let (content_type, body) = get("&format=parquet".to_string(), Some("text/csv")).await;
+        assert_eq!(content_type, "application/vnd.apache.parquet");
+        let batches: Vec<_> =
+            parquet::arrow::arrow_reader::ParquetRecordBatchReader::try_new(body, 1024)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+
+        let (content_type, body) = get("&format=arrow".to_string(), None).await;
+        assert_eq!(content_type, "application/vnd.apache.arrow.stream");
+        let batches: Vec<_> = arrow::ipc::reader::StreamReader::try_new(body.as_bytes(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn query_influxql() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+        write_lp(
+            &server,
+            "foo",
+            "cpu,host=a val=1i 1000000000\ncpu,host=b val=2i 2000000000",
+            None,
+        )
+        .await;
+
+        let client = Client::new();
+        let get = |url: String| {
+            let request = Request::builder()
+                .uri(url)
+                .method("GET")
+                .body(Body::empty())
+                .unwrap();
+            client.request(request)
+        };
+
+        let q = urlencoding::encode("SELECT val FROM cpu GROUP BY host");
+        let res = get(format!("{server}/query?db=foo&epoch=s&q={q}"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"results": [{"statement_id": 0, "series": [
+                {"name": "cpu", "tags": {"host": "a"}, "columns": ["time", "val"], "values": [[1, 1]]},
+                {"name": "cpu", "tags": {"host": "b"}, "columns": ["time", "val"], "values": [[2, 2]]},
+            ]}]})
+        );
+
+        let q = urlencoding::encode("SELECT val FROM cpu WHERE host = 'b'");
+        let res = get(format!(
+            "{server}/api/v3/query_influxql?db=foo&format=json&q={q}"
+        ))
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["iox::measurement"], "cpu");
+        assert_eq!(body[0]["val"], 2);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn query_errors() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+        write_lp(&server, "foo", "cpu,host=a val=1i 1", None).await;
+
+        let res = query(&server, "bar", "select * from cpu", None).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "database not found: bar");
+
+        for bad_sql in [
+            "selec * from cpu",
+            "select * from mem",
+            "select nope from cpu",
+        ] {
+            let res = query(&server, "foo", bad_sql, None).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "query: {bad_sql}");
+            let body = body::to_bytes(res.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("error while planning query"));
+        }
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn query_persisted_and_buffered_data() {
+        let object_store: Arc<DynObjectStore> = Arc::new(InMemory::new());
+        let (server, shutdown, write_buffer) = setup_server(Arc::clone(&object_store)).await;
+
+        write_lp(
+            &server,
+            "foo",
+            "cpu,host=a val=1i 123\ncpu,host=b val=2i 456",
+            None,
+        )
+        .await;
+        write_buffer.close_open_segment().await.unwrap();
+
+        // wait for the segment to be persisted
+        let persister = PersisterImpl::new(Arc::clone(&object_store));
+        while persister.load_segments(1).await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.cancel();
+        drop(write_buffer);
+
+        // after a restart the first write is only in parquet, and the new value for host a
+        // should replace the persisted one
+        let (server, shutdown, _) = setup_server(object_store).await;
+        write_lp(&server, "foo", "cpu,host=a val=3i 123", None).await;
+        let res = query(server, "foo", "select * from cpu order by host", None).await;
+
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let expected = vec![
+            "+------+-------------------------------+-----+",
+            "| host | time                          | val |",
+            "+------+-------------------------------+-----+",
+            "| a    | 1970-01-01T00:00:00.000000123 | 3   |",
+            "| b    | 1970-01-01T00:00:00.000000456 | 2   |",
+            "+------+-------------------------------+-----+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(
+            expected, actual,
+            "\n\nexpected:\n\n{:#?}\nactual:\n\n{:#?}\n\n",
+            expected, actual
+        );
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn query_overwritten_values_of_one_segment() {
+        let object_store: Arc<DynObjectStore> = Arc::new(InMemory::new());
+        let (server, shutdown, write_buffer) = setup_server(Arc::clone(&object_store)).await;
+        let expected = vec![
+            "+------+-------------------------------+-----+",
+            "| host | time                          | val |",
+            "+------+-------------------------------+-----+",
+            "| a    | 1970-01-01T00:00:00.000000123 | 30  |",
+            "| b    | 1970-01-01T00:00:00.000000123 | 30  |",
+            "+------+-------------------------------+-----+",
+        ];
+
+        // every write of the fields goes into the buffer of the same segment, and only the
+        // latest values are returned, from the buffer and then from the persisted file. There
+        // are enough rows of a series at the same time for an unstable sort to reorder them.
+        for val in 1..=30 {
+            let lp = format!("cpu,host=b val={val}i 123\ncpu,host=a val={val}i 123");
+            write_lp(&server, "foo", lp, None).await;
+        }
+        let res = query(&server, "foo", "select * from cpu order by host", None).await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        write_buffer.close_open_segment().await.unwrap();
+        let persister = PersisterImpl::new(Arc::clone(&object_store));
+        while persister.load_segments(1).await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.cancel();
+        drop(write_buffer);
+
+        let (server, shutdown, _) = setup_server(object_store).await;
+        let res = query(&server, "foo", "select * from cpu order by host", None).await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn write_lp_reports_invalid_lines() {
+        let (server, shutdown, write_buffer) = setup_server(Arc::new(InMemory::new())).await;
+
+        // in strict mode nothing is written if any line is invalid
+        let request = Request::builder()
+            .uri(format!(
+                "{server}/api/v3/write_lp?db=foo&accept_partial=false"
+            ))
+            .method("POST")
+            .body(Body::from("mem,host=a free=1i 123\nnot_valid"))
+            .unwrap();
+        let res = Client::new().request(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"][0]["line_number"], 2);
+        assert_eq!(body["data"][0]["original_line"], "not_valid");
+        assert_eq!(body["data"][0]["column"], 10);
+        assert!(write_buffer.catalog().db_schema("foo").is_none());
+
+        // by default the valid lines are written and the invalid ones are returned
+        let res = write_lp(
+            &server,
+            "foo",
+            "cpu,host=a val=1i 123\ncpu,host=b val=2.0 456\ncpu,host=c val=3i 789",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "partial write of line protocol occurred");
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"][0]["line_number"], 2);
+        assert_eq!(body["data"][0]["original_line"], "cpu,host=b val=2.0 456");
+
+        let res = query(
+            &server,
+            "foo",
+            "select host, val from cpu order by host",
+            None,
+        )
+        .await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let expected = vec![
+            "+------+-----+",
+            "| host | val |",
+            "+------+-----+",
+            "| a    | 1   |",
+            "| c    | 3   |",
+            "+------+-----+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        // a write where every line is invalid fails
+        let res = write_lp(&server, "foo", "not_valid", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // the body is parsed as it is received, with lines split across its chunks
+        let chunks: Vec<Result<&'static [u8], std::io::Error>> = vec![
+            Ok(b"# comment\ncpu,host=d val=4i 1"),
+            Ok(b"23\ncpu,host=e val=\xff5i 123\ncpu,ho"),
+            Ok(b"st=f val=6i 123"),
+        ];
+        let request = Request::builder()
+            .uri(format!("{server}/api/v3/write_lp?db=foo"))
+            .method("POST")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let res = Client::new().request(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"][0]["line_number"], 3);
+
+        let res = query(
+            &server,
+            "foo",
+            "select host, val from cpu where host > 'c' order by host",
+            None,
+        )
+        .await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let expected = vec![
+            "+------+-----+",
+            "| host | val |",
+            "+------+-----+",
+            "| d    | 4   |",
+            "| f    | 6   |",
+            "+------+-----+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn write_lp_with_lint() {
+        let (server, shutdown, write_buffer) = setup_server(Arc::new(InMemory::new())).await;
+
+        // the problems of every line are returned, and nothing is written
+        let res = post(
+            format!("{server}/api/v3/write_lp?db=foo&lint=true&precision=s"),
+            "cpu,host=a val=1i 1\ncpu,host=b val=2.5 2\ncpu,host=c val=3i,val=4i 3\ncpu val=4i 9999999999",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let errors: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                (
+                    e["line_number"].as_u64().unwrap(),
+                    e["column"].as_u64().unwrap(),
+                    e["token"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(2, 16, "2.5"), (3, 19, "val"), (4, 12, "9999999999")]
+        );
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("2 | cpu,host=b val=2.5 2\n  |                ^^^"));
+        assert!(write_buffer.catalog().db_schema("foo").is_none());
+
+        // a write without problems is written as usual
+        let res = post(
+            format!("{server}/api/v3/write_lp?db=foo&lint=true"),
+            "cpu,host=a val=1i 1",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn write_to_v1_and_v2_endpoints() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+
+        // v1 with a gzipped body and second precision
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"cpu,host=a val=1i 2").unwrap();
+        let res = post(
+            format!("{server}/write?db=foo&precision=s"),
+            encoder.finish().unwrap(),
+            Some("gzip"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // v2 with millisecond precision
+        let res = post(
+            format!("{server}/api/v2/write?bucket=foo&org=myorg&precision=ms"),
+            "cpu,host=b val=2i 3",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // minute and hour precisions are only accepted by v1
+        let res = post(
+            format!("{server}/write?db=foo&precision=m"),
+            "cpu,host=c val=3i 2",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = post(
+            format!("{server}/api/v2/write?bucket=foo&precision=h"),
+            "cpu,host=d val=4i 1",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = query(
+            &server,
+            "foo",
+            "select host, time, val from cpu order by host",
+            None,
+        )
+        .await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
+        let expected = vec![
+            "+------+-------------------------+-----+",
+            "| host | time                    | val |",
+            "+------+-------------------------+-----+",
+            "| a    | 1970-01-01T00:00:02     | 1   |",
+            "| b    | 1970-01-01T00:00:00.003 | 2   |",
+            "| c    | 1970-01-01T00:02:00     | 3   |",
+            "+------+-------------------------+-----+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        // invalid lines are reported in the format of each api
+        let res = post(
+            format!("{server}/write?db=foo"),
+            "cpu,host=c val=3i 4\nnot_valid",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("partial write: unable to parse 'not_valid'"));
+
+        let res = post(format!("{server}/api/v2/write?org=myorg"), "", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "invalid");
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn query_over_flight_and_flight_sql() {
+        let (server, grpc, shutdown, _) =
+            setup_server_with_grpc(Arc::new(InMemory::new()), None).await;
+        write_lp(
+            &server,
+            "foo",
+            "cpu,host=a val=1i 1\ncpu,host=b val=2i 2",
+            None,
+        )
+        .await;
+        let expected = [
+            "+------+-----+",
+            "| host | val |",
+            "+------+-----+",
+            "| a    | 1   |",
+            "| b    | 2   |",
+            "+------+-----+",
+        ];
+        let sql = "select host, val from cpu order by host";
+
+        let connection = influxdb_iox_client::connection::Builder::new()
+            .build(grpc)
+            .await
+            .unwrap();
+
+        let mut client = influxdb_iox_client::flight::Client::new(connection.clone());
+        let batches: Vec<_> = client
+            .sql("foo", sql)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_batches_eq!(expected, &batches);
+
+        let mut client = influxdb_iox_client::flight::Client::new(connection.clone());
+        let batches: Vec<_> = client
+            .influxql("foo", "SELECT host, val FROM cpu")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+
+        // unknown databases are reported to the client
+        let mut client = influxdb_iox_client::flight::Client::new(connection.clone());
+        assert!(client.sql("bar", sql).await.is_err());
+
+        let (channel, _) = connection.into_grpc_connection().into_parts();
+        let mut client = FlightSqlClient::new(channel);
+        client.add_header("database", "foo").unwrap();
+        let batches: Vec<_> = client
+            .query(sql)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_batches_eq!(expected, &batches);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn token_authorization() {
+        let object_store: Arc<DynObjectStore> = Arc::new(InMemory::new());
+        let (server, _, shutdown, _) =
+            setup_server_with_grpc(Arc::clone(&object_store), Some("admin")).await;
+        let client = Client::new();
+        let tokens_request = |method: &str, params: &str, token: &str, body: &'static str| {
+            Request::builder()
+                .uri(format!("{server}/api/v3/configure/token{params}"))
+                .method(method)
+                .header(hyper::header::AUTHORIZATION, format!("Token {token}"))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let create =
+            r#"{"description":"writer","permissions":[{"database":"foo","actions":["write"]}]}"#;
+        let res = client
+            .request(tokens_request("POST", "", "admin", create))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let token = created["token"].as_str().unwrap().to_string();
+        let id = created["id"].as_str().unwrap().to_string();
+        let authorization = format!("Bearer {token}");
+
+        // only the admin token can manage tokens
+        let res = client
+            .request(tokens_request("GET", "", &token, ""))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = write_lp(&server, "foo", "cpu val=1i 1", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = write_lp(&server, "foo", "cpu val=1i 1", Some("Token wrong")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = write_lp(&server, "bar", "cpu val=1i 1", Some(&authorization)).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = write_lp(&server, "foo", "cpu val=1i 1", Some(&authorization)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the token can't read what it wrote, but the admin token can
+        let res = query(&server, "foo", "select * from cpu", Some(&authorization)).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = query(&server, "foo", "select * from cpu", Some("Token admin")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the 1.x endpoints take the token as the password of the query string credentials
+        let password = urlencoding::encode(&token);
+        let res = post(
+            format!("{server}/write?db=foo&u=writer&p={password}"),
+            "cpu val=2i 2",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = post(
+            format!("{server}/write?db=foo&u=writer&p=wrong"),
+            "cpu val=2i 2",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = client
+            .request(
+                Request::builder()
+                    .uri(format!(
+                        "{server}/query?db=foo&q=SELECT%20*%20FROM%20cpu&u=admin&p=admin"
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let metrics = |authorization: Option<String>| {
+            let mut builder = Request::builder().uri(format!("{server}/metrics"));
+            if let Some(authorization) = authorization {
+                builder = builder.header(hyper::header::AUTHORIZATION, authorization);
+            }
+            client.request(builder.body(Body::empty()).unwrap())
+        };
+        assert_eq!(
+            metrics(None).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            metrics(Some(authorization.clone())).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        // the token is persisted as a hash with the catalog
+        let persisted = PersisterImpl::new(Arc::clone(&object_store))
+            .load_catalog()
+            .await
+            .unwrap()
+            .unwrap();
+        let tokens = Catalog::from_inner(persisted.catalog).tokens();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].id, id);
+        assert_ne!(tokens[0].hash, token);
+
+        let res = client
+            .request(tokens_request("DELETE", &format!("?id={id}"), "admin", ""))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client
+            .request(tokens_request("DELETE", &format!("?id={id}"), "admin", ""))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = write_lp(&server, "foo", "cpu val=1i 1", Some(&authorization)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn configure_databases_and_tables() {
+        let (server, shutdown, write_buffer) = setup_server(Arc::new(InMemory::new())).await;
+        let client = Client::new();
+        let configure = |method: &str, path: &str, body: &'static str| {
+            let request = Request::builder()
+                .uri(format!("{server}/api/v3/configure/{path}"))
+                .method(method)
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+
+        let res = configure("POST", "database", r#"{"db":"foo"}"#)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = configure("POST", "database", r#"{"db":"foo"}"#)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let create_table = r#"{"db":"foo","table":"cpu","tags":["host"],"fields":[{"name":"usage","type":"f64"}]}"#;
+        let res = configure("POST", "table", create_table).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = configure(
+            "POST",
+            "table",
+            r#"{"db":"bar","table":"cpu","tags":["host"]}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // writes have to match the schema of the created table
+        let res = write_lp(&server, "foo", "cpu,host=a usage=1i 1", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = write_lp(
+            &server,
+            "foo",
+            "cpu,host=a usage=0.5 1\nmem free=1i 1",
+            None,
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = configure("GET", "table?db=foo", "").await.unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let tables: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            tables[0],
+            serde_json::json!({
+                "name": "cpu",
+                "columns": [
+                    {"name": "host", "type": "tag"},
+                    {"name": "time", "type": "time"},
+                    {"name": "usage", "type": "f64"},
+                ],
+                "partition_template": null,
+                "last_caches": [],
+                "distinct_caches": [],
+            })
+        );
+        assert_eq!(tables[1]["name"], "mem");
+
+        let res = configure("DELETE", "table?db=foo&table=cpu", "")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = configure("DELETE", "table?db=foo&table=cpu", "")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = query(&server, "foo", "select * from cpu", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = configure("GET", "database", "").await.unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let databases: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            databases,
+            serde_json::json!([{
+                "name": "foo",
+                "tables": ["mem"],
+                "partition_template": null,
+                "retention_period": null,
+            }])
+        );
+
+        let res = configure("DELETE", "database?db=foo", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(write_buffer.catalog().db_schema("foo").is_none());
+        let res = query(&server, "foo", "select * from mem", None).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn retention_period_hides_expired_data() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+        let client = Client::new();
+        let create_database = |body: &'static str| {
+            let request = Request::builder()
+                .uri(format!("{server}/api/v3/configure/database"))
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+
+        let res = create_database(r#"{"db":"foo","retention_period":"0s"}"#)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = create_database(r#"{"db":"foo","retention_period":"1h"}"#)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let request = Request::builder()
+            .uri(format!("{server}/api/v3/configure/database"))
+            .body(Body::empty())
+            .unwrap();
+        let res = client.request(request).await.unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let databases: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(databases[0]["retention_period"], "1h");
+
+        let now = iox_time::SystemProvider::new().now().timestamp_nanos();
+        let lp = format!(
+            "cpu val=1i 1
+cpu val=2i {now}"
+        );
+        let res = write_lp(&server, "foo", lp, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = query(&server, "foo", "select val from cpu", None).await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let expected = vec!["+-----+", "| val |", "+-----+", "| 2   |", "+-----+"];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        // the rows in the caches that are past the retention period are hidden too
+        let res = write_lp(&server, "foo", format!("mem,host=a free=1i {now}"), None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        for (path, body) in [
+            (
+                "last_cache",
+                r#"{"db":"foo","table":"mem","key_columns":["host"]}"#,
+            ),
+            (
+                "distinct_cache",
+                r#"{"db":"foo","table":"mem","columns":["host"]}"#,
+            ),
+        ] {
+            let request = Request::builder()
+                .uri(format!("{server}/api/v3/configure/{path}"))
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap();
+            let res = client.request(request).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let two_hours_ago = now - 2 * 60 * 60 * 1_000_000_000;
+        let lp = format!("mem,host=b free=2i {two_hours_ago}");
+        let res = write_lp(&server, "foo", lp, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = query(
+            &server,
+            "foo",
+            "select host, free from last_cache('mem')",
+            None,
+        )
+        .await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let expected = vec![
+            "+------+------+",
+            "| host | free |",
+            "+------+------+",
+            "| a    | 1    |",
+            "+------+------+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        let res = query(&server, "foo", "select * from distinct_cache('mem')", None).await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let expected = vec!["+------+", "| host |", "+------+", "| a    |", "+------+"];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn last_cache_returns_latest_values() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+        let client = Client::new();
+        let configure = |method: &str, path: &str, body: &'static str| {
+            let request = Request::builder()
+                .uri(format!("{server}/api/v3/configure/{path}"))
+                .method(method)
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+
+        let res = write_lp(&server, "foo", "cpu,host=a,region=us usage=1.0 1", None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = configure(
+            "POST",
+            "last_cache",
+            r#"{"db":"foo","table":"cpu","key_columns":["host"],"count":2}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let definition: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            definition,
+            serde_json::json!({
+                "name": "cpu_host_last_cache",
+                "key_columns": ["host"],
+                "value_columns": null,
+                "count": 2,
+            })
+        );
+        let res = configure(
+            "POST",
+            "last_cache",
+            r#"{"db":"foo","table":"cpu","key_columns":["usage"]}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let lp = "cpu,host=a,region=us usage=2.0 2
+cpu,host=b,region=eu usage=3.0 3
+cpu,host=a,region=us usage=4.0 4
+cpu,host=a,region=us usage=5.0 3";
+        let res = write_lp(&server, "foo", lp, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = query(
+            &server,
+            "foo",
+            "select host, usage from last_cache('cpu') order by host, time desc",
+            None,
+        )
+        .await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let expected = vec![
+            "+------+-------+",
+            "| host | usage |",
+            "+------+-------+",
+            "| a    | 4.0   |",
+            "| a    | 5.0   |",
+            "| b    | 3.0   |",
+            "+------+-------+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        let res = configure(
+            "DELETE",
+            "last_cache?db=foo&table=cpu&name=cpu_host_last_cache",
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = query(&server, "foo", "select * from last_cache('cpu')", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn distinct_cache_answers_tag_value_queries() {
+        let (server, shutdown, _) = setup_server(Arc::new(InMemory::new())).await;
+        let client = Client::new();
+
+        // the values written before the cache is created are seeded into it
+        let lp = "cpu,region=us,host=a usage=1.0
+cpu,region=us,host=b usage=2.0
+cpu,region=eu,host=c usage=3.0";
+        let res = write_lp(&server, "foo", lp, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let request = Request::builder()
+            .uri(format!("{server}/api/v3/configure/distinct_cache"))
+            .method("POST")
+            .body(Body::from(
+                r#"{"db":"foo","table":"cpu","columns":["region","host"]}"#,
+            ))
+            .unwrap();
+        let res = client.request(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let definition: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(definition["name"], "cpu_region_host_distinct_cache");
+        let res = write_lp(&server, "foo", "cpu,region=eu,host=d usage=4.0", None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = query(&server, "foo", "select * from distinct_cache('cpu')", None).await;
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let expected = vec![
+            "+--------+------+",
+            "| region | host |",
+            "+--------+------+",
+            "| eu     | c    |",
+            "| eu     | d    |",
+            "| us     | a    |",
+            "| us     | b    |",
+            "+--------+------+",
+        ];
+        let actual: Vec<_> = body.split('\n').collect();
+        assert_eq!(expected, actual);
+
+        // SHOW TAG VALUES gives the same values whether or not it's answered from the cache
+        async fn show_tag_values(server: &str) -> serde_json::Value {
+            let q = urlencoding::encode(r#"SHOW TAG VALUES WITH KEY = "host""#);
+            let request = Request::builder()
+                .uri(format!(
+                    "{server}/api/v3/query_influxql?db=foo&format=json&q={q}"
+                ))
+                .method("GET")
+                .body(Body::empty())
+                .unwrap();
+            let res = Client::new().request(request).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = body::to_bytes(res.into_body()).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+        let expected = serde_json::json!([
+            {"iox::measurement": "cpu", "key": "host", "value": "a"},
+            {"iox::measurement": "cpu", "key": "host", "value": "b"},
+            {"iox::measurement": "cpu", "key": "host", "value": "c"},
+            {"iox::measurement": "cpu", "key": "host", "value": "d"},
+        ]);
+        assert_eq!(show_tag_values(&server).await, expected);
+
+        let request = Request::builder()
+            .uri(format!(
+                "{server}/api/v3/configure/distinct_cache?db=foo&table=cpu&name=cpu_region_host_distinct_cache"
+            ))
+            .method("DELETE")
+            .body(Body::empty())
+            .unwrap();
+        let res = client.request(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(show_tag_values(&server).await, expected);
+
+        shutdown.cancel();
+    }
+
+    /// Starts a server over the object store, returning its address, the token to shut it down
+    /// and its write buffer.
+    async fn setup_server(
+        object_store: Arc<DynObjectStore>,
+    ) -> (String, CancellationToken, Arc<WriteBufferImpl<WalImpl>>) {
+        let (http_url, _, shutdown, write_buffer) =
+            setup_server_with_grpc(object_store, None).await;
+        (http_url, shutdown, write_buffer)
+    }
+
+    /// Starts a server and returns the urls of its HTTP and gRPC listeners. Requests need a token
+    /// if there is an admin token.
+    async fn setup_server_with_grpc(
+        object_store: Arc<DynObjectStore>,
+        admin_token: Option<&str>,
+    ) -> (
+        String,
+        String,
+        CancellationToken,
+        Arc<WriteBufferImpl<WalImpl>>,
+    ) {
         let addr = get_free_port();
+        let grpc_addr = get_free_port();
         let trace_header_parser = trace_http::ctx::TraceHeaderParser::new();
         let metrics = Arc::new(metric::Registry::new());
-        let common_state =
-            crate::CommonServerState::new(Arc::clone(&metrics), None, trace_header_parser, addr);
-        let catalog = Arc::new(influxdb3_write::catalog::Catalog::new());
-        let object_store: Arc<DynObjectStore> = Arc::new(object_store::memory::InMemory::new());
-        let parquet_store =
-            ParquetStorage::new(Arc::clone(&object_store), StorageId::from("influxdb3"));
+        let common_state = crate::CommonServerState::new(
+            Arc::clone(&metrics),
+            None,
+            trace_header_parser,
+            addr,
+            grpc_addr,
+        );
+        let parquet_store = ParquetStorage::new(
+            Arc::clone(&object_store),
+            StorageId::from(PARQUET_STORAGE_ID),
+        );
         let num_threads = NonZeroUsize::new(2).unwrap();
         let exec = Arc::new(Executor::new_with_config(ExecutorConfig {
             num_threads,
@@ -197,19 +1348,25 @@ mod tests {
             mem_pool_size: usize::MAX,
         }));
 
-        let write_buffer = Arc::new(influxdb3_write::write_buffer::WriteBufferImpl::new(
-            Arc::clone(&catalog),
-            None::<Arc<influxdb3_write::wal::WalImpl>>,
-        ));
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let write_buffer = Arc::new(
+            WriteBufferImpl::new(
+                Arc::clone(&persister) as _,
+                None::<Arc<WalImpl>>,
+                Arc::new(iox_time::SystemProvider::new()),
+                SegmentConfig::default(),
+            )
+            .await
+            .unwrap(),
+        );
         let query_executor = crate::query_executor::QueryExecutorImpl::new(
-            catalog,
+            write_buffer.catalog(),
             Arc::clone(&write_buffer),
             Arc::clone(&exec),
             Arc::clone(&metrics),
             Arc::new(HashMap::new()),
             10,
         );
-        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
 
         let server = crate::Server::new(
             common_state,
@@ -217,33 +1374,19 @@ mod tests {
             Arc::clone(&write_buffer),
             Arc::new(query_executor),
             usize::MAX,
+            admin_token.map(|token| Arc::new(TokenAuthorizer::new(write_buffer.catalog(), token))),
         );
         let frontend_shutdown = CancellationToken::new();
         let shutdown = frontend_shutdown.clone();
 
         tokio::spawn(async move { serve(server, frontend_shutdown).await });
 
-        let server = format!("http://{}", addr);
-        write_lp(&server, "foo", "cpu,host=a val=1i 123", None).await;
-        let res = query(server, "foo", "select * from cpu", None).await;
-
-        let body = body::to_bytes(res.into_body()).await.unwrap();
-        let body = String::from_utf8(body.as_bytes().to_vec()).unwrap();
-        let expected = vec![
-            "+------+-------------------------------+-----+",
-            "| host | time                          | val |",
-            "+------+-------------------------------+-----+",
-            "| a    | 1970-01-01T00:00:00.000000123 | 1   |",
-            "+------+-------------------------------+-----+",
-        ];
-        let actual: Vec<_> = body.split('\n').collect();
-        assert_eq!(
-            expected, actual,
-            "\n\nexpected:\n\n{:#?}\nactual:\n\n{:#?}\n\n",
-            expected, actual
-        );
-
-        shutdown.cancel();
+        (
+            format!("http://{}", addr),
+            format!("http://{}", grpc_addr),
+            shutdown,
+            write_buffer,
+        )
     }
 
     pub(crate) async fn write_lp(
@@ -271,6 +1414,25 @@ mod tests {
             .expect("http error sending write")
     }
 
+    async fn post(
+        url: String,
+        body: impl Into<Body> + Send,
+        content_encoding: Option<&str>,
+    ) -> Response<Body> {
+        let mut builder = Request::builder().uri(url).method("POST");
+        if let Some(content_encoding) = content_encoding {
+            builder = builder.header(hyper::header::CONTENT_ENCODING, content_encoding);
+        };
+        let request = builder
+            .body(body.into())
+            .expect("failed to construct HTTP request");
+
+        Client::new()
+            .request(request)
+            .await
+            .expect("http error sending request")
+    }
+
     pub(crate) async fn query(
         server: impl Into<String> + Send,
         database: impl Into<String> + Send,
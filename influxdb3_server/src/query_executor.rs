@@ -1,14 +1,13 @@
 //! module for query executor
-use crate::QueryExecutor;
+use crate::{QueryExecutor, QueryExecutorError, QueryKind};
 use arrow::datatypes::SchemaRef;
 use async_trait::async_trait;
 use data_types::NamespaceId;
-use data_types::{ChunkId, ChunkOrder, TransitionPartitionId};
 use datafusion::catalog::schema::SchemaProvider;
 use datafusion::catalog::CatalogProvider;
-use datafusion::common::ParamValues;
-use datafusion::common::Statistics;
-use datafusion::datasource::{TableProvider, TableType};
+use datafusion::common::{plan_err, ParamValues, ScalarValue};
+use datafusion::datasource::function::TableFunctionImpl;
+use datafusion::datasource::{MemTable, TableProvider, TableType};
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::SessionState;
 use datafusion::execution::SendableRecordBatchStream;
@@ -17,20 +16,25 @@ use datafusion::prelude::Expr;
 use datafusion_util::config::DEFAULT_SCHEMA;
 use influxdb3_write::{
     catalog::{Catalog, DatabaseSchema},
+    distinct_cache::DistinctCacheProvider,
+    last_cache::LastCacheProvider,
     WriteBuffer,
 };
 use iox_query::exec::{Executor, ExecutorType, IOxSessionContext};
 use iox_query::frontend::sql::SqlQueryPlanner;
 use iox_query::provider::ProviderBuilder;
+use iox_query::pruning::retention_expr;
 use iox_query::query_log::QueryCompletedToken;
 use iox_query::query_log::QueryLog;
 use iox_query::query_log::QueryText;
 use iox_query::query_log::StateReceived;
 use iox_query::QueryNamespaceProvider;
-use iox_query::{QueryChunk, QueryChunkData, QueryNamespace};
+use iox_query::{QueryChunk, QueryNamespace};
+use iox_query_influxql::frontend::planner::InfluxQLQueryPlanner;
+use iox_query_influxql::plan::{TagValuesCache, TagValuesCacheExtension};
+use iox_time::{SystemProvider, TimeProvider};
 use metric::Registry;
 use observability_deps::tracing::info;
-use schema::sort::SortKey;
 use schema::Schema;
 use std::any::Any;
 use std::collections::HashMap;
@@ -83,14 +87,15 @@ impl<W: WriteBuffer> QueryExecutor for QueryExecutorImpl<W> {
         &self,
         database: &str,
         q: &str,
+        kind: QueryKind,
         span_ctx: Option<SpanContext>,
         external_span_ctx: Option<RequestLogContext>,
-    ) -> crate::Result<SendableRecordBatchStream> {
+    ) -> Result<SendableRecordBatchStream, QueryExecutorError> {
         info!("query in executor {}", database);
         let db = self
             .db(database, span_ctx.child_span("get database"), false)
             .await
-            .ok_or_else(|| crate::Error::DatabaseNotFound {
+            .ok_or_else(|| QueryExecutorError::DatabaseNotFound {
                 db_name: database.to_string(),
             })?;
 
@@ -98,16 +103,19 @@ impl<W: WriteBuffer> QueryExecutor for QueryExecutorImpl<W> {
 
         let token = db.record_query(
             external_span_ctx.as_ref().map(RequestLogContext::ctx),
-            "sql",
+            kind.query_type(),
             Box::new(q.to_string()),
         );
 
         info!("plan");
-        let planner = SqlQueryPlanner::new();
         // TODO: Figure out if we want to support parameter values in SQL
         // queries
         let params = ParamValues::List(Vec::new());
-        let plan = planner.query(q, params, &ctx).await?;
+        let plan = match kind {
+            QueryKind::Sql => SqlQueryPlanner::new().query(q, params, &ctx).await,
+            QueryKind::InfluxQl => InfluxQLQueryPlanner::new().query(q, params, &ctx).await,
+        }
+        .map_err(QueryExecutorError::QueryPlanning)?;
         let token = token.planned(Arc::clone(&plan));
 
         // TODO: Enforce concurrency limit here
@@ -121,7 +129,7 @@ impl<W: WriteBuffer> QueryExecutor for QueryExecutorImpl<W> {
             }
             Err(err) => {
                 token.fail();
-                Err(err.into())
+                Err(QueryExecutorError::ExecuteStream(err))
             }
         }
     }
@@ -163,6 +171,7 @@ pub struct QueryDatabase<B> {
     exec: Arc<Executor>,
     datafusion_config: Arc<HashMap<String, String>>,
     query_log: Arc<QueryLog>,
+    time_provider: Arc<dyn TimeProvider>,
 }
 
 impl<B: WriteBuffer> QueryDatabase<B> {
@@ -175,16 +184,15 @@ impl<B: WriteBuffer> QueryDatabase<B> {
         // TODO Fine tune this number
         const QUERY_LOG_LIMIT: usize = 10;
 
-        let query_log = Arc::new(QueryLog::new(
-            QUERY_LOG_LIMIT,
-            Arc::new(iox_time::SystemProvider::new()),
-        ));
+        let time_provider: Arc<dyn TimeProvider> = Arc::new(SystemProvider::new());
+        let query_log = Arc::new(QueryLog::new(QUERY_LOG_LIMIT, Arc::clone(&time_provider)));
         Self {
             db_schema,
             write_buffer,
             exec,
             datafusion_config,
             query_log,
+            time_provider,
         }
     }
 }
@@ -193,17 +201,24 @@ impl<B: WriteBuffer> QueryDatabase<B> {
 impl<B: WriteBuffer> QueryNamespace for QueryDatabase<B> {
     async fn chunks(
         &self,
-        _table_name: &str,
-        _filters: &[Expr],
-        _projection: Option<&Vec<usize>>,
-        _ctx: IOxSessionContext,
+        table_name: &str,
+        filters: &[Expr],
+        projection: Option<&Vec<usize>>,
+        ctx: IOxSessionContext,
     ) -> Result<Vec<Arc<dyn QueryChunk>>, DataFusionError> {
         info!("called chunks on querydatabase");
-        todo!()
+        self.write_buffer.get_table_chunks(
+            &self.db_schema.name,
+            table_name,
+            filters,
+            projection,
+            &ctx.inner().state(),
+        )
     }
 
     fn retention_time_ns(&self) -> Option<i64> {
-        None
+        self.db_schema
+            .retention_time_ns(self.time_provider.now().timestamp_nanos())
     }
 
     fn record_query(
@@ -231,17 +246,42 @@ impl<B: WriteBuffer> QueryNamespace for QueryDatabase<B> {
             Arc::clone(&self.datafusion_config),
         );
 
+        let tag_values_cache = DistinctCacheTagValues {
+            db_name: self.db_schema.name.clone(),
+            provider: self.write_buffer.distinct_cache_provider(),
+            retention_time_ns: self.retention_time_ns(),
+        };
         let mut cfg = self
             .exec
             .new_execution_config(ExecutorType::Query)
             .with_default_catalog(Arc::new(qdb))
-            .with_span_context(span_ctx);
+            .with_span_context(span_ctx)
+            .with_extension(Arc::new(TagValuesCacheExtension(Arc::new(
+                tag_values_cache,
+            ))));
 
         for (k, v) in self.datafusion_config.as_ref() {
             cfg = cfg.with_config_option(k, v);
         }
 
-        cfg.build()
+        let ctx = cfg.build();
+        ctx.inner().register_udtf(
+            LAST_CACHE_UDTF_NAME,
+            Arc::new(LastCacheFunction {
+                db_schema: Arc::clone(&self.db_schema),
+                provider: self.write_buffer.last_cache_provider(),
+                retention_time_ns: self.retention_time_ns(),
+            }),
+        );
+        ctx.inner().register_udtf(
+            DISTINCT_CACHE_UDTF_NAME,
+            Arc::new(DistinctCacheFunction {
+                db_schema: Arc::clone(&self.db_schema),
+                provider: self.write_buffer.distinct_cache_provider(),
+                retention_time_ns: self.retention_time_ns(),
+            }),
+        );
+        ctx
     }
 }
 
@@ -284,7 +324,7 @@ impl<B: WriteBuffer> SchemaProvider for QueryDatabase<B> {
     async fn table(&self, name: &str) -> Option<Arc<dyn TableProvider>> {
         info!("table {}", name);
 
-        let schema = self.db_schema.get_table_schema(name).unwrap();
+        let schema = self.db_schema.get_table_schema(name)?;
 
         info!("return QueryTable");
         let name: Arc<str> = name.into();
@@ -293,6 +333,7 @@ impl<B: WriteBuffer> SchemaProvider for QueryDatabase<B> {
             name,
             schema,
             write_buffer: Arc::clone(&self.write_buffer),
+            retention_time_ns: self.retention_time_ns(),
         }))
     }
 
@@ -307,6 +348,8 @@ pub struct QueryTable<B> {
     name: Arc<str>,
     schema: Schema,
     write_buffer: Arc<B>,
+    /// Rows at or before this time are past the retention period of the database
+    retention_time_ns: Option<i64>,
 }
 
 impl<B: WriteBuffer> QueryTable<B> {
@@ -348,7 +391,10 @@ impl<B: WriteBuffer> TableProvider for QueryTable<B> {
         filters: &[Expr],
         limit: Option<usize>,
     ) -> datafusion::common::Result<Arc<dyn ExecutionPlan>> {
-        let filters = filters.to_vec();
+        let mut filters = filters.to_vec();
+        if let Some(retention_time_ns) = self.retention_time_ns {
+            filters.push(retention_expr(retention_time_ns));
+        }
         info!(
             "TableProvider scan {:?} {:?} {:?}",
             projection, filters, limit
@@ -369,47 +415,124 @@ impl<B: WriteBuffer> TableProvider for QueryTable<B> {
     }
 }
 
-#[derive(Debug)]
-pub struct ParquetChunk {}
+/// The name of the table function that reads the rows in the last cache of a table.
+const LAST_CACHE_UDTF_NAME: &str = "last_cache";
 
-impl QueryChunk for ParquetChunk {
-    fn stats(&self) -> Arc<Statistics> {
-        todo!()
-    }
+/// Reads the rows in the last cache of a table, which is given by name along with the name of
+/// the cache if the table has more than one, like `SELECT * FROM last_cache('cpu')`.
+#[derive(Debug)]
+struct LastCacheFunction {
+    db_schema: Arc<DatabaseSchema>,
+    provider: Arc<LastCacheProvider>,
+    /// Rows at or before this time are past the retention period of the database
+    retention_time_ns: Option<i64>,
+}
 
-    fn schema(&self) -> &Schema {
-        todo!()
+impl TableFunctionImpl for LastCacheFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>, DataFusionError> {
+        let (table_name, cache_name) = cache_function_args(LAST_CACHE_UDTF_NAME, args)?;
+        let schema = table_schema(&self.db_schema, table_name)?;
+
+        let batch = self
+            .provider
+            .cached_rows(
+                &self.db_schema.name,
+                table_name,
+                cache_name,
+                &schema,
+                self.retention_time_ns,
+            )
+            .map_err(|e| DataFusionError::Plan(e.to_string()))?;
+        Ok(Arc::new(MemTable::try_new(
+            batch.schema(),
+            vec![vec![batch]],
+        )?))
     }
+}
 
-    fn partition_id(&self) -> &TransitionPartitionId {
-        todo!()
-    }
+/// The name of the table function that reads the values in the distinct cache of a table.
+const DISTINCT_CACHE_UDTF_NAME: &str = "distinct_cache";
 
-    fn sort_key(&self) -> Option<&SortKey> {
-        todo!()
-    }
+/// Reads the combinations of values in the distinct cache of a table, which is given by name
+/// along with the name of the cache if the table has more than one, like
+/// `SELECT * FROM distinct_cache('cpu')`.
+#[derive(Debug)]
+struct DistinctCacheFunction {
+    db_schema: Arc<DatabaseSchema>,
+    provider: Arc<DistinctCacheProvider>,
+    /// Values last written at or before this time are past the retention period of the database
+    retention_time_ns: Option<i64>,
+}
 
-    fn id(&self) -> ChunkId {
-        todo!()
+impl TableFunctionImpl for DistinctCacheFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>, DataFusionError> {
+        let (table_name, cache_name) = cache_function_args(DISTINCT_CACHE_UDTF_NAME, args)?;
+        let schema = table_schema(&self.db_schema, table_name)?;
+
+        let batch = self
+            .provider
+            .cached_values(
+                &self.db_schema.name,
+                table_name,
+                cache_name,
+                &schema,
+                self.retention_time_ns,
+            )
+            .map_err(|e| DataFusionError::Plan(e.to_string()))?;
+        Ok(Arc::new(MemTable::try_new(
+            batch.schema(),
+            vec![vec![batch]],
+        )?))
     }
+}
 
-    fn may_contain_pk_duplicates(&self) -> bool {
-        todo!()
+/// Returns the name of the table and, if it's given, of the cache that the arguments of a cache
+/// table function name.
+fn cache_function_args<'a>(
+    function_name: &str,
+    args: &'a [Expr],
+) -> Result<(&'a str, Option<&'a str>), DataFusionError> {
+    let mut names = vec![];
+    for arg in args {
+        match arg {
+            Expr::Literal(ScalarValue::Utf8(Some(name))) => names.push(name.as_str()),
+            _ => return plan_err!("{function_name} arguments must be string literals"),
+        }
     }
-
-    fn data(&self) -> QueryChunkData {
-        todo!()
+    match names.as_slice() {
+        [table_name] => Ok((*table_name, None)),
+        [table_name, cache_name] => Ok((*table_name, Some(*cache_name))),
+        _ => plan_err!("{function_name} takes the name of a table and optionally of its cache"),
     }
+}
 
-    fn chunk_type(&self) -> &str {
-        todo!()
+fn table_schema(db_schema: &DatabaseSchema, table_name: &str) -> Result<Schema, DataFusionError> {
+    match db_schema.get_table_schema(table_name) {
+        Some(schema) => Ok(schema),
+        None => plan_err!(
+            "table {table_name} not found in database {}",
+            db_schema.name
+        ),
     }
+}
 
-    fn order(&self) -> ChunkOrder {
-        todo!()
-    }
+/// Answers `SHOW TAG VALUES` for the tags that have a distinct cache.
+#[derive(Debug)]
+struct DistinctCacheTagValues {
+    db_name: String,
+    provider: Arc<DistinctCacheProvider>,
+    /// Values last written at or before this time are past the retention period of the database
+    retention_time_ns: Option<i64>,
+}
 
-    fn as_any(&self) -> &dyn Any {
-        todo!()
+impl TagValuesCache for DistinctCacheTagValues {
+    fn tag_values(&self, table_name: &str, tag_key: &str, min_time: i64) -> Option<Vec<String>> {
+        let min_time = self
+            .retention_time_ns
+            .map_or(min_time, |retention_time_ns| {
+                min_time.max(retention_time_ns.saturating_add(1))
+            });
+        self.provider
+            .tag_values(&self.db_name, table_name, tag_key, min_time)
     }
 }
@@ -1,6 +1,10 @@
 //! Implementation of the Catalog that sits entirely in memory.
 
+use crate::SegmentId;
+use data_types::partition_template::{TablePartitionTemplateOverride, ValidationError};
 use data_types::ColumnType;
+use generated_types::influxdata::iox::partition_template::v1::PartitionTemplate;
+use iox_catalog::constants::TIME_COLUMN;
 use observability_deps::tracing::info;
 use parking_lot::RwLock;
 use schema::{InfluxColumnType, InfluxFieldType, Schema, SchemaBuilder};
@@ -15,6 +19,60 @@ use thiserror::Error;
 pub enum Error {
     #[error("catalog updated elsewhere")]
     CatalogUpdatedElsewhere,
+
+    #[error("database {0} already exists")]
+    DatabaseExists(String),
+
+    #[error("database {0} not found")]
+    DatabaseNotFound(String),
+
+    #[error("table {table_name} already exists in database {db_name}")]
+    TableExists { db_name: String, table_name: String },
+
+    #[error("table {table_name} not found in database {db_name}")]
+    TableNotFound { db_name: String, table_name: String },
+
+    #[error("invalid partition template: {0}")]
+    InvalidPartitionTemplate(#[from] ValidationError),
+
+    #[error("invalid retention period of {0}ns, it must be greater than zero")]
+    InvalidRetentionPeriod(i64),
+
+    #[error("last cache {cache_name} already exists for table {table_name} in database {db_name}")]
+    LastCacheExists {
+        db_name: String,
+        table_name: String,
+        cache_name: String,
+    },
+
+    #[error("last cache {cache_name} not found for table {table_name} in database {db_name}")]
+    LastCacheNotFound {
+        db_name: String,
+        table_name: String,
+        cache_name: String,
+    },
+
+    #[error("invalid last cache: {0}")]
+    InvalidLastCache(String),
+
+    #[error(
+        "distinct cache {cache_name} already exists for table {table_name} in database {db_name}"
+    )]
+    DistinctCacheExists {
+        db_name: String,
+        table_name: String,
+        cache_name: String,
+    },
+
+    #[error("distinct cache {cache_name} not found for table {table_name} in database {db_name}")]
+    DistinctCacheNotFound {
+        db_name: String,
+        table_name: String,
+        cache_name: String,
+    },
+
+    #[error("invalid distinct cache: {0}")]
+    InvalidDistinctCache(String),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -75,21 +133,508 @@ impl Catalog {
         (sequence, db)
     }
 
+    /// Creates an empty database. Its tables are partitioned with the partition template, or
+    /// by day if there isn't one, unless a table is created with its own template. Data older
+    /// than the retention period is dropped; without one it is kept forever.
+    pub fn create_database(
+        &self,
+        db_name: &str,
+        partition_template: Option<PartitionTemplate>,
+        retention_period_ns: Option<i64>,
+    ) -> Result<()> {
+        let partition_template = TablePartitionTemplateOverride::try_from(partition_template)?;
+        if let Some(period) = retention_period_ns.filter(|period| *period <= 0) {
+            return Err(Error::InvalidRetentionPeriod(period));
+        }
+        let mut inner = self.inner.write();
+        if inner.databases.contains_key(db_name) {
+            return Err(Error::DatabaseExists(db_name.to_string()));
+        }
+
+        info!("created db {}", db_name);
+        inner.sequence += 1;
+        let mut db = DatabaseSchema::new(db_name);
+        db.partition_template = partition_template;
+        db.retention_period_ns = retention_period_ns;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+
+        Ok(())
+    }
+
+    /// Deletes a database while `segment_id` is the open segment. Data for the database in
+    /// earlier segments is ignored from then on, even if a database with the same name is
+    /// created again.
+    pub fn delete_database(&self, db_name: &str, segment_id: SegmentId) -> Result<()> {
+        let mut inner = self.inner.write();
+        if inner.databases.remove(db_name).is_none() {
+            return Err(Error::DatabaseNotFound(db_name.to_string()));
+        }
+
+        info!("deleted db {}", db_name);
+        inner.sequence += 1;
+        inner
+            .deleted_databases
+            .insert(db_name.to_string(), segment_id);
+
+        Ok(())
+    }
+
+    /// Creates a table with the given columns in an existing database. Every table has a time
+    /// column, which is added if it isn't one of the columns. The table is partitioned with the
+    /// partition template if there is one, and otherwise with the template of the database.
+    pub fn create_table(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        columns: BTreeMap<String, ColumnType>,
+        partition_template: Option<PartitionTemplate>,
+    ) -> Result<()> {
+        let partition_template = partition_template
+            .map(|template| TablePartitionTemplateOverride::try_from(Some(template)))
+            .transpose()?;
+        let mut inner = self.inner.write();
+        let db = inner
+            .databases
+            .get(db_name)
+            .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+        if db.table_exists(table_name) {
+            return Err(Error::TableExists {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+            });
+        }
+
+        let mut columns: BTreeMap<_, _> = columns
+            .into_iter()
+            .map(|(name, column_type)| (name, column_type as i16))
+            .collect();
+        columns
+            .entry(TIME_COLUMN.to_string())
+            .or_insert(ColumnType::Time as i16);
+        let partition_template =
+            partition_template.unwrap_or_else(|| db.partition_template.clone());
+        let mut db = DatabaseSchema::clone(db);
+        db.tables.insert(
+            table_name.to_string(),
+            TableDefinition::new(table_name, columns, partition_template),
+        );
+
+        info!("created table {} in db {}", table_name, db_name);
+        inner.sequence += 1;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+
+        Ok(())
+    }
+
+    /// Deletes a table while `segment_id` is the open segment. Like with databases, data for
+    /// the table in earlier segments is ignored from then on.
+    pub fn delete_table(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        segment_id: SegmentId,
+    ) -> Result<()> {
+        let mut inner = self.inner.write();
+        let db = inner
+            .databases
+            .get(db_name)
+            .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+        if !db.table_exists(table_name) {
+            return Err(Error::TableNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+            });
+        }
+
+        let mut db = DatabaseSchema::clone(db);
+        db.tables.remove(table_name);
+
+        info!("deleted table {} in db {}", table_name, db_name);
+        inner.sequence += 1;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+        inner
+            .deleted_tables
+            .entry(db_name.to_string())
+            .or_default()
+            .insert(table_name.to_string(), segment_id);
+
+        Ok(())
+    }
+
+    /// Adds a last cache to a table. The cache is named after the table and its key columns if
+    /// it isn't given a name. The key columns must be tags of the table, and are all of its tags
+    /// if none are given. The value columns must be other columns of the table; if none are
+    /// given every column that isn't a key is cached, including columns added later. Returns the
+    /// definition of the cache.
+    pub fn create_last_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        key_columns: Option<Vec<String>>,
+        value_columns: Option<Vec<String>>,
+        count: Option<usize>,
+    ) -> Result<LastCacheDefinition> {
+        let mut inner = self.inner.write();
+        let db = inner
+            .databases
+            .get(db_name)
+            .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+        let table = db
+            .tables
+            .get(table_name)
+            .ok_or_else(|| Error::TableNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+            })?;
+
+        let is_tag = |column: &str| table.columns.get(column) == Some(&(ColumnType::Tag as i16));
+        let key_columns = match key_columns {
+            Some(key_columns) => {
+                if let Some(column) = key_columns.iter().find(|column| !is_tag(column)) {
+                    return Err(Error::InvalidLastCache(format!(
+                        "key column {column} is not a tag of table {table_name}"
+                    )));
+                }
+                key_columns
+            }
+            None => table
+                .columns
+                .keys()
+                .filter(|column| is_tag(column))
+                .cloned()
+                .collect(),
+        };
+        if let Some(value_columns) = &value_columns {
+            if let Some(column) = value_columns.iter().find(|column| {
+                !table.column_exists(column)
+                    || key_columns.contains(column)
+                    || column.as_str() == TIME_COLUMN
+            }) {
+                return Err(Error::InvalidLastCache(format!(
+                    "value column {column} is not a column of table {table_name} other than a key \
+                     or time"
+                )));
+            }
+        }
+        let count = count.unwrap_or(LastCacheDefinition::DEFAULT_COUNT);
+        if !(1..=LastCacheDefinition::MAX_COUNT).contains(&count) {
+            return Err(Error::InvalidLastCache(format!(
+                "count must be between 1 and {}",
+                LastCacheDefinition::MAX_COUNT
+            )));
+        }
+
+        let cache_name = match cache_name {
+            Some(name) => name.to_string(),
+            None => [table_name]
+                .into_iter()
+                .chain(key_columns.iter().map(String::as_str))
+                .chain(["last_cache"])
+                .collect::<Vec<_>>()
+                .join("_"),
+        };
+        if table.last_caches.contains_key(&cache_name) {
+            return Err(Error::LastCacheExists {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+                cache_name,
+            });
+        }
+
+        let definition = LastCacheDefinition {
+            name: cache_name.clone(),
+            key_columns,
+            value_columns,
+            count,
+        };
+        let mut db = DatabaseSchema::clone(db);
+        db.tables
+            .get_mut(table_name)
+            .expect("table exists")
+            .last_caches
+            .insert(cache_name, definition.clone());
+
+        info!(
+            cache_name = %definition.name,
+            "created last cache for table {} in db {}", table_name, db_name
+        );
+        inner.sequence += 1;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+
+        Ok(definition)
+    }
+
+    /// Removes a last cache from a table.
+    pub fn delete_last_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+    ) -> Result<()> {
+        let mut inner = self.inner.write();
+        let db = inner
+            .databases
+            .get(db_name)
+            .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+        let mut db = DatabaseSchema::clone(db);
+        let table = db
+            .tables
+            .get_mut(table_name)
+            .ok_or_else(|| Error::TableNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+            })?;
+        if table.last_caches.remove(cache_name).is_none() {
+            return Err(Error::LastCacheNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+                cache_name: cache_name.to_string(),
+            });
+        }
+
+        info!(
+            %cache_name,
+            "deleted last cache for table {} in db {}", table_name, db_name
+        );
+        inner.sequence += 1;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+
+        Ok(())
+    }
+
+    /// Adds a distinct value cache to a table for a hierarchy of its tags, given from the top
+    /// down, like a region and then the hosts in it. The cache is named after the table and its
+    /// columns if it isn't given a name. Returns the definition of the cache.
+    pub fn create_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        columns: Vec<String>,
+        max_cardinality: Option<usize>,
+        max_age_seconds: Option<u64>,
+    ) -> Result<DistinctCacheDefinition> {
+        let mut inner = self.inner.write();
+        let db = inner
+            .databases
+            .get(db_name)
+            .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+        let table = db
+            .tables
+            .get(table_name)
+            .ok_or_else(|| Error::TableNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+            })?;
+
+        if columns.is_empty() {
+            return Err(Error::InvalidDistinctCache(
+                "at least one column must be cached".to_string(),
+            ));
+        }
+        for (i, column) in columns.iter().enumerate() {
+            if table.columns.get(column) != Some(&(ColumnType::Tag as i16)) {
+                return Err(Error::InvalidDistinctCache(format!(
+                    "column {column} is not a tag of table {table_name}"
+                )));
+            }
+            if columns[..i].contains(column) {
+                return Err(Error::InvalidDistinctCache(format!(
+                    "column {column} is given more than once"
+                )));
+            }
+        }
+        let max_cardinality =
+            max_cardinality.unwrap_or(DistinctCacheDefinition::DEFAULT_MAX_CARDINALITY);
+        if max_cardinality == 0 {
+            return Err(Error::InvalidDistinctCache(
+                "max cardinality must be greater than zero".to_string(),
+            ));
+        }
+        let max_age_seconds =
+            max_age_seconds.unwrap_or(DistinctCacheDefinition::DEFAULT_MAX_AGE_SECONDS);
+        if max_age_seconds == 0 {
+            return Err(Error::InvalidDistinctCache(
+                "max age must be greater than zero".to_string(),
+            ));
+        }
+
+        let cache_name = match cache_name {
+            Some(name) => name.to_string(),
+            None => [table_name]
+                .into_iter()
+                .chain(columns.iter().map(String::as_str))
+                .chain(["distinct_cache"])
+                .collect::<Vec<_>>()
+                .join("_"),
+        };
+        if table.distinct_caches.contains_key(&cache_name) {
+            return Err(Error::DistinctCacheExists {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+                cache_name,
+            });
+        }
+
+        let definition = DistinctCacheDefinition {
+            name: cache_name.clone(),
+            columns,
+            max_cardinality,
+            max_age_seconds,
+        };
+        let mut db = DatabaseSchema::clone(db);
+        db.tables
+            .get_mut(table_name)
+            .expect("table exists")
+            .distinct_caches
+            .insert(cache_name, definition.clone());
+
+        info!(
+            cache_name = %definition.name,
+            "created distinct cache for table {} in db {}", table_name, db_name
+        );
+        inner.sequence += 1;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+
+        Ok(definition)
+    }
+
+    /// Removes a distinct value cache from a table.
+    pub fn delete_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+    ) -> Result<()> {
+        let mut inner = self.inner.write();
+        let db = inner
+            .databases
+            .get(db_name)
+            .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+        let mut db = DatabaseSchema::clone(db);
+        let table = db
+            .tables
+            .get_mut(table_name)
+            .ok_or_else(|| Error::TableNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+            })?;
+        if table.distinct_caches.remove(cache_name).is_none() {
+            return Err(Error::DistinctCacheNotFound {
+                db_name: db_name.to_string(),
+                table_name: table_name.to_string(),
+                cache_name: cache_name.to_string(),
+            });
+        }
+
+        info!(
+            %cache_name,
+            "deleted distinct cache for table {} in db {}", table_name, db_name
+        );
+        inner.sequence += 1;
+        inner.databases.insert(db_name.to_string(), Arc::new(db));
+
+        Ok(())
+    }
+
+    /// Returns true if the data for the table in the segment was deleted, because the database
+    /// or the table was deleted after the segment was closed.
+    pub(crate) fn is_deleted(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        segment_id: SegmentId,
+    ) -> bool {
+        let inner = self.inner.read();
+        let deleted_in = |deleted: Option<&SegmentId>| deleted.is_some_and(|id| segment_id < *id);
+
+        deleted_in(inner.deleted_databases.get(db_name))
+            || deleted_in(
+                inner
+                    .deleted_tables
+                    .get(db_name)
+                    .and_then(|tables| tables.get(table_name)),
+            )
+    }
+
+    /// Returns every database, ordered by name.
+    pub fn databases(&self) -> Vec<Arc<DatabaseSchema>> {
+        let mut databases: Vec<_> = self.inner.read().databases.values().cloned().collect();
+        databases.sort_by(|a, b| a.name.cmp(&b.name));
+        databases
+    }
+
     pub fn db_schema(&self, name: &str) -> Option<Arc<DatabaseSchema>> {
         info!("db_schema {}", name);
         self.inner.read().databases.get(name).cloned()
     }
 
+    pub fn from_inner(inner: InnerCatalog) -> Self {
+        Self {
+            inner: RwLock::new(inner),
+        }
+    }
+
     pub fn into_inner(self) -> InnerCatalog {
         self.inner.into_inner()
     }
+
+    pub fn clone_inner(&self) -> InnerCatalog {
+        self.inner.read().clone()
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.inner.read().sequence
+    }
+
+    /// Adds an API token to the catalog. Only the hash of the token is kept.
+    ///
+    /// Tokens don't move the sequence number, which tracks changes to the schema, so they don't
+    /// conflict with writes. They aren't in the WAL either, so the catalog should be persisted
+    /// after they change.
+    pub fn insert_token(&self, token: TokenInfo) {
+        info!(id = %token.id, "inserted token");
+        self.inner.write().tokens.insert(token.id.clone(), token);
+    }
+
+    /// Removes the API token with the given id, returning it if it existed.
+    pub fn revoke_token(&self, id: &str) -> Option<TokenInfo> {
+        let token = self.inner.write().tokens.remove(id)?;
+        info!(%id, "revoked token");
+        Some(token)
+    }
+
+    /// Returns the API token with the given hash.
+    pub fn token_by_hash(&self, hash: &str) -> Option<TokenInfo> {
+        self.inner
+            .read()
+            .tokens
+            .values()
+            .find(|token| token.hash == hash)
+            .cloned()
+    }
+
+    /// Returns every API token, ordered by id.
+    pub fn tokens(&self) -> Vec<TokenInfo> {
+        self.inner.read().tokens.values().cloned().collect()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct InnerCatalog {
     /// The catalog is a map of databases with their table schemas
     databases: HashMap<String, Arc<DatabaseSchema>>,
     sequence: u64,
+    /// The API tokens by id. Catalogs persisted before tokens existed don't have any.
+    #[serde(default)]
+    tokens: BTreeMap<String, TokenInfo>,
+    /// The segment that was open when each deleted database was last deleted.
+    #[serde(default)]
+    deleted_databases: BTreeMap<String, SegmentId>,
+    /// The segment that was open when each deleted table was last deleted, by database.
+    #[serde(default)]
+    deleted_tables: BTreeMap<String, BTreeMap<String, SegmentId>>,
 }
 
 impl InnerCatalog {
@@ -97,6 +642,9 @@ impl InnerCatalog {
         Self {
             databases: HashMap::new(),
             sequence: 0,
+            tokens: BTreeMap::new(),
+            deleted_databases: BTreeMap::new(),
+            deleted_tables: BTreeMap::new(),
         }
     }
 
@@ -111,6 +659,12 @@ pub struct DatabaseSchema {
     pub name: String,
     /// The database is a map of tables
     pub(crate) tables: BTreeMap<String, TableDefinition>,
+    /// The partition template of tables that are created without one
+    #[serde(default, with = "serde_partition_template")]
+    pub(crate) partition_template: TablePartitionTemplateOverride,
+    /// How long data is kept for, in nanoseconds, or forever if it isn't set
+    #[serde(default)]
+    pub(crate) retention_period_ns: Option<i64>,
 }
 
 impl DatabaseSchema {
@@ -118,9 +672,26 @@ impl DatabaseSchema {
         Self {
             name: name.into(),
             tables: BTreeMap::new(),
+            partition_template: TablePartitionTemplateOverride::default(),
+            retention_period_ns: None,
         }
     }
 
+    pub fn partition_template(&self) -> &TablePartitionTemplateOverride {
+        &self.partition_template
+    }
+
+    pub fn retention_period_ns(&self) -> Option<i64> {
+        self.retention_period_ns
+    }
+
+    /// Returns the time, in nanoseconds since the epoch, that data must be newer than to be
+    /// retained as of `now_ns`, if the database has a retention period.
+    pub fn retention_time_ns(&self, now_ns: i64) -> Option<i64> {
+        self.retention_period_ns
+            .map(|period| now_ns.saturating_sub(period))
+    }
+
     pub fn get_table_schema(&self, table_name: &str) -> Option<Schema> {
         self.tables
             .get(table_name)
@@ -134,6 +705,36 @@ impl DatabaseSchema {
     pub fn table_exists(&self, table_name: &str) -> bool {
         self.tables.contains_key(table_name)
     }
+
+    /// Returns the tables of the database, ordered by name.
+    pub fn tables(&self) -> impl Iterator<Item = &TableDefinition> {
+        self.tables.values()
+    }
+}
+
+/// An API token that can be used to access the server. The token itself is never stored, only
+/// a hash of it that requests are checked against.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct TokenInfo {
+    pub id: String,
+    pub description: Option<String>,
+    /// The hex encoded SHA-256 hash of the token
+    pub hash: String,
+    pub permissions: Vec<TokenPermission>,
+}
+
+/// The actions that a token is allowed to perform on a database.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct TokenPermission {
+    pub database: String,
+    pub actions: Vec<TokenAction>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenAction {
+    Read,
+    Write,
 }
 
 #[derive(Debug, Serialize, Eq, PartialEq, Clone)]
@@ -142,6 +743,49 @@ pub struct TableDefinition {
     #[serde(skip_serializing, skip_deserializing)]
     pub schema: Option<Schema>,
     columns: BTreeMap<String, i16>,
+    #[serde(with = "serde_partition_template")]
+    partition_template: TablePartitionTemplateOverride,
+    /// The last caches of the table by name
+    last_caches: BTreeMap<String, LastCacheDefinition>,
+    /// The distinct value caches of the table by name
+    distinct_caches: BTreeMap<String, DistinctCacheDefinition>,
+}
+
+/// A cache of the last values written to a table for every combination of the values of its key
+/// columns, which is kept up to date as data is written.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct LastCacheDefinition {
+    pub name: String,
+    /// The tag columns that the last values are kept for each combination of
+    pub key_columns: Vec<String>,
+    /// The columns that are cached, or every column that isn't a key if it isn't set
+    pub value_columns: Option<Vec<String>>,
+    /// How many of the most recent rows are kept for each combination of key values
+    pub count: usize,
+}
+
+impl LastCacheDefinition {
+    pub const DEFAULT_COUNT: usize = 1;
+    pub const MAX_COUNT: usize = 10;
+}
+
+/// A cache of the distinct combinations of values written to a hierarchy of tags of a table,
+/// which is kept up to date as data is written.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct DistinctCacheDefinition {
+    pub name: String,
+    /// The tag columns from the top of the hierarchy down
+    pub columns: Vec<String>,
+    /// The most combinations of values that are kept, past which the least recently written
+    /// ones are evicted
+    pub max_cardinality: usize,
+    /// Combinations of values that haven't been written for this long are aged out
+    pub max_age_seconds: u64,
+}
+
+impl DistinctCacheDefinition {
+    pub const DEFAULT_MAX_CARDINALITY: usize = 100_000;
+    pub const DEFAULT_MAX_AGE_SECONDS: u64 = 24 * 60 * 60;
 }
 
 struct TableDefinitionVisitor;
@@ -159,6 +803,9 @@ impl<'de> Visitor<'de> for TableDefinitionVisitor {
     {
         let mut name = None;
         let mut columns = None;
+        let mut partition_template = None;
+        let mut last_caches = None;
+        let mut distinct_caches = None;
         while let Some(key) = map.next_key::<String>()? {
             match key.as_str() {
                 "name" => {
@@ -173,6 +820,30 @@ impl<'de> Visitor<'de> for TableDefinitionVisitor {
                     }
                     columns = Some(map.next_value::<BTreeMap<String, i16>>()?);
                 }
+                "partition_template" => {
+                    if partition_template.is_some() {
+                        return Err(serde::de::Error::duplicate_field("partition_template"));
+                    }
+                    partition_template = Some(
+                        TablePartitionTemplateOverride::try_from(
+                            map.next_value::<Option<PartitionTemplate>>()?,
+                        )
+                        .map_err(serde::de::Error::custom)?,
+                    );
+                }
+                "last_caches" => {
+                    if last_caches.is_some() {
+                        return Err(serde::de::Error::duplicate_field("last_caches"));
+                    }
+                    last_caches = Some(map.next_value::<BTreeMap<String, LastCacheDefinition>>()?);
+                }
+                "distinct_caches" => {
+                    if distinct_caches.is_some() {
+                        return Err(serde::de::Error::duplicate_field("distinct_caches"));
+                    }
+                    distinct_caches =
+                        Some(map.next_value::<BTreeMap<String, DistinctCacheDefinition>>()?);
+                }
                 _ => {
                     let _ = map.next_value::<serde::de::IgnoredAny>()?;
                 }
@@ -181,7 +852,11 @@ impl<'de> Visitor<'de> for TableDefinitionVisitor {
         let name = name.ok_or_else(|| serde::de::Error::missing_field("name"))?;
         let columns = columns.ok_or_else(|| serde::de::Error::missing_field("columns"))?;
 
-        Ok(TableDefinition::new(name, columns))
+        // tables created before partition templates were configurable are partitioned by day
+        let mut table = TableDefinition::new(name, columns, partition_template.unwrap_or_default());
+        table.last_caches = last_caches.unwrap_or_default();
+        table.distinct_caches = distinct_caches.unwrap_or_default();
+        Ok(table)
     }
 }
 
@@ -195,7 +870,11 @@ impl<'de> Deserialize<'de> for TableDefinition {
 }
 
 impl TableDefinition {
-    pub(crate) fn new(name: impl Into<String>, columns: BTreeMap<String, i16>) -> Self {
+    pub(crate) fn new(
+        name: impl Into<String>,
+        columns: BTreeMap<String, i16>,
+        partition_template: TablePartitionTemplateOverride,
+    ) -> Self {
         let mut schema_builder = SchemaBuilder::with_capacity(columns.len());
         for (name, column_type) in &columns {
             schema_builder.influx_column(
@@ -209,6 +888,9 @@ impl TableDefinition {
             name: name.into(),
             schema: Some(schema),
             columns,
+            partition_template,
+            last_caches: BTreeMap::new(),
+            distinct_caches: BTreeMap::new(),
         }
     }
 
@@ -216,10 +898,14 @@ impl TableDefinition {
         self.columns.contains_key(column)
     }
 
-    pub(crate) fn add_columns(&mut self, mut columns: Vec<(String, i16)>) {
-        let mut schema_builder = SchemaBuilder::with_capacity(columns.len());
-        columns.sort_by(|(a, _), (b, _)| a.cmp(b));
-        for (name, column_type) in &columns {
+    pub(crate) fn add_columns(&mut self, columns: Vec<(String, i16)>) {
+        for (name, column_type) in columns.into_iter() {
+            self.columns.insert(name, column_type);
+        }
+
+        // the schema must include the existing columns as well as the new ones
+        let mut schema_builder = SchemaBuilder::with_capacity(self.columns.len());
+        for (name, column_type) in &self.columns {
             schema_builder.influx_column(
                 name,
                 column_type_to_influx_column_type(&ColumnType::try_from(*column_type).unwrap()),
@@ -227,15 +913,58 @@ impl TableDefinition {
         }
         let schema = schema_builder.build().unwrap();
 
-        for (name, column_type) in columns.into_iter() {
-            self.columns.insert(name, column_type);
-        }
         self.schema = Some(schema);
     }
 
     pub(crate) fn columns(&self) -> &BTreeMap<String, i16> {
         &self.columns
     }
+
+    pub fn partition_template(&self) -> &TablePartitionTemplateOverride {
+        &self.partition_template
+    }
+
+    /// Returns the last caches of the table, ordered by name.
+    pub fn last_caches(&self) -> impl Iterator<Item = &LastCacheDefinition> {
+        self.last_caches.values()
+    }
+
+    /// Returns the distinct value caches of the table, ordered by name.
+    pub fn distinct_caches(&self) -> impl Iterator<Item = &DistinctCacheDefinition> {
+        self.distinct_caches.values()
+    }
+
+    /// Returns the name and type of every column, ordered by name.
+    pub fn column_types(&self) -> impl Iterator<Item = (&str, ColumnType)> {
+        self.columns.iter().map(|(name, column_type)| {
+            (
+                name.as_str(),
+                ColumnType::try_from(*column_type).expect("catalog should have valid column types"),
+            )
+        })
+    }
+}
+
+/// Serializes partition templates as their protobuf JSON representation, which is `null` for
+/// the default template of partitioning by day.
+mod serde_partition_template {
+    use super::PartitionTemplate;
+    use data_types::partition_template::TablePartitionTemplateOverride;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        template: &TablePartitionTemplateOverride,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        template.as_proto().serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<TablePartitionTemplateOverride, D::Error> {
+        let template = Option::<PartitionTemplate>::deserialize(deserializer)?;
+        TablePartitionTemplateOverride::try_from(template).map_err(serde::de::Error::custom)
+    }
 }
 
 fn column_type_to_influx_column_type(column_type: &ColumnType) -> InfluxColumnType {
@@ -253,6 +982,16 @@ fn column_type_to_influx_column_type(column_type: &ColumnType) -> InfluxColumnTy
 #[cfg(test)]
 mod tests {
     use super::*;
+    use data_types::partition_template::TemplatePart as Part;
+    use generated_types::influxdata::iox::partition_template::v1::{template_part, TemplatePart};
+
+    fn time_format_template(format: &str) -> PartitionTemplate {
+        PartitionTemplate {
+            parts: vec![TemplatePart {
+                part: Some(template_part::Part::TimeFormat(format.to_string())),
+            }],
+        }
+    }
 
     #[test]
     fn catalog_serialization() {
@@ -260,12 +999,16 @@ mod tests {
         let mut database = DatabaseSchema {
             name: "test".to_string(),
             tables: BTreeMap::new(),
+            partition_template: TablePartitionTemplateOverride::default(),
+            retention_period_ns: Some(30 * 24 * 60 * 60 * 1_000_000_000),
         };
         database.tables.insert(
             "test".into(),
             TableDefinition::new(
                 "test",
                 BTreeMap::from([("test".to_string(), ColumnType::String as i16)]),
+                TablePartitionTemplateOverride::try_from(Some(time_format_template("%Y-%m")))
+                    .unwrap(),
             ),
         );
         let database = Arc::new(database);
@@ -277,4 +1020,303 @@ mod tests {
 
         assert_eq!(*inner, deserialized);
     }
+
+    #[test]
+    fn create_and_delete_databases_and_tables() {
+        let catalog = Catalog::new();
+        catalog.create_database("foo", None, None).unwrap();
+        assert!(matches!(
+            catalog.create_database("foo", None, None),
+            Err(Error::DatabaseExists(_))
+        ));
+        assert!(matches!(
+            catalog.create_database("bar", None, Some(0)),
+            Err(Error::InvalidRetentionPeriod(0))
+        ));
+
+        catalog
+            .create_table(
+                "foo",
+                "cpu",
+                BTreeMap::from([
+                    ("host".to_string(), ColumnType::Tag),
+                    ("usage".to_string(), ColumnType::F64),
+                ]),
+                None,
+            )
+            .unwrap();
+        let db = catalog.db_schema("foo").unwrap();
+        assert_eq!(
+            db.tables["cpu"].columns().keys().collect::<Vec<_>>(),
+            vec!["host", "time", "usage"]
+        );
+        assert!(matches!(
+            catalog.create_table("foo", "cpu", BTreeMap::new(), None),
+            Err(Error::TableExists { .. })
+        ));
+        assert!(matches!(
+            catalog.create_table("bar", "cpu", BTreeMap::new(), None),
+            Err(Error::DatabaseNotFound(_))
+        ));
+
+        catalog
+            .delete_table("foo", "cpu", SegmentId::new(2))
+            .unwrap();
+        assert!(!catalog.db_schema("foo").unwrap().table_exists("cpu"));
+        assert!(catalog.is_deleted("foo", "cpu", SegmentId::new(1)));
+        assert!(!catalog.is_deleted("foo", "cpu", SegmentId::new(2)));
+        assert!(!catalog.is_deleted("foo", "mem", SegmentId::new(1)));
+
+        catalog.delete_database("foo", SegmentId::new(3)).unwrap();
+        assert!(catalog.db_schema("foo").is_none());
+        assert!(catalog.is_deleted("foo", "mem", SegmentId::new(2)));
+        assert!(matches!(
+            catalog.delete_database("foo", SegmentId::new(3)),
+            Err(Error::DatabaseNotFound(_))
+        ));
+        assert_eq!(catalog.sequence_number(), 4);
+    }
+
+    #[test]
+    fn tables_use_the_partition_template_of_their_database() {
+        let catalog = Catalog::new();
+        catalog
+            .create_database("foo", Some(time_format_template("%Y-%m-%d %H")), None)
+            .unwrap();
+        catalog
+            .create_table("foo", "cpu", BTreeMap::new(), None)
+            .unwrap();
+        catalog
+            .create_table(
+                "foo",
+                "mem",
+                BTreeMap::new(),
+                Some(time_format_template("%Y-%m")),
+            )
+            .unwrap();
+        assert!(matches!(
+            catalog.create_table(
+                "foo",
+                "disk",
+                BTreeMap::new(),
+                Some(PartitionTemplate { parts: vec![] })
+            ),
+            Err(Error::InvalidPartitionTemplate(_))
+        ));
+
+        // the templates survive persisting the catalog
+        let serialized = serde_json::to_string(&catalog.clone_inner()).unwrap();
+        let catalog = Catalog::from_inner(serde_json::from_str(&serialized).unwrap());
+        let db = catalog.db_schema("foo").unwrap();
+        let time_formats = |template: &TablePartitionTemplateOverride| {
+            template
+                .parts()
+                .map(|part| match part {
+                    Part::TimeFormat(format) => format.to_string(),
+                    part => panic!("unexpected template part {part:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            time_formats(&db.tables["cpu"].partition_template),
+            ["%Y-%m-%d %H"]
+        );
+        assert_eq!(
+            time_formats(&db.tables["mem"].partition_template),
+            ["%Y-%m"]
+        );
+
+        // tables of catalogs persisted before templates were configurable are partitioned by day
+        let table: TableDefinition =
+            serde_json::from_str(r#"{"name":"cpu","columns":{"time":6}}"#).unwrap();
+        assert_eq!(time_formats(&table.partition_template), ["%Y-%m-%d"]);
+    }
+
+    #[test]
+    fn create_and_delete_last_caches() {
+        let catalog = Catalog::new();
+        catalog.create_database("foo", None, None).unwrap();
+        catalog
+            .create_table(
+                "foo",
+                "cpu",
+                BTreeMap::from([
+                    ("host".to_string(), ColumnType::Tag),
+                    ("region".to_string(), ColumnType::Tag),
+                    ("usage".to_string(), ColumnType::F64),
+                ]),
+                None,
+            )
+            .unwrap();
+
+        let definition = catalog
+            .create_last_cache("foo", "cpu", None, None, None, None)
+            .unwrap();
+        assert_eq!(
+            definition,
+            LastCacheDefinition {
+                name: "cpu_host_region_last_cache".to_string(),
+                key_columns: vec!["host".to_string(), "region".to_string()],
+                value_columns: None,
+                count: 1,
+            }
+        );
+        assert!(matches!(
+            catalog.create_last_cache("foo", "cpu", None, None, None, None),
+            Err(Error::LastCacheExists { .. })
+        ));
+        for (key_columns, value_columns, count) in [
+            (Some(vec!["usage".to_string()]), None, None),
+            (None, Some(vec!["host".to_string()]), None),
+            (None, Some(vec!["time".to_string()]), None),
+            (Some(vec![]), Some(vec!["idle".to_string()]), None),
+            (Some(vec![]), None, Some(0)),
+        ] {
+            assert!(matches!(
+                catalog.create_last_cache(
+                    "foo",
+                    "cpu",
+                    Some("a"),
+                    key_columns,
+                    value_columns,
+                    count
+                ),
+                Err(Error::InvalidLastCache(_))
+            ));
+        }
+        catalog
+            .create_last_cache(
+                "foo",
+                "cpu",
+                Some("usage_by_host"),
+                Some(vec!["host".to_string()]),
+                Some(vec!["usage".to_string()]),
+                Some(5),
+            )
+            .unwrap();
+
+        // the caches survive persisting the catalog
+        let serialized = serde_json::to_string(&catalog.clone_inner()).unwrap();
+        let catalog = Catalog::from_inner(serde_json::from_str(&serialized).unwrap());
+        let db = catalog.db_schema("foo").unwrap();
+        assert_eq!(
+            db.tables["cpu"]
+                .last_caches()
+                .map(|cache| cache.name.as_str())
+                .collect::<Vec<_>>(),
+            ["cpu_host_region_last_cache", "usage_by_host"]
+        );
+
+        catalog
+            .delete_last_cache("foo", "cpu", "usage_by_host")
+            .unwrap();
+        assert!(matches!(
+            catalog.delete_last_cache("foo", "cpu", "usage_by_host"),
+            Err(Error::LastCacheNotFound { .. })
+        ));
+        assert_eq!(
+            catalog.db_schema("foo").unwrap().tables["cpu"]
+                .last_caches()
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn create_and_delete_distinct_caches() {
+        let catalog = Catalog::new();
+        catalog.create_database("foo", None, None).unwrap();
+        catalog
+            .create_table(
+                "foo",
+                "cpu",
+                BTreeMap::from([
+                    ("host".to_string(), ColumnType::Tag),
+                    ("region".to_string(), ColumnType::Tag),
+                    ("usage".to_string(), ColumnType::F64),
+                ]),
+                None,
+            )
+            .unwrap();
+
+        let columns = vec!["region".to_string(), "host".to_string()];
+        let definition = catalog
+            .create_distinct_cache("foo", "cpu", None, columns.clone(), None, Some(60))
+            .unwrap();
+        assert_eq!(
+            definition,
+            DistinctCacheDefinition {
+                name: "cpu_region_host_distinct_cache".to_string(),
+                columns: columns.clone(),
+                max_cardinality: DistinctCacheDefinition::DEFAULT_MAX_CARDINALITY,
+                max_age_seconds: 60,
+            }
+        );
+        assert!(matches!(
+            catalog.create_distinct_cache("foo", "cpu", None, columns, None, None),
+            Err(Error::DistinctCacheExists { .. })
+        ));
+        for (columns, max_cardinality, max_age_seconds) in [
+            (vec![], None, None),
+            (vec!["usage".to_string()], None, None),
+            (vec!["host".to_string(), "host".to_string()], None, None),
+            (vec!["host".to_string()], Some(0), None),
+            (vec!["host".to_string()], None, Some(0)),
+        ] {
+            assert!(matches!(
+                catalog.create_distinct_cache(
+                    "foo",
+                    "cpu",
+                    Some("a"),
+                    columns,
+                    max_cardinality,
+                    max_age_seconds
+                ),
+                Err(Error::InvalidDistinctCache(_))
+            ));
+        }
+
+        // the cache survives persisting the catalog
+        let serialized = serde_json::to_string(&catalog.clone_inner()).unwrap();
+        let catalog = Catalog::from_inner(serde_json::from_str(&serialized).unwrap());
+        let db = catalog.db_schema("foo").unwrap();
+        assert_eq!(db.tables["cpu"].distinct_caches().next(), Some(&definition));
+
+        catalog
+            .delete_distinct_cache("foo", "cpu", &definition.name)
+            .unwrap();
+        assert!(matches!(
+            catalog.delete_distinct_cache("foo", "cpu", &definition.name),
+            Err(Error::DistinctCacheNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn tokens_are_persisted_and_revoked() {
+        let catalog = Catalog::new();
+        catalog.insert_token(TokenInfo {
+            id: "a".to_string(),
+            description: Some("writer".to_string()),
+            hash: "abc".to_string(),
+            permissions: vec![TokenPermission {
+                database: "foo".to_string(),
+                actions: vec![TokenAction::Write],
+            }],
+        });
+        assert_eq!(catalog.sequence_number(), 0);
+        assert_eq!(catalog.token_by_hash("abc").unwrap().id, "a");
+
+        let serialized = serde_json::to_string(&catalog.clone_inner()).unwrap();
+        let catalog = Catalog::from_inner(serde_json::from_str(&serialized).unwrap());
+        assert_eq!(catalog.tokens().len(), 1);
+
+        assert!(catalog.revoke_token("a").is_some());
+        assert!(catalog.revoke_token("a").is_none());
+        assert!(catalog.token_by_hash("abc").is_none());
+
+        // catalogs persisted before tokens were added still load
+        let catalog: InnerCatalog =
+            serde_json::from_str(r#"{"databases":{},"sequence":3}"#).unwrap();
+        assert!(catalog.tokens.is_empty());
+    }
 }
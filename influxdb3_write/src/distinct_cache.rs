@@ -0,0 +1,639 @@
+//! The distinct value caches of tables, which keep the distinct combinations of values written to
+//! a hierarchy of tags, like the regions and then the hosts in each region. They answer metadata
+//! queries like `SHOW TAG VALUES` without scanning the buffer and the persisted parquet files.
+
+use crate::catalog::{Catalog, DistinctCacheDefinition};
+use arrow::array::{ArrayRef, AsArray, StringArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema as ArrowSchema, TimestampNanosecondType};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use iox_time::TimeProvider;
+use observability_deps::tracing::error;
+use parking_lot::RwLock;
+use schema::{Schema, TIME_COLUMN_NAME};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("table {0} has no distinct cache")]
+    NoCache(String),
+
+    #[error("table {0} has more than one distinct cache, so the name of the cache must be given")]
+    AmbiguousCache(String),
+
+    #[error("distinct cache {cache_name} not found for table {table_name}")]
+    CacheNotFound {
+        table_name: String,
+        cache_name: String,
+    },
+
+    #[error("error building the cached values: {0}")]
+    Arrow(#[from] ArrowError),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The distinct value caches of every table, by database, table and cache name.
+#[derive(Debug)]
+pub struct DistinctCacheProvider {
+    time_provider: Arc<dyn TimeProvider>,
+    caches: RwLock<HashMap<String, HashMap<String, HashMap<String, DistinctCache>>>>,
+}
+
+impl DistinctCacheProvider {
+    pub fn new(time_provider: Arc<dyn TimeProvider>) -> Self {
+        Self {
+            time_provider,
+            caches: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Creates the caches defined in the catalog. They are empty until they are seeded with the
+    /// data that has already been written, or until data is written.
+    pub fn new_from_catalog(catalog: &Catalog, time_provider: Arc<dyn TimeProvider>) -> Self {
+        let provider = Self::new(time_provider);
+        for db in catalog.databases() {
+            for table in db.tables() {
+                for definition in table.distinct_caches() {
+                    provider.create_cache(&db.name, &table.name, definition.clone());
+                }
+            }
+        }
+        provider
+    }
+
+    /// Adds an empty cache for the table, replacing any cache with the same name.
+    pub fn create_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        definition: DistinctCacheDefinition,
+    ) {
+        self.caches
+            .write()
+            .entry(db_name.to_string())
+            .or_default()
+            .entry(table_name.to_string())
+            .or_default()
+            .insert(definition.name.clone(), DistinctCache::new(definition));
+    }
+
+    pub fn delete_cache(&self, db_name: &str, table_name: &str, cache_name: &str) {
+        let mut caches = self.caches.write();
+        if let Some(tables) = caches.get_mut(db_name) {
+            if let Some(table_caches) = tables.get_mut(table_name) {
+                table_caches.remove(cache_name);
+                if table_caches.is_empty() {
+                    tables.remove(table_name);
+                }
+            }
+            if tables.is_empty() {
+                caches.remove(db_name);
+            }
+        }
+    }
+
+    /// Drops the caches of a deleted table.
+    pub fn delete_table(&self, db_name: &str, table_name: &str) {
+        let mut caches = self.caches.write();
+        if let Some(tables) = caches.get_mut(db_name) {
+            tables.remove(table_name);
+            if tables.is_empty() {
+                caches.remove(db_name);
+            }
+        }
+    }
+
+    /// Drops the caches of every table of a deleted database.
+    pub fn delete_database(&self, db_name: &str) {
+        self.caches.write().remove(db_name);
+    }
+
+    /// Returns true if the table has any distinct caches, so the data written to it is needed to
+    /// update them.
+    pub(crate) fn has_caches(&self, db_name: &str, table_name: &str) -> bool {
+        self.caches
+            .read()
+            .get(db_name)
+            .is_some_and(|tables| tables.contains_key(table_name))
+    }
+
+    /// Returns the time before which values are aged out of a cache with the given definition.
+    pub(crate) fn min_time(&self, definition: &DistinctCacheDefinition) -> i64 {
+        let max_age = Duration::from_secs(definition.max_age_seconds);
+        self.time_provider
+            .now()
+            .timestamp_nanos()
+            .saturating_sub(max_age.as_nanos().try_into().unwrap_or(i64::MAX))
+    }
+
+    /// Adds the values written to tables to their caches, then ages out and evicts values to
+    /// keep the caches of those tables within their limits. The values of the caches of other
+    /// tables that are past their max age are left until the next write to the table, as they
+    /// are filtered out when the caches are read.
+    pub(crate) fn write_batches(&self, db_name: &str, batches: &[(String, RecordBatch)]) {
+        let mut caches = self.caches.write();
+        let Some(tables) = caches.get_mut(db_name) else {
+            return;
+        };
+
+        for (table_name, batch) in batches {
+            let Some(table_caches) = tables.get_mut(table_name) else {
+                continue;
+            };
+            for cache in table_caches.values_mut() {
+                if let Err(error) = cache.push_batch(batch) {
+                    error!(
+                        %error,
+                        %table_name,
+                        cache_name = %cache.definition.name,
+                        "error updating distinct cache"
+                    );
+                }
+                cache.evict(self.min_time(&cache.definition));
+            }
+        }
+    }
+
+    /// Adds the values of data that was written before the cache was created, or before the
+    /// server was started, to the cache.
+    pub(crate) fn seed_batch(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+        batch: &RecordBatch,
+    ) -> Result<()> {
+        let mut caches = self.caches.write();
+        let Some(cache) = caches
+            .get_mut(db_name)
+            .and_then(|tables| tables.get_mut(table_name))
+            .and_then(|table_caches| table_caches.get_mut(cache_name))
+        else {
+            return Ok(());
+        };
+
+        cache.push_batch(batch)?;
+        cache.evict(self.min_time(&cache.definition));
+        Ok(())
+    }
+
+    /// Marks the cache as having every combination of values written from `min_time` on, once
+    /// it has been seeded with the data written since then.
+    pub(crate) fn finish_seeding(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+        min_time: i64,
+    ) {
+        if let Some(cache) = self
+            .caches
+            .write()
+            .get_mut(db_name)
+            .and_then(|tables| tables.get_mut(table_name))
+            .and_then(|table_caches| table_caches.get_mut(cache_name))
+        {
+            cache.seeded_from = Some(min_time);
+        }
+    }
+
+    /// Returns the combinations of values in a cache of the table, which has the given schema.
+    /// The name of the cache can be left out if the table only has one. The rows have a column
+    /// for each level of the hierarchy and are ordered by their values. A row is null from the
+    /// first level that the data it was written in didn't have a value for. Combinations last
+    /// written at or before `retention_time_ns` are past the retention period of the database
+    /// and left out.
+    pub fn cached_values(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        schema: &Schema,
+        retention_time_ns: Option<i64>,
+    ) -> Result<RecordBatch> {
+        let caches = self.caches.read();
+        let table_caches = caches
+            .get(db_name)
+            .and_then(|tables| tables.get(table_name))
+            .filter(|table_caches| !table_caches.is_empty())
+            .ok_or_else(|| Error::NoCache(table_name.to_string()))?;
+
+        let cache = match cache_name {
+            Some(cache_name) => {
+                table_caches
+                    .get(cache_name)
+                    .ok_or_else(|| Error::CacheNotFound {
+                        table_name: table_name.to_string(),
+                        cache_name: cache_name.to_string(),
+                    })?
+            }
+            None if table_caches.len() == 1 => table_caches.values().next().expect("one cache"),
+            None => return Err(Error::AmbiguousCache(table_name.to_string())),
+        };
+
+        let min_time = self.min_time(&cache.definition);
+        let min_time = retention_time_ns.map_or(min_time, |retention_time_ns| {
+            min_time.max(retention_time_ns.saturating_add(1))
+        });
+        cache.record_batch(schema, min_time)
+    }
+
+    /// Returns the distinct values of the tag of the table that were written at or after
+    /// `min_time`, ordered by value, if the table has a cache of the tag that has all of them.
+    pub fn tag_values(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        tag_key: &str,
+        min_time: i64,
+    ) -> Option<Vec<String>> {
+        let caches = self.caches.read();
+        caches
+            .get(db_name)?
+            .get(table_name)?
+            .values()
+            .find_map(|cache| cache.tag_values(tag_key, min_time))
+    }
+}
+
+/// The values of the columns of the cache from the top of the hierarchy down, which stop at the
+/// first column that the row they were written in didn't have a value for.
+type CacheKey = Vec<String>;
+
+#[derive(Debug)]
+struct DistinctCache {
+    definition: DistinctCacheDefinition,
+    /// The combinations of values along with the time of the newest row they were written in.
+    values: BTreeMap<CacheKey, i64>,
+    /// The distinct values of each column on their own, along with the time of the newest row
+    /// they were written in. Unlike the combinations, these have the values of lower levels of
+    /// the hierarchy that were written without a value for a level above them.
+    column_values: Vec<BTreeMap<String, i64>>,
+    /// A time at or before the oldest time of the values, which is checked so that the values
+    /// are only walked to age them out once some of them are past the max age.
+    oldest_time: i64,
+    /// The time from which the cache has every combination of values that was written, which is
+    /// set once it has been seeded with the data written before it was created.
+    seeded_from: Option<i64>,
+    /// The newest time of the combinations that have been aged out or evicted. The cache may be
+    /// missing combinations of values that were last written up to this time.
+    evicted_until: i64,
+}
+
+impl DistinctCache {
+    fn new(definition: DistinctCacheDefinition) -> Self {
+        Self {
+            column_values: vec![BTreeMap::new(); definition.columns.len()],
+            definition,
+            values: BTreeMap::new(),
+            oldest_time: i64::MAX,
+            seeded_from: None,
+            evicted_until: i64::MIN,
+        }
+    }
+
+    fn push_batch(&mut self, batch: &RecordBatch) -> Result<()> {
+        let columns = self
+            .definition
+            .columns
+            .iter()
+            .map(|column| {
+                batch
+                    .column_by_name(column)
+                    .map(|column| cast(column, &DataType::Utf8))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let columns: Vec<_> = columns
+            .iter()
+            .map(|column| column.as_ref().map(|column| column.as_string::<i32>()))
+            .collect();
+        let times = batch
+            .column_by_name(TIME_COLUMN_NAME)
+            .expect("every row has a time")
+            .as_primitive::<TimestampNanosecondType>();
+
+        for row in 0..batch.num_rows() {
+            let time = times.value(row);
+            let mut key = Vec::with_capacity(columns.len());
+            let mut in_key = true;
+            for (column, column_values) in columns.iter().zip(&mut self.column_values) {
+                let Some(value) = column
+                    .filter(|column| !column.is_null(row))
+                    .map(|column| column.value(row))
+                else {
+                    in_key = false;
+                    continue;
+                };
+                match column_values.get_mut(value) {
+                    Some(newest) => *newest = (*newest).max(time),
+                    None => {
+                        column_values.insert(value.to_string(), time);
+                    }
+                }
+                if in_key {
+                    key.push(value.to_string());
+                }
+            }
+            self.oldest_time = self.oldest_time.min(time);
+            if key.is_empty() {
+                continue;
+            }
+
+            let newest = self.values.entry(key).or_insert(time);
+            *newest = (*newest).max(time);
+        }
+
+        Ok(())
+    }
+
+    /// Ages out the values that were last written before `min_time`, then evicts the least
+    /// recently written ones if there are more than the max cardinality. Nothing is done until
+    /// some values are past the max age or over the max cardinality.
+    fn evict(&mut self, min_time: i64) {
+        let max_cardinality = self.definition.max_cardinality;
+        let over_cardinality = self.values.len() > max_cardinality
+            || self
+                .column_values
+                .iter()
+                .any(|values| values.len() > max_cardinality);
+        if self.oldest_time >= min_time && !over_cardinality {
+            return;
+        }
+
+        let (evicted_until, oldest_time) =
+            evict_values(&mut self.values, min_time, max_cardinality);
+        self.evicted_until = self.evicted_until.max(evicted_until);
+        self.oldest_time = oldest_time;
+        for values in &mut self.column_values {
+            let (evicted_until, oldest_time) = evict_values(values, min_time, max_cardinality);
+            self.evicted_until = self.evicted_until.max(evicted_until);
+            self.oldest_time = self.oldest_time.min(oldest_time);
+        }
+    }
+
+    /// Returns true if the cache has every combination of values written from `min_time` on.
+    fn is_complete_from(&self, min_time: i64) -> bool {
+        self.seeded_from
+            .is_some_and(|seeded_from| min_time >= seeded_from)
+            && min_time > self.evicted_until
+    }
+
+    fn tag_values(&self, tag_key: &str, min_time: i64) -> Option<Vec<String>> {
+        let index = self
+            .definition
+            .columns
+            .iter()
+            .position(|column| column == tag_key)?;
+        if !self.is_complete_from(min_time) {
+            return None;
+        }
+
+        let values = self.column_values[index]
+            .iter()
+            .filter(|(_, time)| **time >= min_time)
+            .map(|(value, _)| value.clone())
+            .collect();
+        Some(values)
+    }
+
+    fn record_batch(&self, schema: &Schema, min_time: i64) -> Result<RecordBatch> {
+        let table_schema = schema.as_arrow();
+        let fields: Vec<Field> = self
+            .definition
+            .columns
+            .iter()
+            .filter_map(|column| table_schema.field_with_name(column).ok())
+            .cloned()
+            .collect();
+        let output_schema = Arc::new(ArrowSchema::new(fields.clone()));
+
+        let keys: Vec<_> = self
+            .values
+            .iter()
+            .filter(|(_, time)| **time >= min_time)
+            .map(|(key, _)| key)
+            .collect();
+        let columns = fields
+            .iter()
+            .map(|field| {
+                let index = self
+                    .definition
+                    .columns
+                    .iter()
+                    .position(|column| column == field.name())
+                    .expect("fields are cached columns");
+                let values: StringArray = keys
+                    .iter()
+                    .map(|key| key.get(index).map(String::as_str))
+                    .collect();
+                cast(&values, field.data_type())
+            })
+            .collect::<Result<Vec<ArrayRef>, _>>()?;
+
+        Ok(RecordBatch::try_new(output_schema, columns)?)
+    }
+}
+
+/// Ages out the values that were last written before `min_time`, then evicts the least recently
+/// written ones if there are more than `max_cardinality`, returning the newest time of the
+/// removed values and the oldest time of those that are left. Every value last written at the
+/// time of the newest evicted one is also evicted, so that the cache has all of those written
+/// after it.
+fn evict_values<K: Ord>(
+    values: &mut BTreeMap<K, i64>,
+    min_time: i64,
+    max_cardinality: usize,
+) -> (i64, i64) {
+    let mut evicted_until = i64::MIN;
+    values.retain(|_, time| {
+        let keep = *time >= min_time;
+        if !keep {
+            evicted_until = evicted_until.max(*time);
+        }
+        keep
+    });
+
+    if values.len() > max_cardinality {
+        // values are evicted down to a tenth below the max cardinality, so that they aren't
+        // evicted again by every write that adds a value
+        let excess = values.len() - (max_cardinality - max_cardinality / 10);
+        let mut times: Vec<_> = values.values().copied().collect();
+        let (_, &mut newest_evicted, _) = times.select_nth_unstable(excess - 1);
+        values.retain(|_, time| *time > newest_evicted);
+        evicted_until = evicted_until.max(newest_evicted);
+    }
+
+    let oldest_time = values.values().copied().min().unwrap_or(i64::MAX);
+    (evicted_until, oldest_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_buffer::{batches_to_cache, parse_validate_and_update_schema};
+    use crate::Precision;
+    use arrow::util::pretty::pretty_format_batches;
+    use data_types::ColumnType;
+    use iox_time::{MockProvider, Time};
+
+    const SECOND: i64 = 1_000_000_000;
+
+    fn write(catalog: &Catalog, provider: &DistinctCacheProvider, lp: &str) {
+        let (_, db) = catalog.db_or_create("foo");
+        let mut result =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Second).unwrap();
+        if let Some(schema) = result.schema.take() {
+            catalog
+                .replace_database(catalog.sequence_number(), Arc::new(schema))
+                .unwrap();
+        }
+        let batches = batches_to_cache(&result.table_batches, |table_name| {
+            provider.has_caches("foo", table_name)
+        });
+        provider.write_batches("foo", &batches);
+    }
+
+    fn cached_values(
+        catalog: &Catalog,
+        provider: &DistinctCacheProvider,
+        retention_time_ns: Option<i64>,
+    ) -> String {
+        let schema = catalog
+            .db_schema("foo")
+            .unwrap()
+            .get_table_schema("cpu")
+            .unwrap();
+        let batch = provider
+            .cached_values("foo", "cpu", None, &schema, retention_time_ns)
+            .unwrap();
+        pretty_format_batches(&[batch]).unwrap().to_string()
+    }
+
+    #[test]
+    fn keeps_distinct_values_of_hierarchy() {
+        let catalog = Catalog::new();
+        catalog.create_database("foo", None, None).unwrap();
+        catalog
+            .create_table(
+                "foo",
+                "cpu",
+                BTreeMap::from([
+                    ("host".to_string(), ColumnType::Tag),
+                    ("region".to_string(), ColumnType::Tag),
+                    ("usage".to_string(), ColumnType::F64),
+                ]),
+                None,
+            )
+            .unwrap();
+        let definition = catalog
+            .create_distinct_cache(
+                "foo",
+                "cpu",
+                None,
+                vec!["region".to_string(), "host".to_string()],
+                Some(3),
+                Some(100),
+            )
+            .unwrap();
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(100 * SECOND)));
+        let provider = DistinctCacheProvider::new(Arc::clone(&time_provider) as _);
+        provider.create_cache("foo", "cpu", definition.clone());
+        // the cache can't answer for the values written before it was created until it's seeded
+        assert_eq!(provider.tag_values("foo", "cpu", "host", 0), None);
+        provider.finish_seeding("foo", "cpu", &definition.name, 0);
+
+        write(
+            &catalog,
+            &provider,
+            "cpu,region=us,host=a usage=1.0 10\n\
+             cpu,region=us,host=b usage=2.0 20\n\
+             cpu,region=us,host=a usage=3.0 30\n\
+             cpu,host=c usage=4.0 40\n\
+             cpu,region=eu usage=5.0 50",
+        );
+        assert_eq!(
+            cached_values(&catalog, &provider, None),
+            [
+                "+--------+------+",
+                "| region | host |",
+                "+--------+------+",
+                "| eu     |      |",
+                "| us     | a    |",
+                "| us     | b    |",
+                "+--------+------+",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            provider.tag_values("foo", "cpu", "region", 0),
+            Some(vec!["eu".to_string(), "us".to_string()])
+        );
+        // host c is written without a region, so it's only in the values of the host column
+        assert_eq!(
+            provider.tag_values("foo", "cpu", "host", 25 * SECOND),
+            Some(vec!["a".to_string(), "c".to_string()])
+        );
+        assert_eq!(provider.tag_values("foo", "cpu", "usage", 0), None);
+
+        // going past the max cardinality evicts the least recently written values, after which
+        // the cache can't answer for the times they were written at
+        write(&catalog, &provider, "cpu,region=eu,host=d usage=6.0 60");
+        assert_eq!(
+            cached_values(&catalog, &provider, None),
+            [
+                "+--------+------+",
+                "| region | host |",
+                "+--------+------+",
+                "| eu     |      |",
+                "| eu     | d    |",
+                "| us     | a    |",
+                "+--------+------+",
+            ]
+            .join("\n")
+        );
+        assert_eq!(provider.tag_values("foo", "cpu", "host", 20 * SECOND), None);
+        assert_eq!(
+            provider.tag_values("foo", "cpu", "host", 21 * SECOND),
+            Some(vec!["a".to_string(), "c".to_string(), "d".to_string()])
+        );
+
+        // values that haven't been written within the max age are aged out
+        time_provider.set(Time::from_timestamp_nanos(140 * SECOND));
+        write(&catalog, &provider, "cpu,region=us,host=e usage=7.0 140");
+        assert_eq!(
+            cached_values(&catalog, &provider, None),
+            [
+                "+--------+------+",
+                "| region | host |",
+                "+--------+------+",
+                "| eu     |      |",
+                "| eu     | d    |",
+                "| us     | e    |",
+                "+--------+------+",
+            ]
+            .join("\n")
+        );
+        // values last written at or before the retention time of the database are left out
+        assert_eq!(
+            cached_values(&catalog, &provider, Some(50 * SECOND)),
+            [
+                "+--------+------+",
+                "| region | host |",
+                "+--------+------+",
+                "| eu     | d    |",
+                "| us     | e    |",
+                "+--------+------+",
+            ]
+            .join("\n")
+        );
+
+        provider.delete_table("foo", "cpu");
+        assert!(!provider.has_caches("foo", "cpu"));
+    }
+}
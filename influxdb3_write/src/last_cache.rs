@@ -0,0 +1,494 @@
+//! The last caches of tables, which keep the most recent rows written to a table for every
+//! combination of the values of their key columns. They answer queries for the latest values of
+//! a series without scanning the buffer and the persisted parquet files.
+
+use crate::catalog::{Catalog, LastCacheDefinition};
+use arrow::array::{ArrayRef, AsArray, StringArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema as ArrowSchema, TimestampNanosecondType};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use datafusion::common::ScalarValue;
+use datafusion::error::DataFusionError;
+use observability_deps::tracing::error;
+use parking_lot::RwLock;
+use schema::{Schema, TIME_COLUMN_NAME};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("table {0} has no last cache")]
+    NoCache(String),
+
+    #[error("table {0} has more than one last cache, so the name of the cache must be given")]
+    AmbiguousCache(String),
+
+    #[error("last cache {cache_name} not found for table {table_name}")]
+    CacheNotFound {
+        table_name: String,
+        cache_name: String,
+    },
+
+    #[error("error building the cached rows: {0}")]
+    Arrow(#[from] ArrowError),
+
+    #[error("error building the cached rows: {0}")]
+    DataFusion(#[from] DataFusionError),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The last caches of every table, by database, table and cache name.
+#[derive(Debug, Default)]
+pub struct LastCacheProvider {
+    caches: RwLock<HashMap<String, HashMap<String, HashMap<String, LastCache>>>>,
+}
+
+impl LastCacheProvider {
+    /// Creates the caches defined in the catalog. They are empty until data is written.
+    pub fn new_from_catalog(catalog: &Catalog) -> Self {
+        let provider = Self::default();
+        for db in catalog.databases() {
+            for table in db.tables() {
+                for definition in table.last_caches() {
+                    provider.create_cache(&db.name, &table.name, definition.clone());
+                }
+            }
+        }
+        provider
+    }
+
+    /// Adds an empty cache for the table, replacing any cache with the same name.
+    pub fn create_cache(&self, db_name: &str, table_name: &str, definition: LastCacheDefinition) {
+        self.caches
+            .write()
+            .entry(db_name.to_string())
+            .or_default()
+            .entry(table_name.to_string())
+            .or_default()
+            .insert(definition.name.clone(), LastCache::new(definition));
+    }
+
+    pub fn delete_cache(&self, db_name: &str, table_name: &str, cache_name: &str) {
+        let mut caches = self.caches.write();
+        if let Some(tables) = caches.get_mut(db_name) {
+            if let Some(table_caches) = tables.get_mut(table_name) {
+                table_caches.remove(cache_name);
+                if table_caches.is_empty() {
+                    tables.remove(table_name);
+                }
+            }
+            if tables.is_empty() {
+                caches.remove(db_name);
+            }
+        }
+    }
+
+    /// Drops the caches of a deleted table.
+    pub fn delete_table(&self, db_name: &str, table_name: &str) {
+        let mut caches = self.caches.write();
+        if let Some(tables) = caches.get_mut(db_name) {
+            tables.remove(table_name);
+            if tables.is_empty() {
+                caches.remove(db_name);
+            }
+        }
+    }
+
+    /// Drops the caches of every table of a deleted database.
+    pub fn delete_database(&self, db_name: &str) {
+        self.caches.write().remove(db_name);
+    }
+
+    /// Returns true if the table has any last caches, so the data written to it is needed to
+    /// update them.
+    pub(crate) fn has_caches(&self, db_name: &str, table_name: &str) -> bool {
+        self.caches
+            .read()
+            .get(db_name)
+            .is_some_and(|tables| tables.contains_key(table_name))
+    }
+
+    /// Adds the rows written to tables to their caches.
+    pub(crate) fn write_batches(&self, db_name: &str, batches: &[(String, RecordBatch)]) {
+        let mut caches = self.caches.write();
+        let Some(tables) = caches.get_mut(db_name) else {
+            return;
+        };
+
+        for (table_name, batch) in batches {
+            for cache in tables
+                .get_mut(table_name)
+                .into_iter()
+                .flat_map(|table_caches| table_caches.values_mut())
+            {
+                if let Err(error) = cache.push_batch(batch) {
+                    error!(
+                        %error,
+                        %table_name,
+                        cache_name = %cache.definition.name,
+                        "error updating last cache"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the rows in a cache of the table, which has the given schema. The name of the
+    /// cache can be left out if the table only has one. The rows have the key columns, followed
+    /// by the value columns and time, and are ordered by their key values and then from newest
+    /// to oldest. Rows at or before `retention_time_ns` are past the retention period of the
+    /// database and left out.
+    pub fn cached_rows(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        schema: &Schema,
+        retention_time_ns: Option<i64>,
+    ) -> Result<RecordBatch> {
+        let caches = self.caches.read();
+        let table_caches = caches
+            .get(db_name)
+            .and_then(|tables| tables.get(table_name))
+            .filter(|table_caches| !table_caches.is_empty())
+            .ok_or_else(|| Error::NoCache(table_name.to_string()))?;
+
+        let cache = match cache_name {
+            Some(cache_name) => {
+                table_caches
+                    .get(cache_name)
+                    .ok_or_else(|| Error::CacheNotFound {
+                        table_name: table_name.to_string(),
+                        cache_name: cache_name.to_string(),
+                    })?
+            }
+            None if table_caches.len() == 1 => table_caches.values().next().expect("one cache"),
+            None => return Err(Error::AmbiguousCache(table_name.to_string())),
+        };
+
+        cache.record_batch(schema, retention_time_ns.unwrap_or(i64::MIN))
+    }
+}
+
+/// The values of the key columns of a row, which are null if the row doesn't have the tag.
+type CacheKey = Vec<Option<String>>;
+
+#[derive(Debug)]
+struct LastCache {
+    definition: LastCacheDefinition,
+    /// The most recent rows for each combination of key values, newest first.
+    rows: BTreeMap<CacheKey, VecDeque<CachedRow>>,
+}
+
+#[derive(Debug)]
+struct CachedRow {
+    time: i64,
+    /// The tags of the row that aren't keys of the cache.
+    tags: BTreeMap<String, ScalarValue>,
+    fields: BTreeMap<String, ScalarValue>,
+}
+
+impl LastCache {
+    fn new(definition: LastCacheDefinition) -> Self {
+        Self {
+            definition,
+            rows: BTreeMap::new(),
+        }
+    }
+
+    fn is_value_column(&self, column: &str) -> bool {
+        if column == TIME_COLUMN_NAME || self.definition.key_columns.iter().any(|key| key == column)
+        {
+            return false;
+        }
+        match &self.definition.value_columns {
+            Some(value_columns) => value_columns.iter().any(|value| value == column),
+            None => true,
+        }
+    }
+
+    fn push_batch(&mut self, batch: &RecordBatch) -> Result<()> {
+        let key_columns = self
+            .definition
+            .key_columns
+            .iter()
+            .map(|key| {
+                batch
+                    .column_by_name(key)
+                    .map(|column| cast(column, &DataType::Utf8))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let times = batch
+            .column_by_name(TIME_COLUMN_NAME)
+            .expect("every row has a time")
+            .as_primitive::<TimestampNanosecondType>();
+        let schema = batch.schema();
+        let value_columns: Vec<_> = schema
+            .fields()
+            .iter()
+            .zip(batch.columns())
+            .filter(|(field, _)| self.is_value_column(field.name()))
+            .collect();
+
+        for row in 0..batch.num_rows() {
+            let key = key_columns
+                .iter()
+                .map(|column| {
+                    column.as_ref().and_then(|column| {
+                        let column = column.as_string::<i32>();
+                        column.is_valid(row).then(|| column.value(row).to_string())
+                    })
+                })
+                .collect();
+
+            let mut cached_row = CachedRow {
+                time: times.value(row),
+                tags: BTreeMap::new(),
+                fields: BTreeMap::new(),
+            };
+            for &(field, column) in &value_columns {
+                if column.is_null(row) {
+                    continue;
+                }
+                let value = ScalarValue::try_from_array(column, row)?;
+                match field.data_type() {
+                    DataType::Dictionary(_, _) => {
+                        cached_row.tags.insert(field.name().clone(), value)
+                    }
+                    _ => cached_row.fields.insert(field.name().clone(), value),
+                };
+            }
+
+            self.push_row(key, cached_row);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the row to the rows for its key, unless there are already as many newer rows as
+    /// the cache keeps. A row of the same series at the same time as a cached one is merged into
+    /// it, with its field values replacing the cached ones like they do when the data is queried.
+    fn push_row(&mut self, key: CacheKey, row: CachedRow) {
+        let rows = self.rows.entry(key).or_default();
+        if let Some(cached) = rows
+            .iter_mut()
+            .find(|cached| cached.time == row.time && cached.tags == row.tags)
+        {
+            cached.fields.extend(row.fields);
+            return;
+        }
+
+        let index = rows
+            .iter()
+            .position(|cached| cached.time < row.time)
+            .unwrap_or(rows.len());
+        if index < self.definition.count {
+            rows.insert(index, row);
+            rows.truncate(self.definition.count);
+        }
+    }
+
+    /// Returns the cached rows that are newer than `after`.
+    fn record_batch(&self, schema: &Schema, after: i64) -> Result<RecordBatch> {
+        let table_schema = schema.as_arrow();
+        let key_fields = self
+            .definition
+            .key_columns
+            .iter()
+            .filter_map(|key| table_schema.field_with_name(key).ok());
+        let value_fields = table_schema
+            .fields()
+            .iter()
+            .filter(|field| field.name() == TIME_COLUMN_NAME || self.is_value_column(field.name()))
+            .map(|field| field.as_ref());
+        let fields: Vec<Field> = key_fields.chain(value_fields).cloned().collect();
+        let output_schema = Arc::new(ArrowSchema::new(fields.clone()));
+
+        let rows: Vec<_> = self
+            .rows
+            .iter()
+            .flat_map(|(key, rows)| rows.iter().map(move |row| (key, row)))
+            .filter(|(_, row)| row.time > after)
+            .collect();
+        if rows.is_empty() {
+            return Ok(RecordBatch::new_empty(output_schema));
+        }
+        let columns = fields
+            .iter()
+            .map(|field| {
+                if let Some(key_index) = self
+                    .definition
+                    .key_columns
+                    .iter()
+                    .position(|key| key == field.name())
+                {
+                    let values: StringArray = rows
+                        .iter()
+                        .map(|(key, _)| key[key_index].as_deref())
+                        .collect();
+                    return Ok(cast(&values, field.data_type())?);
+                }
+
+                let null = ScalarValue::try_from(field.data_type())?;
+                let values = rows.iter().map(|(_, row)| {
+                    if field.name() == TIME_COLUMN_NAME {
+                        return ScalarValue::TimestampNanosecond(Some(row.time), None);
+                    }
+                    row.tags
+                        .get(field.name())
+                        .or_else(|| row.fields.get(field.name()))
+                        .cloned()
+                        .unwrap_or_else(|| null.clone())
+                });
+                Ok(ScalarValue::iter_to_array(values)?)
+            })
+            .collect::<Result<Vec<ArrayRef>>>()?;
+
+        Ok(RecordBatch::try_new(output_schema, columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_buffer::{batches_to_cache, parse_validate_and_update_schema};
+    use crate::Precision;
+    use arrow::util::pretty::pretty_format_batches;
+    use data_types::ColumnType;
+
+    fn write(catalog: &Catalog, provider: &LastCacheProvider, lp: &str) {
+        let (_, db) = catalog.db_or_create("foo");
+        let mut result =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond).unwrap();
+        if let Some(schema) = result.schema.take() {
+            catalog
+                .replace_database(catalog.sequence_number(), Arc::new(schema))
+                .unwrap();
+        }
+        let batches = batches_to_cache(&result.table_batches, |table_name| {
+            provider.has_caches("foo", table_name)
+        });
+        provider.write_batches("foo", &batches);
+    }
+
+    fn cached_rows(catalog: &Catalog, provider: &LastCacheProvider, cache_name: &str) -> String {
+        let schema = catalog
+            .db_schema("foo")
+            .unwrap()
+            .get_table_schema("cpu")
+            .unwrap();
+        let batch = provider
+            .cached_rows("foo", "cpu", Some(cache_name), &schema, None)
+            .unwrap();
+        pretty_format_batches(&[batch]).unwrap().to_string()
+    }
+
+    #[test]
+    fn keeps_last_rows_for_each_key() {
+        let catalog = Catalog::new();
+        catalog.create_database("foo", None, None).unwrap();
+        catalog
+            .create_table(
+                "foo",
+                "cpu",
+                BTreeMap::from([
+                    ("host".to_string(), ColumnType::Tag),
+                    ("region".to_string(), ColumnType::Tag),
+                    ("usage".to_string(), ColumnType::F64),
+                ]),
+                None,
+            )
+            .unwrap();
+        let provider = LastCacheProvider::default();
+        for (name, count) in [("last", 1), ("last_two", 2)] {
+            let definition = catalog
+                .create_last_cache(
+                    "foo",
+                    "cpu",
+                    Some(name),
+                    Some(vec!["host".to_string()]),
+                    None,
+                    Some(count),
+                )
+                .unwrap();
+            provider.create_cache("foo", "cpu", definition);
+        }
+
+        write(
+            &catalog,
+            &provider,
+            "cpu,host=a,region=us usage=1.0 10\n\
+             cpu,host=b,region=eu usage=2.0 10\n\
+             cpu,host=a,region=us usage=3.0 30\n\
+             cpu,host=a,region=us usage=4.0 20",
+        );
+        // a late write to an older time doesn't replace the newer rows, and a write at the same
+        // time as a cached row replaces its fields
+        write(
+            &catalog,
+            &provider,
+            "cpu,host=a,region=us usage=0.0 5\ncpu,host=b,region=eu usage=5.0 10",
+        );
+
+        assert_eq!(
+            cached_rows(&catalog, &provider, "last"),
+            [
+                "+------+--------+--------------------------------+-------+",
+                "| host | region | time                           | usage |",
+                "+------+--------+--------------------------------+-------+",
+                "| a    | us     | 1970-01-01T00:00:00.000000030Z | 3.0   |",
+                "| b    | eu     | 1970-01-01T00:00:00.000000010Z | 5.0   |",
+                "+------+--------+--------------------------------+-------+",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            cached_rows(&catalog, &provider, "last_two"),
+            [
+                "+------+--------+--------------------------------+-------+",
+                "| host | region | time                           | usage |",
+                "+------+--------+--------------------------------+-------+",
+                "| a    | us     | 1970-01-01T00:00:00.000000030Z | 3.0   |",
+                "| a    | us     | 1970-01-01T00:00:00.000000020Z | 4.0   |",
+                "| b    | eu     | 1970-01-01T00:00:00.000000010Z | 5.0   |",
+                "+------+--------+--------------------------------+-------+",
+            ]
+            .join("\n")
+        );
+
+        let schema = catalog
+            .db_schema("foo")
+            .unwrap()
+            .get_table_schema("cpu")
+            .unwrap();
+        assert!(matches!(
+            provider.cached_rows("foo", "cpu", None, &schema, None),
+            Err(Error::AmbiguousCache(_))
+        ));
+        provider.delete_cache("foo", "cpu", "last_two");
+        assert_eq!(
+            provider
+                .cached_rows("foo", "cpu", None, &schema, None)
+                .unwrap()
+                .num_rows(),
+            2
+        );
+        // the row of host b is past the retention period
+        assert_eq!(
+            provider
+                .cached_rows("foo", "cpu", None, &schema, Some(10))
+                .unwrap()
+                .num_rows(),
+            1
+        );
+        provider.delete_table("foo", "cpu");
+        assert!(matches!(
+            provider.cached_rows("foo", "cpu", None, &schema, None),
+            Err(Error::NoCache(_))
+        ));
+    }
+}
@@ -7,25 +7,34 @@
 //! to be persisted. A new open segment will be created and new writes will be written to that segment.
 
 pub mod catalog;
+pub mod distinct_cache;
+pub mod last_cache;
+pub mod migrate;
 pub mod paths;
 pub mod persister;
 pub mod wal;
 pub mod write_buffer;
 
-use crate::catalog::Catalog;
+use crate::catalog::{Catalog, DistinctCacheDefinition, LastCacheDefinition};
+use crate::distinct_cache::DistinctCacheProvider;
+use crate::last_cache::LastCacheProvider;
 use crate::paths::ParquetFilePath;
 use async_trait::async_trait;
 use bytes::Bytes;
-use data_types::NamespaceName;
+use data_types::{ColumnType, NamespaceName};
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::SessionState;
+use datafusion::execution::object_store::ObjectStoreUrl;
 use datafusion::physical_plan::SendableRecordBatchStream;
 use datafusion::prelude::Expr;
+use generated_types::influxdata::iox::partition_template::v1::PartitionTemplate;
+use influxdb_line_protocol::lint::{Diagnostic, LintOptions};
 use iox_query::QueryChunk;
 use parquet::format::FileMetaData;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::sync::Arc;
 use thiserror::Error;
@@ -56,13 +65,25 @@ pub enum Error {
     #[error("parquet error: {0}")]
     ParquetError(#[from] parquet::errors::ParquetError),
 
+    #[error("arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+
     #[error("tried to serialize a parquet file with no rows")]
     NoRows,
+
+    #[error("wal error: {0}")]
+    Wal(#[from] wal::Error),
+
+    #[error("catalog error: {0}")]
+    Catalog(#[from] catalog::Error),
+
+    #[error("distinct cache error: {0}")]
+    DistinctCache(#[from] distinct_cache::Error),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub trait WriteBuffer: Bufferer + ChunkContainer {}
+pub trait WriteBuffer: Bufferer + ChunkContainer + LastCacheManager + DistinctCacheManager {}
 
 /// The buffer is for buffering data in memory before it is persisted to object storage. The buffer is queryable and
 /// aims to use as little memory as possible, converting data into in-memory Parquet data periodically as it arrives
@@ -76,16 +97,20 @@ pub trait Bufferer: Debug + Send + Sync + 'static {
     /// Validates the line protocol, writes it into the WAL if configured, writes it into the in memory buffer
     /// and returns the result with any lines that had errors and summary statistics. This writes into the currently
     /// open segment or it will open one. The open segment id and the memory usage of the currently open segment are
-    /// returned.
+    /// returned. If `accept_partial` is set, the valid lines are written even if some lines have errors, otherwise
+    /// nothing is written if any line has an error. Timestamps in the line protocol are in the given `precision`
+    /// and the `default_time` is in nanoseconds.
     async fn write_lp(
         &self,
         database: NamespaceName<'static>,
         lp: &str,
         default_time: i64,
+        accept_partial: bool,
+        precision: Precision,
     ) -> write_buffer::Result<BufferedWriteRequest>;
 
-    /// Closes the open segment and returns it so that it can be persisted or thrown away. A new segment will be opened
-    /// with the catalog rolling over.
+    /// Closes the open segment, starts persisting it in the background and returns it. A new segment will be opened
+    /// with the catalog rolling over. The closed segment remains queryable until it has been persisted.
     async fn close_open_segment(&self) -> Result<Arc<dyn BufferSegment>>;
 
     /// Once a process opens segments with the Persister, they'll know the last segment that was persisted.
@@ -99,6 +124,98 @@ pub trait Bufferer: Debug + Send + Sync + 'static {
 
     /// Returns the configured WAL, if there is one.
     fn wal(&self) -> Option<Arc<impl Wal>>;
+
+    /// Returns the catalog of the databases and tables in the buffer, which also holds the API tokens.
+    fn catalog(&self) -> Arc<Catalog>;
+
+    /// Persists the catalog right away with the id of the open segment. This is for changes to the catalog that
+    /// aren't written to the WAL, like API tokens, so they aren't lost if the server stops before the open segment
+    /// is persisted.
+    async fn persist_catalog(&self) -> Result<()>;
+
+    /// Creates an empty database and persists the catalog. Tables in the database are partitioned with the
+    /// partition template, or by day if it isn't set, unless they are created with their own template. Data older
+    /// than the retention period is no longer queryable and is deleted in the background.
+    async fn create_database(
+        &self,
+        db_name: &str,
+        partition_template: Option<PartitionTemplate>,
+        retention_period_ns: Option<i64>,
+    ) -> Result<()>;
+
+    /// Deletes a database and all of its data. The deletion is written to the WAL, the buffered data is dropped and
+    /// the parquet files that were persisted for the database are deleted from object storage in the background.
+    async fn delete_database(&self, db_name: &str) -> Result<()>;
+
+    /// Creates a table with the given columns in an existing database and persists the catalog. The table is
+    /// partitioned with the partition template if it is set, and otherwise with the template of the database.
+    async fn create_table(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        columns: BTreeMap<String, ColumnType>,
+        partition_template: Option<PartitionTemplate>,
+    ) -> Result<()>;
+
+    /// Deletes a table and all of its data, like `delete_database` does for a database.
+    async fn delete_table(&self, db_name: &str, table_name: &str) -> Result<()>;
+}
+
+/// Manages the last caches of tables, which keep the most recent rows written to a table for every combination of
+/// the values of their key columns. The caches are defined in the catalog and are updated as data is written.
+#[async_trait]
+pub trait LastCacheManager: Debug + Send + Sync + 'static {
+    /// Returns the provider that the cached rows are queried from.
+    fn last_cache_provider(&self) -> Arc<LastCacheProvider>;
+
+    /// Creates an empty last cache for a table and persists the catalog. The cache starts filling up with the next
+    /// write to the table. Returns the definition of the cache, which has its name if one wasn't given.
+    async fn create_last_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        key_columns: Option<Vec<String>>,
+        value_columns: Option<Vec<String>>,
+        count: Option<usize>,
+    ) -> Result<LastCacheDefinition>;
+
+    /// Deletes a last cache of a table and persists the catalog.
+    async fn delete_last_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+    ) -> Result<()>;
+}
+
+/// Manages the distinct value caches of tables, which keep the distinct combinations of values written to a hierarchy
+/// of their tags. The caches are defined in the catalog, are seeded with the data already written when they are
+/// created and when the server starts, and are updated as data is written.
+#[async_trait]
+pub trait DistinctCacheManager: Debug + Send + Sync + 'static {
+    /// Returns the provider that the cached values are queried from.
+    fn distinct_cache_provider(&self) -> Arc<DistinctCacheProvider>;
+
+    /// Creates a distinct value cache for a table, persists the catalog and seeds the cache with the values written
+    /// within its max age. Returns the definition of the cache, which has its name if one wasn't given.
+    async fn create_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        columns: Vec<String>,
+        max_cardinality: Option<usize>,
+        max_age_seconds: Option<u64>,
+    ) -> Result<DistinctCacheDefinition>;
+
+    /// Deletes a distinct value cache of a table and persists the catalog.
+    async fn delete_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+    ) -> Result<()>;
 }
 
 /// A segment in the buffer that corresponds to a single WAL segment file. It contains a catalog with any updates
@@ -112,8 +229,8 @@ pub trait BufferSegment: Debug + Send + Sync + 'static {
 
     /// If the catalog has been updated in this buffer segment, it is written using the passed in persister. Then it
     /// writes all data in the buffered segment to parquet files using the passed persister. Finally, it writes
-    /// the segment file with all parquet file summaries to object storage using the passed persister.
-    async fn persist(&self, persister: Arc<dyn Persister>) -> Result<()>;
+    /// the segment file with all parquet file summaries to object storage using the passed persister and returns it.
+    async fn persist(&self, persister: Arc<dyn Persister>) -> Result<PersistedSegment>;
 }
 
 /// ChunkContainer is used by the query engine to get chunks for a given table. Chunks will generally be in the
@@ -175,6 +292,13 @@ pub trait Persister: Debug + Send + Sync + 'static {
     /// Loads the most recently persisted N segment parquet file lists from object storage.
     async fn load_segments(&self, most_recent_n: usize) -> Result<Vec<PersistedSegment>>;
 
+    /// Loads the parquet file list of a single segment from object storage, if it was persisted.
+    async fn load_segment(&self, segment_id: SegmentId) -> Result<Option<PersistedSegment>>;
+
+    /// Deletes the parquet file list of a segment from object storage. The parquet files themselves are not
+    /// deleted.
+    async fn delete_segment(&self, segment_id: SegmentId) -> Result<()>;
+
     // Loads a Parquet file from ObjectStore
     async fn load_parquet_file(&self, path: ParquetFilePath) -> crate::Result<Bytes>;
 
@@ -188,23 +312,28 @@ pub trait Persister: Debug + Send + Sync + 'static {
     async fn persist_segment(&self, persisted_segment: PersistedSegment) -> Result<()>;
 
     // Writes a SendableRecorgBatchStream to the Parquet format and perists it
-    // to Object Store at the given path
+    // to Object Store at the given path. Returns the size of the file in bytes
+    // along with its metadata.
     async fn persist_parquet_file(
         &self,
         path: ParquetFilePath,
         record_batch: SendableRecordBatchStream,
-    ) -> crate::Result<FileMetaData>;
+    ) -> crate::Result<(u64, FileMetaData)>;
 
     /// Returns the configured `ObjectStore` that data is loaded from and persisted to.
     fn object_store(&self) -> Arc<dyn object_store::ObjectStore>;
+
+    /// Returns the URL that the `ObjectStore` is registered under in the query executor, which is used to read
+    /// persisted parquet files when querying.
+    fn object_store_url(&self) -> &ObjectStoreUrl;
 }
 
 pub trait Wal: Debug + Send + Sync + 'static {
     /// Opens a writer to a segment, either creating a new file or appending to an existing file.
-    fn open_segment_writer(&self, segment_id: SegmentId) -> wal::Result<impl WalSegmentWriter>;
+    fn open_segment_writer(&self, segment_id: SegmentId) -> wal::Result<Box<dyn WalSegmentWriter>>;
 
     /// Opens a reader to a segment file.
-    fn open_segment_reader(&self, segment_id: SegmentId) -> wal::Result<impl WalSegmentReader>;
+    fn open_segment_reader(&self, segment_id: SegmentId) -> wal::Result<Box<dyn WalSegmentReader>>;
 
     /// Checks the WAL directory for any segment files and returns them.
     fn segment_files(&self) -> wal::Result<Vec<SegmentFile>>;
@@ -247,15 +376,112 @@ pub struct WalOpBatch {
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum WalOp {
     LpWrite(LpWriteOp),
+    DeleteDatabase(DeleteDatabaseOp),
+    DeleteTable(DeleteTableOp),
 }
 
 /// A write of 1 or more lines of line protocol to a single database. The default time is set by the server at the
-/// time the write comes in.
+/// time the write comes in. The precision of the timestamps in the line protocol is kept so that the write is
+/// replayed with the same timestamps.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct LpWriteOp {
     pub db_name: String,
     pub lp: String,
     pub default_time: u64,
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+/// The deletion of a database. It's in the WAL so that writes to the database before the deletion aren't replayed
+/// into it.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct DeleteDatabaseOp {
+    pub db_name: String,
+}
+
+/// The deletion of a table, which is in the WAL for the same reason as [`DeleteDatabaseOp`].
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct DeleteTableOp {
+    pub db_name: String,
+    pub table_name: String,
+}
+
+/// The precision of the timestamps in a write of line protocol. Timestamps are converted to nanoseconds when they
+/// are buffered.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Precision {
+    /// Guess the precision of each timestamp from its magnitude.
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "s")]
+    Second,
+    #[serde(rename = "ms")]
+    Millisecond,
+    // the aliases are the names used by the InfluxDB 1.x write API
+    #[serde(rename = "us", alias = "u", alias = "µ")]
+    Microsecond,
+    #[default]
+    #[serde(rename = "ns", alias = "n")]
+    Nanosecond,
+    /// Only accepted by the InfluxDB 1.x write API.
+    #[serde(rename = "m")]
+    Minute,
+    /// Only accepted by the InfluxDB 1.x write API.
+    #[serde(rename = "h")]
+    Hour,
+}
+
+impl Precision {
+    /// Converts a timestamp in this precision to nanoseconds, returning `None` if it overflows.
+    pub fn to_nanos(self, timestamp: i64) -> Option<i64> {
+        let multiplier = match self {
+            Self::Auto => return Self::guess(timestamp).to_nanos(timestamp),
+            Self::Second => 1_000_000_000,
+            Self::Millisecond => 1_000_000,
+            Self::Microsecond => 1_000,
+            Self::Nanosecond => 1,
+            Self::Minute => 60_000_000_000,
+            Self::Hour => 3_600_000_000_000,
+        };
+        timestamp.checked_mul(multiplier)
+    }
+
+    /// Truncates a nanosecond timestamp to this precision. Timestamps are left as is for `Auto`.
+    pub fn truncate_nanos(self, timestamp: i64) -> i64 {
+        match self {
+            Self::Auto | Self::Nanosecond => timestamp,
+            _ => {
+                let nanos_per_unit = self.to_nanos(1).expect("one unit fits in nanoseconds");
+                timestamp - timestamp.rem_euclid(nanos_per_unit)
+            }
+        }
+    }
+
+    /// The range of timestamps in this precision that convert to nanosecond timestamps InfluxDB
+    /// accepts. Timestamps near the limits of `i64` are guessed to be nanoseconds for `Auto`, so
+    /// its range is the nanosecond range.
+    pub fn timestamp_range(self) -> RangeInclusive<i64> {
+        let range = LintOptions::default().timestamp_range();
+        match self {
+            Self::Auto | Self::Nanosecond => range,
+            _ => {
+                let nanos_per_unit = self.to_nanos(1).expect("one unit fits in nanoseconds");
+                (range.start() / nanos_per_unit)..=(range.end() / nanos_per_unit)
+            }
+        }
+    }
+
+    /// Guesses the precision of a timestamp by assuming it is within a couple hundred years of
+    /// the epoch.
+    fn guess(timestamp: i64) -> Self {
+        const NANOS_PER_SECOND: i64 = 1_000_000_000;
+        match timestamp.unsigned_abs() / NANOS_PER_SECOND as u64 {
+            0..=4 => Self::Second,
+            5..=4_999 => Self::Millisecond,
+            5_000..=4_999_999 => Self::Microsecond,
+            _ => Self::Nanosecond,
+        }
+    }
 }
 
 /// A single write request can have many lines in it. A writer can request to accept all lines that are valid, while
@@ -265,6 +491,25 @@ pub struct WriteLineError {
     pub original_line: String,
     pub line_number: usize,
     pub error_message: String,
+    /// The byte offset in the line of the token that caused the error, starting at 1. This is only set for errors
+    /// found while parsing or linting the line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    /// The token that caused the error, which is empty if something is missing from the line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl From<&Diagnostic> for WriteLineError {
+    fn from(diagnostic: &Diagnostic) -> Self {
+        Self {
+            original_line: diagnostic.line.clone(),
+            line_number: diagnostic.line_number,
+            error_message: diagnostic.problem.to_string(),
+            column: Some(diagnostic.column),
+            token: Some(diagnostic.token.clone()),
+        }
+    }
 }
 
 /// A write that has been validated against the catalog schema, written to the WAL (if configured), and buffered in
@@ -290,7 +535,7 @@ pub struct PersistedCatalog {
 }
 
 /// The collection of Parquet files that were persisted for a segment.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistedSegment {
     /// The segment_id that these parquet files were persisted with.
     pub segment_id: SegmentId,
@@ -306,11 +551,18 @@ pub struct PersistedSegment {
     pub segment_max_time: i64,
     /// The collection of databases that had tables persisted in this segment. The tables will then have their
     /// name and the parquet files.
-    pub databases: HashMap<String, TableParquetFiles>,
+    pub databases: HashMap<String, DatabaseTables>,
+}
+
+/// The tables in a database that had parquet files persisted in a segment.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseTables {
+    /// Map of table name to the parquet files persisted for it.
+    pub tables: HashMap<String, TableParquetFiles>,
 }
 
 /// A collection of parquet files persisted in a segment for a specific table.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TableParquetFiles {
     /// The table name.
     pub table_name: String,
@@ -321,11 +573,22 @@ pub struct TableParquetFiles {
 }
 
 /// The summary data for a persisted parquet file in a segment.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ParquetFile {
     pub path: String,
+    /// The key of the partition the rows in the file are from.
+    #[serde(default)]
+    pub partition_key: String,
     pub size_bytes: u64,
     pub row_count: u32,
     pub min_time: i64,
     pub max_time: i64,
+    /// Whether the rows in the file were migrated from the TSM files of an earlier version of
+    /// InfluxDB, which makes them older than any row written to the server.
+    #[serde(default)]
+    pub migrated: bool,
+    /// The columns the rows in the file are sorted by, if they differ from the sort key of the
+    /// table in its segment, as they can for a file that compaction merged from several segments.
+    #[serde(default)]
+    pub sort_key: Option<Vec<String>>,
 }
@@ -0,0 +1,1021 @@
+//! Migrates data from the TSM files of an InfluxDB 1.x or 2.x storage engine into InfluxDB 3.0.
+//! Each shard of TSM files is converted into the parquet files of a persisted segment, along with
+//! the databases, tables and columns it adds to the catalog, so the server loads the migrated data
+//! from object storage like any other persisted segment when it starts.
+//!
+//! In InfluxDB 2.x the series keys in the TSM files carry the organization and bucket they belong
+//! to. In InfluxDB 1.x they don't, and the database and retention policy of a shard are the
+//! directories it is in: `<data dir>/<database>/<retention policy>/<shard id>`.
+
+use crate::catalog::{self, Catalog, TableDefinition};
+use crate::write_buffer::{add_parquet_file_stats, persist_partition, PartitionBuffer};
+use crate::{PersistedSegment, Persister, SegmentId, TableParquetFiles, Wal};
+use data_types::{ColumnType, NamespaceName, NamespaceNameError};
+use influxdb_tsm::key::KeyFormat;
+use influxdb_tsm::mapper::{ColumnData, MeasurementTable, TableSection};
+use influxdb_tsm::shard::TsmShardReader;
+use influxdb_tsm::{BlockType, InfluxId, TsmError};
+use mutable_batch::writer::Writer;
+use mutable_batch::MutableBatch;
+use observability_deps::tracing::{info, warn};
+use partition::partition_batch;
+use schema::TIME_COLUMN_NAME;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The extension of the TSM files in a shard directory.
+const TSM_FILE_EXTENSION: &str = "tsm";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error reading {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("error reading the tsm data of shard {path:?}: {source}")]
+    Tsm { path: PathBuf, source: TsmError },
+
+    #[error("invalid database name {db_name:?} for {origin}: {source}")]
+    InvalidDatabaseName {
+        db_name: String,
+        origin: String,
+        source: NamespaceNameError,
+    },
+
+    #[error(
+        "invalid shard directory {path:?}: expected <database>/<retention policy>/<shard id> \
+        under the data directory"
+    )]
+    InvalidShardPath { path: PathBuf },
+
+    #[error("invalid checkpoint file {path:?}: {source}")]
+    InvalidCheckpoint {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error("catalog error: {0}")]
+    Catalog(#[from] catalog::Error),
+
+    #[error("wal error: {0}")]
+    Wal(#[from] crate::wal::Error),
+
+    #[error("error persisting migrated data: {0}")]
+    Persist(#[from] crate::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The version of InfluxDB that the TSM files being migrated are from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InfluxDbVersion {
+    /// InfluxDB 1.x, the data of which is migrated into a database named like the database that
+    /// a write to the `/write` endpoint with the database and retention policy of the shard goes
+    /// to: `<database>` for the `autogen` retention policy and `<database>/<retention policy>`
+    /// for others.
+    V1,
+    /// InfluxDB 2.x, the data of which is migrated into a database per bucket.
+    #[default]
+    V2,
+}
+
+/// The retention policy that InfluxDB 1.x creates databases with, the data of which is migrated
+/// into a database named after the database alone.
+const V1_DEFAULT_RETENTION_POLICY: &str = "autogen";
+
+/// The configuration of a migration from TSM files.
+#[derive(Debug, Clone)]
+pub struct TsmMigrationConfig {
+    /// The data directory of the storage engine. Every directory under it that has TSM files is
+    /// migrated as a shard.
+    pub data_dir: PathBuf,
+    /// The version of InfluxDB that wrote the data directory.
+    pub version: InfluxDbVersion,
+    /// The name of the database that the data of a bucket is migrated into, by the bucket ID as
+    /// 16 lowercase hex digits. The data of buckets that aren't in the map is migrated into a
+    /// database named after the bucket ID. Only used for data from InfluxDB 2.x.
+    pub bucket_databases: HashMap<String, String>,
+    /// The file that the shards that have been migrated are recorded in, so an interrupted
+    /// migration can be resumed without migrating them again.
+    pub checkpoint_path: PathBuf,
+}
+
+/// The totals of a migration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationSummary {
+    /// The number of shards that were migrated.
+    pub shards_migrated: usize,
+    /// The number of shards that were skipped because an earlier run had migrated them.
+    pub shards_skipped: usize,
+    /// The number of parquet files that were persisted.
+    pub parquet_files: usize,
+    /// The number of rows in the persisted parquet files.
+    pub rows: u64,
+}
+
+/// Migrates the shards of TSM files in a data directory, one segment per shard. The server must
+/// not be running, as the catalog of the server is replaced with one that has the migrated
+/// tables.
+#[derive(Debug)]
+pub struct TsmMigrator {
+    persister: Arc<dyn Persister>,
+    catalog: Catalog,
+    config: TsmMigrationConfig,
+    checkpoint: Checkpoint,
+    next_segment_id: SegmentId,
+}
+
+impl TsmMigrator {
+    /// Loads the catalog from object storage and the checkpoint of an earlier run, if there was
+    /// one. Migrated segments get ids after every segment that has been persisted, or that is in
+    /// the WAL of the server, so they don't collide with segments the server has yet to persist.
+    /// Their parquet files are marked as migrated, which orders them before the data of every
+    /// segment when queries deduplicate rows, so rows written to the server win.
+    pub async fn new<W: Wal>(
+        persister: Arc<dyn Persister>,
+        wal: Option<&W>,
+        config: TsmMigrationConfig,
+    ) -> Result<Self> {
+        let persisted_catalog = persister.load_catalog().await?;
+        let mut last_segment_id = persister
+            .load_segments(1)
+            .await?
+            .first()
+            .map(|segment| segment.segment_id)
+            .max(persisted_catalog.as_ref().map(|catalog| catalog.segment_id));
+        if let Some(wal) = wal {
+            for segment_file in wal.segment_files()? {
+                last_segment_id = last_segment_id.max(Some(segment_file.segment_id));
+            }
+        }
+        let catalog = persisted_catalog
+            .map(|persisted| Catalog::from_inner(persisted.catalog))
+            .unwrap_or_default();
+        let checkpoint = Checkpoint::load(&config.checkpoint_path)?;
+
+        Ok(Self {
+            persister,
+            catalog,
+            config,
+            checkpoint,
+            next_segment_id: last_segment_id
+                .map(|segment_id| segment_id.next())
+                .unwrap_or(SegmentId::new(0)),
+        })
+    }
+
+    /// Migrates every shard that hasn't been migrated yet, in the order of their paths. The
+    /// checkpoint is saved after each shard.
+    pub async fn run(&mut self) -> Result<MigrationSummary> {
+        let mut summary = MigrationSummary::default();
+
+        for shard_dir in shard_dirs(&self.config.data_dir)? {
+            let shard = shard_dir
+                .strip_prefix(&self.config.data_dir)
+                .unwrap_or(&shard_dir)
+                .to_string_lossy()
+                .into_owned();
+            if self.checkpoint.migrated_shards.contains_key(&shard) {
+                info!(%shard, "skipping shard that has already been migrated");
+                summary.shards_skipped += 1;
+                continue;
+            }
+
+            info!(%shard, "migrating shard");
+            let segment = self.migrate_shard(&shard_dir).await?;
+            if let Some(segment) = &segment {
+                self.next_segment_id = segment.segment_id.next();
+                summary.rows += segment.segment_row_count;
+                summary.parquet_files += segment
+                    .databases
+                    .values()
+                    .flat_map(|db| db.tables.values())
+                    .map(|table| table.parquet_files.len())
+                    .sum::<usize>();
+            }
+            summary.shards_migrated += 1;
+
+            self.checkpoint
+                .migrated_shards
+                .insert(shard, segment.map(|segment| segment.segment_id));
+            self.checkpoint.save(&self.config.checkpoint_path)?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Converts the measurements in the TSM files of a shard into tables and persists them as a
+    /// segment, which is returned. Nothing is persisted if the shard has no data.
+    async fn migrate_shard(&self, shard_dir: &Path) -> Result<Option<PersistedSegment>> {
+        let tsm_error = |source| Error::Tsm {
+            path: shard_dir.to_path_buf(),
+            source,
+        };
+
+        // the data of an InfluxDB 1.x shard is all in the database of the shard
+        let (key_format, v1_database) = match self.config.version {
+            InfluxDbVersion::V1 => (KeyFormat::V1, Some(self.v1_database_name(shard_dir)?)),
+            InfluxDbVersion::V2 => (KeyFormat::V2, None),
+        };
+        let shard = TsmShardReader::open(shard_dir)
+            .map_err(tsm_error)?
+            .with_key_format(key_format);
+        if !shard.wal_files().is_empty() {
+            warn!(
+                shard = %shard_dir.display(),
+                wal_files = shard.wal_files().len(),
+                "not migrating values in the WAL of the shard, which must be compacted into TSM \
+                files first"
+            );
+        }
+
+        // the measurements of buckets that are migrated into the same database are merged
+        let mut tables: BTreeMap<(String, String), MeasurementTable> = BTreeMap::new();
+        for ((_, bucket_id, name), mut table) in shard.measurement_tables().map_err(tsm_error)? {
+            let db_name = match &v1_database {
+                Some(db_name) => db_name.clone(),
+                None => self.database_name(bucket_id)?,
+            };
+            match tables.entry((db_name, name)) {
+                Entry::Occupied(mut entry) => {
+                    entry.get_mut().merge(&mut table).map_err(tsm_error)?
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(table);
+                }
+            }
+        }
+        let Some(mut block_reader) = shard.block_reader().map_err(tsm_error)? else {
+            return Ok(None);
+        };
+
+        let segment_id = self.next_segment_id;
+        let mut persisted_segment = PersistedSegment {
+            segment_id,
+            segment_wal_size_bytes: 0,
+            segment_parquet_size_bytes: 0,
+            segment_row_count: 0,
+            segment_min_time: i64::MAX,
+            segment_max_time: i64::MIN,
+            databases: HashMap::new(),
+        };
+
+        for ((db_name, table_name), mut table) in tables {
+            let Some(table_definition) = self.add_table_to_catalog(&db_name, &table)? else {
+                continue;
+            };
+            let schema = table_definition
+                .schema
+                .clone()
+                .expect("table definition should have a schema");
+            let partition_template = table_definition.partition_template();
+
+            let mut partition_buffers: HashMap<String, PartitionBuffer> = HashMap::new();
+            table
+                .process(&mut block_reader, |section| {
+                    let Some(batch) =
+                        section_batch(&section, &table_definition).map_err(|e| TsmError {
+                            description: e.to_string(),
+                        })?
+                    else {
+                        return Ok(());
+                    };
+                    for (partition_key, range) in partition_batch(&batch, partition_template) {
+                        let partition_key = partition_key.map_err(|e| TsmError {
+                            description: e.to_string(),
+                        })?;
+                        let mut partition_rows = MutableBatch::new();
+                        partition_rows
+                            .extend_from_range(&batch, range)
+                            .and_then(|()| {
+                                partition_buffers
+                                    .entry(partition_key)
+                                    .or_default()
+                                    .add_batch(partition_rows)
+                            })
+                            .map_err(|e| TsmError {
+                                description: e.to_string(),
+                            })?;
+                    }
+                    Ok(())
+                })
+                .map_err(tsm_error)?;
+            if partition_buffers.is_empty() {
+                continue;
+            }
+
+            let sort_key: Vec<String> =
+                schema.primary_key().into_iter().map(String::from).collect();
+            let mut parquet_files = Vec::with_capacity(partition_buffers.len());
+            for (partition_key, partition_buffer) in &partition_buffers {
+                let mut parquet_file = persist_partition(
+                    self.persister.as_ref(),
+                    segment_id,
+                    &db_name,
+                    &table_name,
+                    partition_key,
+                    partition_buffer,
+                    &schema,
+                    &sort_key,
+                )
+                .await?;
+                parquet_file.migrated = true;
+                add_parquet_file_stats(&mut persisted_segment, &parquet_file);
+                parquet_files.push(parquet_file);
+            }
+            info!(
+                %db_name,
+                %table_name,
+                parquet_files = parquet_files.len(),
+                "migrated measurement"
+            );
+
+            persisted_segment
+                .databases
+                .entry(db_name)
+                .or_default()
+                .tables
+                .insert(
+                    table_name.clone(),
+                    TableParquetFiles {
+                        table_name,
+                        parquet_files,
+                        sort_key,
+                    },
+                );
+        }
+        if persisted_segment.databases.is_empty() {
+            return Ok(None);
+        }
+
+        // like a buffer segment, the catalog is persisted before the segment so that the tables
+        // of the segment are in the catalog that gets loaded with it
+        self.persister
+            .persist_catalog(segment_id, Catalog::from_inner(self.catalog.clone_inner()))
+            .await?;
+        self.persister
+            .persist_segment(persisted_segment.clone())
+            .await?;
+
+        Ok(Some(persisted_segment))
+    }
+
+    /// Returns the name of the database that the data of a bucket is migrated into.
+    fn database_name(&self, bucket_id: InfluxId) -> Result<String> {
+        let bucket = bucket_id.to_string();
+        let db_name = self
+            .config
+            .bucket_databases
+            .get(&bucket)
+            .cloned()
+            .unwrap_or(bucket);
+        NamespaceName::new(db_name.as_str()).map_err(|source| Error::InvalidDatabaseName {
+            db_name: db_name.clone(),
+            origin: format!("bucket {bucket_id}"),
+            source,
+        })?;
+
+        Ok(db_name)
+    }
+
+    /// Returns the name of the database that the data of an InfluxDB 1.x shard is migrated into,
+    /// from the database and retention policy directories that the shard is in.
+    fn v1_database_name(&self, shard_dir: &Path) -> Result<String> {
+        let invalid_path = || Error::InvalidShardPath {
+            path: shard_dir.to_path_buf(),
+        };
+        let relative_path = shard_dir
+            .strip_prefix(&self.config.data_dir)
+            .map_err(|_| invalid_path())?;
+        let components = relative_path
+            .iter()
+            .map(|component| component.to_str())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid_path)?;
+        let [db, rp, _shard_id] = components.as_slice() else {
+            return Err(invalid_path());
+        };
+
+        let db_name = if *rp == V1_DEFAULT_RETENTION_POLICY {
+            db.to_string()
+        } else {
+            format!("{db}/{rp}")
+        };
+        NamespaceName::new(db_name.as_str()).map_err(|source| Error::InvalidDatabaseName {
+            db_name: db_name.clone(),
+            origin: format!("shard {}", relative_path.display()),
+            source,
+        })?;
+
+        Ok(db_name)
+    }
+
+    /// Adds the table of a measurement to the catalog, creating its database if it doesn't
+    /// exist, and returns its definition. Tags and fields that conflict with the type of a
+    /// column of the table are left out. Returns `None` if the measurement has no fields left.
+    fn add_table_to_catalog(
+        &self,
+        db_name: &str,
+        table: &MeasurementTable,
+    ) -> Result<Option<TableDefinition>> {
+        let (sequence, db_schema) = self.catalog.db_or_create(db_name);
+        let existing_table = db_schema.tables.get(&table.name);
+        let conflicts = |name: &str, column_type: ColumnType| {
+            let conflicts = name == TIME_COLUMN_NAME
+                || existing_table
+                    .and_then(|existing| existing.columns().get(name))
+                    .is_some_and(|existing| *existing != column_type as i16);
+            if conflicts {
+                warn!(
+                    %db_name,
+                    table_name = %table.name,
+                    column_name = %name,
+                    %column_type,
+                    "not migrating column that conflicts with an existing column"
+                );
+            }
+            conflicts
+        };
+
+        let mut columns = BTreeMap::new();
+        for tag in table.tag_columns() {
+            if !conflicts(tag, ColumnType::Tag) {
+                columns.insert(tag.clone(), ColumnType::Tag as i16);
+            }
+        }
+        let tag_count = columns.len();
+        for (field, block_type) in table.field_columns() {
+            let column_type = column_type_from_block_type(*block_type);
+            if !columns.contains_key(field) && !conflicts(field, column_type) {
+                columns.insert(field.clone(), column_type as i16);
+            }
+        }
+        if columns.len() == tag_count {
+            warn!(%db_name, table_name = %table.name, "not migrating measurement with no fields");
+            return Ok(None);
+        }
+
+        let mut db_schema = (*db_schema).clone();
+        match db_schema.tables.get_mut(&table.name) {
+            Some(existing) => {
+                let new_columns: Vec<_> = columns
+                    .into_iter()
+                    .filter(|(name, _)| !existing.column_exists(name))
+                    .collect();
+                if new_columns.is_empty() {
+                    return Ok(Some(existing.clone()));
+                }
+                existing.add_columns(new_columns);
+            }
+            None => {
+                columns.insert(TIME_COLUMN_NAME.to_string(), ColumnType::Time as i16);
+                let table_definition = TableDefinition::new(
+                    &table.name,
+                    columns,
+                    db_schema.partition_template().clone(),
+                );
+                db_schema
+                    .tables
+                    .insert(table.name.clone(), table_definition);
+            }
+        }
+        let table_definition = db_schema.tables[&table.name].clone();
+        self.catalog
+            .replace_database(sequence, Arc::new(db_schema))?;
+
+        Ok(Some(table_definition))
+    }
+}
+
+/// The shards that have been migrated, which is saved after each shard.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// The segment that the data of a shard was persisted in, by the path of the shard relative
+    /// to the data directory. Shards without any data have no segment.
+    migrated_shards: BTreeMap<String, Option<SegmentId>>,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).map_err(|source| Error::InvalidCheckpoint {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(source) => Err(Error::Io {
+                path: path.to_path_buf(),
+                source,
+            }),
+        }
+    }
+
+    /// Saves the checkpoint by replacing the file, so an interruption leaves either the old or
+    /// the new checkpoint.
+    fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).expect("checkpoint should serialize");
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)
+            .and_then(|()| std::fs::rename(&tmp_path, path))
+            .map_err(|source| Error::Io {
+                path: path.to_path_buf(),
+                source,
+            })
+    }
+}
+
+/// Returns every directory under `data_dir`, including itself, that has TSM files, in order.
+fn shard_dirs(data_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut shard_dirs = Vec::new();
+    let mut dirs = vec![data_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut has_tsm_files = false;
+        for path in dir_entries(&dir)? {
+            if path.is_dir() {
+                dirs.push(path);
+            } else if is_tsm_file(&path) {
+                has_tsm_files = true;
+            }
+        }
+        if has_tsm_files {
+            shard_dirs.push(dir);
+        }
+    }
+    shard_dirs.sort();
+
+    Ok(shard_dirs)
+}
+
+fn dir_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let io_error = |source| Error::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+    std::fs::read_dir(dir)
+        .map_err(io_error)?
+        .map(|entry| entry.map(|entry| entry.path()).map_err(io_error))
+        .collect()
+}
+
+fn is_tsm_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == TSM_FILE_EXTENSION)
+}
+
+fn column_type_from_block_type(block_type: BlockType) -> ColumnType {
+    match block_type {
+        BlockType::Float => ColumnType::F64,
+        BlockType::Integer => ColumnType::I64,
+        BlockType::Bool => ColumnType::Bool,
+        BlockType::Str => ColumnType::String,
+        BlockType::Unsigned => ColumnType::U64,
+    }
+}
+
+/// Converts a section of a measurement, which is the data of a single series, into a batch with
+/// the columns of the table it is migrated into. Returns `None` if the table has none of the
+/// fields of the section.
+fn section_batch(
+    section: &TableSection,
+    table: &TableDefinition,
+) -> mutable_batch::writer::Result<Option<MutableBatch>> {
+    let is_column = |name: &str, column_type: ColumnType| {
+        table
+            .columns()
+            .get(name)
+            .is_some_and(|existing| *existing == column_type as i16)
+    };
+    let rows = section.len();
+    let mut batch = MutableBatch::new();
+    let mut writer = Writer::new(&mut batch, rows);
+
+    let mut field_count = 0;
+    for (name, column) in &section.field_cols {
+        match column {
+            ColumnData::Float(values) if is_column(name, ColumnType::F64) => writer.write_f64(
+                name,
+                Some(&valid_mask(values)),
+                values.iter().flatten().copied(),
+            )?,
+            ColumnData::Integer(values) if is_column(name, ColumnType::I64) => writer.write_i64(
+                name,
+                Some(&valid_mask(values)),
+                values.iter().flatten().copied(),
+            )?,
+            ColumnData::Unsigned(values) if is_column(name, ColumnType::U64) => writer.write_u64(
+                name,
+                Some(&valid_mask(values)),
+                values.iter().flatten().copied(),
+            )?,
+            ColumnData::Bool(values) if is_column(name, ColumnType::Bool) => writer.write_bool(
+                name,
+                Some(&valid_mask(values)),
+                values.iter().flatten().copied(),
+            )?,
+            ColumnData::Str(values) if is_column(name, ColumnType::String) => {
+                let strings: Vec<_> = values
+                    .iter()
+                    .flatten()
+                    .map(|value| String::from_utf8_lossy(value))
+                    .collect();
+                writer.write_string(
+                    name,
+                    Some(&valid_mask(values)),
+                    strings.iter().map(AsRef::as_ref),
+                )?
+            }
+            _ => continue,
+        }
+        field_count += 1;
+    }
+    if field_count == 0 {
+        return Ok(None);
+    }
+
+    for (key, value) in &section.tag_cols {
+        if is_column(key, ColumnType::Tag) {
+            writer.write_tag(key, None, std::iter::repeat(value.as_str()).take(rows))?;
+        }
+    }
+    writer.write_time(TIME_COLUMN_NAME, section.ts.iter().copied())?;
+    writer.commit();
+
+    Ok(Some(batch))
+}
+
+/// Returns the bitmask of the values that aren't null, with the bit of the first value being the
+/// least significant bit of the first byte.
+fn valid_mask<T>(values: &[Option<T>]) -> Vec<u8> {
+    let mut mask = vec![0; (values.len() + 7) / 8];
+    for (idx, value) in values.iter().enumerate() {
+        if value.is_some() {
+            mask[idx / 8] |= 1 << (idx % 8);
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persister::{PersisterImpl, PARQUET_STORAGE_ID};
+    use crate::wal::WalImpl;
+    use crate::write_buffer::{CompactionConfig, SegmentConfig, WriteBufferImpl};
+    use crate::{Bufferer, ChunkContainer, Precision};
+    use arrow::util::pretty::pretty_format_batches;
+    use datafusion::prelude::SessionContext;
+    use flate2::read::GzDecoder;
+    use influxdb_tsm::reader::BlockData;
+    use influxdb_tsm::writer::TsmWriter;
+    use influxdb_tsm::ParsedTsmKey;
+    use iox_query::exec::{Executor, ExecutorConfig};
+    use iox_query::QueryChunk;
+    use iox_time::{MockProvider, Time};
+    use object_store::memory::InMemory;
+    use object_store::path::Path as ObjPath;
+    use object_store::ObjectStore;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet_file::storage::StorageId;
+    use std::fs::File;
+    use std::io::Read;
+
+    // every block in the fixture file is for this bucket
+    const FIXTURE_BUCKET_ID: &str = "05c19117091a1001";
+
+    /// Writes the TSM fixture into a shard directory laid out like the engine of InfluxDB 2.x.
+    fn write_shard(data_dir: &Path) {
+        let file = File::open("../test_fixtures/000000000000005-000000002.tsm.gz").unwrap();
+        let mut buf = Vec::new();
+        GzDecoder::new(file).read_to_end(&mut buf).unwrap();
+
+        let shard_dir = data_dir.join(FIXTURE_BUCKET_ID).join("autogen").join("1");
+        std::fs::create_dir_all(&shard_dir).unwrap();
+        std::fs::write(shard_dir.join("000000005-000000002.tsm"), buf).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrates_shards_and_resumes_from_checkpoint() {
+        let data_dir = test_helpers::tmp_dir().unwrap();
+        write_shard(data_dir.path());
+        let checkpoint_dir = test_helpers::tmp_dir().unwrap();
+        let config = TsmMigrationConfig {
+            data_dir: data_dir.path().to_path_buf(),
+            version: InfluxDbVersion::V2,
+            bucket_databases: HashMap::from([(
+                FIXTURE_BUCKET_ID.to_string(),
+                "telegraf".to_string(),
+            )]),
+            checkpoint_path: checkpoint_dir.path().join("checkpoint.json"),
+        };
+        let persister: Arc<dyn Persister> = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+
+        let mut migrator =
+            TsmMigrator::new(Arc::clone(&persister), None::<&WalImpl>, config.clone())
+                .await
+                .unwrap();
+        let summary = migrator.run().await.unwrap();
+        assert_eq!(summary.shards_migrated, 1);
+        assert_eq!(summary.shards_skipped, 0);
+        assert!(summary.rows > 0);
+
+        let segment = persister
+            .load_segment(SegmentId::new(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(segment.segment_row_count, summary.rows);
+        let telegraf = &segment.databases["telegraf"];
+        assert_eq!(telegraf.tables["cpu"].sort_key, vec!["cpu", "host", "time"]);
+
+        let catalog = persister.load_catalog().await.unwrap().unwrap();
+        assert_eq!(catalog.segment_id, SegmentId::new(0));
+        let catalog = Catalog::from_inner(catalog.catalog);
+        let cpu = catalog.db_schema("telegraf").unwrap().tables["cpu"].clone();
+        assert_eq!(
+            cpu.column_types()
+                .filter(|(_, column_type)| *column_type == ColumnType::Tag)
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            vec!["cpu", "host"]
+        );
+        assert_eq!(cpu.columns()["usage_idle"], ColumnType::F64 as i16);
+
+        // the shard is skipped when the migration is run again
+        let mut migrator = TsmMigrator::new(Arc::clone(&persister), None::<&WalImpl>, config)
+            .await
+            .unwrap();
+        assert_eq!(migrator.next_segment_id, SegmentId::new(1));
+        let summary = migrator.run().await.unwrap();
+        assert_eq!(
+            summary,
+            MigrationSummary {
+                shards_skipped: 1,
+                ..Default::default()
+            }
+        );
+        assert!(persister
+            .load_segment(SegmentId::new(1))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    /// Writes a TSM file with InfluxDB 1.x keys into the directory of a shard of a database and
+    /// retention policy, laid out like the data directory of InfluxDB 1.x.
+    fn write_v1_shard(data_dir: &Path, db: &str, rp: &str, shard_id: u64, hosts: &[&str]) {
+        let shard_dir = data_dir.join(db).join(rp).join(shard_id.to_string());
+        std::fs::create_dir_all(&shard_dir).unwrap();
+        let file = File::create(shard_dir.join("000000001-000000001.tsm")).unwrap();
+        let mut writer = TsmWriter::try_new(file).unwrap();
+        for host in hosts {
+            let key = ParsedTsmKey {
+                org_id: InfluxId::new_str("0").unwrap(),
+                bucket_id: InfluxId::new_str("0").unwrap(),
+                measurement: "cpu".to_string(),
+                tagset: vec![("host".to_string(), host.to_string())],
+                field_key: "usage".to_string(),
+            };
+            let data = BlockData::Float {
+                i: 0,
+                ts: vec![10, 20],
+                values: vec![1.0, 2.0],
+            };
+            writer.write_block(&key.to_v1_tsm_key(), &data).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrates_v1_shards_into_databases_of_their_retention_policies() {
+        let data_dir = test_helpers::tmp_dir().unwrap();
+        write_v1_shard(data_dir.path(), "telegraf", "autogen", 1, &["a", "b"]);
+        write_v1_shard(data_dir.path(), "telegraf", "one_week", 2, &["c"]);
+        let checkpoint_dir = test_helpers::tmp_dir().unwrap();
+        let config = TsmMigrationConfig {
+            data_dir: data_dir.path().to_path_buf(),
+            version: InfluxDbVersion::V1,
+            bucket_databases: HashMap::new(),
+            checkpoint_path: checkpoint_dir.path().join("checkpoint.json"),
+        };
+        let persister: Arc<dyn Persister> = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+
+        let mut migrator = TsmMigrator::new(Arc::clone(&persister), None::<&WalImpl>, config)
+            .await
+            .unwrap();
+        let summary = migrator.run().await.unwrap();
+        assert_eq!(summary.shards_migrated, 2);
+        assert_eq!(summary.rows, 6);
+
+        // the shards are migrated in the order of their paths
+        let autogen = persister
+            .load_segment(SegmentId::new(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            autogen.databases.keys().collect::<Vec<_>>(),
+            vec!["telegraf"]
+        );
+        assert_eq!(autogen.segment_row_count, 4);
+        let one_week = persister
+            .load_segment(SegmentId::new(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            one_week.databases.keys().collect::<Vec<_>>(),
+            vec!["telegraf/one_week"]
+        );
+        assert_eq!(one_week.segment_row_count, 2);
+
+        let catalog = Catalog::from_inner(persister.load_catalog().await.unwrap().unwrap().catalog);
+        for db_name in ["telegraf", "telegraf/one_week"] {
+            let cpu = catalog.db_schema(db_name).unwrap().tables["cpu"].clone();
+            assert_eq!(cpu.columns()["host"], ColumnType::Tag as i16);
+            assert_eq!(cpu.columns()["usage"], ColumnType::F64 as i16);
+        }
+    }
+
+    async fn write_cpu(write_buffer: &WriteBufferImpl<WalImpl>, lp: &str) {
+        Bufferer::write_lp(
+            write_buffer,
+            NamespaceName::new("telegraf").unwrap(),
+            lp,
+            0,
+            false,
+            Precision::Nanosecond,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rows_written_to_the_server_win_over_migrated_rows() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let start_write_buffer = || {
+            WriteBufferImpl::new(
+                Arc::clone(&persister) as _,
+                None::<Arc<WalImpl>>,
+                Arc::clone(&time_provider) as _,
+                SegmentConfig::default(),
+            )
+        };
+
+        // a value written to the server is persisted in segment 0 before the migration
+        let write_buffer = start_write_buffer().await.unwrap();
+        write_cpu(&write_buffer, "cpu,host=a usage=5.0 10").await;
+        write_buffer.close_open_segment().await.unwrap();
+        for _ in 0..100 {
+            if persister
+                .load_segment(SegmentId::new(0))
+                .await
+                .unwrap()
+                .is_some()
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        drop(write_buffer);
+
+        // the migrated shard has values of the same series at the same time, in segment 1
+        let data_dir = test_helpers::tmp_dir().unwrap();
+        write_v1_shard(data_dir.path(), "telegraf", "autogen", 1, &["a"]);
+        let checkpoint_dir = test_helpers::tmp_dir().unwrap();
+        let config = TsmMigrationConfig {
+            data_dir: data_dir.path().to_path_buf(),
+            version: InfluxDbVersion::V1,
+            bucket_databases: HashMap::new(),
+            checkpoint_path: checkpoint_dir.path().join("checkpoint.json"),
+        };
+        let mut migrator = TsmMigrator::new(Arc::clone(&persister) as _, None::<&WalImpl>, config)
+            .await
+            .unwrap();
+        assert_eq!(migrator.next_segment_id, SegmentId::new(1));
+        migrator.run().await.unwrap();
+
+        // the migrated file is ordered before the file of segment 0 and the buffered data of
+        // the server that is started after the migration
+        let write_buffer = start_write_buffer().await.unwrap();
+        write_cpu(&write_buffer, "cpu,host=a usage=7.0 30").await;
+        let ctx = SessionContext::new();
+        let mut chunks = ChunkContainer::get_table_chunks(
+            &write_buffer,
+            "telegraf",
+            "cpu",
+            &[],
+            None,
+            &ctx.state(),
+        )
+        .unwrap();
+        chunks.sort_by_key(|chunk| chunk.order());
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| (chunk.chunk_type(), chunk.order().get()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ParquetChunk", 1 - (1_i64 << 32)),
+                ("ParquetChunk", 0),
+                ("BufferChunk", 2),
+            ]
+        );
+
+        // so deduplication keeps the value written to the server when the files are merged
+        let executor = Executor::new_with_config(ExecutorConfig {
+            object_stores: [(
+                StorageId::from(PARQUET_STORAGE_ID),
+                Arc::clone(&object_store),
+            )]
+            .into_iter()
+            .collect(),
+            ..ExecutorConfig::testing()
+        });
+        write_buffer
+            .compact(&executor, &CompactionConfig { min_files: 2 })
+            .await
+            .unwrap();
+        let segment = persister
+            .load_segment(SegmentId::new(1))
+            .await
+            .unwrap()
+            .unwrap();
+        let compacted_file = &segment.databases["telegraf"].tables["cpu"].parquet_files[0];
+        assert!(!compacted_file.migrated);
+        let bytes = object_store
+            .get(&ObjPath::parse(&compacted_file.path).unwrap())
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+------+--------------------------------+-------+",
+                "| host | time                           | usage |",
+                "+------+--------------------------------+-------+",
+                "| a    | 1970-01-01T00:00:00.000000010Z | 5.0   |",
+                "| a    | 1970-01-01T00:00:00.000000020Z | 2.0   |",
+                "+------+--------------------------------+-------+",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_v1_shards_outside_of_a_retention_policy() {
+        let data_dir = test_helpers::tmp_dir().unwrap();
+        write_v1_shard(data_dir.path(), "telegraf", "autogen", 1, &["a"]);
+        let checkpoint_dir = test_helpers::tmp_dir().unwrap();
+        let config = TsmMigrationConfig {
+            data_dir: data_dir.path().join("telegraf"),
+            version: InfluxDbVersion::V1,
+            bucket_databases: HashMap::new(),
+            checkpoint_path: checkpoint_dir.path().join("checkpoint.json"),
+        };
+        let persister: Arc<dyn Persister> = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+
+        let mut migrator = TsmMigrator::new(persister, None::<&WalImpl>, config)
+            .await
+            .unwrap();
+        assert!(matches!(
+            migrator.run().await,
+            Err(Error::InvalidShardPath { .. })
+        ));
+    }
+
+    #[test]
+    fn valid_mask_of_values() {
+        assert_eq!(
+            valid_mask(&[
+                Some(1),
+                None,
+                Some(3),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(9)
+            ]),
+            vec![0b0000_0101, 0b0000_0001]
+        );
+    }
+}
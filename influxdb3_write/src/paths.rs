@@ -1,5 +1,4 @@
 use crate::SegmentId;
-use chrono::prelude::*;
 use object_store::path::Path as ObjPath;
 use std::convert::AsRef;
 use std::ops::Deref;
@@ -58,13 +57,34 @@ impl AsRef<ObjPath> for CatalogFilePath {
 pub struct ParquetFilePath(ObjPath);
 
 impl ParquetFilePath {
-    pub fn new(db_name: &str, table_name: &str, date: DateTime<Utc>, file_number: u32) -> Self {
-        let path = ObjPath::from(format!(
-            "dbs/{db_name}/{table_name}/{}/{:010}.{}",
-            date.format("%Y-%m-%d"),
+    /// The partition key is a single part of the path, even if it contains a `/`.
+    pub fn new(db_name: &str, table_name: &str, partition_key: &str, file_number: u32) -> Self {
+        let file_name = format!(
+            "{:010}.{}",
             object_store_file_stem(file_number),
             PARQUET_FILE_EXTENSION
-        ));
+        );
+        let path = ObjPath::from_iter(["dbs", db_name, table_name, partition_key, &file_name]);
+        Self(path)
+    }
+
+    /// The path of a file that compaction merged from the files of a table partition. The time of
+    /// the compaction keeps it apart from the file persisted for the partition in the same segment
+    /// and from earlier compactions into it.
+    pub fn new_compacted(
+        db_name: &str,
+        table_name: &str,
+        partition_key: &str,
+        file_number: u32,
+        compaction_time_ns: i64,
+    ) -> Self {
+        let file_name = format!(
+            "{:010}-{}.{}",
+            object_store_file_stem(file_number),
+            compaction_time_ns,
+            PARQUET_FILE_EXTENSION
+        );
+        let path = ObjPath::from_iter(["dbs", db_name, table_name, partition_key, &file_name]);
         Self(path)
     }
 }
@@ -152,28 +172,26 @@ fn catalog_file_path_new() {
 #[test]
 fn parquet_file_path_new() {
     assert_eq!(
-        *ParquetFilePath::new(
-            "my_db",
-            "my_table",
-            Utc.with_ymd_and_hms(2038, 1, 19, 3, 14, 7).unwrap(),
-            0
-        ),
+        *ParquetFilePath::new("my_db", "my_table", "2038-01-19", 0),
         ObjPath::from("dbs/my_db/my_table/2038-01-19/4294967295.parquet")
     );
 }
 
+#[test]
+fn parquet_file_path_new_compacted() {
+    assert_eq!(
+        *ParquetFilePath::new_compacted("my_db", "my_table", "2038-01-19", 0, 10),
+        ObjPath::from("dbs/my_db/my_table/2038-01-19/4294967295-10.parquet")
+    );
+}
+
 #[test]
 fn parquet_file_percent_encoded() {
     assert_eq!(
-        ParquetFilePath::new(
-            "..",
-            "..",
-            Utc.with_ymd_and_hms(2038, 1, 19, 3, 14, 7).unwrap(),
-            0
-        )
-        .as_ref()
-        .as_ref(),
-        "dbs/%2E%2E/%2E%2E/2038-01-19/4294967295.parquet"
+        ParquetFilePath::new("..", "..", "2038-01-19|us/west", 0)
+            .as_ref()
+            .as_ref(),
+        "dbs/%2E%2E/%2E%2E/2038-01-19%7Cus%2Fwest/4294967295.parquet"
     );
 }
 
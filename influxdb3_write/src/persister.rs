@@ -17,6 +17,7 @@ use datafusion::execution::memory_pool::MemoryConsumer;
 use datafusion::execution::memory_pool::MemoryPool;
 use datafusion::execution::memory_pool::MemoryReservation;
 use datafusion::execution::memory_pool::UnboundedMemoryPool;
+use datafusion::execution::object_store::ObjectStoreUrl;
 use datafusion::physical_plan::SendableRecordBatchStream;
 use futures_util::pin_mut;
 use futures_util::stream::StreamExt;
@@ -30,9 +31,14 @@ use parquet::format::FileMetaData;
 use std::io::Write;
 use std::sync::Arc;
 
+/// The id that the object store must be registered under with the query executor for queries
+/// to be able to read persisted parquet files.
+pub const PARQUET_STORAGE_ID: &str = "influxdb3";
+
 #[derive(Debug)]
 pub struct PersisterImpl {
     object_store: Arc<dyn ObjectStore>,
+    object_store_url: ObjectStoreUrl,
     mem_pool: Arc<dyn MemoryPool>,
 }
 
@@ -40,6 +46,8 @@ impl PersisterImpl {
     pub fn new(object_store: Arc<dyn ObjectStore>) -> Self {
         Self {
             object_store,
+            object_store_url: ObjectStoreUrl::parse(format!("iox://{PARQUET_STORAGE_ID}/"))
+                .expect("valid object store URL"),
             mem_pool: Arc::new(UnboundedMemoryPool::default()),
         }
     }
@@ -171,6 +179,11 @@ impl Persister for PersisterImpl {
 
             list.sort_unstable_by(|a, b| a.location.cmp(&b.location));
 
+            // There are no more segments to load
+            if list.is_empty() {
+                break;
+            }
+
             let len = list.len();
             let end = if len <= count { len } else { count };
 
@@ -188,6 +201,23 @@ impl Persister for PersisterImpl {
         Ok(output)
     }
 
+    async fn load_segment(&self, segment_id: SegmentId) -> Result<Option<PersistedSegment>> {
+        let path = SegmentInfoFilePath::new(segment_id);
+        match self.object_store.get(&path).await {
+            Ok(result) => Ok(Some(serde_json::from_slice(&result.bytes().await?)?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete_segment(&self, segment_id: SegmentId) -> Result<()> {
+        let path = SegmentInfoFilePath::new(segment_id);
+        match self.object_store.delete(&path).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     async fn load_parquet_file(&self, path: ParquetFilePath) -> Result<Bytes> {
         Ok(self.object_store.get(&path).await?.bytes().await?)
     }
@@ -214,16 +244,21 @@ impl Persister for PersisterImpl {
         &self,
         path: ParquetFilePath,
         record_batch: SendableRecordBatchStream,
-    ) -> Result<FileMetaData> {
+    ) -> Result<(u64, FileMetaData)> {
         let parquet = self.serialize_to_parquet(record_batch).await?;
+        let size_bytes = parquet.bytes.len() as u64;
         self.object_store.put(path.as_ref(), parquet.bytes).await?;
 
-        Ok(parquet.meta_data)
+        Ok((size_bytes, parquet.meta_data))
     }
 
     fn object_store(&self) -> Arc<dyn ObjectStore> {
         self.object_store.clone()
     }
+
+    fn object_store_url(&self) -> &ObjectStoreUrl {
+        &self.object_store_url
+    }
 }
 
 pub struct ParquetBytes {
@@ -286,8 +321,7 @@ impl<W: Write + Send> TrackedMemoryArrowWriter<W> {
 #[cfg(test)]
 use {
     arrow::array::Int32Array, arrow::datatypes::DataType, arrow::datatypes::Field,
-    arrow::datatypes::Schema, chrono::Utc,
-    datafusion::physical_plan::stream::RecordBatchReceiverStreamBuilder,
+    arrow::datatypes::Schema, datafusion::physical_plan::stream::RecordBatchReceiverStreamBuilder,
     object_store::local::LocalFileSystem, std::collections::HashMap,
 };
 
@@ -415,6 +449,14 @@ async fn persist_and_load_segment_info_files_with_fewer_than_requested() {
     assert_eq!(segments[0].segment_id.0, 0);
 }
 
+#[tokio::test]
+async fn load_segments_with_none_persisted() {
+    let local_disk = LocalFileSystem::new_with_prefix(test_helpers::tmp_dir().unwrap()).unwrap();
+    let persister = PersisterImpl::new(Arc::new(local_disk));
+    let segments = persister.load_segments(2).await.unwrap();
+    assert!(segments.is_empty());
+}
+
 #[tokio::test]
 /// This test makes sure that the logic for offset lists works
 async fn persist_and_load_over_9000_segment_info_files() {
@@ -481,8 +523,8 @@ async fn persist_and_load_parquet_bytes() {
     stream_builder.tx().send(Ok(batch1)).await.unwrap();
     stream_builder.tx().send(Ok(batch2)).await.unwrap();
 
-    let path = ParquetFilePath::new("db_one", "table_one", Utc::now(), 1);
-    let meta = persister
+    let path = ParquetFilePath::new("db_one", "table_one", "2024-01-01", 1);
+    let (bytes_written, meta) = persister
         .persist_parquet_file(path.clone(), stream_builder.build())
         .await
         .unwrap();
@@ -493,5 +535,6 @@ async fn persist_and_load_parquet_bytes() {
     let bytes = persister.load_parquet_file(path).await.unwrap();
 
     // Assert that we have a file of bytes > 0
-    assert!(!bytes.is_empty())
+    assert!(!bytes.is_empty());
+    assert_eq!(bytes.len() as u64, bytes_written);
 }
@@ -166,12 +166,14 @@ impl WalImpl {
 }
 
 impl Wal for WalImpl {
-    fn open_segment_writer(&self, segment_id: SegmentId) -> Result<impl WalSegmentWriter> {
-        self.open_segment_writer(segment_id)
+    fn open_segment_writer(&self, segment_id: SegmentId) -> Result<Box<dyn WalSegmentWriter>> {
+        let writer = self.open_segment_writer(segment_id)?;
+        Ok(Box::new(writer))
     }
 
-    fn open_segment_reader(&self, segment_id: SegmentId) -> Result<impl WalSegmentReader> {
-        self.open_segment_reader(segment_id)
+    fn open_segment_reader(&self, segment_id: SegmentId) -> Result<Box<dyn WalSegmentReader>> {
+        let reader = self.open_segment_reader(segment_id)?;
+        Ok(Box::new(reader))
     }
 
     fn segment_files(&self) -> Result<Vec<SegmentFile>> {
@@ -560,7 +562,7 @@ fn segment_id_from_file_name(name: &str) -> Result<SegmentId> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::LpWriteOp;
+    use crate::{LpWriteOp, Precision};
 
     #[test]
     fn segment_writer_reader() {
@@ -571,6 +573,7 @@ mod tests {
             db_name: "foo".to_string(),
             lp: "cpu host=a val=10i 10".to_string(),
             default_time: 1,
+            precision: Precision::Nanosecond,
         });
         writer.write_batch(vec![wal_op.clone()]).unwrap();
 
@@ -588,6 +591,7 @@ mod tests {
             db_name: "foo".to_string(),
             lp: "cpu host=a val=10i 10".to_string(),
             default_time: 1,
+            precision: Precision::Nanosecond,
         });
 
         // open the file, write and close it
@@ -624,6 +628,7 @@ mod tests {
             db_name: "foo".to_string(),
             lp: "cpu host=a val=10i 10".to_string(),
             default_time: 1,
+            precision: Precision::Nanosecond,
         });
 
         let wal = WalImpl::new(dir.clone()).unwrap();
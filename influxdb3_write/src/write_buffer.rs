@@ -1,46 +1,72 @@
 //! Implementation of an in-memory buffer for writes
 
-use crate::catalog::{Catalog, DatabaseSchema, TableDefinition};
-use crate::{
-    BufferSegment, BufferedWriteRequest, Bufferer, ChunkContainer, SegmentId, Wal, WriteBuffer,
+mod buffer_segment;
+mod compactor;
+mod flusher;
+mod loader;
+
+pub(crate) use buffer_segment::{add_parquet_file_stats, persist_partition, PartitionBuffer};
+
+pub use compactor::CompactionConfig;
+
+use crate::catalog::{
+    self, Catalog, DatabaseSchema, DistinctCacheDefinition, LastCacheDefinition, TableDefinition,
 };
-use arrow::array::ArrayRef;
-use arrow::{
-    array::{
-        BooleanBuilder, Float64Builder, Int64Builder, StringBuilder, StringDictionaryBuilder,
-        TimestampNanosecondBuilder, UInt64Builder,
-    },
-    datatypes::Int32Type,
-    record_batch::RecordBatch,
+use crate::distinct_cache::DistinctCacheProvider;
+use crate::last_cache::LastCacheProvider;
+use crate::write_buffer::buffer_segment::{ClosedBufferSegment, OpenBufferSegment};
+use crate::write_buffer::flusher::{BufferedWrite, WriteBufferFlusher};
+use crate::write_buffer::loader::{load_starting_state, load_wal_segments};
+use crate::{
+    wal, BufferSegment, BufferedWriteRequest, Bufferer, ChunkContainer, DeleteDatabaseOp,
+    DeleteTableOp, DistinctCacheManager, LastCacheManager, LpWriteOp, ParquetFile,
+    PersistedSegment, Persister, Precision, SegmentId, TableParquetFiles, Wal, WalOp,
+    WalSegmentWriter, WriteBuffer, WriteLineError,
 };
+use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
+use backoff::{Backoff, BackoffConfig};
 use chrono::{TimeZone, Utc};
+use data_types::partition_template::TablePartitionTemplateOverride;
 use data_types::{
     column_type_from_field, ChunkId, ChunkOrder, ColumnType, NamespaceName, PartitionKey, TableId,
     TimestampMinMax, TransitionPartitionId,
 };
 use datafusion::common::{DataFusionError, Statistics};
 use datafusion::execution::context::SessionState;
+use datafusion::execution::object_store::ObjectStoreUrl;
 use datafusion::logical_expr::Expr;
-use influxdb_line_protocol::{parse_lines, FieldValue, ParsedLine};
+use futures_util::TryStreamExt;
+use generated_types::influxdata::iox::partition_template::v1::PartitionTemplate;
+use influxdb_line_protocol::{lint::Diagnostic, parse_split_line, split_lines, ParsedLine};
 use iox_catalog::constants::TIME_COLUMN;
-use iox_query::chunk_statistics::{create_chunk_statistics, ColumnRange};
+use iox_query::chunk_statistics::create_chunk_statistics;
 use iox_query::{QueryChunk, QueryChunkData};
-use observability_deps::tracing::{debug, info};
+use iox_time::{Time, TimeProvider};
+use mutable_batch::writer::Writer;
+use mutable_batch::MutableBatch;
+use mutable_batch_lp::{write_line, LineWriteError};
+use object_store::path::Path as ObjPath;
+use object_store::ObjectMeta;
+use observability_deps::tracing::{debug, error, info};
 use parking_lot::RwLock;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+use parquet_file::storage::ParquetExecInput;
+use partition::{partition_batch, Batch, PartitionKeyError, PartitioningColumn, TimeColumnError};
 use schema::sort::SortKey;
-use schema::Schema;
-use serde::{Deserialize, Serialize};
+use schema::{Projection, Schema, TIME_COLUMN_NAME};
 use std::any::Any;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("error parsing line {line_number}: {message}")]
-    ParseError { line_number: usize, message: String },
+    #[error("failed to parse or validate {} lines of line protocol", .0.len())]
+    ParseError(Vec<WriteLineError>),
 
     #[error("column type mismatch for column {name}: existing: {existing:?}, new: {new:?}")]
     ColumnTypeMismatch {
@@ -48,6 +74,39 @@ pub enum Error {
         existing: ColumnType,
         new: ColumnType,
     },
+
+    #[error("timestamp {timestamp} with precision {precision:?} is out of range")]
+    TimestampOutOfRange {
+        timestamp: i64,
+        precision: Precision,
+    },
+
+    #[error("error writing to the wal: {0}")]
+    WalWrite(Arc<wal::Error>),
+
+    #[error("the write buffer flusher has stopped")]
+    FlusherStopped,
+
+    #[error("catalog error: {0}")]
+    Catalog(#[from] catalog::Error),
+
+    #[error("error generating partition key: {0}")]
+    PartitionKey(#[from] PartitionKeyError),
+
+    #[error("{0}")]
+    LineWrite(#[from] LineWriteError),
+
+    #[error(
+        "the write buffer is full: {used_bytes} bytes used of a limit of {limit_bytes} bytes, \
+         retry once buffered data has been persisted"
+    )]
+    BufferFull {
+        used_bytes: usize,
+        limit_bytes: usize,
+    },
+
+    #[error("error planning compaction: {0}")]
+    CompactionPlan(#[from] iox_query::frontend::reorg::Error),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -59,72 +118,788 @@ pub struct WriteRequest<'a> {
     pub default_time: u64,
 }
 
+/// Configuration for when the open segment in the buffer gets closed and persisted.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentConfig {
+    /// The open segment is closed once this many bytes of line protocol have been written into it.
+    pub max_size_bytes: usize,
+    /// The open segment is closed once its first write is older than this.
+    pub max_duration: Duration,
+    /// The limit on the memory used by the data buffered in the open and persisting segments.
+    /// The open segment is closed early as the limit is approached, and writes are rejected with
+    /// [`Error::BufferFull`] once it has been reached.
+    pub max_buffer_memory_bytes: usize,
+}
+
+impl SegmentConfig {
+    /// The percentage of `max_buffer_memory_bytes` at which the open segment is closed early.
+    const FORCE_PERSIST_PERCENT: usize = 80;
+
+    fn force_persist_threshold_bytes(&self) -> usize {
+        self.max_buffer_memory_bytes / 100 * Self::FORCE_PERSIST_PERCENT
+    }
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        Self {
+            max_size_bytes: 256 * 1024 * 1024,
+            max_duration: Duration::from_secs(60 * 60),
+            max_buffer_memory_bytes: usize::MAX,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WriteBufferImpl<W> {
     catalog: Arc<Catalog>,
-    buffered_data: RwLock<HashMap<String, DatabaseBuffer>>,
-    #[allow(dead_code)]
+    segment_state: Arc<RwLock<SegmentState<W>>>,
+    flusher: WriteBufferFlusher,
+    persister: Arc<dyn Persister>,
+    time_provider: Arc<dyn TimeProvider>,
+    segment_config: SegmentConfig,
     wal: Option<Arc<W>>,
+    /// Held while retention or compaction rewrite the parquet file lists of persisted segments,
+    /// so they don't overwrite each other's changes.
+    persisted_files_lock: tokio::sync::Mutex<()>,
+    last_cache_provider: Arc<LastCacheProvider>,
+    distinct_cache_provider: Arc<DistinctCacheProvider>,
+}
+
+/// The segments held in memory by the buffer.
+#[derive(Debug)]
+struct SegmentState<W> {
+    open_segment: OpenBufferSegment,
+    /// Segments that have been closed and are being persisted. They stay here, and are
+    /// queryable, until their persistence has completed.
+    persisting_segments: BTreeMap<SegmentId, Arc<ClosedBufferSegment>>,
+    /// The parquet files of persisted segments by database and table name, along with the id of
+    /// the segment they were persisted in.
+    persisted_files: HashMap<String, HashMap<String, Vec<(SegmentId, TableParquetFiles)>>>,
+    wal: Option<Arc<W>>,
+}
+
+impl<W: Wal> SegmentState<W> {
+    /// Writes the ops for the batch of writes into the WAL, then buffers their data into the open
+    /// segment and lets each writer know the segment it went into.
+    fn buffer_writes(&mut self, writes: Vec<BufferedWrite>, now: Time) {
+        let segment_id = self.open_segment.segment_id();
+        let mut ops = Vec::with_capacity(writes.len());
+        let mut pending = Vec::with_capacity(writes.len());
+        for write in writes {
+            ops.push(write.wal_op);
+            pending.push((
+                write.db_name,
+                write.lp_size_bytes,
+                write.table_batches,
+                write.response_tx,
+            ));
+        }
+
+        match self.open_segment.write_wal_ops(ops) {
+            Ok(()) => {
+                for (db_name, lp_size_bytes, table_batches, response_tx) in pending {
+                    self.open_segment
+                        .buffer_writes(&db_name, table_batches, lp_size_bytes, now);
+                    let _ = response_tx.send(Ok(segment_id));
+                }
+            }
+            Err(e) => {
+                error!(error=%e, ?segment_id, "error writing to the wal");
+                let e = Arc::new(e);
+                for (_, _, _, response_tx) in pending {
+                    let _ = response_tx.send(Err(Error::WalWrite(Arc::clone(&e))));
+                }
+            }
+        }
+    }
+
+    /// Returns the number of bytes of memory used by the data buffered in the open segment and in
+    /// the segments that are being persisted.
+    fn buffer_size_bytes(&self) -> usize {
+        self.open_segment.buffer_size_bytes()
+            + self
+                .persisting_segments
+                .values()
+                .map(|segment| segment.buffer_size_bytes())
+                .sum::<usize>()
+    }
+
+    /// Returns true if the buffer is nearing its memory limit and closing the open segment would
+    /// free a meaningful share of it. Segments that are already persisting will free their memory
+    /// on their own, so small open segments are left alone.
+    fn should_force_persist(&self, segment_config: &SegmentConfig) -> bool {
+        let open_bytes = self.open_segment.buffer_size_bytes();
+        let used_bytes = self.buffer_size_bytes();
+        open_bytes > 0
+            && used_bytes >= segment_config.force_persist_threshold_bytes()
+            && open_bytes >= used_bytes / 2
+    }
+
+    /// Closes the open segment, replacing it with a new one that has the next segment id.
+    fn close_open_segment(&mut self, catalog: &Catalog) -> crate::Result<Arc<ClosedBufferSegment>> {
+        let next_segment_id = self.open_segment.segment_id().next();
+        let next_segment = OpenBufferSegment::new(
+            next_segment_id,
+            catalog.sequence_number(),
+            open_wal_writer(self.wal.as_deref(), next_segment_id)?,
+        );
+        let open_segment = std::mem::replace(&mut self.open_segment, next_segment);
+        let closed_segment = Arc::new(open_segment.into_closed_segment(catalog));
+        self.persisting_segments
+            .insert(closed_segment.id(), Arc::clone(&closed_segment));
+
+        Ok(closed_segment)
+    }
+
+    /// Registers the parquet files of a persisted segment so that they are returned for queries.
+    /// Files of tables that were deleted after the segment was closed are left out, and their
+    /// paths are returned.
+    fn add_persisted_segment(
+        &mut self,
+        segment: PersistedSegment,
+        catalog: &Catalog,
+    ) -> Vec<String> {
+        let mut deleted_files = vec![];
+        for (db_name, database_tables) in segment.databases {
+            for (table_name, table_files) in database_tables.tables {
+                if catalog.is_deleted(&db_name, &table_name, segment.segment_id) {
+                    deleted_files.extend(table_files.parquet_files.into_iter().map(|f| f.path));
+                    continue;
+                }
+                self.persisted_files
+                    .entry(db_name.clone())
+                    .or_default()
+                    .entry(table_name)
+                    .or_default()
+                    .push((segment.segment_id, table_files));
+            }
+        }
+        deleted_files
+    }
+
+    /// Drops the data of a deleted database or table from the open segment and the persisted
+    /// files. Returns the paths of the parquet files that were persisted for it.
+    fn delete(&mut self, op: WalOp) -> Vec<String> {
+        let removed_files = match op {
+            WalOp::DeleteDatabase(DeleteDatabaseOp { db_name }) => {
+                self.open_segment.remove_database(&db_name);
+                self.persisted_files
+                    .remove(&db_name)
+                    .into_iter()
+                    .flat_map(|tables| tables.into_values())
+                    .flatten()
+                    .collect()
+            }
+            WalOp::DeleteTable(DeleteTableOp {
+                db_name,
+                table_name,
+            }) => {
+                self.open_segment.remove_table(&db_name, &table_name);
+                self.persisted_files
+                    .get_mut(&db_name)
+                    .and_then(|tables| tables.remove(&table_name))
+                    .unwrap_or_default()
+            }
+            WalOp::LpWrite(_) => vec![],
+        };
+
+        removed_files
+            .into_iter()
+            .flat_map(|(_, table_files)| table_files.parquet_files)
+            .map(|file| file.path)
+            .collect()
+    }
+
+    /// Drops the persisted parquet files whose data is all older than the retention period of
+    /// their database as of `now_ns`, so they are no longer queried. Returns the paths of the
+    /// dropped files by the segment they were persisted in.
+    fn remove_expired_files(
+        &mut self,
+        catalog: &Catalog,
+        now_ns: i64,
+    ) -> BTreeMap<SegmentId, HashSet<String>> {
+        let mut expired_files: BTreeMap<SegmentId, HashSet<String>> = BTreeMap::new();
+
+        for (db_name, tables) in &mut self.persisted_files {
+            let Some(retention_time) = catalog
+                .db_schema(db_name)
+                .and_then(|db| db.retention_time_ns(now_ns))
+            else {
+                continue;
+            };
+
+            for segments in tables.values_mut() {
+                for (segment_id, table_files) in segments.iter_mut() {
+                    table_files.parquet_files.retain(|file| {
+                        if file.max_time > retention_time {
+                            return true;
+                        }
+                        expired_files
+                            .entry(*segment_id)
+                            .or_default()
+                            .insert(file.path.clone());
+                        false
+                    });
+                }
+                segments.retain(|(_, table_files)| !table_files.parquet_files.is_empty());
+            }
+            tables.retain(|_, segments| !segments.is_empty());
+        }
+        self.persisted_files.retain(|_, tables| !tables.is_empty());
+
+        expired_files
+    }
+}
+
+/// Removes the parquet files from the segment's lists, along with any tables and databases
+/// left without files, and updates the totals of the segment to match the files that are left.
+fn remove_parquet_files(segment: &mut PersistedSegment, paths: &HashSet<String>) {
+    segment.segment_parquet_size_bytes = 0;
+    segment.segment_row_count = 0;
+    segment.segment_min_time = i64::MAX;
+    segment.segment_max_time = i64::MIN;
+
+    for database_tables in segment.databases.values_mut() {
+        for table_files in database_tables.tables.values_mut() {
+            table_files
+                .parquet_files
+                .retain(|file| !paths.contains(&file.path));
+            for file in &table_files.parquet_files {
+                segment.segment_parquet_size_bytes += file.size_bytes;
+                segment.segment_row_count += file.row_count as u64;
+                segment.segment_min_time = segment.segment_min_time.min(file.min_time);
+                segment.segment_max_time = segment.segment_max_time.max(file.max_time);
+            }
+        }
+        database_tables
+            .tables
+            .retain(|_, table_files| !table_files.parquet_files.is_empty());
+    }
+    segment
+        .databases
+        .retain(|_, database_tables| !database_tables.tables.is_empty());
+}
+
+fn open_wal_writer<W: Wal>(
+    wal: Option<&W>,
+    segment_id: SegmentId,
+) -> wal::Result<Option<Box<dyn WalSegmentWriter>>> {
+    wal.map(|wal| wal.open_segment_writer(segment_id))
+        .transpose()
 }
 
 impl<W: Wal> WriteBufferImpl<W> {
-    pub fn new(catalog: Arc<Catalog>, wal: Option<Arc<W>>) -> Self {
-        Self {
+    /// Creates the write buffer, loading the most recently persisted catalog and segments from
+    /// object storage and replaying the segments in the WAL that were not persisted. Replayed
+    /// segments are closed and persisted in the background, and new writes go into a new segment.
+    pub async fn new(
+        persister: Arc<dyn Persister>,
+        wal: Option<Arc<W>>,
+        time_provider: Arc<dyn TimeProvider>,
+        segment_config: SegmentConfig,
+    ) -> crate::Result<Self> {
+        let loaded_state =
+            load_starting_state(persister.as_ref(), wal.as_deref(), time_provider.now()).await?;
+        let catalog = Arc::new(loaded_state.catalog);
+        let last_cache_provider = Arc::new(LastCacheProvider::new_from_catalog(&catalog));
+        let distinct_cache_provider = Arc::new(DistinctCacheProvider::new_from_catalog(
+            &catalog,
+            Arc::clone(&time_provider),
+        ));
+        let next_segment_id = loaded_state.next_segment_id;
+
+        let open_segment = OpenBufferSegment::new(
+            next_segment_id,
+            catalog.sequence_number(),
+            open_wal_writer(wal.as_deref(), next_segment_id)?,
+        );
+        let replayed_segments: Vec<_> = loaded_state
+            .replayed_segments
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        let mut segment_state = SegmentState {
+            open_segment,
+            persisting_segments: replayed_segments
+                .iter()
+                .map(|segment| (segment.id(), Arc::clone(segment)))
+                .collect(),
+            persisted_files: HashMap::new(),
+            wal: wal.clone(),
+        };
+        for segment in loaded_state.persisted_segments {
+            let _ = segment_state.add_persisted_segment(segment, &catalog);
+        }
+        let segment_state = Arc::new(RwLock::new(segment_state));
+        let flusher =
+            WriteBufferFlusher::new(Arc::clone(&segment_state), Arc::clone(&time_provider));
+
+        let write_buffer = Self {
             catalog,
-            buffered_data: RwLock::new(HashMap::new()),
+            segment_state,
+            flusher,
+            persister,
+            time_provider,
+            segment_config,
             wal,
+            persisted_files_lock: tokio::sync::Mutex::new(()),
+            last_cache_provider,
+            distinct_cache_provider,
+        };
+
+        for segment in replayed_segments {
+            write_buffer.persist_segment_in_background(segment);
         }
+        for db in write_buffer.catalog.databases() {
+            for table in db.tables() {
+                for definition in table.distinct_caches() {
+                    write_buffer
+                        .seed_distinct_cache(&db.name, &table.name, definition)
+                        .await;
+                }
+            }
+        }
+
+        Ok(write_buffer)
     }
 
-    // TODO: write into segments and wal
     async fn write_lp(
         &self,
         db_name: NamespaceName<'static>,
         lp: &str,
         default_time: i64,
+        accept_partial: bool,
+        precision: Precision,
     ) -> Result<BufferedWriteRequest> {
         debug!("write_lp to {} in writebuffer", db_name);
-        let (sequence, db) = self.catalog.db_or_create(db_name.as_str());
-        let result = parse_validate_and_update_schema(
-            lp,
-            &db,
-            &Partitioner::new_per_day_partitioner(),
-            default_time,
-        )?;
-
-        if let Some(schema) = result.schema {
-            debug!("replacing schema for {:?}", schema);
-            self.catalog
-                .replace_database(sequence, Arc::new(schema))
-                .unwrap();
+        let used_bytes = self.segment_state.read().buffer_size_bytes();
+        let limit_bytes = self.segment_config.max_buffer_memory_bytes;
+        if used_bytes >= limit_bytes {
+            // make sure the open segment is on its way to being persisted so memory gets freed
+            self.close_open_segment_if_needed();
+            return Err(Error::BufferFull {
+                used_bytes,
+                limit_bytes,
+            });
         }
 
-        let mut buffered_data = self.buffered_data.write();
-        let db_buffer = buffered_data
-            .entry(db_name.as_str().to_string())
-            .or_default();
-        for (table_name, table_batch) in result.table_batches {
-            let table_buffer = db_buffer.table_buffers.entry(table_name).or_default();
-            for (partition_key, partition_batch) in table_batch.partition_batches {
-                let partition_buffer = table_buffer
-                    .partition_buffers
-                    .entry(partition_key)
-                    .or_default();
-                partition_buffer.rows.extend(partition_batch.rows);
+        // the lines are validated again if the catalog is changed while they are validated
+        let result = loop {
+            let (sequence, db) = self.catalog.db_or_create(db_name.as_str());
+            let mut result =
+                parse_validate_and_update_schema(lp, &db, default_time, accept_partial, precision)?;
+
+            let Some(schema) = result.schema.take() else {
+                break result;
+            };
+            debug!("replacing schema for {:?}", schema);
+            match self.catalog.replace_database(sequence, Arc::new(schema)) {
+                Ok(()) => break result,
+                Err(catalog::Error::CatalogUpdatedElsewhere) => continue,
+                Err(e) => return Err(e.into()),
             }
+        };
+
+        // if every line was rejected there is nothing to write
+        if result.line_count == 0 {
+            return Ok(BufferedWriteRequest {
+                db_name,
+                invalid_lines: result.errors,
+                line_count: 0,
+                field_count: 0,
+                tag_count: 0,
+                total_buffer_memory_used: used_bytes,
+                segment_id: self.segment_state.read().open_segment.segment_id(),
+            });
         }
 
+        // only the valid lines go into the WAL so that it can be replayed without errors
+        let lp = result.valid_lp.unwrap_or_else(|| lp.to_string());
+        let lp_size_bytes = lp.len();
+        let cached_batches = batches_to_cache(&result.table_batches, |table_name| {
+            self.last_cache_provider
+                .has_caches(db_name.as_str(), table_name)
+                || self
+                    .distinct_cache_provider
+                    .has_caches(db_name.as_str(), table_name)
+        });
+        let wal_op = WalOp::LpWrite(LpWriteOp {
+            db_name: db_name.to_string(),
+            lp,
+            default_time: default_time as u64,
+            precision,
+        });
+        let segment_id = self
+            .flusher
+            .write_to_open_segment(
+                wal_op,
+                db_name.to_string(),
+                lp_size_bytes,
+                result.table_batches,
+            )
+            .await?;
+        self.last_cache_provider
+            .write_batches(db_name.as_str(), &cached_batches);
+        self.distinct_cache_provider
+            .write_batches(db_name.as_str(), &cached_batches);
+
+        self.close_open_segment_if_needed();
+        let total_buffer_memory_used = self.segment_state.read().buffer_size_bytes();
+
         Ok(BufferedWriteRequest {
             db_name,
-            invalid_lines: vec![],
+            invalid_lines: result.errors,
             line_count: result.line_count,
             field_count: result.field_count,
             tag_count: result.tag_count,
-            total_buffer_memory_used: 0,
-            segment_id: SegmentId(0),
+            total_buffer_memory_used,
+            segment_id,
         })
     }
 
+    /// Closes the open segment and starts persisting it if it has grown past the configured
+    /// size, has been open for longer than the configured duration, or if the buffer is nearing
+    /// its memory limit and the open segment holds at least half of the buffered data.
+    pub fn close_open_segment_if_needed(&self) {
+        let now = self.time_provider.now();
+        let closed_segment = {
+            let mut segment_state = self.segment_state.write();
+            if !segment_state
+                .open_segment
+                .should_close(&self.segment_config, now)
+                && !segment_state.should_force_persist(&self.segment_config)
+            {
+                return;
+            }
+            segment_state.close_open_segment(&self.catalog)
+        };
+
+        match closed_segment {
+            Ok(closed_segment) => {
+                info!(
+                    segment_id=?closed_segment.id(),
+                    "closed open segment in write buffer"
+                );
+                self.persist_segment_in_background(closed_segment);
+            }
+            Err(e) => error!(error=%e, "error closing the open segment"),
+        }
+    }
+
+    /// Spawns a task that checks the open segment at the given interval, so that it gets closed
+    /// once it is old enough even if no more writes arrive. The task stops once the write buffer
+    /// has been dropped.
+    pub fn start_segment_rotation_task(self: &Arc<Self>, check_interval: Duration) {
+        let write_buffer = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                let Some(write_buffer) = write_buffer.upgrade() else {
+                    return;
+                };
+                write_buffer.close_open_segment_if_needed();
+            }
+        });
+    }
+
+    /// Persists the closed segment, retrying until it succeeds, and then drops it from the
+    /// buffer, and its file from the WAL, so its memory is freed. Queries get its data from the
+    /// persisted parquet files from then on.
+    fn persist_segment_in_background(&self, segment: Arc<ClosedBufferSegment>) {
+        let persister = Arc::clone(&self.persister);
+        let segment_state = Arc::clone(&self.segment_state);
+        let catalog = Arc::clone(&self.catalog);
+        let wal = self.wal.clone();
+
+        tokio::spawn(async move {
+            let segment_id = segment.id();
+            let persisted_segment = Backoff::new(&BackoffConfig::default())
+                .retry_all_errors("persist buffer segment", || {
+                    let segment = Arc::clone(&segment);
+                    let persister = Arc::clone(&persister);
+                    let catalog = Arc::clone(&catalog);
+                    async move { segment.persist_tables(persister, &catalog).await }
+                })
+                .await
+                .expect("retry forever");
+
+            if let Some(wal) = wal {
+                if let Err(error) = wal.delete_wal_segment(segment_id) {
+                    error!(%error, ?segment_id, "error deleting wal segment after persisting it");
+                }
+            }
+
+            // a table deleted while the segment was being persisted still had its files written
+            let deleted_files = {
+                let mut segment_state = segment_state.write();
+                let deleted_files =
+                    segment_state.add_persisted_segment(persisted_segment, &catalog);
+                segment_state.persisting_segments.remove(&segment_id);
+                deleted_files
+            };
+            info!(
+                ?segment_id,
+                "persisted segment and removed it from the buffer"
+            );
+
+            let object_store = persister.object_store();
+            for path in deleted_files {
+                let location = ObjPath::parse(&path).expect("parquet file paths are valid");
+                if let Err(error) = object_store.delete(&location).await {
+                    error!(%error, %path, "error deleting parquet file of deleted table");
+                }
+            }
+        });
+    }
+
+    /// Spawns a task that deletes data older than the retention period of its database at the
+    /// given interval. The task stops once the write buffer has been dropped.
+    pub fn start_retention_task(self: &Arc<Self>, check_interval: Duration) {
+        let write_buffer = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                let Some(write_buffer) = write_buffer.upgrade() else {
+                    return;
+                };
+                if let Err(error) = write_buffer.enforce_retention().await {
+                    error!(%error, "error deleting data past its retention period");
+                }
+            }
+        });
+    }
+
+    /// Deletes the persisted parquet files whose data is all older than the retention period of
+    /// their database. The files are dropped from queries right away, then removed from the
+    /// parquet file lists of their segments and deleted from object storage. Segments left
+    /// without any files have their list deleted, unless it is the most recent one, which is
+    /// kept so that segment ids aren't reused after a restart.
+    pub async fn enforce_retention(&self) -> crate::Result<()> {
+        let _persisted_files_guard = self.persisted_files_lock.lock().await;
+        let now_ns = self.time_provider.now().timestamp_nanos();
+        let expired_files = self
+            .segment_state
+            .write()
+            .remove_expired_files(&self.catalog, now_ns);
+        if expired_files.is_empty() {
+            return Ok(());
+        }
+
+        let newest_segment_id = self
+            .persister
+            .load_segments(1)
+            .await?
+            .first()
+            .map(|segment| segment.segment_id);
+        let object_store = self.persister.object_store();
+
+        for (segment_id, paths) in expired_files {
+            if let Some(mut segment) = self.persister.load_segment(segment_id).await? {
+                remove_parquet_files(&mut segment, &paths);
+                if segment.databases.is_empty() && Some(segment_id) != newest_segment_id {
+                    self.persister.delete_segment(segment_id).await?;
+                } else {
+                    self.persister.persist_segment(segment).await?;
+                }
+            }
+
+            for path in paths {
+                let location = ObjPath::parse(&path).expect("parquet file paths are valid");
+                if let Err(error) = object_store.delete(&location).await {
+                    error!(%error, %path, "error deleting parquet file past its retention period");
+                }
+            }
+            info!(
+                ?segment_id,
+                "deleted parquet files past their retention period"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Seeds the distinct value cache with the values written to the table within the max age of
+    /// the cache, from the buffered data and the persisted parquet files. If that fails the cache
+    /// is left unseeded, so it isn't used to answer for the values written before it was seeded.
+    async fn seed_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        definition: &DistinctCacheDefinition,
+    ) {
+        if let Err(error) = self
+            .try_seed_distinct_cache(db_name, table_name, definition)
+            .await
+        {
+            error!(
+                %error,
+                %db_name,
+                %table_name,
+                cache_name = %definition.name,
+                "error seeding distinct cache"
+            );
+        }
+    }
+
+    async fn try_seed_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        definition: &DistinctCacheDefinition,
+    ) -> crate::Result<()> {
+        let min_time = self.distinct_cache_provider.min_time(definition);
+        let Some(schema) = self
+            .catalog
+            .db_schema(db_name)
+            .and_then(|db| db.get_table_schema(table_name))
+        else {
+            return Ok(());
+        };
+        let seed = |batch: &RecordBatch| {
+            self.distinct_cache_provider
+                .seed_batch(db_name, table_name, &definition.name, batch)
+        };
+
+        let (persisted_files, buffer_chunks) = {
+            let segment_state = self.segment_state.read();
+            let persisted_files: Vec<_> = segment_state
+                .persisted_files
+                .get(db_name)
+                .and_then(|tables| tables.get(table_name))
+                .into_iter()
+                .flatten()
+                .flat_map(|(_, table_files)| &table_files.parquet_files)
+                .filter(|file| file.max_time >= min_time)
+                .cloned()
+                .collect();
+            let buffer_chunks: Vec<_> = segment_state
+                .persisting_segments
+                .values()
+                .filter(|segment| !self.catalog.is_deleted(db_name, table_name, segment.id()))
+                .flat_map(|segment| segment.table_chunks(db_name, table_name, &schema))
+                .chain(
+                    segment_state
+                        .open_segment
+                        .table_chunks(db_name, table_name, &schema),
+                )
+                .collect();
+
+            (persisted_files, buffer_chunks)
+        };
+
+        for chunk in buffer_chunks {
+            if let QueryChunkData::RecordBatches(stream) = chunk.data() {
+                let batches: Vec<RecordBatch> = stream.try_collect().await?;
+                for batch in &batches {
+                    seed(batch)?;
+                }
+            }
+        }
+
+        // only the cached tags and time are read from the parquet files
+        let object_store = self.persister.object_store();
+        for file in persisted_files {
+            let path = ObjPath::parse(&file.path).expect("parquet file paths are valid");
+            let bytes = object_store.get(&path).await?.bytes().await?;
+            let builder = ParquetRecordBatchReaderBuilder::try_new(bytes)?;
+            let indexes: Vec<_> = builder
+                .schema()
+                .fields()
+                .iter()
+                .enumerate()
+                .filter(|(_, field)| {
+                    field.name() == TIME_COLUMN_NAME || definition.columns.contains(field.name())
+                })
+                .map(|(index, _)| index)
+                .collect();
+            let projection = ProjectionMask::roots(builder.parquet_schema(), indexes);
+            for batch in builder.with_projection(projection).build()? {
+                seed(&batch?)?;
+            }
+        }
+
+        self.distinct_cache_provider.finish_seeding(
+            db_name,
+            table_name,
+            &definition.name,
+            min_time,
+        );
+        Ok(())
+    }
+
+    /// Deletes a database or table, then persists the catalog and deletes its parquet files
+    /// from object storage in the background.
+    async fn delete(&self, op: WalOp) -> crate::Result<()> {
+        let removed_files = {
+            let mut segment_state = self.segment_state.write();
+            let segment_id = segment_state.open_segment.segment_id();
+
+            // the delete is only applied once it is in the WAL, so that it is replayed on restart
+            match &op {
+                WalOp::DeleteDatabase(op) => {
+                    if self.catalog.db_schema(&op.db_name).is_none() {
+                        return Err(Error::Catalog(catalog::Error::DatabaseNotFound(
+                            op.db_name.clone(),
+                        )));
+                    }
+                }
+                WalOp::DeleteTable(op) => {
+                    let db_schema = self.catalog.db_schema(&op.db_name).ok_or_else(|| {
+                        Error::Catalog(catalog::Error::DatabaseNotFound(op.db_name.clone()))
+                    })?;
+                    if !db_schema.table_exists(&op.table_name) {
+                        return Err(Error::Catalog(catalog::Error::TableNotFound {
+                            db_name: op.db_name.clone(),
+                            table_name: op.table_name.clone(),
+                        }));
+                    }
+                }
+                WalOp::LpWrite(_) => unreachable!("only deletes are passed in"),
+            }
+            segment_state
+                .open_segment
+                .write_wal_ops(vec![op.clone()])
+                .map_err(|e| Error::WalWrite(Arc::new(e)))?;
+
+            match &op {
+                WalOp::DeleteDatabase(op) => {
+                    self.catalog
+                        .delete_database(&op.db_name, segment_id)
+                        .map_err(Error::Catalog)?;
+                    self.last_cache_provider.delete_database(&op.db_name);
+                    self.distinct_cache_provider.delete_database(&op.db_name);
+                }
+                WalOp::DeleteTable(op) => {
+                    self.catalog
+                        .delete_table(&op.db_name, &op.table_name, segment_id)
+                        .map_err(Error::Catalog)?;
+                    self.last_cache_provider
+                        .delete_table(&op.db_name, &op.table_name);
+                    self.distinct_cache_provider
+                        .delete_table(&op.db_name, &op.table_name);
+                }
+                WalOp::LpWrite(_) => unreachable!("only deletes are passed in"),
+            };
+            segment_state.delete(op)
+        };
+        self.persist_catalog().await?;
+
+        let object_store = self.persister.object_store();
+        tokio::spawn(async move {
+            for path in removed_files {
+                let location = ObjPath::parse(&path).expect("parquet file paths are valid");
+                if let Err(error) = object_store.delete(&location).await {
+                    error!(%error, %path, "error deleting parquet file of deleted table");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
     fn get_table_chunks(
         &self,
         database_name: &str,
@@ -133,48 +908,75 @@ impl<W: Wal> WriteBufferImpl<W> {
         _projection: Option<&Vec<usize>>,
         _ctx: &SessionState,
     ) -> Result<Vec<Arc<dyn QueryChunk>>, DataFusionError> {
-        let db_schema = self.catalog.db_schema(database_name).unwrap();
-        let table = db_schema.tables.get(table_name).unwrap();
+        let Some(db_schema) = self.catalog.db_schema(database_name) else {
+            return Err(DataFusionError::Execution(format!(
+                "database {} not found",
+                database_name
+            )));
+        };
+        let Some(table) = db_schema.tables.get(table_name) else {
+            return Err(DataFusionError::Execution(format!(
+                "table {} not found in database {}",
+                table_name, database_name
+            )));
+        };
         let schema = table.schema.as_ref().cloned().unwrap();
+        let retention_time =
+            db_schema.retention_time_ns(self.time_provider.now().timestamp_nanos());
+
+        // the buffered data is already columnar, so its chunks are snapshotted under the lock
+        let (persisted_files, buffer_chunks) = {
+            let segment_state = self.segment_state.read();
+            let persisted_files = segment_state
+                .persisted_files
+                .get(database_name)
+                .and_then(|tables| tables.get(table_name))
+                .cloned()
+                .unwrap_or_default();
+            let open_segment = &segment_state.open_segment;
+            let buffer_chunks: Vec<_> = segment_state
+                .persisting_segments
+                .values()
+                .filter(|segment| {
+                    !self
+                        .catalog
+                        .is_deleted(database_name, table_name, segment.id())
+                })
+                .flat_map(|segment| segment.table_chunks(database_name, table_name, &schema))
+                .chain(open_segment.table_chunks(database_name, table_name, &schema))
+                .collect();
+
+            (persisted_files, buffer_chunks)
+        };
 
-        let table_buffer = self.clone_table_buffer(database_name, table_name).unwrap();
-
-        let mut chunks = Vec::with_capacity(table_buffer.partition_buffers.len());
-
-        for (partition_key, partition_buffer) in table_buffer.partition_buffers {
-            let partition_key: PartitionKey = partition_key.into();
-            let batch = partition_buffer.rows_to_record_batch(&schema, table.columns());
-            let column_ranges = Arc::new(partition_buffer.column_ranges);
-            let batch_stats = create_chunk_statistics(
-                Some(partition_buffer.rows.len()),
-                &schema,
-                Some(TimestampMinMax {
-                    min: partition_buffer.timestamp_min,
-                    max: partition_buffer.timestamp_max,
-                }),
-                Some(&column_ranges),
-            );
-
-            let chunk = BufferChunk {
-                batches: vec![batch],
-                schema: schema.clone(),
-                stats: Arc::new(batch_stats),
-                partition_id: TransitionPartitionId::new(TableId::new(0), &partition_key),
-                sort_key: None,
-                id: ChunkId::new(),
-                chunk_order: ChunkOrder::new(0),
-            };
-
-            chunks.push(Arc::new(chunk) as _);
-        }
-
-        Ok(chunks)
-    }
-
-    fn clone_table_buffer(&self, database_name: &str, table_name: &str) -> Option<TableBuffer> {
-        let binding = self.buffered_data.read();
-        let table_buffer = binding.get(database_name)?.table_buffers.get(table_name)?;
-        Some(table_buffer.clone())
+        let object_store_url = self.persister.object_store_url();
+        let parquet_chunks = persisted_files
+            .into_iter()
+            .flat_map(|(segment_id, table_files)| {
+                let segment_sort_key = table_files.sort_key;
+                table_files.parquet_files.into_iter().map(move |file| {
+                    let sort_key = SortKey::from_columns(
+                        file.sort_key
+                            .clone()
+                            .unwrap_or_else(|| segment_sort_key.clone()),
+                    );
+                    (segment_id, sort_key, file)
+                })
+            })
+            // files that are entirely past the retention period are skipped until they get
+            // deleted, the rest is filtered when the query is executed
+            .filter(|(_, _, file)| !retention_time.is_some_and(|time| file.max_time <= time))
+            .map(|(segment_id, sort_key, file)| {
+                Arc::new(ParquetChunk::new(
+                    &file,
+                    segment_id,
+                    &schema,
+                    sort_key,
+                    object_store_url,
+                )) as Arc<dyn QueryChunk>
+            });
+
+        Ok(parquet_chunks.chain(buffer_chunks).collect())
     }
 }
 
@@ -185,25 +987,99 @@ impl<W: Wal> Bufferer for WriteBufferImpl<W> {
         database: NamespaceName<'static>,
         lp: &str,
         default_time: i64,
+        accept_partial: bool,
+        precision: Precision,
     ) -> Result<BufferedWriteRequest> {
-        self.write_lp(database, lp, default_time).await
+        self.write_lp(database, lp, default_time, accept_partial, precision)
+            .await
     }
 
     async fn close_open_segment(&self) -> crate::Result<Arc<dyn BufferSegment>> {
-        todo!()
+        let closed_segment = self
+            .segment_state
+            .write()
+            .close_open_segment(&self.catalog)?;
+        self.persist_segment_in_background(Arc::clone(&closed_segment));
+
+        Ok(closed_segment as Arc<dyn BufferSegment>)
     }
 
     async fn load_segments_after(
         &self,
-        _segment_id: SegmentId,
-        _catalog: Catalog,
+        segment_id: SegmentId,
+        catalog: Catalog,
     ) -> crate::Result<Vec<Arc<dyn BufferSegment>>> {
-        todo!()
+        let Some(wal) = &self.wal else {
+            return Ok(vec![]);
+        };
+
+        let segments = load_wal_segments(
+            wal.as_ref(),
+            Some(segment_id),
+            &catalog,
+            self.time_provider.now(),
+        )?;
+
+        Ok(segments
+            .into_iter()
+            .map(|segment| Arc::new(segment) as _)
+            .collect())
     }
 
     fn wal(&self) -> Option<Arc<impl Wal>> {
         self.wal.clone()
     }
+
+    fn catalog(&self) -> Arc<Catalog> {
+        Arc::clone(&self.catalog)
+    }
+
+    async fn persist_catalog(&self) -> crate::Result<()> {
+        let segment_id = self.segment_state.read().open_segment.segment_id();
+        self.persister
+            .persist_catalog(segment_id, Catalog::from_inner(self.catalog.clone_inner()))
+            .await
+    }
+
+    async fn create_database(
+        &self,
+        db_name: &str,
+        partition_template: Option<PartitionTemplate>,
+        retention_period_ns: Option<i64>,
+    ) -> crate::Result<()> {
+        self.catalog
+            .create_database(db_name, partition_template, retention_period_ns)
+            .map_err(Error::Catalog)?;
+        self.persist_catalog().await
+    }
+
+    async fn delete_database(&self, db_name: &str) -> crate::Result<()> {
+        self.delete(WalOp::DeleteDatabase(DeleteDatabaseOp {
+            db_name: db_name.to_string(),
+        }))
+        .await
+    }
+
+    async fn create_table(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        columns: BTreeMap<String, ColumnType>,
+        partition_template: Option<PartitionTemplate>,
+    ) -> crate::Result<()> {
+        self.catalog
+            .create_table(db_name, table_name, columns, partition_template)
+            .map_err(Error::Catalog)?;
+        self.persist_catalog().await
+    }
+
+    async fn delete_table(&self, db_name: &str, table_name: &str) -> crate::Result<()> {
+        self.delete(WalOp::DeleteTable(DeleteTableOp {
+            db_name: db_name.to_string(),
+            table_name: table_name.to_string(),
+        }))
+        .await
+    }
 }
 
 impl<W: Wal> ChunkContainer for WriteBufferImpl<W> {
@@ -219,130 +1095,104 @@ impl<W: Wal> ChunkContainer for WriteBufferImpl<W> {
     }
 }
 
-impl<W: Wal> WriteBuffer for WriteBufferImpl<W> {}
+#[async_trait]
+impl<W: Wal> LastCacheManager for WriteBufferImpl<W> {
+    fn last_cache_provider(&self) -> Arc<LastCacheProvider> {
+        Arc::clone(&self.last_cache_provider)
+    }
 
-#[derive(Debug, Default)]
-struct DatabaseBuffer {
-    table_buffers: HashMap<String, TableBuffer>,
-}
+    async fn create_last_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        key_columns: Option<Vec<String>>,
+        value_columns: Option<Vec<String>>,
+        count: Option<usize>,
+    ) -> crate::Result<LastCacheDefinition> {
+        let definition = self
+            .catalog
+            .create_last_cache(
+                db_name,
+                table_name,
+                cache_name,
+                key_columns,
+                value_columns,
+                count,
+            )
+            .map_err(Error::Catalog)?;
+        self.last_cache_provider
+            .create_cache(db_name, table_name, definition.clone());
+        self.persist_catalog().await?;
+        Ok(definition)
+    }
 
-#[derive(Debug, Default, Clone)]
-struct TableBuffer {
-    partition_buffers: HashMap<String, PartitionBuffer>,
+    async fn delete_last_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+    ) -> crate::Result<()> {
+        self.catalog
+            .delete_last_cache(db_name, table_name, cache_name)
+            .map_err(Error::Catalog)?;
+        self.last_cache_provider
+            .delete_cache(db_name, table_name, cache_name);
+        self.persist_catalog().await
+    }
 }
 
-#[derive(Debug, Default, Clone)]
-struct PartitionBuffer {
-    rows: Vec<Row>,
-    column_ranges: HashMap<Arc<str>, ColumnRange>,
-    timestamp_min: i64,
-    timestamp_max: i64,
-}
+#[async_trait]
+impl<W: Wal> DistinctCacheManager for WriteBufferImpl<W> {
+    fn distinct_cache_provider(&self) -> Arc<DistinctCacheProvider> {
+        Arc::clone(&self.distinct_cache_provider)
+    }
 
-impl PartitionBuffer {
-    fn rows_to_record_batch(
+    async fn create_distinct_cache(
         &self,
-        schema: &Schema,
-        column_types: &BTreeMap<String, i16>,
-    ) -> RecordBatch {
-        let row_count = self.rows.len();
-        let mut columns = BTreeMap::new();
-        for (name, column_type) in column_types {
-            match ColumnType::try_from(*column_type).unwrap() {
-                ColumnType::Bool => columns.insert(
-                    name,
-                    Builder::Bool(BooleanBuilder::with_capacity(row_count)),
-                ),
-                ColumnType::F64 => {
-                    columns.insert(name, Builder::F64(Float64Builder::with_capacity(row_count)))
-                }
-                ColumnType::I64 => {
-                    columns.insert(name, Builder::I64(Int64Builder::with_capacity(row_count)))
-                }
-                ColumnType::U64 => {
-                    columns.insert(name, Builder::U64(UInt64Builder::with_capacity(row_count)))
-                }
-                ColumnType::String => columns.insert(name, Builder::String(StringBuilder::new())),
-                ColumnType::Tag => {
-                    columns.insert(name, Builder::Tag(StringDictionaryBuilder::new()))
-                }
-                ColumnType::Time => columns.insert(
-                    name,
-                    Builder::Time(TimestampNanosecondBuilder::with_capacity(row_count)),
-                ),
-            };
-        }
-
-        for r in &self.rows {
-            let mut value_added = HashSet::with_capacity(r.fields.len());
-
-            for f in &r.fields {
-                let builder = columns.get_mut(&f.name).unwrap();
-                match (&f.value, builder) {
-                    (FieldData::Timestamp(v), Builder::Time(b)) => b.append_value(*v),
-                    (FieldData::Tag(v), Builder::Tag(b)) => {
-                        b.append(v).unwrap();
-                    }
-                    (FieldData::String(v), Builder::String(b)) => b.append_value(v),
-                    (FieldData::Integer(v), Builder::I64(b)) => b.append_value(*v),
-                    (FieldData::UInteger(v), Builder::U64(b)) => b.append_value(*v),
-                    (FieldData::Float(v), Builder::F64(b)) => b.append_value(*v),
-                    (FieldData::Boolean(v), Builder::Bool(b)) => b.append_value(*v),
-                    _ => panic!("unexpected field type"),
-                }
-                value_added.insert(&f.name);
-            }
-
-            for (name, builder) in &mut columns {
-                if !value_added.contains(name) {
-                    match builder {
-                        Builder::Bool(b) => b.append_null(),
-                        Builder::F64(b) => b.append_null(),
-                        Builder::I64(b) => b.append_null(),
-                        Builder::U64(b) => b.append_null(),
-                        Builder::String(b) => b.append_null(),
-                        Builder::Tag(b) => b.append_null(),
-                        Builder::Time(b) => b.append_null(),
-                    }
-                }
-            }
-        }
-
-        // ensure the order of the columns matches their order in the Arrow schema definition
-        let mut cols = Vec::with_capacity(columns.len());
-        let schema = schema.as_arrow();
-        for f in &schema.fields {
-            cols.push(columns.remove(f.name()).unwrap().into_arrow());
-        }
-
-        RecordBatch::try_new(schema, cols).unwrap()
+        db_name: &str,
+        table_name: &str,
+        cache_name: Option<&str>,
+        columns: Vec<String>,
+        max_cardinality: Option<usize>,
+        max_age_seconds: Option<u64>,
+    ) -> crate::Result<DistinctCacheDefinition> {
+        let definition = self
+            .catalog
+            .create_distinct_cache(
+                db_name,
+                table_name,
+                cache_name,
+                columns,
+                max_cardinality,
+                max_age_seconds,
+            )
+            .map_err(Error::Catalog)?;
+        self.distinct_cache_provider
+            .create_cache(db_name, table_name, definition.clone());
+        self.persist_catalog().await?;
+        self.seed_distinct_cache(db_name, table_name, &definition)
+            .await;
+        Ok(definition)
     }
-}
-
-enum Builder {
-    Bool(BooleanBuilder),
-    I64(Int64Builder),
-    F64(Float64Builder),
-    U64(UInt64Builder),
-    String(StringBuilder),
-    Tag(StringDictionaryBuilder<Int32Type>),
-    Time(TimestampNanosecondBuilder),
-}
 
-impl Builder {
-    fn into_arrow(self) -> ArrayRef {
-        match self {
-            Self::Bool(mut b) => Arc::new(b.finish()),
-            Self::I64(mut b) => Arc::new(b.finish()),
-            Self::F64(mut b) => Arc::new(b.finish()),
-            Self::U64(mut b) => Arc::new(b.finish()),
-            Self::String(mut b) => Arc::new(b.finish()),
-            Self::Tag(mut b) => Arc::new(b.finish()),
-            Self::Time(mut b) => Arc::new(b.finish()),
-        }
+    async fn delete_distinct_cache(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        cache_name: &str,
+    ) -> crate::Result<()> {
+        self.catalog
+            .delete_distinct_cache(db_name, table_name, cache_name)
+            .map_err(Error::Catalog)?;
+        self.distinct_cache_provider
+            .delete_cache(db_name, table_name, cache_name);
+        self.persist_catalog().await
     }
 }
 
+impl<W: Wal> WriteBuffer for WriteBufferImpl<W> {}
+
 #[derive(Debug)]
 pub struct BufferChunk {
     batches: Vec<RecordBatch>,
@@ -381,7 +1231,9 @@ impl QueryChunk for BufferChunk {
     }
 
     fn may_contain_pk_duplicates(&self) -> bool {
-        false
+        // every write is appended to the partition buffer, so a series can have several rows at
+        // the same time within a segment
+        true
     }
 
     fn data(&self) -> QueryChunkData {
@@ -402,86 +1254,331 @@ impl QueryChunk for BufferChunk {
         self
     }
 }
+/// A parquet file persisted from a segment.
+#[derive(Debug)]
+pub struct ParquetChunk {
+    parquet_exec: ParquetExecInput,
+    schema: Schema,
+    stats: Arc<Statistics>,
+    partition_id: TransitionPartitionId,
+    sort_key: Option<SortKey>,
+    id: ChunkId,
+    chunk_order: ChunkOrder,
+}
+
+impl ParquetChunk {
+    /// Creates a chunk for the parquet file, which is read from the object store registered in
+    /// the query executor under the given URL. The chunk is ordered by the segment it was
+    /// persisted in, like the chunks of buffered data, so deduplication keeps the newest rows.
+    /// Migrated files are ordered before every segment, as their rows are older than any written
+    /// to the server even though their segments come after those that were persisted before the
+    /// migration.
+    /// The statistics come from the row count and time range recorded when the file was
+    /// persisted, which lets the query planner prune files outside of a query's time range.
+    fn new(
+        file: &ParquetFile,
+        segment_id: SegmentId,
+        schema: &Schema,
+        sort_key: SortKey,
+        object_store_url: &ObjectStoreUrl,
+    ) -> Self {
+        let partition_key = PartitionKey::from(parquet_file_partition_key(file));
+        let stats = create_chunk_statistics(
+            Some(file.row_count as usize),
+            schema,
+            Some(TimestampMinMax {
+                min: file.min_time,
+                max: file.max_time,
+            }),
+            None,
+        );
+
+        Self {
+            parquet_exec: ParquetExecInput {
+                object_store_url: object_store_url.clone(),
+                object_meta: ObjectMeta {
+                    // the path was already encoded when the file was persisted
+                    location: ObjPath::parse(&file.path).expect("parquet file paths are valid"),
+                    // the last modified time isn't used when reading the file
+                    last_modified: Default::default(),
+                    size: file.size_bytes as usize,
+                    e_tag: None,
+                    version: None,
+                },
+            },
+            schema: schema.clone(),
+            stats: Arc::new(stats),
+            partition_id: TransitionPartitionId::new(TableId::new(0), &partition_key),
+            sort_key: Some(sort_key),
+            id: ChunkId::new(),
+            chunk_order: parquet_chunk_order(file, segment_id),
+        }
+    }
+}
+
+/// Returns the order of the chunk of a parquet file. The orders of migrated files are negative,
+/// below the order of every segment, and keep the order of their segments among themselves.
+fn parquet_chunk_order(file: &ParquetFile, segment_id: SegmentId) -> ChunkOrder {
+    let order = segment_id.0 as i64;
+    if file.migrated {
+        ChunkOrder::new(order - (1 << 32))
+    } else {
+        ChunkOrder::new(order)
+    }
+}
+
+impl QueryChunk for ParquetChunk {
+    fn stats(&self) -> Arc<Statistics> {
+        Arc::clone(&self.stats)
+    }
+
+    fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    fn partition_id(&self) -> &TransitionPartitionId {
+        &self.partition_id
+    }
+
+    fn sort_key(&self) -> Option<&SortKey> {
+        self.sort_key.as_ref()
+    }
+
+    fn id(&self) -> ChunkId {
+        self.id
+    }
+
+    fn may_contain_pk_duplicates(&self) -> bool {
+        true
+    }
+
+    fn data(&self) -> QueryChunkData {
+        QueryChunkData::Parquet(self.parquet_exec.clone())
+    }
+
+    fn chunk_type(&self) -> &str {
+        "ParquetChunk"
+    }
+
+    fn order(&self) -> ChunkOrder {
+        self.chunk_order
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
 const YEAR_MONTH_DAY_TIME_FORMAT: &str = "%Y-%m-%d";
 
+/// Converts the rows written to the tables that `is_cached` is true for into record batches, so
+/// that they can be added to the caches of those tables once the write has been buffered.
+pub(crate) fn batches_to_cache(
+    table_batches: &HashMap<String, TableBatch>,
+    is_cached: impl Fn(&str) -> bool,
+) -> Vec<(String, RecordBatch)> {
+    table_batches
+        .iter()
+        .filter(|(table_name, _)| is_cached(table_name))
+        .flat_map(|(table_name, table_batch)| {
+            table_batch
+                .partition_batches
+                .values()
+                .filter_map(|partition_batch| {
+                    match partition_batch.batch.to_arrow(Projection::All) {
+                        Ok(batch) => Some((table_name.clone(), batch)),
+                        Err(error) => {
+                            error!(%error, %table_name, "error converting write for caches");
+                            None
+                        }
+                    }
+                })
+        })
+        .collect()
+}
+
+/// The key of the partition the rows in the parquet file are from. Files persisted before
+/// partition templates were configurable don't record it, and were partitioned by day.
+fn parquet_file_partition_key(file: &ParquetFile) -> String {
+    if file.partition_key.is_empty() {
+        Utc.timestamp_nanos(file.min_time)
+            .format(YEAR_MONTH_DAY_TIME_FORMAT)
+            .to_string()
+    } else {
+        file.partition_key.clone()
+    }
+}
+
 /// Takes &str of line protocol, parses lines, validates the schema, and inserts new columns
-/// and partitions if present. Assigns the default time to any lines that do not include a time
+/// and partitions if present. Timestamps are converted from `precision` to nanoseconds and the
+/// default time, truncated to `precision`, is assigned to any lines that do not include a time.
+///
+/// Lines that fail to parse or validate are returned as errors in the result if
+/// `accept_partial` is set, and the rest of the lines are still buffered. Otherwise any invalid
+/// line fails the whole write, with the errors for every invalid line.
 pub(crate) fn parse_validate_and_update_schema(
     lp: &str,
     schema: &DatabaseSchema,
-    partitioner: &Partitioner,
     default_time: i64,
+    accept_partial: bool,
+    precision: Precision,
 ) -> Result<ValidationResult> {
+    let default_time = precision.truncate_nanos(default_time);
+    let mut errors = vec![];
     let mut lines = vec![];
-    for (line_idx, maybe_line) in parse_lines(lp).enumerate() {
-        let line = maybe_line.map_err(|e| Error::ParseError {
-            line_number: line_idx + 1,
-            message: e.to_string(),
-        })?;
-        lines.push(line);
+    for (line_idx, line) in split_lines(lp).enumerate() {
+        // blank lines are skipped by the parser
+        let Some(maybe_line) = parse_split_line(line) else {
+            continue;
+        };
+        match maybe_line {
+            Ok(parsed_line) => lines.push((line_idx + 1, line, parsed_line)),
+            Err(e) => errors.push(WriteLineError::from(&Diagnostic::from_parse_error(
+                line_idx + 1,
+                line,
+                e,
+            ))),
+        }
     }
 
-    validate_or_insert_schema_and_partitions(lines, schema, partitioner, default_time)
+    let result =
+        validate_or_insert_schema_and_partitions(lines, schema, default_time, precision, errors);
+
+    if !accept_partial && !result.errors.is_empty() {
+        return Err(Error::ParseError(result.errors));
+    }
+
+    Ok(result)
 }
 
 /// Takes parsed lines, validates their schema. If new tables or columns are defined, they
 /// are passed back as a new DatabaseSchema as part of the ValidationResult. Lines are split
 /// into partitions and the validation result contains the data that can then be serialized
-/// into the WAL.
-pub(crate) fn validate_or_insert_schema_and_partitions(
-    lines: Vec<ParsedLine<'_>>,
+/// into the WAL. Lines that fail validation are added to the errors, in line order, and
+/// don't change the schema.
+fn validate_or_insert_schema_and_partitions(
+    lines: Vec<(usize, &str, ParsedLine<'_>)>,
     schema: &DatabaseSchema,
-    partitioner: &Partitioner,
     default_time: i64,
-) -> Result<ValidationResult> {
+    precision: Precision,
+    mut errors: Vec<WriteLineError>,
+) -> ValidationResult {
     // The (potentially updated) DatabaseSchema to return to the caller.
     let mut schema = Cow::Borrowed(schema);
 
     // The parsed and validated table_batches
     let mut table_batches: HashMap<String, TableBatch> = HashMap::new();
 
-    let line_count = lines.len();
+    let mut valid_lines = Vec::with_capacity(lines.len());
     let mut field_count = 0;
     let mut tag_count = 0;
 
-    for line in lines.into_iter() {
-        field_count += line.field_set.len();
-        tag_count += line.series.tag_set.as_ref().map(|t| t.len()).unwrap_or(0);
+    for (line_number, original_line, line) in lines.into_iter() {
+        let line_field_count = line.field_set.len();
+        let line_tag_count = line.series.tag_set.as_ref().map(|t| t.len()).unwrap_or(0);
 
-        validate_and_convert_parsed_line(
+        match validate_and_convert_parsed_line(
             line,
             &mut table_batches,
             &mut schema,
-            partitioner,
             default_time,
-        )?;
+            precision,
+        ) {
+            Ok(()) => {
+                field_count += line_field_count;
+                tag_count += line_tag_count;
+                valid_lines.push(original_line);
+            }
+            Err(e) => errors.push(WriteLineError {
+                original_line: original_line.to_string(),
+                line_number,
+                error_message: e.to_string(),
+                column: None,
+                token: None,
+            }),
+        }
+    }
+    errors.sort_by_key(|e| e.line_number);
+
+    // rejected lines are rolled back out of their batches, which can leave some of them empty
+    for table_batch in table_batches.values_mut() {
+        table_batch
+            .partition_batches
+            .retain(|_, partition_batch| partition_batch.batch.rows() > 0);
     }
+    table_batches.retain(|_, table_batch| !table_batch.partition_batches.is_empty());
 
     let schema = match schema {
         Cow::Owned(s) => Some(s),
         Cow::Borrowed(_) => None,
     };
 
-    Ok(ValidationResult {
+    ValidationResult {
         schema,
         table_batches,
-        line_count,
+        line_count: valid_lines.len(),
         field_count,
         tag_count,
-    })
+        valid_lp: (!errors.is_empty()).then(|| valid_lines.join("\n")),
+        errors,
+    }
 }
 
 // &mut Cow is used to avoid a copy, so allow it
 #[allow(clippy::ptr_arg)]
 fn validate_and_convert_parsed_line(
-    line: ParsedLine<'_>,
+    mut line: ParsedLine<'_>,
     table_batches: &mut HashMap<String, TableBatch>,
     schema: &mut Cow<'_, DatabaseSchema>,
-    partitioner: &Partitioner,
     default_time: i64,
+    precision: Precision,
 ) -> Result<()> {
     let table_name = line.series.measurement.as_str();
 
+    // convert the time before anything is added to the schema so out of range lines don't
+    // change it
+    let time_value = match line.timestamp {
+        Some(timestamp) => precision
+            .to_nanos(timestamp)
+            .ok_or(Error::TimestampOutOfRange {
+                timestamp,
+                precision,
+            })?,
+        None => default_time,
+    };
+
+    // the partition key is generated before anything is added to the schema so lines that
+    // can't be partitioned don't change it
+    let partition_template = match schema.tables.get(table_name) {
+        Some(t) => t.partition_template(),
+        None => schema.partition_template(),
+    };
+    let partition_key = partition_key_for_line(&line, time_value, partition_template)?;
+
+    // Validate the types of existing columns before anything is added to the schema
+    if let Some(t) = schema.tables.get(table_name) {
+        if let Some(tagset) = &line.series.tag_set {
+            for (tag_key, _) in tagset {
+                validate_column_type(t, tag_key.as_str(), ColumnType::Tag)?;
+            }
+        }
+        for (field_name, value) in &line.field_set {
+            validate_column_type(t, field_name.as_str(), column_type_from_field(value))?;
+        }
+    }
+
+    // the line is written into the columns of its partition's batch up front, which also rejects
+    // lines with conflicting duplicate tags or fields. The write is rolled back if the writer is
+    // dropped without being committed, so it is only committed once the line has been validated.
+    line.timestamp = Some(time_value);
+    let table_batch = table_batches.entry(table_name.to_string()).or_default();
+    let partition_batch = table_batch
+        .partition_batches
+        .entry(partition_key)
+        .or_default();
+    let mut writer = Writer::new(&mut partition_batch.batch, 1);
+    write_line(&mut writer, &line, time_value)?;
+
     // Check if the table exists in the schema.
     //
     // Because the entry API requires &mut it is not used to avoid a premature
@@ -521,7 +1618,8 @@ fn validate_and_convert_parsed_line(
 
             columns.insert(TIME_COLUMN.to_string(), ColumnType::Time as i16);
 
-            let table = TableDefinition::new(table_name, columns);
+            let table =
+                TableDefinition::new(table_name, columns, schema.partition_template().clone());
 
             assert!(schema
                 .to_mut()
@@ -531,62 +1629,28 @@ fn validate_and_convert_parsed_line(
         }
     };
 
-    let partition_key = partitioner.partition_key_for_line(&line, default_time);
-
-    // now that we've ensured all columns exist in the schema, construct the actual row and values
-    // while validating the column types match.
-    let mut values = Vec::with_capacity(line.column_count() + 1);
+    writer.commit();
 
-    // validate tags, collecting any new ones that must be inserted, or adding the values
-    if let Some(tagset) = line.series.tag_set {
-        for (tag_key, value) in tagset {
-            let value = Field {
-                name: tag_key.to_string(),
-                value: FieldData::Tag(value.to_string()),
-            };
-            values.push(value);
-        }
-    }
+    Ok(())
+}
 
-    // validate fields, collecting any new ones that must be inserted, or adding values
-    for (field_name, value) in line.field_set {
-        let field_data = match value {
-            FieldValue::I64(v) => FieldData::Integer(v),
-            FieldValue::F64(v) => FieldData::Float(v),
-            FieldValue::U64(v) => FieldData::UInteger(v),
-            FieldValue::Boolean(v) => FieldData::Boolean(v),
-            FieldValue::String(v) => FieldData::String(v.to_string()),
-        };
-        let value = Field {
-            name: field_name.to_string(),
-            value: field_data,
-        };
-        values.push(value);
+/// Returns an error if the column exists in the table with a different type.
+fn validate_column_type(
+    table: &TableDefinition,
+    column_name: &str,
+    column_type: ColumnType,
+) -> Result<()> {
+    match table.columns().get(column_name) {
+        Some(existing) if *existing != column_type as i16 => Err(Error::ColumnTypeMismatch {
+            name: column_name.to_string(),
+            existing: ColumnType::try_from(*existing).expect("valid column type in catalog"),
+            new: column_type,
+        }),
+        _ => Ok(()),
     }
-
-    // set the time value
-    let time_value = line.timestamp.unwrap_or(default_time);
-    values.push(Field {
-        name: TIME_COLUMN.to_string(),
-        value: FieldData::Timestamp(time_value),
-    });
-
-    let table_batch = table_batches.entry(table_name.to_string()).or_default();
-    let partition_batch = table_batch
-        .partition_batches
-        .entry(partition_key)
-        .or_default();
-
-    // insert the row into the partition batch
-    partition_batch.rows.push(Row {
-        time: time_value,
-        fields: values,
-    });
-
-    Ok(())
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default)]
 pub(crate) struct TableBatch {
     #[allow(dead_code)]
     pub(crate) name: String,
@@ -594,32 +1658,10 @@ pub(crate) struct TableBatch {
     pub(crate) partition_batches: HashMap<String, PartitionBatch>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// The validated rows of a write for a single partition of a table, in columnar form.
+#[derive(Debug, Default)]
 pub(crate) struct PartitionBatch {
-    pub(crate) rows: Vec<Row>,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct Row {
-    pub(crate) time: i64,
-    pub(crate) fields: Vec<Field>,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct Field {
-    pub(crate) name: String,
-    pub(crate) value: FieldData,
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) enum FieldData {
-    Timestamp(i64),
-    Tag(String),
-    String(String),
-    Integer(i64),
-    UInteger(u64),
-    Float(f64),
-    Boolean(bool),
+    pub(crate) batch: MutableBatch,
 }
 
 /// Result of the validation. If the NamespaceSchema or PartitionMap were updated, they will be
@@ -632,54 +1674,138 @@ pub(crate) struct ValidationResult {
     pub(crate) schema: Option<DatabaseSchema>,
     /// Map of table name to TableBatch
     pub(crate) table_batches: HashMap<String, TableBatch>,
-    /// Number of lines passed in
+    /// Number of valid lines passed in
     pub(crate) line_count: usize,
-    /// Number of fields passed in
+    /// Number of fields passed in on valid lines
     pub(crate) field_count: usize,
-    /// Number of tags passed in
+    /// Number of tags passed in on valid lines
     pub(crate) tag_count: usize,
+    /// The lines that failed to parse or validate
+    pub(crate) errors: Vec<WriteLineError>,
+    /// The line protocol of only the valid lines, if any lines had errors
+    pub(crate) valid_lp: Option<String>,
+}
+
+/// Generates the partition key of a line from the partition template of its table, the same
+/// way that keys are generated for batches of rows.
+fn partition_key_for_line(
+    line: &ParsedLine<'_>,
+    time: i64,
+    template: &TablePartitionTemplateOverride,
+) -> Result<String> {
+    let batch = LineBatch::new(line, time);
+    let (key, _) = partition_batch(&batch, template)
+        .next()
+        .expect("a batch with one row has a partition key");
+
+    Ok(key?)
 }
 
-/// Generates the partition key for a given line or row
+/// A line of line protocol as a batch with a single row, so it can be partitioned.
 #[derive(Debug)]
-pub struct Partitioner {
-    time_format: String,
+struct LineBatch<'a> {
+    columns: HashMap<&'a str, LineColumn<'a>>,
+    time: [i64; 1],
 }
 
-impl Partitioner {
-    /// Create a new time based partitioner using the time format
-    pub fn new_time_partitioner(time_format: impl Into<String>) -> Self {
+impl<'a> LineBatch<'a> {
+    fn new(line: &'a ParsedLine<'_>, time: i64) -> Self {
+        let tags = line.series.tag_set.iter().flatten().map(|(key, value)| {
+            (
+                key.as_str(),
+                LineColumn {
+                    tag_value: Some(value.as_str()),
+                    column_type: ColumnType::Tag,
+                },
+            )
+        });
+        let fields = line.field_set.iter().map(|(key, value)| {
+            (
+                key.as_str(),
+                LineColumn {
+                    tag_value: None,
+                    column_type: column_type_from_field(value),
+                },
+            )
+        });
+
         Self {
-            time_format: time_format.into(),
+            columns: tags.chain(fields).collect(),
+            time: [time],
         }
     }
+}
 
-    /// Create a new time based partitioner that partitions by day
-    pub fn new_per_day_partitioner() -> Self {
-        Self::new_time_partitioner(YEAR_MONTH_DAY_TIME_FORMAT)
+impl<'a> Batch for LineBatch<'a> {
+    type Column = LineColumn<'a>;
+
+    fn num_rows(&self) -> usize {
+        1
     }
 
-    /// Given a parsed line and a default time, generate the string partition key
-    pub fn partition_key_for_line(&self, line: &ParsedLine<'_>, default_time: i64) -> String {
-        let timestamp = line.timestamp.unwrap_or(default_time);
-        format!(
-            "{}",
-            Utc.timestamp_nanos(timestamp).format(&self.time_format)
-        )
+    fn column(&self, column: &str) -> Option<&Self::Column> {
+        self.columns.get(column)
+    }
+
+    fn time_column(&self) -> Result<&[i64], TimeColumnError> {
+        Ok(&self.time)
+    }
+}
+
+/// A column in a [`LineBatch`]. Only tags have a value, as only tags can be partitioned by.
+#[derive(Debug)]
+struct LineColumn<'a> {
+    tag_value: Option<&'a str>,
+    column_type: ColumnType,
+}
+
+impl<'a> PartitioningColumn for LineColumn<'a> {
+    type TagIdentityKey = str;
+
+    fn is_valid(&self, _idx: usize) -> bool {
+        true
+    }
+
+    fn valid_bytes(&self) -> &[u8] {
+        &[1]
+    }
+
+    fn get_tag_identity_key(&self, _idx: usize) -> Option<&Self::TagIdentityKey> {
+        self.tag_value
+    }
+
+    fn get_tag_value<'b>(&'b self, tag_identity_key: &'b Self::TagIdentityKey) -> Option<&'b str> {
+        Some(tag_identity_key)
+    }
+
+    fn type_description(&self) -> String {
+        self.column_type.to_string()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::persister::{PersisterImpl, PARQUET_STORAGE_ID};
+    use crate::wal::WalImpl;
+    use arrow::util::pretty::pretty_format_batches;
+    use datafusion::prelude::SessionContext;
+    use generated_types::influxdata::iox::partition_template::v1::{template_part, TemplatePart};
+    use iox_query::exec::{Executor, ExecutorConfig};
+    use iox_time::{MockProvider, Time};
+    use mutable_batch::column::ColumnData;
+    use object_store::memory::InMemory;
+    use object_store::ObjectStore;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet_file::storage::StorageId;
     use std::sync::Arc;
 
     #[test]
     fn parse_lp_into_buffer() {
         let db = Arc::new(DatabaseSchema::new("foo"));
-        let partitioner = Partitioner::new_per_day_partitioner();
         let lp = "cpu,region=west user=23.2 100\nfoo f1=1i";
-        let result = parse_validate_and_update_schema(lp, &db, &partitioner, 0).unwrap();
+        let result =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond).unwrap();
 
         println!("result: {:#?}", result);
         let db = result.schema.unwrap();
@@ -688,4 +1814,862 @@ mod tests {
         assert_eq!(db.tables.get("cpu").unwrap().columns().len(), 3);
         assert_eq!(db.tables.get("foo").unwrap().columns().len(), 2);
     }
+
+    #[test]
+    fn parse_lp_with_invalid_lines() {
+        let db = Arc::new(DatabaseSchema::new("foo"));
+        let lp = "cpu,host=a val=1i 10\nnot_valid\ncpu,host=b val=1.5 20\n\ncpu val=2i 30";
+
+        let result =
+            parse_validate_and_update_schema(lp, &db, 0, true, Precision::Nanosecond).unwrap();
+        assert_eq!(result.line_count, 2);
+        assert_eq!(
+            result.valid_lp.as_deref(),
+            Some("cpu,host=a val=1i 10\ncpu val=2i 30")
+        );
+        let error_lines: Vec<_> = result
+            .errors
+            .iter()
+            .map(|e| (e.line_number, e.original_line.as_str()))
+            .collect();
+        assert_eq!(
+            error_lines,
+            vec![(2, "not_valid"), (3, "cpu,host=b val=1.5 20")]
+        );
+        // parse errors have the position of the invalid part of the line
+        assert_eq!(result.errors[0].column, Some(10));
+        assert_eq!(result.errors[0].token.as_deref(), Some(""));
+        assert_eq!(result.errors[1].column, None);
+        // the rejected line doesn't change the type of the column
+        let table = result.schema.unwrap().tables.remove("cpu").unwrap();
+        assert_eq!(table.columns()["val"], ColumnType::I64 as i16);
+
+        let Err(Error::ParseError(errors)) =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond)
+        else {
+            panic!("expected the strict write to fail");
+        };
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn parse_lp_into_partitions_of_template() {
+        let catalog = Catalog::new();
+        let template = PartitionTemplate {
+            parts: vec![
+                TemplatePart {
+                    part: Some(template_part::Part::TimeFormat("%Y-%m-%d %H".to_string())),
+                },
+                TemplatePart {
+                    part: Some(template_part::Part::TagValue("region".to_string())),
+                },
+            ],
+        };
+        catalog
+            .create_database("foo", Some(template), None)
+            .unwrap();
+        let db = catalog.db_schema("foo").unwrap();
+
+        let lp = "cpu,region=west val=1i 0\ncpu,region=east val=2i 3600000000000\ncpu val=3i 0\ncpu,region=west val=4i 60000000000";
+        let result =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond).unwrap();
+        let mut keys: Vec<_> = result.table_batches["cpu"]
+            .partition_batches
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "1970-01-01 00|!",
+                "1970-01-01 00|west",
+                "1970-01-01 01|east"
+            ]
+        );
+
+        // a field can't be partitioned by like a tag, so the line is rejected
+        let result = parse_validate_and_update_schema(
+            "mem region=1i 0",
+            &db,
+            0,
+            true,
+            Precision::Nanosecond,
+        )
+        .unwrap();
+        assert_eq!(result.errors.len(), 1);
+        assert!(result.schema.is_none());
+    }
+
+    #[test]
+    fn parse_lp_with_precision() {
+        let db = Arc::new(DatabaseSchema::new("foo"));
+        let default_time = 1_700_000_000_123_456_789;
+        let row_times = |result: &ValidationResult| -> Vec<i64> {
+            let mut times: Vec<_> = result.table_batches["cpu"]
+                .partition_batches
+                .values()
+                .flat_map(|partition_batch| {
+                    match partition_batch.batch.column(TIME_COLUMN).unwrap().data() {
+                        ColumnData::I64(times, _) => times.clone(),
+                        _ => panic!("time column should be i64"),
+                    }
+                })
+                .collect();
+            times.sort();
+            times
+        };
+
+        // the default time is truncated to the precision of the write
+        let lp = "cpu val=1i 1700000001\ncpu val=2i";
+        let result =
+            parse_validate_and_update_schema(lp, &db, default_time, false, Precision::Second)
+                .unwrap();
+        assert_eq!(
+            row_times(&result),
+            vec![1_700_000_000_000_000_000, 1_700_000_001_000_000_000]
+        );
+
+        // the precision of each line is guessed from the magnitude of its timestamp
+        let lp = "cpu val=1i 1700000000\ncpu val=2i 1700000000001\ncpu val=3i 1700000000000002\ncpu val=4i 1700000000000000003";
+        let result =
+            parse_validate_and_update_schema(lp, &db, default_time, false, Precision::Auto)
+                .unwrap();
+        assert_eq!(
+            row_times(&result),
+            vec![
+                1_700_000_000_000_000_000,
+                1_700_000_000_000_000_003,
+                1_700_000_000_000_002_000,
+                1_700_000_000_001_000_000,
+            ]
+        );
+
+        // timestamps that overflow when converted to nanoseconds are rejected
+        let lp = "cpu val=1i 10\ncpu val=2i 9223372036854775807";
+        let result =
+            parse_validate_and_update_schema(lp, &db, default_time, true, Precision::Millisecond)
+                .unwrap();
+        assert_eq!(row_times(&result), vec![10_000_000]);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].line_number, 2);
+
+        assert_eq!(
+            Precision::Millisecond.timestamp_range(),
+            -9_223_372_036_854..=9_223_372_036_854
+        );
+        assert_eq!(
+            Precision::Auto.timestamp_range(),
+            Precision::Nanosecond.timestamp_range()
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_segment_when_size_exceeded() {
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_config = SegmentConfig {
+            max_size_bytes: 30,
+            max_duration: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            time_provider,
+            segment_config,
+        )
+        .await
+        .unwrap();
+        let db_name = NamespaceName::new("foo").unwrap();
+
+        let result = write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=1.0 10",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(0));
+
+        // this write pushes the open segment past the size limit, closing it
+        let result = write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=2.0 20",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(0));
+
+        let result = write_buffer
+            .write_lp(
+                db_name,
+                "cpu,host=a usage=3.0 30",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(1));
+
+        // wait for the closed segment to be persisted and dropped from the buffer
+        wait_for_persisted_segments(&write_buffer).await;
+
+        let segments = persister.load_segments(1).await.unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].segment_id, SegmentId::new(0));
+        assert_eq!(segments[0].segment_row_count, 2);
+    }
+
+    #[tokio::test]
+    async fn closes_segment_when_duration_exceeded() {
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            persister,
+            None::<Arc<WalImpl>>,
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        let db_name = NamespaceName::new("foo").unwrap();
+
+        let result = write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=1.0 10",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(0));
+
+        write_buffer.close_open_segment_if_needed();
+        assert_eq!(
+            write_buffer.segment_state.read().open_segment.segment_id(),
+            SegmentId::new(0)
+        );
+
+        time_provider.inc(SegmentConfig::default().max_duration);
+        write_buffer.close_open_segment_if_needed();
+        assert_eq!(
+            write_buffer.segment_state.read().open_segment.segment_id(),
+            SegmentId::new(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn deletes_data_past_retention_period() {
+        let object_store = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store) as _));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_duration = SegmentConfig::default().max_duration;
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        write_buffer
+            .create_database("foo", None, Some(segment_duration.as_nanos() as i64))
+            .await
+            .unwrap();
+
+        // each write goes into its own segment, which is persisted once it is old enough
+        for lp in ["cpu val=1i 0", "cpu val=2i 7200000000000"] {
+            write_buffer
+                .write_lp(
+                    NamespaceName::new("foo").unwrap(),
+                    lp,
+                    0,
+                    false,
+                    Precision::Nanosecond,
+                )
+                .await
+                .unwrap();
+            time_provider.inc(segment_duration);
+            write_buffer.close_open_segment_if_needed();
+            wait_for_persisted_segments(&write_buffer).await;
+        }
+        let expired_file = persister
+            .load_segment(SegmentId::new(0))
+            .await
+            .unwrap()
+            .unwrap()
+            .databases["foo"]
+            .tables["cpu"]
+            .parquet_files[0]
+            .path
+            .clone();
+
+        // only the data of the first segment is older than an hour
+        write_buffer.enforce_retention().await.unwrap();
+
+        let persisted_segment_ids: Vec<_> = write_buffer.segment_state.read().persisted_files
+            ["foo"]["cpu"]
+            .iter()
+            .map(|(segment_id, _)| *segment_id)
+            .collect();
+        assert_eq!(persisted_segment_ids, vec![SegmentId::new(1)]);
+        assert!(persister
+            .load_segment(SegmentId::new(0))
+            .await
+            .unwrap()
+            .is_none());
+        let segment = persister
+            .load_segment(SegmentId::new(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(segment.segment_row_count, 1);
+        let location = ObjPath::parse(&expired_file).unwrap();
+        assert!(matches!(
+            object_store.head(&location).await,
+            Err(object_store::Error::NotFound { .. })
+        ));
+
+        // once everything has expired the most recent segment keeps its empty file list
+        time_provider.inc(segment_duration * 2);
+        write_buffer.enforce_retention().await.unwrap();
+        assert!(write_buffer.segment_state.read().persisted_files.is_empty());
+        let segment = persister
+            .load_segment(SegmentId::new(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(segment.databases.is_empty());
+        assert_eq!(segment.segment_row_count, 0);
+    }
+
+    #[tokio::test]
+    async fn sheds_writes_when_buffer_memory_limit_reached() {
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_config = SegmentConfig {
+            max_buffer_memory_bytes: 1,
+            ..Default::default()
+        };
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            time_provider,
+            segment_config,
+        )
+        .await
+        .unwrap();
+        let db_name = NamespaceName::new("foo").unwrap();
+
+        // the first write fits, and pushes the buffer past its limit, which closes the segment
+        // early so that its memory is freed once it has been persisted
+        let result = write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=1.0 10",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(0));
+        let used_bytes = result.total_buffer_memory_used;
+        assert!(used_bytes > 0);
+        assert_eq!(
+            write_buffer.segment_state.read().buffer_size_bytes(),
+            used_bytes
+        );
+        assert_eq!(
+            write_buffer.segment_state.read().open_segment.segment_id(),
+            SegmentId::new(1)
+        );
+
+        // writes are shed until the closed segment has been persisted
+        let err = write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=2.0 20",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::BufferFull {
+                used_bytes: u,
+                limit_bytes: 1,
+            } if u == used_bytes
+        ));
+
+        wait_for_persisted_segments(&write_buffer).await;
+        assert_eq!(write_buffer.segment_state.read().buffer_size_bytes(), 0);
+
+        let result = write_buffer
+            .write_lp(
+                db_name,
+                "cpu,host=a usage=3.0 30",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(1));
+
+        wait_for_persisted_segments(&write_buffer).await;
+        let segments = persister.load_segments(2).await.unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].segment_id, SegmentId::new(1));
+        assert_eq!(segments[0].segment_row_count, 1);
+        assert_eq!(segments[1].segment_id, SegmentId::new(0));
+        assert_eq!(segments[1].segment_row_count, 1);
+    }
+
+    #[tokio::test]
+    async fn replays_wal_on_startup() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = Arc::new(WalImpl::new(dir).unwrap());
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            Some(Arc::clone(&wal)),
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        let result = write_buffer
+            .write_lp(
+                NamespaceName::new("foo").unwrap(),
+                "cpu,host=a usage=1.0 10\ncpu,host=b usage=2.0 20",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(0));
+        drop(write_buffer);
+
+        // a new buffer over the same wal replays the segment and persists it
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            Some(Arc::clone(&wal)),
+            time_provider,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            write_buffer.segment_state.read().open_segment.segment_id(),
+            SegmentId::new(1)
+        );
+        assert!(write_buffer
+            .catalog()
+            .db_schema("foo")
+            .unwrap()
+            .table_exists("cpu"));
+
+        wait_for_persisted_segments(&write_buffer).await;
+
+        let segments = persister.load_segments(1).await.unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].segment_id, SegmentId::new(0));
+        assert_eq!(segments[0].segment_row_count, 2);
+
+        let wal_segments: Vec<_> = wal
+            .segment_files()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.segment_id)
+            .collect();
+        assert_eq!(wal_segments, vec![SegmentId::new(1)]);
+    }
+
+    #[tokio::test]
+    async fn loads_persisted_segments_on_startup() {
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let db_name = NamespaceName::new("foo").unwrap();
+
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=1.0 10",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        write_buffer.close_open_segment().await.unwrap();
+        wait_for_persisted_segments(&write_buffer).await;
+        drop(write_buffer);
+
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            time_provider,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert!(write_buffer
+            .catalog()
+            .db_schema("foo")
+            .unwrap()
+            .table_exists("cpu"));
+
+        // new writes go into the segment after the persisted one
+        let result = write_buffer
+            .write_lp(
+                db_name,
+                "cpu,host=b usage=2.0 20",
+                0,
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.segment_id, SegmentId::new(1));
+
+        let ctx = SessionContext::new();
+        let chunks = write_buffer
+            .get_table_chunks("foo", "cpu", &[], None, &ctx.state())
+            .unwrap();
+        let mut chunk_types: Vec<_> = chunks.iter().map(|c| c.chunk_type()).collect();
+        chunk_types.sort_unstable();
+        assert_eq!(chunk_types, vec!["BufferChunk", "ParquetChunk"]);
+    }
+
+    #[tokio::test]
+    async fn seeds_distinct_cache_from_written_data() {
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let db_name = NamespaceName::new("foo").unwrap();
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        let write = |lp: &'static str| {
+            write_buffer.write_lp(db_name.clone(), lp, 0, false, Precision::Nanosecond)
+        };
+        let host_values = |write_buffer: &WriteBufferImpl<WalImpl>| {
+            write_buffer
+                .distinct_cache_provider()
+                .tag_values("foo", "cpu", "host", 0)
+        };
+
+        // the cache is seeded with both persisted and buffered data when it's created
+        write("cpu,host=a usage=1.0 10").await.unwrap();
+        write_buffer.close_open_segment().await.unwrap();
+        wait_for_persisted_segments(&write_buffer).await;
+        write("cpu,host=b usage=2.0 20").await.unwrap();
+        write_buffer
+            .create_distinct_cache("foo", "cpu", None, vec!["host".to_string()], None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            host_values(&write_buffer),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        write("cpu,host=c usage=3.0 30").await.unwrap();
+        assert_eq!(
+            host_values(&write_buffer),
+            Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+
+        // and from the persisted data when the server starts
+        write_buffer.close_open_segment().await.unwrap();
+        wait_for_persisted_segments(&write_buffer).await;
+        drop(write_buffer);
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            time_provider,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            host_values(&write_buffer),
+            Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn compacts_partition_files_across_segments() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_duration = SegmentConfig::default().max_duration;
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        let executor = Executor::new_with_config(ExecutorConfig {
+            object_stores: [(
+                StorageId::from(PARQUET_STORAGE_ID),
+                Arc::clone(&object_store),
+            )]
+            .into_iter()
+            .collect(),
+            ..ExecutorConfig::testing()
+        });
+
+        // each write goes into its own segment, and the second one overwrites the first
+        for lp in [
+            "cpu,host=a usage=1.0 10",
+            "cpu,host=a usage=2.0 10",
+            "cpu,host=b usage=3.0 20",
+        ] {
+            write_buffer
+                .write_lp(
+                    NamespaceName::new("foo").unwrap(),
+                    lp,
+                    0,
+                    false,
+                    Precision::Nanosecond,
+                )
+                .await
+                .unwrap();
+            time_provider.inc(segment_duration);
+            write_buffer.close_open_segment_if_needed();
+            wait_for_persisted_segments(&write_buffer).await;
+        }
+        let original_paths: Vec<_> = write_buffer.segment_state.read().persisted_files["foo"]
+            ["cpu"]
+            .iter()
+            .flat_map(|(_, table_files)| &table_files.parquet_files)
+            .map(|file| file.path.clone())
+            .collect();
+        assert_eq!(original_paths.len(), 3);
+
+        // there aren't enough files to compact yet
+        write_buffer
+            .compact(&executor, &CompactionConfig { min_files: 4 })
+            .await
+            .unwrap();
+        assert_eq!(
+            write_buffer.segment_state.read().persisted_files["foo"]["cpu"].len(),
+            3
+        );
+
+        write_buffer
+            .compact(&executor, &CompactionConfig { min_files: 3 })
+            .await
+            .unwrap();
+
+        let persisted_files =
+            write_buffer.segment_state.read().persisted_files["foo"]["cpu"].clone();
+        assert_eq!(persisted_files.len(), 1);
+        let (segment_id, table_files) = &persisted_files[0];
+        assert_eq!(*segment_id, SegmentId::new(2));
+        assert_eq!(table_files.parquet_files.len(), 1);
+        let compacted_file = &table_files.parquet_files[0];
+        assert_eq!(compacted_file.row_count, 2);
+        assert_eq!(compacted_file.partition_key, "1970-01-01");
+        assert_eq!((compacted_file.min_time, compacted_file.max_time), (10, 20));
+
+        // the merged file replaces the originals in the file lists and in object storage
+        for segment_id in [SegmentId::new(0), SegmentId::new(1)] {
+            assert!(persister.load_segment(segment_id).await.unwrap().is_none());
+        }
+        let segment = persister
+            .load_segment(SegmentId::new(2))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(segment.segment_row_count, 2);
+        assert_eq!(
+            segment.databases["foo"].tables["cpu"].parquet_files[0].path,
+            compacted_file.path
+        );
+        for path in original_paths {
+            let location = ObjPath::parse(&path).unwrap();
+            assert!(matches!(
+                object_store.head(&location).await,
+                Err(object_store::Error::NotFound { .. })
+            ));
+        }
+
+        let bytes = object_store
+            .get(&ObjPath::parse(&compacted_file.path).unwrap())
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+------+--------------------------------+-------+",
+                "| host | time                           | usage |",
+                "+------+--------------------------------+-------+",
+                "| a    | 1970-01-01T00:00:00.000000010Z | 2.0   |",
+                "| b    | 1970-01-01T00:00:00.000000020Z | 3.0   |",
+                "+------+--------------------------------+-------+",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn compacts_partition_files_with_different_tags() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_duration = SegmentConfig::default().max_duration;
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister) as _,
+            None::<Arc<WalImpl>>,
+            Arc::clone(&time_provider) as _,
+            SegmentConfig::default(),
+        )
+        .await
+        .unwrap();
+        let executor = Executor::new_with_config(ExecutorConfig {
+            object_stores: [(
+                StorageId::from(PARQUET_STORAGE_ID),
+                Arc::clone(&object_store),
+            )]
+            .into_iter()
+            .collect(),
+            ..ExecutorConfig::testing()
+        });
+
+        // the region tag is added in the second segment, so the files of the segments are sorted
+        // by different keys, and the series with and without a region are distinct
+        for lp in [
+            "cpu,host=b usage=1.0 10\ncpu,host=a usage=2.0 10",
+            "cpu,host=a,region=us usage=3.0 10",
+            "cpu,host=a usage=4.0 20",
+        ] {
+            write_buffer
+                .write_lp(
+                    NamespaceName::new("foo").unwrap(),
+                    lp,
+                    0,
+                    false,
+                    Precision::Nanosecond,
+                )
+                .await
+                .unwrap();
+            time_provider.inc(segment_duration);
+            write_buffer.close_open_segment_if_needed();
+            wait_for_persisted_segments(&write_buffer).await;
+        }
+        let sort_keys: Vec<_> = write_buffer.segment_state.read().persisted_files["foo"]["cpu"]
+            .iter()
+            .map(|(_, table_files)| table_files.sort_key.clone())
+            .collect();
+        assert_eq!(
+            sort_keys,
+            vec![
+                vec!["host", "time"],
+                vec!["host", "region", "time"],
+                vec!["host", "region", "time"],
+            ]
+        );
+
+        write_buffer
+            .compact(&executor, &CompactionConfig { min_files: 3 })
+            .await
+            .unwrap();
+
+        let persisted_files =
+            write_buffer.segment_state.read().persisted_files["foo"]["cpu"].clone();
+        assert_eq!(persisted_files.len(), 1);
+        let compacted_file = &persisted_files[0].1.parquet_files[0];
+        assert_eq!(
+            compacted_file.sort_key,
+            Some(vec![
+                "host".to_string(),
+                "region".to_string(),
+                "time".to_string()
+            ])
+        );
+        assert_eq!(compacted_file.row_count, 4);
+
+        let bytes = object_store
+            .get(&ObjPath::parse(&compacted_file.path).unwrap())
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+------+--------+--------------------------------+-------+",
+                "| host | region | time                           | usage |",
+                "+------+--------+--------------------------------+-------+",
+                "| a    |        | 1970-01-01T00:00:00.000000010Z | 2.0   |",
+                "| a    |        | 1970-01-01T00:00:00.000000020Z | 4.0   |",
+                "| a    | us     | 1970-01-01T00:00:00.000000010Z | 3.0   |",
+                "| b    |        | 1970-01-01T00:00:00.000000010Z | 1.0   |",
+                "+------+--------+--------------------------------+-------+",
+            ]
+            .join("\n")
+        );
+    }
+
+    async fn wait_for_persisted_segments<W: Wal>(write_buffer: &WriteBufferImpl<W>) {
+        for _ in 0..100 {
+            if write_buffer
+                .segment_state
+                .read()
+                .persisting_segments
+                .is_empty()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for segments to be persisted");
+    }
 }
@@ -0,0 +1,700 @@
+//! A single buffer segment used by the write buffer. This is all the data in memory for a
+//! single WAL segment. Only one segment should be open for writes in the write buffer at any
+//! given time.
+
+use crate::catalog::Catalog;
+use crate::paths::ParquetFilePath;
+use crate::write_buffer::{BufferChunk, SegmentConfig, TableBatch};
+use crate::{
+    wal, BufferSegment, DatabaseTables, ParquetFile, PersistedSegment, Persister, SegmentId,
+    TableParquetFiles, WalOp, WalSegmentWriter,
+};
+use arrow::array::{new_null_array, UInt32Array};
+use arrow::compute::{lexsort_to_indices, take, SortColumn};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use data_types::{
+    ChunkId, ChunkOrder, PartitionKey, Statistics, TableId, TimestampMinMax, TransitionPartitionId,
+};
+use datafusion::physical_plan::memory::MemoryStream;
+use iox_query::chunk_statistics::create_chunk_statistics;
+use iox_query::QueryChunk;
+use iox_time::Time;
+use mutable_batch::MutableBatch;
+use observability_deps::tracing::{error, info};
+use schema::sort::SortKey;
+use schema::{Schema, TIME_COLUMN_NAME};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct OpenBufferSegment {
+    segment_id: SegmentId,
+    /// The catalog sequence number when this segment was opened. If the catalog has moved past
+    /// it by the time the segment is closed, the catalog gets persisted along with the segment.
+    starting_catalog_sequence_number: u64,
+    /// The time the first write was buffered into this segment.
+    first_write_time: Option<Time>,
+    /// The number of bytes of line protocol that have been written into this segment.
+    segment_size_bytes: usize,
+    /// The number of bytes of memory used by the buffered data of this segment.
+    buffer_size_bytes: usize,
+    /// The writer for this segment's WAL file, if the WAL is configured.
+    wal_writer: Option<Box<dyn WalSegmentWriter>>,
+    buffered_data: HashMap<String, DatabaseBuffer>,
+}
+
+impl OpenBufferSegment {
+    pub fn new(
+        segment_id: SegmentId,
+        starting_catalog_sequence_number: u64,
+        wal_writer: Option<Box<dyn WalSegmentWriter>>,
+    ) -> Self {
+        Self {
+            segment_id,
+            starting_catalog_sequence_number,
+            first_write_time: None,
+            segment_size_bytes: 0,
+            buffer_size_bytes: 0,
+            wal_writer,
+            buffered_data: HashMap::new(),
+        }
+    }
+
+    pub fn segment_id(&self) -> SegmentId {
+        self.segment_id
+    }
+
+    /// Returns the number of bytes of memory used by the data buffered in this segment.
+    pub fn buffer_size_bytes(&self) -> usize {
+        self.buffer_size_bytes
+    }
+
+    /// Returns true if the segment has grown past the configured size or the first write into
+    /// it is older than the configured duration. Empty segments never need to be closed.
+    pub fn should_close(&self, config: &SegmentConfig, now: Time) -> bool {
+        let Some(first_write_time) = self.first_write_time else {
+            return false;
+        };
+
+        self.segment_size_bytes >= config.max_size_bytes
+            || now
+                .checked_duration_since(first_write_time)
+                .map(|age| age >= config.max_duration)
+                .unwrap_or(false)
+    }
+
+    /// Writes the ops into this segment's WAL file, if it has one. Returns once they have been
+    /// fsynced.
+    pub fn write_wal_ops(&mut self, ops: Vec<WalOp>) -> wal::Result<()> {
+        if let Some(wal_writer) = self.wal_writer.as_mut() {
+            wal_writer.write_batch(ops)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the validated table batches for a write of `lp_size_bytes` of line protocol into
+    /// the segment.
+    pub fn buffer_writes(
+        &mut self,
+        db_name: &str,
+        table_batches: HashMap<String, TableBatch>,
+        lp_size_bytes: usize,
+        now: Time,
+    ) {
+        self.first_write_time.get_or_insert(now);
+        self.segment_size_bytes += lp_size_bytes;
+
+        let db_buffer = self.buffered_data.entry(db_name.to_string()).or_default();
+        for (table_name, table_batch) in table_batches {
+            let table_buffer = db_buffer.table_buffers.entry(table_name).or_default();
+            for (partition_key, partition_batch) in table_batch.partition_batches {
+                let partition_buffer = table_buffer
+                    .partition_buffers
+                    .entry(partition_key)
+                    .or_default();
+                match partition_buffer.add_batch(partition_batch.batch) {
+                    Ok(size_bytes) => self.buffer_size_bytes += size_bytes,
+                    // a write validated against a table that has since been deleted and
+                    // recreated with different column types can't be buffered
+                    Err(e) => error!(
+                        error=%e,
+                        %db_name,
+                        segment_id=?self.segment_id,
+                        "dropping write that conflicts with the buffered data"
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Returns the query chunks for the data buffered for the table in this segment.
+    pub fn table_chunks(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        schema: &Schema,
+    ) -> Vec<Arc<dyn QueryChunk>> {
+        table_chunks(
+            &self.buffered_data,
+            self.segment_id,
+            db_name,
+            table_name,
+            schema,
+        )
+    }
+
+    /// Drops the buffered data of a database that has been deleted.
+    pub fn remove_database(&mut self, db_name: &str) {
+        if let Some(db_buffer) = self.buffered_data.remove(db_name) {
+            self.buffer_size_bytes -= db_buffer.size_bytes();
+        }
+    }
+
+    /// Drops the buffered data of a table that has been deleted.
+    pub fn remove_table(&mut self, db_name: &str, table_name: &str) {
+        if let Some(table_buffer) = self
+            .buffered_data
+            .get_mut(db_name)
+            .and_then(|db_buffer| db_buffer.table_buffers.remove(table_name))
+        {
+            self.buffer_size_bytes -= table_buffer.size_bytes();
+        }
+    }
+
+    /// Closes the segment, and its WAL file, taking a snapshot of the catalog as it is at this
+    /// point so that the segment can be persisted with the schema of all the data it contains.
+    pub fn into_closed_segment(self, catalog: &Catalog) -> ClosedBufferSegment {
+        let catalog_sequence_number = catalog.sequence_number();
+
+        ClosedBufferSegment {
+            segment_id: self.segment_id,
+            catalog: Arc::new(Catalog::from_inner(catalog.clone_inner())),
+            catalog_updated: catalog_sequence_number != self.starting_catalog_sequence_number,
+            segment_size_bytes: self.segment_size_bytes,
+            buffer_size_bytes: self.buffer_size_bytes,
+            buffered_data: self.buffered_data,
+        }
+    }
+}
+
+/// A segment that is no longer accepting writes. It is kept in memory, and is queryable, until
+/// it has been persisted to object storage.
+#[derive(Debug)]
+pub struct ClosedBufferSegment {
+    segment_id: SegmentId,
+    catalog: Arc<Catalog>,
+    catalog_updated: bool,
+    segment_size_bytes: usize,
+    buffer_size_bytes: usize,
+    buffered_data: HashMap<String, DatabaseBuffer>,
+}
+
+impl ClosedBufferSegment {
+    /// Returns the number of bytes of memory used by the data buffered in this segment, which
+    /// is freed once it has been persisted.
+    pub fn buffer_size_bytes(&self) -> usize {
+        self.buffer_size_bytes
+    }
+
+    /// Returns the query chunks for the data buffered for the table in this segment.
+    pub fn table_chunks(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        schema: &Schema,
+    ) -> Vec<Arc<dyn QueryChunk>> {
+        table_chunks(
+            &self.buffered_data,
+            self.segment_id,
+            db_name,
+            table_name,
+            schema,
+        )
+    }
+
+    /// Persists the segment like [`BufferSegment::persist`], leaving out the tables that were
+    /// deleted from `catalog` after the segment was closed.
+    pub(crate) async fn persist_tables(
+        &self,
+        persister: Arc<dyn Persister>,
+        catalog: &Catalog,
+    ) -> crate::Result<PersistedSegment> {
+        if self.catalog_updated {
+            info!(segment_id=?self.segment_id, "persisting catalog for segment");
+            persister
+                .persist_catalog(
+                    self.segment_id,
+                    Catalog::from_inner(self.catalog.clone_inner()),
+                )
+                .await?;
+        }
+
+        let mut persisted_segment = PersistedSegment {
+            segment_id: self.segment_id,
+            segment_wal_size_bytes: self.segment_size_bytes as u64,
+            segment_parquet_size_bytes: 0,
+            segment_row_count: 0,
+            segment_min_time: i64::MAX,
+            segment_max_time: i64::MIN,
+            databases: HashMap::new(),
+        };
+
+        for (db_name, db_buffer) in &self.buffered_data {
+            // a write that was validated before its database or table was deleted can still be
+            // buffered after the deletion, and is dropped here
+            let Some(db_schema) = self.catalog.db_schema(db_name) else {
+                continue;
+            };
+            let mut database_tables = DatabaseTables::default();
+
+            for (table_name, table_buffer) in &db_buffer.table_buffers {
+                let Some(table) = db_schema.tables.get(table_name) else {
+                    continue;
+                };
+                if catalog.is_deleted(db_name, table_name, self.segment_id) {
+                    continue;
+                }
+                let schema = table
+                    .schema
+                    .as_ref()
+                    .cloned()
+                    .expect("table definition should have a schema");
+                let sort_key: Vec<String> =
+                    schema.primary_key().into_iter().map(String::from).collect();
+
+                let mut parquet_files = Vec::with_capacity(table_buffer.partition_buffers.len());
+                for (partition_key, partition_buffer) in &table_buffer.partition_buffers {
+                    let parquet_file = persist_partition(
+                        persister.as_ref(),
+                        self.segment_id,
+                        db_name,
+                        table_name,
+                        partition_key,
+                        partition_buffer,
+                        &schema,
+                        &sort_key,
+                    )
+                    .await?;
+                    add_parquet_file_stats(&mut persisted_segment, &parquet_file);
+                    parquet_files.push(parquet_file);
+                }
+
+                database_tables.tables.insert(
+                    table_name.to_string(),
+                    TableParquetFiles {
+                        table_name: table_name.to_string(),
+                        parquet_files,
+                        sort_key,
+                    },
+                );
+            }
+
+            persisted_segment
+                .databases
+                .insert(db_name.to_string(), database_tables);
+        }
+
+        persister.persist_segment(persisted_segment.clone()).await?;
+
+        Ok(persisted_segment)
+    }
+}
+
+#[async_trait]
+impl BufferSegment for ClosedBufferSegment {
+    fn id(&self) -> SegmentId {
+        self.segment_id
+    }
+
+    fn catalog(&self) -> Arc<Catalog> {
+        Arc::clone(&self.catalog)
+    }
+
+    async fn persist(&self, persister: Arc<dyn Persister>) -> crate::Result<PersistedSegment> {
+        self.persist_tables(persister, &self.catalog).await
+    }
+}
+
+/// Sorts the buffered data of a table partition and persists it as a parquet file of the
+/// segment.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn persist_partition(
+    persister: &dyn Persister,
+    segment_id: SegmentId,
+    db_name: &str,
+    table_name: &str,
+    partition_key: &str,
+    partition_buffer: &PartitionBuffer,
+    schema: &Schema,
+    sort_key: &[String],
+) -> crate::Result<ParquetFile> {
+    let batch = partition_buffer.record_batch(schema);
+    let batch = sort_record_batch(batch, sort_key)?;
+
+    // the segment id is unique for a table partition, so use it as the file number
+    let path = ParquetFilePath::new(db_name, table_name, partition_key, segment_id.0);
+    let stream = MemoryStream::try_new(vec![batch], schema.as_arrow(), None)?;
+    let (size_bytes, meta) = persister
+        .persist_parquet_file(path.clone(), Box::pin(stream))
+        .await?;
+
+    Ok(ParquetFile {
+        path: path.to_string(),
+        partition_key: partition_key.to_string(),
+        size_bytes,
+        row_count: meta.num_rows as u32,
+        min_time: partition_buffer.timestamp_min,
+        max_time: partition_buffer.timestamp_max,
+        migrated: false,
+        sort_key: None,
+    })
+}
+
+/// Adds the size, rows and time range of a parquet file persisted for the segment to its totals.
+pub(crate) fn add_parquet_file_stats(segment: &mut PersistedSegment, parquet_file: &ParquetFile) {
+    segment.segment_parquet_size_bytes += parquet_file.size_bytes;
+    segment.segment_row_count += parquet_file.row_count as u64;
+    segment.segment_min_time = segment.segment_min_time.min(parquet_file.min_time);
+    segment.segment_max_time = segment.segment_max_time.max(parquet_file.max_time);
+}
+
+fn table_chunks(
+    buffered_data: &HashMap<String, DatabaseBuffer>,
+    segment_id: SegmentId,
+    db_name: &str,
+    table_name: &str,
+    schema: &Schema,
+) -> Vec<Arc<dyn QueryChunk>> {
+    buffered_data
+        .get(db_name)
+        .and_then(|db_buffer| db_buffer.table_buffers.get(table_name))
+        .map(|table_buffer| table_buffer.chunks(segment_id, schema))
+        .unwrap_or_default()
+}
+
+/// Sorts the record batch by the given sort key, which are the names of columns in the batch.
+///
+/// Rows with the same sort key keep the order they were buffered in, so that deduplication,
+/// which keeps the last of them, keeps the values of the latest write. The sort itself isn't
+/// stable, so the position of each row is used as the last sort column.
+fn sort_record_batch(batch: RecordBatch, sort_key: &[String]) -> crate::Result<RecordBatch> {
+    let mut sort_columns = sort_key
+        .iter()
+        .map(|name| {
+            Ok(SortColumn {
+                values: Arc::clone(batch.column(batch.schema().index_of(name)?)),
+                options: None,
+            })
+        })
+        .collect::<Result<Vec<_>, arrow::error::ArrowError>>()?;
+    sort_columns.push(SortColumn {
+        values: Arc::new(UInt32Array::from_iter_values(0..batch.num_rows() as u32)),
+        options: None,
+    });
+    let indices = lexsort_to_indices(&sort_columns, None)?;
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| take(column, &indices, None))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+#[derive(Debug, Default)]
+struct DatabaseBuffer {
+    table_buffers: HashMap<String, TableBuffer>,
+}
+
+impl DatabaseBuffer {
+    fn size_bytes(&self) -> usize {
+        self.table_buffers
+            .values()
+            .map(TableBuffer::size_bytes)
+            .sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct TableBuffer {
+    partition_buffers: HashMap<String, PartitionBuffer>,
+}
+
+impl TableBuffer {
+    fn size_bytes(&self) -> usize {
+        self.partition_buffers
+            .values()
+            .map(|partition_buffer| partition_buffer.size_bytes)
+            .sum()
+    }
+
+    /// Converts the buffered data for the table into query chunks, one per partition. The
+    /// chunks are ordered by the segment they came from so that newer data overwrites older
+    /// data when deduplicating. The data of a chunk is sorted by the primary key the way it is
+    /// when persisted, so rows written more than once within the segment keep their order.
+    fn chunks(&self, segment_id: SegmentId, schema: &Schema) -> Vec<Arc<dyn QueryChunk>> {
+        let mut chunks = Vec::with_capacity(self.partition_buffers.len());
+        let sort_key: Vec<String> = schema.primary_key().into_iter().map(String::from).collect();
+
+        for (partition_key, partition_buffer) in &self.partition_buffers {
+            let partition_key: PartitionKey = partition_key.as_str().into();
+            let batch = sort_record_batch(partition_buffer.record_batch(schema), &sort_key)
+                .expect("primary key columns should be in the buffered batch");
+            let batch_stats = create_chunk_statistics(
+                Some(partition_buffer.data.rows()),
+                schema,
+                Some(TimestampMinMax {
+                    min: partition_buffer.timestamp_min,
+                    max: partition_buffer.timestamp_max,
+                }),
+                None,
+            );
+
+            let chunk = BufferChunk {
+                batches: vec![batch],
+                schema: schema.clone(),
+                stats: Arc::new(batch_stats),
+                partition_id: TransitionPartitionId::new(TableId::new(0), &partition_key),
+                sort_key: Some(SortKey::from_columns(sort_key.iter().map(String::as_str))),
+                id: ChunkId::new(),
+                chunk_order: ChunkOrder::new(segment_id.0 as i64),
+            };
+
+            chunks.push(Arc::new(chunk) as _);
+        }
+
+        chunks
+    }
+}
+
+/// The buffered data of a partition of a table, held in columns that convert directly into Arrow
+/// arrays. Tag values are dictionary encoded.
+#[derive(Debug, Default)]
+pub(crate) struct PartitionBuffer {
+    data: MutableBatch,
+    timestamp_min: i64,
+    timestamp_max: i64,
+    /// The number of bytes of memory used by the data
+    size_bytes: usize,
+}
+
+impl PartitionBuffer {
+    /// Appends the rows of the batch to the buffer and returns the number of bytes of memory the
+    /// buffer grew by.
+    pub(crate) fn add_batch(&mut self, batch: MutableBatch) -> Result<usize, mutable_batch::Error> {
+        let Some((min, max)) = time_range(&batch) else {
+            return Ok(0);
+        };
+
+        if self.data.rows() == 0 {
+            self.data = batch;
+            self.timestamp_min = min;
+            self.timestamp_max = max;
+        } else {
+            self.data.extend_from(&batch)?;
+            self.timestamp_min = self.timestamp_min.min(min);
+            self.timestamp_max = self.timestamp_max.max(max);
+        }
+
+        let size_bytes = self.data.size();
+        let added_bytes = size_bytes.saturating_sub(self.size_bytes);
+        self.size_bytes = size_bytes;
+
+        Ok(added_bytes)
+    }
+
+    /// Returns the buffered data as a record batch with the columns of the table schema. Columns
+    /// that have no data in this partition are all null.
+    fn record_batch(&self, schema: &Schema) -> RecordBatch {
+        let arrow_schema = schema.as_arrow();
+        let row_count = self.data.rows();
+        let columns = arrow_schema
+            .fields()
+            .iter()
+            .map(|field| match self.data.column(field.name()) {
+                Ok(column) => column
+                    .to_arrow()
+                    .expect("buffered column should convert to arrow"),
+                Err(_) => new_null_array(field.data_type(), row_count),
+            })
+            .collect();
+
+        RecordBatch::try_new(arrow_schema, columns)
+            .expect("buffered columns should match the table schema")
+    }
+}
+
+/// Returns the minimum and maximum timestamps of the rows in the batch, if it has any.
+fn time_range(batch: &MutableBatch) -> Option<(i64, i64)> {
+    match batch.column(TIME_COLUMN_NAME).ok()?.stats() {
+        Statistics::I64(stats) => Some((stats.min?, stats.max?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persister::PersisterImpl;
+    use crate::write_buffer::parse_validate_and_update_schema;
+    use crate::Precision;
+    use futures_util::TryStreamExt;
+    use object_store::memory::InMemory;
+    use object_store::ObjectStore;
+
+    #[test]
+    fn buffers_writes_into_columns() {
+        let catalog = Catalog::new();
+        let mut open_segment =
+            OpenBufferSegment::new(SegmentId::new(0), catalog.sequence_number(), None);
+
+        // the second write adds a column that the first write doesn't have, and a column is
+        // added to the table that neither write into this partition has
+        for lp in [
+            "cpu,host=a usage=1.0 10\ncpu,host=a usage=2.0 20",
+            "cpu,host=b,region=west usage=3.0 30",
+            "cpu,host=c idle=4i 86400000000000",
+        ] {
+            let (sequence, db) = catalog.db_or_create("foo");
+            let result =
+                parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond).unwrap();
+            if let Some(schema) = result.schema {
+                catalog
+                    .replace_database(sequence, Arc::new(schema))
+                    .unwrap();
+            }
+            open_segment.buffer_writes(
+                "foo",
+                result.table_batches,
+                lp.len(),
+                Time::from_timestamp_nanos(0),
+            );
+        }
+        assert!(open_segment.buffer_size_bytes() > 0);
+
+        let db = catalog.db_schema("foo").unwrap();
+        let schema = db.tables["cpu"].schema.clone().unwrap();
+        let partition_buffer =
+            &open_segment.buffered_data["foo"].table_buffers["cpu"].partition_buffers["1970-01-01"];
+        assert_eq!(partition_buffer.timestamp_min, 10);
+        assert_eq!(partition_buffer.timestamp_max, 30);
+
+        let batch = partition_buffer.record_batch(&schema);
+        assert_eq!(batch.schema(), schema.as_arrow());
+        assert_eq!(batch.num_rows(), 3);
+        let column = |name: &str| Arc::clone(batch.column(batch.schema().index_of(name).unwrap()));
+        assert!(matches!(
+            column("host").data_type(),
+            arrow::datatypes::DataType::Dictionary(_, _)
+        ));
+        assert_eq!(column("region").null_count(), 2);
+        assert_eq!(column("idle").null_count(), 3);
+
+        let chunks = open_segment.table_chunks("foo", "cpu", &schema);
+        assert_eq!(chunks.len(), 2);
+
+        // dropping the table frees all of the memory accounted to its buffers
+        open_segment.remove_table("foo", "cpu");
+        assert_eq!(open_segment.buffer_size_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn persist_closed_segment() {
+        let catalog = Catalog::new();
+        let starting_sequence = catalog.sequence_number();
+        let mut open_segment = OpenBufferSegment::new(SegmentId::new(3), starting_sequence, None);
+
+        let lp = "cpu,host=b usage=2.0 20\ncpu,host=a usage=1.0 10\nmem,host=a free=5i 10";
+        let (sequence, db) = catalog.db_or_create("foo");
+        let result =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond).unwrap();
+        catalog
+            .replace_database(sequence, Arc::new(result.schema.unwrap()))
+            .unwrap();
+        open_segment.buffer_writes(
+            "foo",
+            result.table_batches,
+            lp.len(),
+            Time::from_timestamp_nanos(0),
+        );
+
+        let closed_segment = open_segment.into_closed_segment(&catalog);
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        closed_segment
+            .persist(Arc::clone(&persister) as _)
+            .await
+            .unwrap();
+
+        let persisted_catalog = persister.load_catalog().await.unwrap().unwrap();
+        assert_eq!(persisted_catalog.segment_id, SegmentId::new(3));
+        assert!(persisted_catalog.catalog.db_exists("foo"));
+
+        let segments = persister.load_segments(1).await.unwrap();
+        assert_eq!(segments.len(), 1);
+        let segment = &segments[0];
+        assert_eq!(segment.segment_id, SegmentId::new(3));
+        assert_eq!(segment.segment_wal_size_bytes, lp.len() as u64);
+        assert_eq!(segment.segment_row_count, 3);
+        assert_eq!(segment.segment_min_time, 10);
+        assert_eq!(segment.segment_max_time, 20);
+
+        let cpu = segment.databases["foo"].tables.get("cpu").unwrap();
+        assert_eq!(cpu.sort_key, vec!["host", "time"]);
+        assert_eq!(cpu.parquet_files.len(), 1);
+        assert_eq!(cpu.parquet_files[0].row_count, 2);
+        assert_eq!(cpu.parquet_files[0].min_time, 10);
+        assert_eq!(cpu.parquet_files[0].max_time, 20);
+        assert_eq!(
+            cpu.parquet_files[0].path,
+            "dbs/foo/cpu/1970-01-01/4294967292.parquet"
+        );
+    }
+
+    #[tokio::test]
+    async fn persist_closed_segment_without_tables_deleted_after_close() {
+        let catalog = Catalog::new();
+        let starting_sequence = catalog.sequence_number();
+        let mut open_segment = OpenBufferSegment::new(SegmentId::new(3), starting_sequence, None);
+
+        let lp = "cpu,host=a usage=1.0 10\nmem,host=a free=5i 10";
+        let (sequence, db) = catalog.db_or_create("foo");
+        let result =
+            parse_validate_and_update_schema(lp, &db, 0, false, Precision::Nanosecond).unwrap();
+        catalog
+            .replace_database(sequence, Arc::new(result.schema.unwrap()))
+            .unwrap();
+        open_segment.buffer_writes(
+            "foo",
+            result.table_batches,
+            lp.len(),
+            Time::from_timestamp_nanos(0),
+        );
+
+        let closed_segment = open_segment.into_closed_segment(&catalog);
+        catalog
+            .delete_table("foo", "cpu", SegmentId::new(4))
+            .unwrap();
+
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let segment = closed_segment
+            .persist_tables(Arc::clone(&persister) as _, &catalog)
+            .await
+            .unwrap();
+
+        let tables = &segment.databases["foo"].tables;
+        assert!(!tables.contains_key("cpu"));
+        assert_eq!(tables["mem"].parquet_files.len(), 1);
+        assert_eq!(segment.segment_row_count, 1);
+
+        let files = persister
+            .object_store()
+            .list(None)
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(files.iter().all(|path| !path.contains("/cpu/")));
+    }
+}
@@ -0,0 +1,346 @@
+//! Compaction of the parquet files persisted for a table partition. Every segment persists a file
+//! per table partition, so the files of a partition are merged into a single sorted and
+//! deduplicated file once there are enough of them.
+
+use crate::paths::ParquetFilePath;
+use crate::write_buffer::{
+    parquet_file_partition_key, remove_parquet_files, Error, ParquetChunk, SegmentState,
+    WriteBufferImpl,
+};
+use crate::{ParquetFile, SegmentId, TableParquetFiles, Wal};
+use iox_query::exec::{Executor, ExecutorType};
+use iox_query::frontend::reorg::ReorgPlanner;
+use iox_query::QueryChunk;
+use object_store::path::Path as ObjPath;
+use observability_deps::tracing::{error, info};
+use schema::sort::SortKey;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configuration for when the parquet files of a table partition get compacted.
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionConfig {
+    /// The files of a table partition are compacted once there are at least this many of them.
+    pub min_files: usize,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self { min_files: 10 }
+    }
+}
+
+/// The parquet files of a table partition that get merged into one file.
+#[derive(Debug)]
+struct CompactionCandidate {
+    db_name: String,
+    table_name: String,
+    partition_key: String,
+    /// The files along with the segment they were persisted in, ordered by segment. The sort
+    /// key of every file is set, from the table in its segment if the file doesn't have its own.
+    files: Vec<(SegmentId, ParquetFile)>,
+}
+
+impl CompactionCandidate {
+    /// The merged file is put in the newest segment of the files it replaces, so that it is
+    /// ordered before the data of later segments when deduplicating.
+    fn newest_segment_id(&self) -> SegmentId {
+        self.files.last().expect("candidates have files").0
+    }
+
+    fn segment_ids(&self) -> BTreeSet<SegmentId> {
+        self.files
+            .iter()
+            .map(|(segment_id, _)| *segment_id)
+            .collect()
+    }
+
+    fn paths(&self) -> HashSet<String> {
+        self.files
+            .iter()
+            .map(|(_, file)| file.path.clone())
+            .collect()
+    }
+}
+
+impl<W: Wal> SegmentState<W> {
+    /// Returns the table partitions that have at least `min_files` persisted parquet files.
+    fn compaction_candidates(&self, min_files: usize) -> Vec<CompactionCandidate> {
+        // merging a single file would only rewrite it
+        let min_files = min_files.max(2);
+        let mut candidates = vec![];
+
+        for (db_name, tables) in &self.persisted_files {
+            for (table_name, segments) in tables {
+                let mut segments: Vec<_> = segments.iter().collect();
+                segments.sort_by_key(|(segment_id, _)| *segment_id);
+
+                let mut partitions: BTreeMap<String, Vec<_>> = BTreeMap::new();
+                for (segment_id, table_files) in segments {
+                    for file in &table_files.parquet_files {
+                        let mut file = file.clone();
+                        file.sort_key
+                            .get_or_insert_with(|| table_files.sort_key.clone());
+                        partitions
+                            .entry(parquet_file_partition_key(&file))
+                            .or_default()
+                            .push((*segment_id, file));
+                    }
+                }
+
+                candidates.extend(
+                    partitions
+                        .into_iter()
+                        .filter(|(_, files)| files.len() >= min_files)
+                        .map(|(partition_key, files)| CompactionCandidate {
+                            db_name: db_name.clone(),
+                            table_name: table_name.clone(),
+                            partition_key,
+                            files,
+                        }),
+                );
+            }
+        }
+
+        candidates
+    }
+
+    /// Replaces the files of the candidate with the merged file, so queries read it instead.
+    /// Returns false, leaving the files as they are, if any of them are no longer persisted
+    /// because the table was deleted while they were being merged.
+    fn replace_compacted_files(
+        &mut self,
+        candidate: &CompactionCandidate,
+        compacted_file: &ParquetFile,
+    ) -> bool {
+        let Some(segments) = self
+            .persisted_files
+            .get_mut(&candidate.db_name)
+            .and_then(|tables| tables.get_mut(&candidate.table_name))
+        else {
+            return false;
+        };
+
+        let mut paths = candidate.paths();
+        let persisted_count = segments
+            .iter()
+            .flat_map(|(_, table_files)| &table_files.parquet_files)
+            .filter(|file| paths.contains(&file.path))
+            .count();
+        if persisted_count != paths.len() {
+            return false;
+        }
+
+        let newest_segment_id = candidate.newest_segment_id();
+        for (segment_id, table_files) in segments.iter_mut() {
+            table_files
+                .parquet_files
+                .retain(|file| !paths.remove(&file.path));
+            if *segment_id == newest_segment_id {
+                table_files.parquet_files.push(compacted_file.clone());
+            }
+        }
+        segments.retain(|(_, table_files)| !table_files.parquet_files.is_empty());
+
+        true
+    }
+}
+
+impl<W: Wal> WriteBufferImpl<W> {
+    /// Spawns a task that compacts the persisted parquet files at the given interval. The task
+    /// stops once the write buffer has been dropped.
+    pub fn start_compaction_task(
+        self: &Arc<Self>,
+        executor: Arc<Executor>,
+        compaction_config: CompactionConfig,
+        check_interval: Duration,
+    ) {
+        let write_buffer = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(check_interval);
+            loop {
+                interval.tick().await;
+                let Some(write_buffer) = write_buffer.upgrade() else {
+                    return;
+                };
+                if let Err(error) = write_buffer.compact(&executor, &compaction_config).await {
+                    error!(%error, "error compacting persisted parquet files");
+                }
+            }
+        });
+    }
+
+    /// Merges the parquet files of every table partition that has at least the configured
+    /// number of them into a single file, which is sorted by the primary key and has duplicate
+    /// rows removed, keeping those from the newest segment.
+    ///
+    /// The merged file replaces the originals in queries right away. It is then added to the
+    /// parquet file list of the newest segment it was merged from before the originals are
+    /// removed from their lists, so a failure in between only leaves rows that deduplication
+    /// already drops. The originals are deleted from object storage last.
+    pub async fn compact(
+        &self,
+        executor: &Executor,
+        compaction_config: &CompactionConfig,
+    ) -> crate::Result<()> {
+        let _persisted_files_guard = self.persisted_files_lock.lock().await;
+        let candidates = self
+            .segment_state
+            .read()
+            .compaction_candidates(compaction_config.min_files);
+
+        for candidate in candidates {
+            self.compact_partition(executor, candidate).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn compact_partition(
+        &self,
+        executor: &Executor,
+        candidate: CompactionCandidate,
+    ) -> crate::Result<()> {
+        let Some(schema) = self
+            .catalog
+            .db_schema(&candidate.db_name)
+            .and_then(|db| db.tables.get(&candidate.table_name)?.schema.clone())
+        else {
+            return Ok(());
+        };
+        // the tags of the table can differ between the segments of the files, so the merged file
+        // is sorted by the current primary key of the table, which has every tag of the files
+        let sort_key_columns: Vec<String> =
+            schema.primary_key().into_iter().map(String::from).collect();
+        let sort_key = SortKey::from_columns(sort_key_columns.clone());
+        let object_store_url = self.persister.object_store_url();
+        let chunks = candidate.files.iter().map(|(segment_id, file)| {
+            let file_sort_key = file
+                .sort_key
+                .clone()
+                .expect("candidate files have a sort key");
+            Arc::new(ParquetChunk::new(
+                file,
+                *segment_id,
+                &schema,
+                SortKey::from_columns(file_sort_key),
+                object_store_url,
+            )) as Arc<dyn QueryChunk>
+        });
+        let plan = ReorgPlanner::new()
+            .compact_plan(
+                Arc::from(candidate.table_name.as_str()),
+                &schema,
+                chunks,
+                sort_key,
+            )
+            .map_err(Error::CompactionPlan)?;
+        let ctx = executor.new_context(ExecutorType::Reorg);
+        let physical_plan = ctx.create_physical_plan(&plan).await?;
+        let stream = ctx.execute_stream(physical_plan).await?;
+
+        let segment_id = candidate.newest_segment_id();
+        let path = ParquetFilePath::new_compacted(
+            &candidate.db_name,
+            &candidate.table_name,
+            &candidate.partition_key,
+            segment_id.0,
+            self.time_provider.now().timestamp_nanos(),
+        );
+        let (size_bytes, meta) = self
+            .persister
+            .persist_parquet_file(path.clone(), stream)
+            .await?;
+        let compacted_file = ParquetFile {
+            path: path.to_string(),
+            partition_key: candidate.partition_key.clone(),
+            size_bytes,
+            row_count: meta.num_rows as u32,
+            min_time: candidate
+                .files
+                .iter()
+                .map(|(_, file)| file.min_time)
+                .min()
+                .expect("candidates have files"),
+            max_time: candidate
+                .files
+                .iter()
+                .map(|(_, file)| file.max_time)
+                .max()
+                .expect("candidates have files"),
+            // once merged with rows written to the server, the file is ordered by its segment
+            migrated: candidate.files.iter().all(|(_, file)| file.migrated),
+            sort_key: Some(sort_key_columns.clone()),
+        };
+
+        let object_store = self.persister.object_store();
+        if !self
+            .segment_state
+            .write()
+            .replace_compacted_files(&candidate, &compacted_file)
+        {
+            if let Err(error) = object_store.delete(&path).await {
+                error!(
+                    %error,
+                    path = %compacted_file.path,
+                    "error deleting compacted parquet file of deleted table"
+                );
+            }
+            return Ok(());
+        }
+
+        let paths = candidate.paths();
+        let newest_persisted_segment_id = self
+            .persister
+            .load_segments(1)
+            .await?
+            .first()
+            .map(|segment| segment.segment_id);
+        for merged_segment_id in candidate.segment_ids().into_iter().rev() {
+            let Some(mut segment) = self.persister.load_segment(merged_segment_id).await? else {
+                continue;
+            };
+            if merged_segment_id == segment_id {
+                segment
+                    .databases
+                    .entry(candidate.db_name.clone())
+                    .or_default()
+                    .tables
+                    .entry(candidate.table_name.clone())
+                    .or_insert_with(|| TableParquetFiles {
+                        table_name: candidate.table_name.clone(),
+                        parquet_files: vec![],
+                        sort_key: sort_key_columns.clone(),
+                    })
+                    .parquet_files
+                    .push(compacted_file.clone());
+            }
+            remove_parquet_files(&mut segment, &paths);
+            if segment.databases.is_empty()
+                && Some(merged_segment_id) != newest_persisted_segment_id
+            {
+                self.persister.delete_segment(merged_segment_id).await?;
+            } else {
+                self.persister.persist_segment(segment).await?;
+            }
+        }
+
+        for path in paths {
+            let location = ObjPath::parse(&path).expect("parquet file paths are valid");
+            if let Err(error) = object_store.delete(&location).await {
+                error!(%error, %path, "error deleting parquet file after compacting it");
+            }
+        }
+        info!(
+            db_name = %candidate.db_name,
+            table_name = %candidate.table_name,
+            partition_key = %candidate.partition_key,
+            file_count = candidate.files.len(),
+            ?segment_id,
+            "compacted parquet files of table partition"
+        );
+
+        Ok(())
+    }
+}
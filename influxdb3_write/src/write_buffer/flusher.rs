@@ -0,0 +1,94 @@
+//! Buffers writes and flushes them into the WAL and the open segment. Writes that arrive while
+//! a previous batch is being written are grouped together into the next batch so that
+//! concurrent requests share a single fsync of the WAL.
+
+use crate::write_buffer::{Error, Result, SegmentState, TableBatch};
+use crate::{SegmentId, Wal, WalOp};
+use iox_time::TimeProvider;
+use observability_deps::tracing::debug;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+// The maximum number of writes that can be waiting to be flushed before new writes wait on the
+// channel.
+const BUFFER_CHANNEL_LIMIT: usize = 10_000;
+
+/// A validated write waiting to be written into the WAL and buffered into the open segment.
+#[derive(Debug)]
+pub(crate) struct BufferedWrite {
+    pub(crate) wal_op: WalOp,
+    pub(crate) db_name: String,
+    pub(crate) lp_size_bytes: usize,
+    pub(crate) table_batches: HashMap<String, TableBatch>,
+    pub(crate) response_tx: oneshot::Sender<Result<SegmentId>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct WriteBufferFlusher {
+    buffer_tx: mpsc::Sender<BufferedWrite>,
+}
+
+impl WriteBufferFlusher {
+    /// Starts the background task that flushes writes into the segment state. The task runs
+    /// until the flusher is dropped.
+    pub(crate) fn new<W: Wal>(
+        segment_state: Arc<RwLock<SegmentState<W>>>,
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Self {
+        let (buffer_tx, buffer_rx) = mpsc::channel(BUFFER_CHANNEL_LIMIT);
+        tokio::spawn(run_flusher(segment_state, time_provider, buffer_rx));
+
+        Self { buffer_tx }
+    }
+
+    /// Writes the op into the WAL and buffers the table batches into the open segment. Returns
+    /// once the op has been fsynced to the WAL with the id of the segment it was written to.
+    pub(crate) async fn write_to_open_segment(
+        &self,
+        wal_op: WalOp,
+        db_name: String,
+        lp_size_bytes: usize,
+        table_batches: HashMap<String, TableBatch>,
+    ) -> Result<SegmentId> {
+        let (response_tx, response_rx) = oneshot::channel();
+
+        self.buffer_tx
+            .send(BufferedWrite {
+                wal_op,
+                db_name,
+                lp_size_bytes,
+                table_batches,
+                response_tx,
+            })
+            .await
+            .map_err(|_| Error::FlusherStopped)?;
+
+        response_rx.await.map_err(|_| Error::FlusherStopped)?
+    }
+}
+
+async fn run_flusher<W: Wal>(
+    segment_state: Arc<RwLock<SegmentState<W>>>,
+    time_provider: Arc<dyn TimeProvider>,
+    mut buffer_rx: mpsc::Receiver<BufferedWrite>,
+) {
+    while let Some(write) = buffer_rx.recv().await {
+        // group every write that arrived while the previous batch was being flushed
+        let mut writes = vec![write];
+        while let Ok(write) = buffer_rx.try_recv() {
+            writes.push(write);
+        }
+        debug!(
+            write_count = writes.len(),
+            "flushing writes to open segment"
+        );
+
+        let segment_state = Arc::clone(&segment_state);
+        let now = time_provider.now();
+        tokio::task::spawn_blocking(move || segment_state.write().buffer_writes(writes, now))
+            .await
+            .expect("flushing writes to the open segment should not panic");
+    }
+}
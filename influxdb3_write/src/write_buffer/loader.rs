@@ -0,0 +1,321 @@
+//! Loads the state of the write buffer from object storage and the WAL when the server starts.
+
+use crate::catalog::Catalog;
+use crate::write_buffer::buffer_segment::{ClosedBufferSegment, OpenBufferSegment};
+use crate::write_buffer::parse_validate_and_update_schema;
+use crate::{
+    BufferSegment, DeleteDatabaseOp, DeleteTableOp, PersistedSegment, Persister, SegmentId, Wal,
+    WalOp,
+};
+use influxdb_line_protocol::{parse_lines, split_lines};
+use iox_time::Time;
+use observability_deps::tracing::info;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// The number of most recently persisted segments that have their parquet files loaded at
+/// startup so they can be queried.
+const PERSISTED_SEGMENTS_TO_LOAD: usize = 1_000;
+
+/// The state of the write buffer as it was loaded at startup.
+#[derive(Debug)]
+pub(crate) struct LoadedState {
+    /// The most recently persisted catalog, with any changes made by replayed writes.
+    pub(crate) catalog: Catalog,
+    /// The most recently persisted segments, newest first.
+    pub(crate) persisted_segments: Vec<PersistedSegment>,
+    /// The segments in the WAL that had not been persisted, replayed in order.
+    pub(crate) replayed_segments: Vec<ClosedBufferSegment>,
+    /// The id to use for the segment that new writes go into.
+    pub(crate) next_segment_id: SegmentId,
+}
+
+/// Loads the most recently persisted catalog and segments from object storage and then replays
+/// every WAL segment that has not been persisted. WAL segments that have already been persisted
+/// are deleted.
+pub(crate) async fn load_starting_state<W: Wal>(
+    persister: &dyn Persister,
+    wal: Option<&W>,
+    now: Time,
+) -> crate::Result<LoadedState> {
+    let persisted_catalog = persister.load_catalog().await?;
+    let persisted_segments = persister.load_segments(PERSISTED_SEGMENTS_TO_LOAD).await?;
+
+    // the catalog is persisted before the segment, so it may be from a segment that isn't
+    // persisted yet
+    let mut last_segment_id = persisted_segments
+        .first()
+        .map(|segment| segment.segment_id)
+        .max(persisted_catalog.as_ref().map(|catalog| catalog.segment_id));
+    let catalog = persisted_catalog
+        .map(|persisted| Catalog::from_inner(persisted.catalog))
+        .unwrap_or_default();
+
+    let replayed_segments = match wal {
+        Some(wal) => {
+            // segments are persisted in the background, so a newer segment can finish before an
+            // older one. Only the WAL files of segments that were persisted can be removed.
+            let persisted_ids: HashSet<_> = persisted_segments
+                .iter()
+                .map(|segment| segment.segment_id)
+                .collect();
+            for segment_file in wal.segment_files()? {
+                if persisted_ids.contains(&segment_file.segment_id) {
+                    info!(segment_id=?segment_file.segment_id, "deleting wal segment that was already persisted");
+                    wal.delete_wal_segment(segment_file.segment_id)?;
+                }
+            }
+
+            load_wal_segments(wal, None, &catalog, now)?
+        }
+        None => vec![],
+    };
+    if let Some(segment) = replayed_segments.last() {
+        last_segment_id = last_segment_id.max(Some(segment.id()));
+    }
+
+    Ok(LoadedState {
+        catalog,
+        persisted_segments,
+        replayed_segments,
+        next_segment_id: last_segment_id
+            .map(|segment_id| segment_id.next())
+            .unwrap_or(SegmentId::new(0)),
+    })
+}
+
+/// Replays every WAL segment file with an id greater than `after_segment_id`, or all of them if
+/// it is `None`, into closed buffer segments. Any tables or columns created by the replayed
+/// writes are added to the catalog, except for databases and tables that were deleted in a
+/// later segment.
+pub(crate) fn load_wal_segments<W: Wal>(
+    wal: &W,
+    after_segment_id: Option<SegmentId>,
+    catalog: &Catalog,
+    now: Time,
+) -> crate::Result<Vec<ClosedBufferSegment>> {
+    let mut segments = Vec::new();
+
+    for segment_file in wal.segment_files()? {
+        if after_segment_id.is_some_and(|after| segment_file.segment_id <= after) {
+            continue;
+        }
+
+        info!(segment_id=?segment_file.segment_id, path=?segment_file.path, "replaying wal segment");
+        let mut segment =
+            OpenBufferSegment::new(segment_file.segment_id, catalog.sequence_number(), None);
+        let mut reader = wal.open_segment_reader(segment_file.segment_id)?;
+
+        while let Some(batch) = reader.next_batch()? {
+            for op in batch.ops {
+                match op {
+                    WalOp::LpWrite(write) => {
+                        let segment_id = segment_file.segment_id;
+                        let lp =
+                            without_deleted_tables(&write.lp, &write.db_name, segment_id, catalog);
+                        if lp.is_empty() {
+                            continue;
+                        }
+
+                        let (sequence, db) = catalog.db_or_create(&write.db_name);
+                        let result = parse_validate_and_update_schema(
+                            &lp,
+                            &db,
+                            write.default_time as i64,
+                            false,
+                            write.precision,
+                        )?;
+                        if let Some(schema) = result.schema {
+                            catalog.replace_database(sequence, Arc::new(schema))?;
+                        }
+                        segment.buffer_writes(
+                            &write.db_name,
+                            result.table_batches,
+                            write.lp.len(),
+                            now,
+                        );
+                    }
+                    // the database or table may already have been deleted if the catalog was
+                    // persisted after the delete
+                    WalOp::DeleteDatabase(DeleteDatabaseOp { db_name }) => {
+                        let _ = catalog.delete_database(&db_name, segment_file.segment_id);
+                        segment.remove_database(&db_name);
+                    }
+                    WalOp::DeleteTable(DeleteTableOp {
+                        db_name,
+                        table_name,
+                    }) => {
+                        let _ =
+                            catalog.delete_table(&db_name, &table_name, segment_file.segment_id);
+                        segment.remove_table(&db_name, &table_name);
+                    }
+                }
+            }
+        }
+
+        segments.push(segment.into_closed_segment(catalog));
+    }
+
+    Ok(segments)
+}
+
+/// Returns the lines of line protocol that aren't for a table that was deleted after the
+/// segment they were written in.
+fn without_deleted_tables<'a>(
+    lp: &'a str,
+    db_name: &str,
+    segment_id: SegmentId,
+    catalog: &Catalog,
+) -> Cow<'a, str> {
+    let is_deleted = |line: &str| {
+        parse_lines(line).next().is_some_and(|parsed| {
+            parsed.is_ok_and(|parsed| {
+                catalog.is_deleted(db_name, parsed.series.measurement.as_str(), segment_id)
+            })
+        })
+    };
+    if !split_lines(lp).any(is_deleted) {
+        return Cow::Borrowed(lp);
+    }
+
+    Cow::Owned(
+        split_lines(lp)
+            .filter(|line| !is_deleted(line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persister::PersisterImpl;
+    use crate::wal::WalImpl;
+    use crate::{LpWriteOp, Precision, WalSegmentWriter};
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn replays_segments_after_id() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = WalImpl::new(dir).unwrap();
+
+        for id in 0..3 {
+            let mut writer = wal.open_segment_writer(SegmentId::new(id)).unwrap();
+            writer
+                .write_batch(vec![WalOp::LpWrite(LpWriteOp {
+                    db_name: "foo".to_string(),
+                    lp: format!("cpu,host=a val={id}i {id}"),
+                    default_time: 0,
+                    precision: Precision::Nanosecond,
+                })])
+                .unwrap();
+        }
+
+        let catalog = Catalog::new();
+        let segments = load_wal_segments(
+            &wal,
+            Some(SegmentId::new(0)),
+            &catalog,
+            Time::from_timestamp_nanos(0),
+        )
+        .unwrap();
+
+        let ids: Vec<_> = segments.iter().map(|s| s.id()).collect();
+        assert_eq!(ids, vec![SegmentId::new(1), SegmentId::new(2)]);
+        let db_schema = catalog.db_schema("foo").unwrap();
+        assert!(db_schema.table_exists("cpu"));
+        let schema = db_schema.tables["cpu"].schema.clone().unwrap();
+        assert_eq!(segments[0].table_chunks("foo", "cpu", &schema).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn loads_persisted_state_and_replays_unpersisted_wal_segments() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = WalImpl::new(dir).unwrap();
+        let persister = Arc::new(PersisterImpl::new(Arc::new(InMemory::new())));
+        let catalog = Catalog::new();
+
+        for id in 0..3 {
+            let segment_id = SegmentId::new(id);
+            let lp = format!("cpu,host=a val={id}i {id}");
+            let mut writer = wal.open_segment_writer(segment_id).unwrap();
+            writer
+                .write_batch(vec![WalOp::LpWrite(LpWriteOp {
+                    db_name: "foo".to_string(),
+                    lp: lp.clone(),
+                    default_time: 0,
+                    precision: Precision::Nanosecond,
+                })])
+                .unwrap();
+
+            // persist the first and last segments, leaving the middle one only in the wal
+            if id == 1 {
+                continue;
+            }
+            let mut segment = OpenBufferSegment::new(segment_id, catalog.sequence_number(), None);
+            let (sequence, db) = catalog.db_or_create("foo");
+            let result =
+                parse_validate_and_update_schema(&lp, &db, 0, false, Precision::Nanosecond)
+                    .unwrap();
+            if let Some(schema) = result.schema {
+                catalog
+                    .replace_database(sequence, Arc::new(schema))
+                    .unwrap();
+            }
+            segment.buffer_writes(
+                "foo",
+                result.table_batches,
+                lp.len(),
+                Time::from_timestamp_nanos(0),
+            );
+            segment
+                .into_closed_segment(&catalog)
+                .persist(Arc::clone(&persister) as _)
+                .await
+                .unwrap();
+        }
+
+        let state = load_starting_state(
+            persister.as_ref(),
+            Some(&wal),
+            Time::from_timestamp_nanos(0),
+        )
+        .await
+        .unwrap();
+
+        assert!(state.catalog.db_schema("foo").unwrap().table_exists("cpu"));
+        let persisted_ids: Vec<_> = state
+            .persisted_segments
+            .iter()
+            .map(|s| s.segment_id)
+            .collect();
+        assert_eq!(persisted_ids, vec![SegmentId::new(2), SegmentId::new(0)]);
+        let replayed_ids: Vec<_> = state.replayed_segments.iter().map(|s| s.id()).collect();
+        assert_eq!(replayed_ids, vec![SegmentId::new(1)]);
+        assert_eq!(state.next_segment_id, SegmentId::new(3));
+
+        // the wal files of the persisted segments are deleted
+        let wal_ids: Vec<_> = wal
+            .segment_files()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.segment_id)
+            .collect();
+        assert_eq!(wal_ids, vec![SegmentId::new(1)]);
+    }
+
+    #[tokio::test]
+    async fn starts_at_first_segment_without_persisted_state() {
+        let persister = PersisterImpl::new(Arc::new(InMemory::new()));
+        let state =
+            load_starting_state(&persister, None::<&WalImpl>, Time::from_timestamp_nanos(0))
+                .await
+                .unwrap();
+
+        assert!(state.catalog.db_schema("foo").is_none());
+        assert!(state.persisted_segments.is_empty());
+        assert!(state.replayed_segments.is_empty());
+        assert_eq!(state.next_segment_id, SegmentId::new(0));
+    }
+}
@@ -9,6 +9,12 @@
 //!
 //! 2. A [builder](crate::builder::LineProtocolBuilder) to construct valid [InfluxDB Line Protocol]
 //!
+//! 3. A [streaming parser](crate::stream::StreamingParser) that parses [InfluxDB Line Protocol]
+//! as it is received in chunks of bytes
+//!
+//! 4. A [linter](crate::lint::Linter) that reports the problems of each line of a batch of
+//! [InfluxDB Line Protocol] with their positions
+//!
 //! # Example
 //!
 //! Here is an example of how to parse the following line
@@ -76,6 +82,12 @@
 pub mod builder;
 pub use builder::LineProtocolBuilder;
 
+pub mod lint;
+pub use lint::{lint_lines, Diagnostic, LintOptions, Linter};
+
+pub mod stream;
+pub use stream::{LineError, StreamingParser};
+
 use fmt::Display;
 use log::debug;
 use nom::{
@@ -95,7 +107,7 @@ use std::{
     collections::{btree_map::Entry, BTreeMap},
     fmt,
     hash::{Hash, Hasher},
-    ops::Deref,
+    ops::{Deref, Range},
 };
 
 /// String fields are limited to 64K per
@@ -163,13 +175,16 @@ pub enum Error {
 
     #[snafu(display(r#"String is greater than 64KB"#))]
     FieldStringValueTooLarge,
+
+    #[snafu(display(r#"Line is not valid UTF-8: {}"#, source))]
+    InvalidUtf8 { source: std::str::Utf8Error },
 }
 
 /// A specialized [`Result`] type with a default error type of [`Error`].
 ///
 /// [`Result`]: std::result::Result
 pub type Result<T, E = Error> = std::result::Result<T, E>;
-type IResult<I, T, E = Error> = nom::IResult<I, T, E>;
+type IResult<I, T, E = ParseFailure> = nom::IResult<I, T, E>;
 
 impl nom::error::ParseError<&str> for Error {
     fn from_error_kind(_input: &str, kind: nom::error::ErrorKind) -> Self {
@@ -189,6 +204,64 @@ impl nom::error::ParseError<&str> for Error {
     }
 }
 
+/// An error parsing a line, along with the part of the line that caused it.
+#[derive(Debug)]
+pub struct SpannedError {
+    /// The reason the line is invalid.
+    pub source: Error,
+    /// The span of the part of the line that caused the error, in bytes from
+    /// the start of the line. It is empty if something is missing.
+    pub span: Range<usize>,
+}
+
+/// The error of the parsers of a line. As the parsers only see the rest of
+/// the line, the span of the error is recorded as the lengths of the input
+/// that remain at its start and end.
+#[derive(Debug)]
+struct ParseFailure {
+    error: Error,
+    start: usize,
+    end: usize,
+}
+
+impl ParseFailure {
+    /// A failure spanning the part of `input` before `remaining`.
+    fn spanning(error: Error, input: &str, remaining: &str) -> Self {
+        Self {
+            error,
+            start: input.len(),
+            end: remaining.len(),
+        }
+    }
+
+    /// A failure at the start of `input`, where something is missing.
+    fn at(error: Error, input: &str) -> Self {
+        Self::spanning(error, input, input)
+    }
+
+    /// Returns the error with its span in `line`, which the input of the
+    /// parsers is the end of.
+    fn into_spanned(self, line: &str) -> SpannedError {
+        SpannedError {
+            source: self.error,
+            span: (line.len() - self.start)..(line.len() - self.end),
+        }
+    }
+}
+
+impl nom::error::ParseError<&str> for ParseFailure {
+    fn from_error_kind(input: &str, kind: nom::error::ErrorKind) -> Self {
+        Self::at(Error::from_error_kind(input, kind), input)
+    }
+
+    fn append(input: &str, kind: nom::error::ErrorKind, other: Self) -> Self {
+        Self {
+            error: Error::append(input, kind, other.error),
+            ..other
+        }
+    }
+}
+
 /// Represents a single parsed line of line protocol data. See the [crate-level documentation](self)
 /// for more information and examples.
 #[derive(Debug)]
@@ -541,36 +614,58 @@ impl PartialEq<String> for EscapedStr<'_> {
 /// [`ParsedLine`]. See the [crate-level documentation](self) for more
 /// information and examples.
 pub fn parse_lines(input: &str) -> impl Iterator<Item = Result<ParsedLine<'_>>> {
-    split_lines(input).filter_map(|line| {
-        let i = trim_leading(line);
-
-        if i.is_empty() {
-            return None;
-        }
+    split_lines(input)
+        .filter_map(parse_split_line)
+        .map(|res| res.map_err(|e| e.source))
+}
 
-        let res = match parse_line(i) {
-            Ok((remaining, line)) => {
-                // should have parsed the whole input line; if any
-                // data remains it is a parse error for this line.
-                // Corresponding Go logic:
-                // https://github.com/influxdata/influxdb/blob/217eddc87e14a79b01d0c22994fc139f530094a2/models/points_parser.go#L259-L266
-                if !remaining.is_empty() {
-                    Some(Err(Error::CannotParseEntireLine {
-                        trailing_content: String::from(remaining),
-                    }))
-                } else {
-                    Some(Ok(line))
-                }
+/// Parses a single line that has been split from its input by
+/// [`split_lines`], returning `None` if the line is blank or a comment.
+/// Unlike [`parse_lines`], errors have the span of the part of the line that
+/// caused them.
+pub fn parse_split_line(line: &str) -> Option<Result<ParsedLine<'_>, SpannedError>> {
+    let i = trim_leading(line);
+
+    if i.is_empty() {
+        return None;
+    }
+
+    let res = match parse_line(i) {
+        Ok((remaining, parsed)) => {
+            // should have parsed the whole input line; if any
+            // data remains it is a parse error for this line.
+            // Corresponding Go logic:
+            // https://github.com/influxdata/influxdb/blob/217eddc87e14a79b01d0c22994fc139f530094a2/models/points_parser.go#L259-L266
+            if !remaining.is_empty() {
+                let error = Error::CannotParseEntireLine {
+                    trailing_content: String::from(remaining),
+                };
+                // a field that doesn't parse ends the field set, which may
+                // have consumed the comma before it
+                let after_comma = line[..line.len() - remaining.len()].ends_with(',');
+                let field = remaining
+                    .strip_prefix(',')
+                    .or_else(|| after_comma.then_some(remaining));
+                let failure = match field {
+                    Some(field) => invalid_pair(error, field, true),
+                    None => {
+                        let trailing = remaining.trim_start();
+                        ParseFailure::spanning(error, trailing, &trailing[token_len(trailing)..])
+                    }
+                };
+                Some(Err(failure.into_spanned(line)))
+            } else {
+                Some(Ok(parsed))
             }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Some(Err(e)),
-            Err(nom::Err::Incomplete(_)) => unreachable!("Cannot have incomplete data"), // Only streaming parsers have this
-        };
-
-        if let Some(Err(r)) = &res {
-            debug!("Error parsing line: '{}'. Error was {:?}", line, r);
         }
-        res
-    })
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Some(Err(e.into_spanned(line))),
+        Err(nom::Err::Incomplete(_)) => unreachable!("Cannot have incomplete data"), // Only streaming parsers have this
+    };
+
+    if let Some(Err(r)) = &res {
+        debug!("Error parsing line: '{}'. Error was {:?}", line, r);
+    }
+    res
 }
 
 /// Split `input` into individual lines to be parsed, based on the
@@ -584,73 +679,140 @@ pub fn parse_lines(input: &str) -> impl Iterator<Item = Result<ParsedLine<'_>>>
 /// we can be more sure of the compatibility of the Rust parser and
 /// the canonical Go parser.
 pub fn split_lines(input: &str) -> impl Iterator<Item = &str> {
-    // NB: This is ported as closely as possible from the original Go code:
-    let mut quoted = false;
-    let mut fields = false;
+    let mut splitter = LineSplitter::default();
+    input.split(move |c| splitter.is_line_end(c))
+}
+
+/// The state of splitting input into lines, which is kept between
+/// characters so that newlines within quoted strings don't end a line.
+#[derive(Debug, Default, Clone, Copy)]
+struct LineSplitter {
+    quoted: bool,
+    fields: bool,
 
     // tracks how many '=' and commas we've seen
     // this duplicates some of the functionality in scanFields
-    let mut equals = 0;
-    let mut commas = 0;
+    equals: usize,
+    commas: usize,
+
+    in_escape: bool,
+}
+
+impl LineSplitter {
+    /// Determines if `c`, the next character of the input, ends the current
+    /// line.
+    fn is_line_end(&mut self, c: char) -> bool {
+        // NB: This is ported as closely as possible from the original Go code:
 
-    let mut in_escape = false;
-    input.split(move |c| {
         // skip past escaped characters
-        if in_escape {
-            in_escape = false;
+        if self.in_escape {
+            self.in_escape = false;
             return false;
         }
 
         if c == '\\' {
-            in_escape = true;
+            self.in_escape = true;
             return false;
         }
 
         if c == ' ' {
-            fields = true;
+            self.fields = true;
             return false;
         }
 
         // If we see a double quote, makes sure it is not escaped
-        if fields {
-            if !quoted && c == '=' {
-                equals += 1;
+        if self.fields {
+            if !self.quoted && c == '=' {
+                self.equals += 1;
                 return false;
-            } else if !quoted && c == ',' {
-                commas += 1;
+            } else if !self.quoted && c == ',' {
+                self.commas += 1;
                 return false;
-            } else if c == '"' && equals > commas {
-                quoted = !quoted;
+            } else if c == '"' && self.equals > self.commas {
+                self.quoted = !self.quoted;
                 return false;
             }
         }
 
-        if c == '\n' && !quoted {
+        if c == '\n' && !self.quoted {
             // reset all the state -- we found a line
-            quoted = false;
-            fields = false;
-            equals = 0;
-            commas = 0;
-            assert!(!in_escape);
-            in_escape = false;
+            assert!(!self.in_escape);
+            *self = Self::default();
             return true;
         }
 
         false
-    })
+    }
 }
 
 fn parse_line(i: &str) -> IResult<&str, ParsedLine<'_>> {
-    let field_set = preceded(whitespace, field_set);
+    let (i, series) = series(i)?;
+    let (i, field_set) = match preceded(whitespace, field_set)(i) {
+        // a tag that doesn't parse ends the tag set, so the fields don't
+        // follow it
+        Err(nom::Err::Error(e)) if i.starts_with(',') => {
+            return Err(nom::Err::Error(invalid_pair(e.error, &i[1..], false)));
+        }
+        res => res?,
+    };
     let timestamp = preceded(whitespace, terminated(timestamp, opt(whitespace)));
+    let (i, timestamp) = opt(timestamp)(i)?;
 
-    let line = tuple((series, field_set, opt(timestamp)));
+    Ok((
+        i,
+        ParsedLine {
+            series,
+            field_set,
+            timestamp,
+        },
+    ))
+}
 
-    map(line, |(series, field_set, timestamp)| ParsedLine {
-        series,
-        field_set,
-        timestamp,
-    })(i)
+/// Returns the failure of a tag or field at the start of `i` that doesn't
+/// parse, which spans its value if its key is followed by `=`, its key if
+/// not, and is empty if it has no key.
+fn invalid_pair(error: Error, i: &str, field: bool) -> ParseFailure {
+    let key = if field { field_key(i) } else { tag_key(i) };
+    match key {
+        Ok((remaining, _)) if remaining.len() == i.len() => ParseFailure::at(error, i),
+        Err(_) => ParseFailure::at(error, i),
+        Ok((remaining, _)) => match remaining.strip_prefix('=') {
+            Some(value) if field => {
+                ParseFailure::spanning(error, value, &value[quoted_token_len(value)..])
+            }
+            Some(value) => ParseFailure::spanning(error, value, &value[token_len(value)..]),
+            None => ParseFailure::spanning(error, i, remaining),
+        },
+    }
+}
+
+/// Returns the length of the token at the start of `i`, which ends at
+/// whitespace or an unescaped comma.
+fn token_len(i: &str) -> usize {
+    scan_token(i, false)
+}
+
+/// Returns the length of the token at the start of `i` like [`token_len`],
+/// skipping over quoted strings.
+fn quoted_token_len(i: &str) -> usize {
+    scan_token(i, true)
+}
+
+fn scan_token(i: &str, quoted: bool) -> usize {
+    let mut in_quotes = false;
+    let mut chars = i.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' if quoted => in_quotes = !in_quotes,
+            ',' if !in_quotes => return idx,
+            c if is_whitespace_boundary_char(c) && !in_quotes => return idx,
+            _ => {}
+        }
+    }
+    i.len()
 }
 
 fn series(i: &str) -> IResult<&str, Series<'_>> {
@@ -669,19 +831,20 @@ fn series(i: &str) -> IResult<&str, Series<'_>> {
 
 /// Tagsets are optional, but if a comma follows the measurement, then we must have at least one tag=value pair.
 /// anything else is an error
-fn maybe_tagset(i: &str) -> IResult<&str, Option<TagSet<'_>>, Error> {
-    match tag::<&str, &str, Error>(",")(i) {
+fn maybe_tagset(i: &str) -> IResult<&str, Option<TagSet<'_>>> {
+    match tag::<&str, &str, ParseFailure>(",")(i) {
         Err(nom::Err::Error(_)) => Ok((i, None)),
         Ok((remainder, _)) => {
+            let malformed = || invalid_pair(Error::TagSetMalformed, remainder, false);
             match tag_set(remainder) {
                 Ok((i, ts)) => {
                     // reaching here, we must find a tagset, which is at least one tag=value pair.
                     if ts.is_empty() {
-                        return Err(nom::Err::Error(Error::TagSetMalformed));
+                        return Err(nom::Err::Error(malformed()));
                     }
                     Ok((i, Some(ts)))
                 }
-                Err(nom::Err::Error(_)) => TagSetMalformedSnafu.fail().map_err(nom::Err::Error),
+                Err(nom::Err::Error(_)) => Err(nom::Err::Error(malformed())),
                 Err(e) => Err(e),
             }
         }
@@ -689,7 +852,7 @@ fn maybe_tagset(i: &str) -> IResult<&str, Option<TagSet<'_>>, Error> {
     }
 }
 
-fn measurement(i: &str) -> IResult<&str, Measurement<'_>, Error> {
+fn measurement(i: &str) -> IResult<&str, Measurement<'_>> {
     let normal_char = take_while1(|c| {
         !is_whitespace_boundary_char(c) && !is_null_char(c) && c != ',' && c != '\\'
     });
@@ -701,7 +864,10 @@ fn measurement(i: &str) -> IResult<&str, Measurement<'_>, Error> {
     let escaped = alt((comma, space, backslash));
 
     match escape_or_fallback(normal_char, "\\", escaped)(i) {
-        Err(nom::Err::Error(_)) => MeasurementValueInvalidSnafu.fail().map_err(nom::Err::Error),
+        Err(nom::Err::Error(_)) => Err(nom::Err::Error(ParseFailure::at(
+            Error::MeasurementValueInvalid,
+            i,
+        ))),
         other => other,
     }
 }
@@ -723,11 +889,15 @@ fn tag_value(i: &str) -> IResult<&str, EscapedStr<'_>> {
 }
 
 fn field_set(i: &str) -> IResult<&str, FieldSet<'_>> {
-    let one_field = separated_pair(field_key, tag("="), field_value);
+    let one_field = separated_pair(field_key, tag("="), delimited_field_value);
     let sep = tag(",");
 
     match parameterized_separated_list1(sep, one_field, SmallVec::new, |v, i| v.push(i))(i) {
-        Err(nom::Err::Error(_)) => FieldSetMissingSnafu.fail().map_err(nom::Err::Error),
+        Err(nom::Err::Error(_)) => Err(nom::Err::Error(invalid_pair(
+            Error::FieldSetMissing,
+            i,
+            true,
+        ))),
         other => other,
     }
 }
@@ -737,6 +907,24 @@ fn field_key(i: &str) -> IResult<&str, EscapedStr<'_>> {
     escaped_value(normal_char)(i)
 }
 
+/// Parses a field value that is followed by the end of the field. The rest of
+/// the line after a value followed by anything else is trailing content that
+/// can't be parsed.
+fn delimited_field_value(i: &str) -> IResult<&str, FieldValue<'_>> {
+    let (remaining, value) = field_value(i)?;
+    match remaining.chars().next() {
+        None | Some(',') => Ok((remaining, value)),
+        Some(c) if is_whitespace_boundary_char(c) => Ok((remaining, value)),
+        Some(_) => {
+            let error = Error::CannotParseEntireLine {
+                trailing_content: String::from(remaining),
+            };
+            let end = &remaining[token_len(remaining)..];
+            Err(nom::Err::Failure(ParseFailure::spanning(error, i, end)))
+        }
+    }
+}
+
 fn field_value(i: &str) -> IResult<&str, FieldValue<'_>> {
     let int = map(field_integer_value, FieldValue::I64);
     let uint = map(field_uinteger_value, FieldValue::U64);
@@ -919,11 +1107,15 @@ fn escape_or_fallback<'a>(
         let (remaining, s) = escape_or_fallback_inner(normal, escape_char, escaped)(i)?;
 
         if s.ends_with('\\') {
-            EndsWithBackslashSnafu.fail().map_err(nom::Err::Failure)
+            let error = Error::EndsWithBackslash;
+            Err(nom::Err::Failure(ParseFailure::spanning(
+                error, i, remaining,
+            )))
         } else if s.len() > STRING_LENGTH_LIMIT_IN_BYTES {
-            FieldStringValueTooLargeSnafu
-                .fail()
-                .map_err(nom::Err::Failure)
+            let error = Error::FieldStringValueTooLarge;
+            Err(nom::Err::Failure(ParseFailure::spanning(
+                error, i, remaining,
+            )))
         } else {
             Ok((remaining, s))
         }
@@ -1121,7 +1313,7 @@ fn map_fail<'a, R1, R2>(
 
         match second(value) {
             Ok(v) => Ok((remaining, v)),
-            Err(e) => Err(nom::Err::Failure(e)),
+            Err(e) => Err(nom::Err::Failure(ParseFailure::spanning(e, i, remaining))),
         }
     }
 }
@@ -2009,7 +2201,10 @@ her"#,
         let parsed = measurement(r"weather\");
         assert!(matches!(
             parsed,
-            Err(nom::Err::Failure(super::Error::EndsWithBackslash))
+            Err(nom::Err::Failure(ParseFailure {
+                error: super::Error::EndsWithBackslash,
+                ..
+            }))
         ));
     }
 
@@ -2065,7 +2260,10 @@ her"#,
         let parsed = tag_key(r"weather\");
         assert!(matches!(
             parsed,
-            Err(nom::Err::Failure(super::Error::EndsWithBackslash))
+            Err(nom::Err::Failure(ParseFailure {
+                error: super::Error::EndsWithBackslash,
+                ..
+            }))
         ));
     }
 
@@ -2121,7 +2319,10 @@ her"#,
         let parsed = tag_value(r"weather\");
         assert!(matches!(
             parsed,
-            Err(nom::Err::Failure(super::Error::EndsWithBackslash))
+            Err(nom::Err::Failure(ParseFailure {
+                error: super::Error::EndsWithBackslash,
+                ..
+            }))
         ));
     }
 
@@ -2177,7 +2378,10 @@ her"#,
         let parsed = field_key(r"weather\");
         assert!(matches!(
             parsed,
-            Err(nom::Err::Failure(super::Error::EndsWithBackslash))
+            Err(nom::Err::Failure(ParseFailure {
+                error: super::Error::EndsWithBackslash,
+                ..
+            }))
         ));
     }
 
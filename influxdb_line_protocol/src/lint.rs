@@ -0,0 +1,654 @@
+//! Checks batches of [line protocol] for problems beyond whether each line
+//! parses, and reports where in each line the problems are, along with the
+//! token that caused them.
+//!
+//! [line protocol]: https://docs.influxdata.com/influxdb/cloud/reference/syntax/line-protocol
+use crate::{parse_split_line, split_lines, trim_leading, Error, FieldValue, SpannedError};
+use snafu::Snafu;
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::{Range, RangeInclusive},
+};
+
+/// The range of nanosecond timestamps that InfluxDB accepts, which leaves out
+/// the values it reserves at the limits of `i64`.
+const DEFAULT_TIMESTAMP_RANGE: RangeInclusive<i64> = (i64::MIN + 2)..=(i64::MAX - 1);
+
+/// The type of a column that is a tag.
+const TAG_TYPE: &str = "tag";
+
+/// A problem with a line of line protocol.
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum Problem {
+    #[snafu(display("{}", source))]
+    Parse { source: Error },
+
+    #[snafu(display(r#"Tag "{}" is repeated"#, tag_key))]
+    DuplicateTag { tag_key: String },
+
+    #[snafu(display(r#"Field "{}" is repeated"#, field_key))]
+    DuplicateField { field_key: String },
+
+    #[snafu(display(
+        r#"Column "{}" of measurement "{}" has type {}, but it has type {} on line {}"#,
+        column,
+        measurement,
+        column_type,
+        existing_type,
+        existing_line_number
+    ))]
+    ColumnTypeConflict {
+        measurement: String,
+        column: String,
+        column_type: &'static str,
+        existing_type: &'static str,
+        existing_line_number: usize,
+    },
+
+    #[snafu(display("Timestamp {} is outside of the range {} to {}", timestamp, min, max))]
+    TimestampOutOfRange { timestamp: i64, min: i64, max: i64 },
+}
+
+/// A problem with a line, along with the position of the token in the line
+/// that caused it.
+///
+/// It is displayed with the line and carets under the token:
+///
+/// ```text
+/// error: Unable to parse integer value '99999999999999999999'
+///  --> line 2, column 18
+///   |
+/// 2 | cpu,host=a usage=99999999999999999999i 1
+///   |                  ^^^^^^^^^^^^^^^^^^^^^
+/// ```
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// The number of the line, starting at 1.
+    pub line_number: usize,
+    /// The offset in bytes of the start of the token from the start of the
+    /// line, starting at 1.
+    pub column: usize,
+    /// The token that caused the problem, which is empty if the problem is
+    /// that something is missing.
+    pub token: String,
+    /// The line the problem is in.
+    pub line: String,
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    /// Returns the diagnostic of an error returned by
+    /// [`parse_split_line`] for a line, which was split from its input by
+    /// [`split_lines`].
+    pub fn from_parse_error(line_number: usize, line: &str, error: SpannedError) -> Self {
+        let SpannedError { source, span } = error;
+        Self::new(line_number, line, span, Problem::Parse { source })
+    }
+
+    fn new(line_number: usize, line: &str, span: Range<usize>, problem: Problem) -> Self {
+        Self {
+            line_number,
+            column: span.start + 1,
+            token: line[span].to_string(),
+            line: line.to_string(),
+            problem,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // string field values may have newlines in them, so only the part of
+        // the line that the token starts in is shown
+        let start = self.column - 1;
+        let line_start = self.line[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = self.line[start..]
+            .find('\n')
+            .map_or(self.line.len(), |idx| start + idx);
+        let line = &self.line[line_start..line_end];
+
+        // tabs are kept so that the carets line up with the token
+        let indent: String = self.line[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let token_len = self.token.split('\n').next().unwrap_or_default().chars();
+        let carets = "^".repeat(token_len.count().max(1));
+
+        let line_number = self.line_number.to_string();
+        let gutter = " ".repeat(line_number.len());
+        writeln!(f, "error: {}", self.problem)?;
+        writeln!(
+            f,
+            "{gutter}--> line {}, column {}",
+            self.line_number, self.column
+        )?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{line_number} | {line}")?;
+        write!(f, "{gutter} | {indent}{carets}")
+    }
+}
+
+/// The options of a [`Linter`].
+#[derive(Debug, Clone)]
+pub struct LintOptions {
+    timestamp_range: RangeInclusive<i64>,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        Self {
+            timestamp_range: DEFAULT_TIMESTAMP_RANGE,
+        }
+    }
+}
+
+impl LintOptions {
+    /// Sets the range of timestamps that are accepted, in the precision of the
+    /// timestamps of the lines. By default, the timestamps are expected to be
+    /// in nanoseconds.
+    pub fn with_timestamp_range(mut self, timestamp_range: RangeInclusive<i64>) -> Self {
+        self.timestamp_range = timestamp_range;
+        self
+    }
+
+    /// The range of timestamps that are accepted.
+    pub fn timestamp_range(&self) -> RangeInclusive<i64> {
+        self.timestamp_range.clone()
+    }
+}
+
+/// Checks the lines of a batch of line protocol for problems. Along with the
+/// errors of lines that don't parse, it reports:
+///
+/// * tags and fields that are repeated within a line
+/// * columns that have a different type than they had on an earlier line of
+///   the batch for the same measurement, including columns that are a tag on
+///   one line and a field on another
+/// * timestamps that are out of range
+///
+/// The columns of a line that has problems are not compared with later lines.
+///
+/// ```
+/// use influxdb_line_protocol::lint::{lint_lines, LintOptions, Problem};
+///
+/// let lp = "cpu,host=a usage=1i 1\ncpu,host=b usage=0.5 2";
+/// let diagnostics = lint_lines(lp, LintOptions::default());
+///
+/// assert_eq!(diagnostics.len(), 1);
+/// assert_eq!(diagnostics[0].line_number, 2);
+/// assert_eq!(diagnostics[0].column, 18);
+/// assert_eq!(diagnostics[0].token, "0.5");
+/// assert!(matches!(
+///     diagnostics[0].problem,
+///     Problem::ColumnTypeConflict { existing_line_number: 1, .. }
+/// ));
+/// ```
+#[derive(Debug, Default)]
+pub struct Linter {
+    options: LintOptions,
+    // The type of each column of each measurement, along with the line it was
+    // first seen on.
+    column_types: HashMap<String, HashMap<String, (&'static str, usize)>>,
+}
+
+impl Linter {
+    pub fn new(options: LintOptions) -> Self {
+        Self {
+            options,
+            column_types: HashMap::new(),
+        }
+    }
+
+    /// Checks the next line of the batch, which was split from it by
+    /// [`split_lines`], returning its problems.
+    pub fn lint_line(&mut self, line_number: usize, line: &str) -> Vec<Diagnostic> {
+        let parsed = match parse_split_line(line) {
+            None => return vec![],
+            Some(Ok(parsed)) => parsed,
+            Some(Err(error)) => {
+                return vec![Diagnostic::from_parse_error(line_number, line, error)]
+            }
+        };
+        let spans = LineSpans::new(line);
+
+        let mut diagnostics = vec![];
+        let measurement = parsed.series.measurement.as_str();
+        let existing_types = self.column_types.get(measurement);
+
+        // the spans of a line that parses line up with its tags and fields;
+        // fields are pointed at by their values, which determine their types
+        let tags = parsed.series.tag_set.iter().flatten();
+        let tags = tags
+            .zip(&spans.tags)
+            .map(|((key, _), span)| (key.as_str(), TAG_TYPE, span.key.clone(), span.key.clone()));
+        let fields = parsed
+            .field_set
+            .iter()
+            .zip(&spans.fields)
+            .map(|((key, value), span)| {
+                let value_span = span.value.clone().unwrap_or_else(|| span.key.clone());
+                (
+                    key.as_str(),
+                    field_type(value),
+                    span.key.clone(),
+                    value_span,
+                )
+            });
+
+        let mut line_types: HashMap<&str, &'static str> = HashMap::new();
+        for (column, column_type, key_span, type_span) in tags.chain(fields) {
+            if let Some(&line_type) = line_types.get(column) {
+                let (span, problem) = match (line_type == TAG_TYPE, column_type == TAG_TYPE) {
+                    (true, true) => (
+                        key_span,
+                        Problem::DuplicateTag {
+                            tag_key: column.to_string(),
+                        },
+                    ),
+                    (false, false) => (
+                        key_span,
+                        Problem::DuplicateField {
+                            field_key: column.to_string(),
+                        },
+                    ),
+                    _ => (
+                        type_span,
+                        Problem::ColumnTypeConflict {
+                            measurement: measurement.to_string(),
+                            column: column.to_string(),
+                            column_type,
+                            existing_type: line_type,
+                            existing_line_number: line_number,
+                        },
+                    ),
+                };
+                diagnostics.push(Diagnostic::new(line_number, line, span, problem));
+                continue;
+            }
+            line_types.insert(column, column_type);
+
+            let existing = existing_types.and_then(|types| types.get(column));
+            if let Some(&(existing_type, existing_line_number)) = existing {
+                if existing_type != column_type {
+                    let problem = Problem::ColumnTypeConflict {
+                        measurement: measurement.to_string(),
+                        column: column.to_string(),
+                        column_type,
+                        existing_type,
+                        existing_line_number,
+                    };
+                    diagnostics.push(Diagnostic::new(line_number, line, type_span, problem));
+                }
+            }
+        }
+
+        if let (Some(timestamp), Some(span)) = (parsed.timestamp, &spans.timestamp) {
+            let range = &self.options.timestamp_range;
+            if !range.contains(&timestamp) {
+                let problem = Problem::TimestampOutOfRange {
+                    timestamp,
+                    min: *range.start(),
+                    max: *range.end(),
+                };
+                diagnostics.push(Diagnostic::new(line_number, line, span.clone(), problem));
+            }
+        }
+
+        if diagnostics.is_empty() {
+            let types = self
+                .column_types
+                .entry(measurement.to_string())
+                .or_default();
+            for (column, column_type) in line_types {
+                types
+                    .entry(column.to_string())
+                    .or_insert((column_type, line_number));
+            }
+        }
+        diagnostics
+    }
+}
+
+/// Checks the lines of a batch of line protocol with a [`Linter`], returning
+/// the problems of every line.
+pub fn lint_lines(input: &str, options: LintOptions) -> Vec<Diagnostic> {
+    let mut linter = Linter::new(options);
+    split_lines(input)
+        .enumerate()
+        .flat_map(|(idx, line)| linter.lint_line(idx + 1, line))
+        .collect()
+}
+
+fn field_type(value: &FieldValue<'_>) -> &'static str {
+    match value {
+        FieldValue::I64(_) => "integer",
+        FieldValue::U64(_) => "unsigned integer",
+        FieldValue::F64(_) => "float",
+        FieldValue::String(_) => "string",
+        FieldValue::Boolean(_) => "boolean",
+    }
+}
+
+/// The spans of the key and value of a tag or field.
+#[derive(Debug, Clone)]
+struct PairSpan {
+    key: Range<usize>,
+    // `None` if the pair has no `=`
+    value: Option<Range<usize>>,
+}
+
+/// The spans of the parts of a line that parses, in bytes from the start of
+/// the line.
+#[derive(Debug)]
+struct LineSpans {
+    tags: Vec<PairSpan>,
+    fields: Vec<PairSpan>,
+    timestamp: Option<Range<usize>>,
+}
+
+impl LineSpans {
+    fn new(line: &str) -> Self {
+        let mut scanner = Scanner {
+            line,
+            pos: line.len() - trim_leading(line).len(),
+        };
+
+        scanner.token(|c| c == ',', false);
+        let mut tags = vec![];
+        if scanner.next_is(',') {
+            tags = scanner.pairs(false);
+        }
+
+        scanner.skip_spaces();
+        let fields = scanner.pairs(true);
+
+        scanner.skip_spaces();
+        let timestamp = Some(scanner.token(|_| false, false)).filter(|span| !span.is_empty());
+
+        Self {
+            tags,
+            fields,
+            timestamp,
+        }
+    }
+}
+
+/// Scans the unescaped separators of a line.
+struct Scanner<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn peek(&self) -> Option<char> {
+        self.line[self.pos..].chars().next()
+    }
+
+    /// Moves past the next character if it is `c`.
+    fn next_is(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += c.len_utf8();
+        }
+        found
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.next_is(' ') {}
+    }
+
+    /// Returns the span of the token at the position, which ends at whitespace
+    /// or an unescaped separator. Quoted strings are skipped if `quoted` is
+    /// set.
+    fn token(&mut self, is_separator: impl Fn(char) -> bool, quoted: bool) -> Range<usize> {
+        let start = self.pos;
+        let mut in_quotes = false;
+        while let Some(c) = self.peek() {
+            if !in_quotes && (matches!(c, ' ' | '\t' | '\n') || is_separator(c)) {
+                break;
+            }
+            self.pos += c.len_utf8();
+            if c == '\\' {
+                if let Some(escaped) = self.peek() {
+                    self.pos += escaped.len_utf8();
+                }
+            } else if c == '"' && quoted {
+                in_quotes = !in_quotes;
+            }
+        }
+        start..self.pos
+    }
+
+    /// Returns the spans of the comma separated `key=value` pairs at the
+    /// position. The values of fields may be quoted.
+    fn pairs(&mut self, fields: bool) -> Vec<PairSpan> {
+        let mut pairs = vec![];
+        loop {
+            let key = self.token(|c| c == ',' || c == '=', false);
+            let value = self.next_is('=').then(|| self.token(|c| c == ',', fields));
+            if key.is_empty() && value.is_none() && pairs.is_empty() {
+                return pairs;
+            }
+            pairs.push(PairSpan { key, value });
+            if !self.next_is(',') {
+                return pairs;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the line number, column and token of each diagnostic.
+    fn positions(diagnostics: &[Diagnostic]) -> Vec<(usize, usize, &str)> {
+        diagnostics
+            .iter()
+            .map(|d| (d.line_number, d.column, d.token.as_str()))
+            .collect()
+    }
+
+    fn parse_error_position(line: &str) -> (usize, String) {
+        let error = parse_split_line(line).unwrap().unwrap_err();
+        let diagnostic = Diagnostic::from_parse_error(1, line, error);
+        (diagnostic.column, diagnostic.token)
+    }
+
+    #[test]
+    fn parse_error_positions() {
+        let cases = [
+            (",host=a usage=1i", 1, ""),
+            ("cpu,host=a,=b usage=1i", 12, ""),
+            ("cpu,host=a,region usage=1i", 12, "region"),
+            (
+                "cpu,host=a usage=99999999999999999999i 1",
+                18,
+                "99999999999999999999i",
+            ),
+            (
+                "cpu usage=1i,free=99999999999999999999u",
+                19,
+                "99999999999999999999u",
+            ),
+            ("cpu usage=1i,free=1.2.3", 19, "1.2.3"),
+            ("cpu usage=1i,free=1x 1", 19, "1x"),
+            ("cpu usage=1i,free", 14, "free"),
+            ("cpu usage=1i,=1i", 14, ""),
+            ("cpu usage=1i 1 2", 16, "2"),
+            (
+                "cpu usage=1i 99999999999999999999",
+                14,
+                "99999999999999999999",
+            ),
+            ("cpu usage=\"unterminated", 11, "\"unterminated"),
+            ("cpu", 4, ""),
+            ("  cpu,host=a", 13, ""),
+        ];
+        for (line, column, token) in cases {
+            assert_eq!(
+                parse_error_position(line),
+                (column, token.to_string()),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_error_position_of_long_string() {
+        let value = "a".repeat(crate::STRING_LENGTH_LIMIT_IN_BYTES + 1);
+        let line = format!("cpu,host=a\\ b name=\"x\",value=\"{value}\" 1");
+        let (column, token) = parse_error_position(&line);
+        assert_eq!(column, 30);
+        assert_eq!(token, format!("\"{value}\""));
+    }
+
+    #[test]
+    fn display_diagnostic() {
+        let lp = "cpu usage=1i\ncpu,host=a usage=99999999999999999999i 1";
+        let diagnostics = lint_lines(lp, LintOptions::default());
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: Unable to parse integer value '99999999999999999999'\n \
+             --> line 2, column 18\n  \
+             |\n\
+             2 | cpu,host=a usage=99999999999999999999i 1\n  \
+             |                  ^^^^^^^^^^^^^^^^^^^^^"
+        );
+
+        // only the part of a line with the token is shown
+        let lp = "cpu name=\"a\nb\",usage=1.5.5";
+        let diagnostics = lint_lines(lp, LintOptions::default());
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: Could not parse entire line. Found trailing content: '.5'\n \
+             --> line 1, column 22\n  \
+             |\n\
+             1 | b\",usage=1.5.5\n  \
+             |          ^^^^^"
+        );
+    }
+
+    #[test]
+    fn lint_duplicates() {
+        let lp = "cpu,host=a,host=b,region=west usage=1i,free=2i,usage=3i 1";
+        let diagnostics = lint_lines(lp, LintOptions::default());
+        assert_eq!(
+            positions(&diagnostics),
+            vec![(1, 12, "host"), (1, 48, "usage")]
+        );
+        assert!(matches!(
+            &diagnostics[0].problem,
+            Problem::DuplicateTag { tag_key } if tag_key == "host"
+        ));
+        assert!(matches!(
+            &diagnostics[1].problem,
+            Problem::DuplicateField { field_key } if field_key == "usage"
+        ));
+
+        // escaped keys are compared once they are unescaped
+        let lp = "cpu,a\\ b=1,a\\ b=2 usage=1i";
+        let diagnostics = lint_lines(lp, LintOptions::default());
+        assert_eq!(positions(&diagnostics), vec![(1, 12, "a\\ b")]);
+    }
+
+    #[test]
+    fn lint_type_conflicts() {
+        let lp = "cpu,host=a usage=1i,idle=true 1\n\
+            mem free=1.0\n\
+            \n\
+            cpu usage=2u,host=\"b\" 2\n\
+            cpu,usage=c idle=false 3\n\
+            mem free=2i\n\
+            mem,free=a used=1 4\n\
+            mem free=3i";
+        let diagnostics = lint_lines(lp, LintOptions::default());
+        assert_eq!(
+            positions(&diagnostics),
+            vec![
+                (4, 11, "2u"),
+                (4, 19, "\"b\""),
+                (5, 5, "usage"),
+                (6, 10, "2i"),
+                (7, 5, "free"),
+                (8, 10, "3i"),
+            ]
+        );
+
+        let Problem::ColumnTypeConflict {
+            measurement,
+            column,
+            column_type,
+            existing_type,
+            existing_line_number,
+        } = &diagnostics[1].problem
+        else {
+            panic!("unexpected problem: {:?}", diagnostics[1].problem);
+        };
+        assert_eq!(measurement, "cpu");
+        assert_eq!(column, "host");
+        assert_eq!(*column_type, "string");
+        assert_eq!(*existing_type, "tag");
+        assert_eq!(*existing_line_number, 1);
+
+        // the types of the lines with problems are not recorded, so the last
+        // line is only compared with the second line
+        assert!(matches!(
+            diagnostics[5].problem,
+            Problem::ColumnTypeConflict {
+                existing_type: "float",
+                existing_line_number: 2,
+                ..
+            }
+        ));
+
+        // a column that is a tag and a field of the same line
+        let diagnostics = lint_lines("cpu,usage=a usage=1i", LintOptions::default());
+        assert_eq!(positions(&diagnostics), vec![(1, 19, "1i")]);
+    }
+
+    #[test]
+    fn lint_timestamps() {
+        let lp = format!(
+            "cpu usage=1i {}\ncpu usage=1i 1\ncpu usage=1i\ncpu usage=1i {}",
+            i64::MIN,
+            i64::MAX
+        );
+        let diagnostics = lint_lines(&lp, LintOptions::default());
+        assert_eq!(
+            positions(&diagnostics),
+            vec![
+                (1, 14, "-9223372036854775808"),
+                (4, 14, "9223372036854775807")
+            ]
+        );
+
+        let options = LintOptions::default().with_timestamp_range(0..=10);
+        let diagnostics = lint_lines("cpu usage=1i -1\ncpu usage=1i 10", options);
+        assert_eq!(positions(&diagnostics), vec![(1, 14, "-1")]);
+        assert!(matches!(
+            diagnostics[0].problem,
+            Problem::TimestampOutOfRange {
+                timestamp: -1,
+                min: 0,
+                max: 10
+            }
+        ));
+    }
+
+    #[test]
+    fn lint_reports_parse_errors() {
+        let lp = "cpu usage=1i\n# comment\ncpu usage=\ncpu usage=1.0";
+        let diagnostics = lint_lines(lp, LintOptions::default());
+        assert_eq!(positions(&diagnostics), vec![(3, 11, ""), (4, 11, "1.0")]);
+        assert!(matches!(
+            diagnostics[0].problem,
+            Problem::Parse {
+                source: Error::FieldSetMissing
+            }
+        ));
+    }
+}
@@ -0,0 +1,351 @@
+//! Incremental parsing of [line protocol] that is received in chunks of bytes,
+//! such as the body of a request, so that lines can be parsed as they arrive
+//! rather than after all of the input has been buffered.
+//!
+//! [line protocol]: https://docs.influxdata.com/influxdb/cloud/reference/syntax/line-protocol
+use crate::{parse_split_line, Error, LineSplitter, ParsedLine, SpannedError};
+use snafu::Snafu;
+use std::ops::Range;
+
+/// An error parsing a line of a stream of line protocol, with the position of
+/// the line in the stream.
+#[derive(Debug, Snafu)]
+#[snafu(display(
+    "Error parsing line {} at byte offset {}: {}",
+    line_number,
+    offset,
+    source
+))]
+pub struct LineError {
+    /// The number of the line in the stream, starting at 1.
+    pub line_number: usize,
+    /// The offset in bytes of the start of the line from the start of the
+    /// stream.
+    pub offset: u64,
+    /// The line, with any invalid UTF-8 replaced.
+    pub line: String,
+    /// The span in bytes of the part of `line` that caused the error.
+    pub span: Range<usize>,
+    /// The reason the line is invalid.
+    pub source: Error,
+}
+
+/// Parses [line protocol] from chunks of bytes as they are received, yielding
+/// the [`ParsedLine`]s of the lines that are complete after each chunk.
+///
+/// Lines may be split across chunks anywhere, including in the middle of a
+/// UTF-8 character; the start of a line that is not complete is kept until
+/// the rest of it is pushed. Like [`parse_lines`](crate::parse_lines), an
+/// invalid line is reported without stopping the lines after it from being
+/// parsed.
+///
+/// The parsed lines borrow from the parser, so they must be converted or
+/// dropped before the next chunk is pushed.
+///
+/// ```
+/// use influxdb_line_protocol::StreamingParser;
+///
+/// let mut parser = StreamingParser::new();
+/// let mut hosts = vec![];
+///
+/// // the second line is split across the chunks
+/// let chunks: [&[u8]; 2] = [
+///     b"cpu,host=A usage=1i 1\ncpu,host=B us",
+///     b"age=2i 1\ncpu,host=C usage=3i 1",
+/// ];
+/// for chunk in chunks {
+///     for line in parser.push(chunk) {
+///         let line = line.expect("should parse");
+///         hosts.push(line.series.tag_set.unwrap()[0].1.to_string());
+///     }
+/// }
+/// assert_eq!(hosts, ["A", "B"]);
+///
+/// // the last line of the stream doesn't need to end with a newline
+/// for line in parser.finish() {
+///     let line = line.expect("should parse");
+///     hosts.push(line.series.tag_set.unwrap()[0].1.to_string());
+/// }
+/// assert_eq!(hosts, ["A", "B", "C"]);
+/// ```
+///
+/// [line protocol]: https://docs.influxdata.com/influxdb/cloud/reference/syntax/line-protocol
+#[derive(Debug, Default)]
+pub struct StreamingParser {
+    // The bytes that have been received but not parsed, which start with the
+    // lines that were last returned until the next chunk is pushed.
+    buf: Vec<u8>,
+    // The length of the lines that were last returned.
+    returned_len: usize,
+    // The length of `buf` that has been scanned for the ends of lines, and the
+    // state of the scan at that point.
+    scanned_len: usize,
+    splitter: LineSplitter,
+    // The ends of the complete lines at the start of `buf`, excluding their
+    // newlines.
+    line_ends: Vec<usize>,
+
+    // The position in the stream of the start of `buf`.
+    offset: u64,
+    line_number: usize,
+
+    // Whether the stream has been finished.
+    finished: bool,
+}
+
+impl StreamingParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next chunk of the stream, returning the lines that it
+    /// completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Lines<'_> {
+        self.consume_returned_lines();
+        self.buf.extend_from_slice(chunk);
+
+        for (idx, &b) in self.buf[self.scanned_len..].iter().enumerate() {
+            // the characters that separate lines are all ASCII, and the bytes
+            // of other UTF-8 characters can't be mistaken for them
+            if self.splitter.is_line_end(b as char) {
+                self.line_ends.push(self.scanned_len + idx);
+            }
+        }
+        self.scanned_len = self.buf.len();
+
+        let len = self.line_ends.last().map(|end| end + 1).unwrap_or(0);
+        self.lines(len)
+    }
+
+    /// Ends the stream, returning the last line if it didn't end with a
+    /// newline.
+    ///
+    /// The parser can be reused for another stream afterwards.
+    pub fn finish(&mut self) -> Lines<'_> {
+        self.consume_returned_lines();
+        if !self.buf.is_empty() {
+            self.line_ends.push(self.buf.len());
+        }
+
+        self.finished = true;
+        self.lines(self.buf.len())
+    }
+
+    /// The number of bytes of the incomplete line at the end of the stream
+    /// that are buffered until it is complete.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len() - self.returned_len
+    }
+
+    /// Removes the lines that were last returned from the buffer.
+    fn consume_returned_lines(&mut self) {
+        if self.finished {
+            // the stream has ended, so the next chunk starts a new one
+            let mut buf = std::mem::take(&mut self.buf);
+            let mut line_ends = std::mem::take(&mut self.line_ends);
+            buf.clear();
+            line_ends.clear();
+            *self = Self {
+                buf,
+                line_ends,
+                ..Default::default()
+            };
+            return;
+        }
+
+        self.buf.drain(..self.returned_len);
+        self.scanned_len -= self.returned_len;
+        self.offset += self.returned_len as u64;
+        self.line_number += self.line_ends.len();
+        self.returned_len = 0;
+        self.line_ends.clear();
+    }
+
+    /// Returns the complete lines in the first `len` bytes of the buffer,
+    /// which are removed from it when the next chunk is pushed.
+    fn lines(&mut self, len: usize) -> Lines<'_> {
+        self.returned_len = len;
+        Lines {
+            buf: &self.buf[..len],
+            line_ends: self.line_ends.iter(),
+            start: 0,
+            offset: self.offset,
+            line_number: self.line_number,
+        }
+    }
+}
+
+/// An iterator over the lines of a stream that a chunk completed, which
+/// yields a [`ParsedLine`] or a [`LineError`] for each line that isn't blank
+/// or a comment.
+#[derive(Debug)]
+pub struct Lines<'a> {
+    buf: &'a [u8],
+    line_ends: std::slice::Iter<'a, usize>,
+    // The start of the next line in `buf`.
+    start: usize,
+
+    // The position in the stream of the start of `buf`.
+    offset: u64,
+    line_number: usize,
+}
+
+impl<'a> Lines<'a> {
+    /// Returns the next line like [`Iterator::next`], along with its number
+    /// in the stream and its bytes, without the newline.
+    pub fn next_line(&mut self) -> Option<(usize, &'a [u8], Result<ParsedLine<'a>, LineError>)> {
+        let buf = self.buf;
+        for &end in self.line_ends.by_ref() {
+            let start = self.start;
+            let line = &buf[start..end];
+            self.start = end + 1;
+            self.line_number += 1;
+
+            let parsed = match std::str::from_utf8(line) {
+                Ok(line) => parse_split_line(line),
+                Err(source) => {
+                    // the first invalid sequence is replaced by U+FFFD, which
+                    // is 3 bytes long
+                    let start = source.valid_up_to();
+                    Some(Err(SpannedError {
+                        source: Error::InvalidUtf8 { source },
+                        span: start..start + char::REPLACEMENT_CHARACTER.len_utf8(),
+                    }))
+                }
+            };
+            if let Some(res) = parsed {
+                let res = res.map_err(|SpannedError { source, span }| LineError {
+                    line_number: self.line_number,
+                    offset: self.offset + start as u64,
+                    line: String::from_utf8_lossy(line).into_owned(),
+                    span,
+                    source,
+                });
+                return Some((self.line_number, line, res));
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Result<ParsedLine<'a>, LineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_line().map(|(_, _, res)| res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_lines, FieldValue};
+
+    const LP: &str = "cpu,host=A,region=west usage_system=64i 1590488773254420000\n\
+        # a comment\n\
+        \n\
+        weather,location=us\\ midwest,city=Caf\u{e9} temperature=82.5,description=\"warm\nand \u{2600}\" 1465839830100400200\n\
+        disk,path=/ free=12u,used=true 1590488773254420000";
+
+    /// Returns the measurement of each line, or the line number and offset of
+    /// the error.
+    fn summarize<'a>(
+        lines: impl Iterator<Item = Result<ParsedLine<'a>, LineError>>,
+    ) -> Vec<Result<String, (usize, u64)>> {
+        lines
+            .map(|line| {
+                line.map(|line| line.to_string())
+                    .map_err(|e| (e.line_number, e.offset))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_stream_in_chunks_of_every_size() {
+        let expected: Vec<Result<String, (usize, u64)>> = parse_lines(LP)
+            .map(|line| Ok(line.unwrap().to_string()))
+            .collect();
+        assert_eq!(expected.len(), 3);
+
+        for chunk_size in 1..=LP.len() {
+            let mut parser = StreamingParser::new();
+            let mut got = vec![];
+            for chunk in LP.as_bytes().chunks(chunk_size) {
+                got.extend(summarize(parser.push(chunk)));
+            }
+            got.extend(summarize(parser.finish()));
+            assert_eq!(got, expected, "chunk size {chunk_size}");
+            assert_eq!(parser.buffered_len(), 0);
+        }
+    }
+
+    #[test]
+    fn parse_stream_with_errors() {
+        let mut parser = StreamingParser::new();
+
+        let lines = summarize(parser.push(b"cpu usage=1i 1\nc"));
+        assert_eq!(lines, vec![Ok("cpu usage=1i 1".to_string())]);
+        assert_eq!(parser.buffered_len(), 1);
+
+        let mut lines = parser.push(b"pu usage=\ncpu\xff usage=2i 2\ncpu usage=3i 3\n");
+        let err = lines.next().unwrap().unwrap_err();
+        assert_eq!(err.line_number, 2);
+        assert_eq!(err.offset, 15);
+        assert_eq!(err.line, "cpu usage=");
+        assert_eq!(err.span, 10..10);
+
+        let err = lines.next().unwrap().unwrap_err();
+        assert_eq!(err.line_number, 3);
+        assert_eq!(err.offset, 26);
+        assert_eq!(err.line, "cpu\u{fffd} usage=2i 2");
+        assert_eq!(&err.line[err.span], "\u{fffd}");
+        assert!(matches!(err.source, Error::InvalidUtf8 { .. }));
+
+        assert_eq!(
+            lines.next().unwrap().unwrap().field_set[0].1,
+            FieldValue::I64(3)
+        );
+        assert!(lines.next().is_none());
+
+        assert!(parser.finish().next().is_none());
+    }
+
+    #[test]
+    fn reuse_parser_after_finish() {
+        let mut parser = StreamingParser::new();
+        parser.push(b"cpu usage=1i 1\ncpu usage=").for_each(drop);
+        assert_eq!(summarize(parser.finish()), vec![Err((2, 15))]);
+
+        // the second stream starts with a new line
+        parser.push(b"mem free=1i 1\nmem").for_each(drop);
+        assert_eq!(summarize(parser.finish()), vec![Err((2, 14))]);
+    }
+
+    #[test]
+    fn lines_have_their_number_and_text() {
+        let mut parser = StreamingParser::new();
+        let mut lines = parser.push(LP.as_bytes());
+        let mut got = vec![];
+        while let Some((line_number, line, res)) = lines.next_line() {
+            got.push((
+                line_number,
+                String::from_utf8_lossy(line).into_owned(),
+                res.is_ok(),
+            ));
+        }
+
+        // the comment and the blank line aren't returned
+        let weather = "weather,location=us\\ midwest,city=Caf\u{e9} temperature=82.5,description=\"warm\nand \u{2600}\" 1465839830100400200";
+        assert_eq!(
+            got,
+            vec![
+                (
+                    1,
+                    "cpu,host=A,region=west usage_system=64i 1590488773254420000".to_string(),
+                    true
+                ),
+                (4, weather.to_string(), true),
+            ]
+        );
+    }
+}
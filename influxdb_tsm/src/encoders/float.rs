@@ -19,8 +19,23 @@ fn is_sentinel_u64(v: u64, sentinel: u64) -> bool {
 /// paper. Each subsequent value is compared to the previous and the XOR of the
 /// two is determined. Leading and trailing zero bits are then analysed and
 /// representations based on those are stored.
-#[allow(clippy::many_single_char_names)]
 pub fn encode(src: &[f64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+    encode_with_sentinel(src, dst, SENTINEL)
+}
+
+/// encode_influxdb encodes a vector of floats into dst in the format of
+/// InfluxDB's encoder, which terminates a block with a different sentinel
+/// value. Blocks encoded with it can be decoded with `decode_influxdb`.
+pub fn encode_influxdb(src: &[f64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+    encode_with_sentinel(src, dst, SENTINEL_INFLUXDB)
+}
+
+#[allow(clippy::many_single_char_names)]
+fn encode_with_sentinel(
+    src: &[f64],
+    dst: &mut Vec<u8>,
+    sentinel: u64,
+) -> Result<(), Box<dyn Error>> {
     dst.clear(); // reset buffer.
     if src.is_empty() {
         return Ok(());
@@ -46,11 +61,11 @@ pub fn encode(src: &[f64], dst: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
         let x;
         if i < src.len() {
             x = src[i];
-            if is_sentinel_f64(x, SENTINEL) {
+            if is_sentinel_f64(x, sentinel) {
                 return Err(From::from("unsupported value"));
             }
         } else {
-            x = f64::from_bits(SENTINEL);
+            x = f64::from_bits(sentinel);
         }
 
         let cur = x.to_bits();
@@ -1693,4 +1708,25 @@ mod tests {
         super::decode_influxdb(&enc_influxdb, &mut got).expect("failed to decode");
         assert_eq!(got, exp);
     }
+
+    #[test]
+    fn encode_influxdb() {
+        let src = vec![0.0, 1.5, -23.25, 1.5, f64::INFINITY, 1e-300];
+        let mut dst = vec![];
+        super::encode_influxdb(&src, &mut dst).expect("failed to encode src");
+
+        let mut got = vec![];
+        super::decode_influxdb(&dst, &mut got).expect("failed to decode");
+        assert_eq!(got, src);
+
+        // InfluxDB's sentinel can't be encoded as a value
+        let src = vec![1.0, f64::from_bits(0x7ff8000000000001)];
+        assert!(super::encode_influxdb(&src, &mut dst).is_err());
+
+        // a block of repeated values, like the one InfluxDB encoded above, round trips
+        super::encode_influxdb(&[0.0; 507], &mut dst).expect("failed to encode src");
+        let mut got = vec![];
+        super::decode_influxdb(&dst, &mut got).expect("failed to decode");
+        assert_eq!(got, vec![0.0; 507]);
+    }
 }
@@ -10,6 +10,107 @@ pub struct ParsedTsmKey {
     pub field_key: String,
 }
 
+impl ParsedTsmKey {
+    /// Encodes the key in the format of the keys of a TSM index, which
+    /// `parse_tsm_key` parses. The tags are written in the order of their keys,
+    /// as series keys are sorted by tag key.
+    ///
+    /// The format looks roughly like:
+    ///
+    /// ```text
+    /// <org_id bucket_id>,\x00=<measurement>,<tag_keys_str>,\xff=<field_key_str>#!
+    /// ~#<field_key_str>
+    /// ```
+    pub fn to_tsm_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(100);
+
+        // the IDs escape space, comma and backslash, see `parse_id`
+        for id in [self.org_id, self.bucket_id] {
+            escape(&mut key, &id.0.to_be_bytes(), b" ,\\");
+        }
+
+        key.extend_from_slice(b",\x00=");
+        escape(&mut key, self.measurement.as_bytes(), TAG_ESCAPES);
+        self.push_tagset(&mut key);
+
+        // the field key isn't escaped after the delimiter, see
+        // `parse_tsm_field_key_value`
+        key.extend_from_slice(b",\xff=");
+        escape(&mut key, self.field_key.as_bytes(), TAG_ESCAPES);
+        key.extend_from_slice(FIELD_KEY_DELIMITER);
+        key.extend_from_slice(self.field_key.as_bytes());
+
+        key
+    }
+
+    /// Encodes the key in the format of the keys that InfluxDB 1.x writes to a
+    /// TSM index, which `parse_v1_tsm_key` parses. The org and bucket IDs are
+    /// left out, as 1.x keys don't have them.
+    ///
+    /// The format looks like:
+    ///
+    /// ```text
+    /// <measurement>,<tag_keys_str>#!~#<field_key>
+    /// ```
+    pub fn to_v1_tsm_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(100);
+
+        escape(&mut key, self.measurement.as_bytes(), MEASUREMENT_ESCAPES);
+        self.push_tagset(&mut key);
+
+        // like the 2.x keys, the field key isn't escaped after the delimiter
+        key.extend_from_slice(FIELD_KEY_DELIMITER);
+        key.extend_from_slice(self.field_key.as_bytes());
+
+        key
+    }
+
+    fn push_tagset(&self, key: &mut Vec<u8>) {
+        let mut tagset: Vec<_> = self.tagset.iter().collect();
+        tagset.sort();
+        for (tag_key, tag_value) in tagset {
+            key.push(b',');
+            escape(key, tag_key.as_bytes(), TAG_ESCAPES);
+            key.push(b'=');
+            escape(key, tag_value.as_bytes(), TAG_ESCAPES);
+        }
+    }
+}
+
+/// The format of the series keys in the index of a TSM file, which depends on
+/// the version of InfluxDB that wrote it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// The keys of InfluxDB 1.x, which don't have an org or bucket ID: the
+    /// database and retention policy of a key are those of the shard the TSM
+    /// file is in. See `ParsedTsmKey::to_v1_tsm_key`.
+    V1,
+    /// The keys of InfluxDB 2.x, which start with the org and bucket IDs. See
+    /// `ParsedTsmKey::to_tsm_key`.
+    #[default]
+    V2,
+}
+
+/// The delimiter between the series key and the field key of a TSM key.
+const FIELD_KEY_DELIMITER: &[u8] = b"#!~#";
+
+/// The bytes that are escaped in the measurement of a 1.x TSM key, which is
+/// escaped like the measurement of a line of line protocol.
+const MEASUREMENT_ESCAPES: &[u8] = b" ,";
+
+/// The bytes that are escaped in the tag keys and values of a TSM key.
+const TAG_ESCAPES: &[u8] = b" ,=\\";
+
+/// Appends `src` to `dst`, escaping every byte in `escapes` with a backslash.
+fn escape(dst: &mut Vec<u8>, src: &[u8], escapes: &[u8]) {
+    for &byte in src {
+        if escapes.contains(&byte) {
+            dst.push(b'\\');
+        }
+        dst.push(byte);
+    }
+}
+
 /// Public error type that wraps the underlying data parsing error
 /// with the actual key value being parsed.
 #[derive(Debug, Snafu, PartialEq, Eq)]
@@ -171,6 +272,112 @@ fn parse_tsm_key_internal(key: &[u8]) -> Result<ParsedTsmKey, DataError> {
     })
 }
 
+/// Parses the measurement, field key and tag set from a TSM index key written
+/// by InfluxDB 1.x, which has no org or bucket ID. The IDs of the returned key
+/// are zero.
+///
+/// Example: the key
+///
+/// ```text
+/// http_api_request_duration_seconds,status=2XX#!~#sum
+/// ```
+///
+/// is parsed into
+///
+/// ```text
+///    measurement = "http_api_request_duration_seconds"
+///    tags = [("status", "2XX")]
+///    field = "sum"
+/// ```
+pub fn parse_v1_tsm_key(key: &[u8]) -> Result<ParsedTsmKey, Error> {
+    parse_v1_tsm_key_internal(key).context(ParsingTsmKeySnafu {
+        key: String::from_utf8_lossy(key),
+    })
+}
+
+fn parse_v1_tsm_key_internal(key: &[u8]) -> Result<ParsedTsmKey, DataError> {
+    // Like InfluxDB, the series key ends at the first delimiter: the field key
+    // after it isn't escaped, so it may contain the delimiter itself.
+    let delimiter = key
+        .windows(FIELD_KEY_DELIMITER.len())
+        .position(|window| window == FIELD_KEY_DELIMITER)
+        .context(NoFieldKeySnafu)?;
+    let field_key = &key[delimiter + FIELD_KEY_DELIMITER.len()..];
+    if field_key.is_empty() {
+        return ParsingFieldKeySnafu {
+            details: "field key too short",
+        }
+        .fail();
+    }
+    let field_key =
+        String::from_utf8(field_key.to_vec()).map_err(|e| DataError::ParsingFieldKey {
+            details: e.to_string(),
+        })?;
+
+    let mut rem_key = key[..delimiter].iter().copied();
+    let (mut has_more_tags, measurement) = parse_v1_measurement(&mut rem_key)?;
+
+    let mut tagset = Vec::with_capacity(10);
+    while has_more_tags {
+        let tag_key = match parse_tsm_tag_key(&mut rem_key)? {
+            KeyType::Tag(tag_key) => tag_key,
+            key_type => {
+                return ParsingTsmTagKeySnafu {
+                    description: format!("unexpected {} tag key", String::from(&key_type)),
+                }
+                .fail()
+            }
+        };
+        let (more_tags, tag_value) = parse_tsm_tag_value(&tag_key, &mut rem_key)?;
+        tagset.push((tag_key, tag_value));
+        has_more_tags = more_tags;
+    }
+
+    Ok(ParsedTsmKey {
+        org_id: InfluxId(0),
+        bucket_id: InfluxId(0),
+        measurement,
+        tagset,
+        field_key,
+    })
+}
+
+/// Parses the measurement at the start of a 1.x TSM key, which ends at the
+/// first unescaped comma. Only commas and spaces are escaped in measurements,
+/// so a backslash before any other byte is part of the measurement.
+///
+/// Returns a tuple `(has_more_tags, measurement)`
+fn parse_v1_measurement(rem_key: impl Iterator<Item = u8>) -> Result<(bool, String), DataError> {
+    let mut measurement = Vec::with_capacity(100);
+    let mut escaped = false;
+    let mut has_more_tags = false;
+    for byte in rem_key {
+        match byte {
+            b',' | b' ' if escaped => {
+                measurement.pop();
+                measurement.push(byte);
+            }
+            b',' => {
+                has_more_tags = true;
+                break;
+            }
+            _ => measurement.push(byte),
+        }
+        escaped = byte == b'\\' && !escaped;
+    }
+
+    if measurement.is_empty() {
+        return NoMeasurementSnafu.fail();
+    }
+    let measurement =
+        String::from_utf8(measurement).map_err(|e| DataError::ParsingTsmTagValue {
+            tag_key: String::from(&KeyType::Measurement),
+            description: e.to_string(),
+        })?;
+
+    Ok((has_more_tags, measurement))
+}
+
 // Parses an influx id from the byte sequence. IDs are generally just 8 bytes, but we escape
 // certain characters ('\', ' ' and '='), so we unescape them as part of this process.
 // The iterator will consume all bytes that are part of the id.
@@ -885,6 +1092,133 @@ mod tests {
         assert_eq!(parsed_key.field_key, String::from("responseSize"));
     }
 
+    #[test]
+    fn to_tsm_key() {
+        let key = ParsedTsmKey {
+            org_id: InfluxId::from_be_bytes(*b"12345678"),
+            bucket_id: InfluxId::from_be_bytes(*b"87654321"),
+            measurement: String::from("m"),
+            tagset: vec![
+                (String::from("tag2"), String::from("val2")),
+                (String::from("tag1"), String::from("val1")),
+            ],
+            field_key: String::from("f"),
+        };
+
+        let mut exp = make_tsm_key_prefix("m", "tag1=val1,tag2=val2");
+        exp = add_field_key(exp, "f");
+        assert_eq!(key.to_tsm_key(), exp);
+    }
+
+    #[test]
+    fn to_tsm_key_escaped() {
+        let key = ParsedTsmKey {
+            // IDs with a space, comma and backslash
+            org_id: InfluxId::from_be_bytes(*b"\x00 \\,\x01,\x03\x04"),
+            bucket_id: InfluxId(2),
+            measurement: String::from("query log"),
+            tagset: vec![
+                (
+                    String::from("error"),
+                    String::from("limit reached: a=1, b\\2"),
+                ),
+                (String::from("env,region"), String::from("prod")),
+            ],
+            field_key: String::from("response size"),
+        };
+
+        let tsm_key = key.to_tsm_key();
+        // the field key is only escaped before the delimiter
+        assert!(tsm_key.ends_with(b",\xff=response\\ size#!~#response size"));
+
+        let parsed_key = super::parse_tsm_key(&tsm_key).unwrap();
+        assert_eq!(parsed_key.org_id, key.org_id);
+        assert_eq!(parsed_key.bucket_id, key.bucket_id);
+        assert_eq!(parsed_key.measurement, key.measurement);
+        assert_eq!(
+            parsed_key.tagset,
+            vec![
+                (String::from("env,region"), String::from("prod")),
+                (
+                    String::from("error"),
+                    String::from("limit reached: a=1, b\\2")
+                ),
+            ]
+        );
+        assert_eq!(parsed_key.field_key, key.field_key);
+    }
+
+    #[test]
+    fn parse_v1_tsm_key_good() {
+        let parsed_key = super::parse_v1_tsm_key(b"m,tag1=val1,tag2=val2#!~#f").unwrap();
+        assert_eq!(parsed_key.org_id, InfluxId(0));
+        assert_eq!(parsed_key.bucket_id, InfluxId(0));
+        assert_eq!(parsed_key.measurement, String::from("m"));
+        assert_eq!(
+            parsed_key.tagset,
+            vec![
+                (String::from("tag1"), String::from("val1")),
+                (String::from("tag2"), String::from("val2")),
+            ]
+        );
+        assert_eq!(parsed_key.field_key, String::from("f"));
+
+        // a measurement without tags, and a field key with the delimiter in it
+        let parsed_key = super::parse_v1_tsm_key(b"m#!~#f#!~#").unwrap();
+        assert_eq!(parsed_key.measurement, String::from("m"));
+        assert!(parsed_key.tagset.is_empty());
+        assert_eq!(parsed_key.field_key, String::from("f#!~#"));
+    }
+
+    #[test]
+    fn parse_v1_tsm_key_bad() {
+        let err_str = parse_v1_tsm_key(b"m,tag1=val1")
+            .expect_err("expect parsing error")
+            .to_string();
+        assert!(err_str.contains("No field key"), "{}", err_str);
+
+        let err_str = parse_v1_tsm_key(b"m,tag1=val1#!~#")
+            .expect_err("expect parsing error")
+            .to_string();
+        assert!(err_str.contains("field key too short"), "{}", err_str);
+
+        let err_str = parse_v1_tsm_key(b",tag1=val1#!~#f")
+            .expect_err("expect parsing error")
+            .to_string();
+        assert!(err_str.contains("No measurement"), "{}", err_str);
+
+        let err_str = parse_v1_tsm_key(b"m,tag1#!~#f")
+            .expect_err("expect parsing error")
+            .to_string();
+        assert!(err_str.contains("unexpected end of data"), "{}", err_str);
+    }
+
+    #[test]
+    fn to_v1_tsm_key_escaped() {
+        let key = ParsedTsmKey {
+            org_id: InfluxId(0),
+            bucket_id: InfluxId(0),
+            measurement: String::from("query log, a=1\\2"),
+            tagset: vec![
+                (String::from("env,region"), String::from("prod")),
+                (String::from("error"), String::from("limit reached: a=1")),
+            ],
+            field_key: String::from("response size"),
+        };
+
+        let tsm_key = key.to_v1_tsm_key();
+        assert_eq!(
+            tsm_key,
+            b"query\\ log\\,\\ a=1\\2,env\\,region=prod,error=limit\\ reached:\\ a\\=1#!~#response size"
+                .to_vec()
+        );
+
+        let parsed_key = super::parse_v1_tsm_key(&tsm_key).unwrap();
+        assert_eq!(parsed_key.measurement, key.measurement);
+        assert_eq!(parsed_key.tagset, key.tagset);
+        assert_eq!(parsed_key.field_key, key.field_key);
+    }
+
     fn do_test_parse_tsm_field_key_value_good(input: &str, expected_field_key: &str) {
         let mut iter = input.bytes();
         let result = parse_tsm_field_key_value(&mut iter);
@@ -17,6 +17,9 @@ pub mod encoders;
 pub mod key;
 pub mod mapper;
 pub mod reader;
+pub mod shard;
+pub mod tombstone;
+pub mod writer;
 
 use std::convert::TryFrom;
 use std::error;
@@ -34,6 +37,18 @@ pub enum BlockType {
     Unsigned,
 }
 
+impl From<BlockType> for u8 {
+    fn from(value: BlockType) -> Self {
+        match value {
+            BlockType::Float => 0,
+            BlockType::Integer => 1,
+            BlockType::Bool => 2,
+            BlockType::Str => 3,
+            BlockType::Unsigned => 4,
+        }
+    }
+}
+
 impl TryFrom<u8> for BlockType {
     type Error = TsmError;
 
@@ -84,8 +99,9 @@ const MAX_BLOCK_VALUES: usize = 1000;
 pub struct InfluxId(u64);
 
 impl InfluxId {
-    #[allow(dead_code)]
-    fn new_str(s: &str) -> Result<Self, TsmError> {
+    /// Parses an ID from its hex representation, which is how IDs are
+    /// displayed.
+    pub fn new_str(s: &str) -> Result<Self, TsmError> {
         let v = u64::from_str_radix(s, 16).map_err(|e| TsmError {
             description: e.to_string(),
         })?;
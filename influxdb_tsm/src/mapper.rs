@@ -1,7 +1,7 @@
 //! Types for mapping and converting series data from TSM indexes produced by
 //! InfluxDB >= 2.x
 use crate::reader::{BlockData, BlockDecoder, TsmIndexReader, ValuePair};
-use crate::{Block, BlockType, TsmError};
+use crate::{Block, BlockType, InfluxId, TsmError};
 
 use observability_deps::tracing::warn;
 
@@ -12,7 +12,7 @@ use std::io::{Read, Seek};
 use std::iter::Peekable;
 
 /// `TSMMeasurementMapper` takes a TSM reader and produces an iterator that
-/// collects all series data for a given measurement.
+/// collects all series data for a given measurement of a bucket.
 ///
 /// The main purpose of the `TSMMeasurementMapper` is to provide a
 /// transformation step that allows one to convert per-series/per-field data
@@ -54,8 +54,12 @@ impl<R: Read + Seek> Iterator for TsmMeasurementMapper<R> {
         let entry = try_or_some!(self.iter.next()?);
 
         let parsed_key = try_or_some!(entry.parse_key());
-        let mut measurement: MeasurementTable =
-            MeasurementTable::new(parsed_key.measurement, self.reader_idx);
+        let mut measurement: MeasurementTable = MeasurementTable::new(
+            parsed_key.org_id,
+            parsed_key.bucket_id,
+            parsed_key.measurement,
+            self.reader_idx,
+        );
         try_or_some!(measurement.add_series_data(
             parsed_key.tagset,
             parsed_key.field_key,
@@ -64,13 +68,16 @@ impl<R: Read + Seek> Iterator for TsmMeasurementMapper<R> {
 
         // The first index entry for the item has been processed, next keep
         // peeking at subsequent entries in the index until a yielded value is
-        // for a different measurement. At that point we will return the
-        // measurement.
+        // for a different measurement, or for the same measurement in a
+        // different bucket. At that point we will return the measurement.
         while let Some(res) = self.iter.peek() {
             match res {
                 Ok(entry) => {
                     let parsed_key = try_or_some!(entry.parse_key());
-                    if measurement.name != parsed_key.measurement {
+                    if measurement.name != parsed_key.measurement
+                        || measurement.org_id != parsed_key.org_id
+                        || measurement.bucket_id != parsed_key.bucket_id
+                    {
                         // Next entry is for a different measurement.
                         return Some(Ok(measurement));
                     }
@@ -105,6 +112,10 @@ pub type FieldKeyBlocks = BTreeMap<String, Vec<Block>>;
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MeasurementTable {
     pub name: String,
+    // The organization and bucket that the series of the measurement belong
+    // to.
+    pub org_id: InfluxId,
+    pub bucket_id: InfluxId,
     // Tagset for key --> map of fields with that tagset to their blocks.
     //
     // Here we are mapping each set of field keys (and their blocks) to a unique
@@ -133,9 +144,11 @@ pub struct MeasurementTable {
 }
 
 impl MeasurementTable {
-    pub fn new(name: String, reader_idx: usize) -> Self {
+    pub fn new(org_id: InfluxId, bucket_id: InfluxId, name: String, reader_idx: usize) -> Self {
         Self {
             name,
+            org_id,
+            bucket_id,
             tag_set_fields_blocks: BTreeMap::new(),
             tag_columns: BTreeSet::new(),
             field_columns: BTreeMap::new(),
@@ -227,7 +240,8 @@ impl MeasurementTable {
     /// Merge another `MeasurementTable` into this one.
     ///
     /// `other` must be associated with the same measurement, otherwise an error
-    /// will be returned.
+    /// will be returned. It may be from a different bucket; the merged table
+    /// keeps the organization and bucket of this one.
     ///
     /// Because measurement table data can originate from multiple sources (TSM
     /// files) it is possible that blocks for the same tagset and field will
@@ -600,27 +614,36 @@ fn refill_block_buffer(
         // the buffer by getting the next block(s), decoding them and making
         // the block data available for consumption.
 
-        // It is possible for fields to have multiple overlapping blocks, e.g.,
-        // if the data has been built up from multiple data sources (TSM files).
-        //
-        // Determine how many overlapping blocks need to be decoded and merged
-        // together
-        let mut i = 0; // track which blocks are overlapping in the vector
-        while i < blocks.len() - 1 {
-            if !blocks[i].overlaps(&blocks[i + 1]) {
-                break;
+        // Blocks that all of the values of have been deleted decode to empty
+        // block data, in which case the next block(s) are decoded instead.
+        loop {
+            // It is possible for fields to have multiple overlapping blocks, e.g.,
+            // if the data has been built up from multiple data sources (TSM files).
+            //
+            // Determine how many overlapping blocks need to be decoded and merged
+            // together
+            let mut i = 0; // track which blocks are overlapping in the vector
+            while i < blocks.len() - 1 {
+                if !blocks[i].overlaps(&blocks[i + 1]) {
+                    break;
+                }
+                i += 1;
             }
-            i += 1;
-        }
 
-        // materialise all the blocks to be merged. Note, a single block is valid
-        // here - the merge will simply return the block data.
-        let decoded_blocks = blocks
-            .drain(..i + 1)
-            .map(|b| decoder.decode(&b))
-            .collect::<Result<Vec<_>, _>>()?;
+            // materialise all the blocks to be merged. Note, a single block is valid
+            // here - the merge will simply return the block data.
+            let decoded_blocks = blocks
+                .drain(..i + 1)
+                .map(|b| decoder.decode(&b))
+                .collect::<Result<Vec<_>, _>>()?;
 
-        dst.insert(field.clone(), BlockData::merge(decoded_blocks));
+            let block_data = BlockData::merge(decoded_blocks);
+            if block_data.is_empty() && !blocks.is_empty() {
+                continue;
+            }
+            dst.insert(field.clone(), block_data);
+            break;
+        }
     }
     Ok(())
 }
@@ -653,6 +676,15 @@ mod tests {
 
     const TSM_FIXTURE_SIZE: usize = 4_222_248;
 
+    // every block in the fixture file is for this org and bucket.
+    fn org_id() -> InfluxId {
+        InfluxId::new_str("05c19117091a1000").unwrap()
+    }
+
+    fn bucket_id() -> InfluxId {
+        InfluxId::new_str("05c19117091a1001").unwrap()
+    }
+
     #[test]
     fn map_tsm_index() {
         let file = File::open("../test_fixtures/000000000000005-000000002.tsm.gz");
@@ -728,6 +760,8 @@ mod tests {
             .unwrap()
             .unwrap();
 
+        assert_eq!(cpu.org_id, org_id());
+        assert_eq!(cpu.bucket_id, bucket_id());
         assert_eq!(cpu.tag_columns(), vec!["cpu", "host"]);
         assert_eq!(
             cpu.field_columns().keys().collect::<Vec<_>>(),
@@ -748,7 +782,7 @@ mod tests {
 
     #[test]
     fn conflicting_field_types() {
-        let mut table = MeasurementTable::new("cpu".to_string(), 0);
+        let mut table = MeasurementTable::new(org_id(), bucket_id(), "cpu".to_string(), 0);
         table
             .add_series_data(
                 vec![("region".to_string(), "west".to_string())],
@@ -789,7 +823,7 @@ mod tests {
 
     #[test]
     fn merge_measurement_table() {
-        let mut table1 = MeasurementTable::new("cpu".to_string(), 0);
+        let mut table1 = MeasurementTable::new(org_id(), bucket_id(), "cpu".to_string(), 0);
         table1
             .add_series_data(
                 vec![("region".to_string(), "west".to_string())],
@@ -805,7 +839,7 @@ mod tests {
             )
             .unwrap();
 
-        let mut table2 = MeasurementTable::new("cpu".to_string(), 1);
+        let mut table2 = MeasurementTable::new(org_id(), bucket_id(), "cpu".to_string(), 1);
         table2
             .add_series_data(
                 vec![("region".to_string(), "west".to_string())],
@@ -1,6 +1,8 @@
 //! Types for reading and writing TSM files produced by InfluxDB >= 2.x
 
 use super::*;
+use crate::key::KeyFormat;
+use crate::tombstone::{BlockTombstones, Tombstones};
 use integer_encoding::VarInt;
 use std::collections::BTreeMap;
 use std::io::{Read, Seek, SeekFrom};
@@ -56,6 +58,11 @@ where
 
     curr: Option<IndexEntry>,
     next: Option<IndexEntry>,
+
+    // blocks that all of the values of have been deleted are skipped.
+    tombstones: Tombstones,
+
+    key_format: KeyFormat,
 }
 
 impl<R> TsmIndexReader<R>
@@ -77,9 +84,29 @@ where
             end_offset: len as u64 - 8,
             curr: None,
             next: None,
+            tombstones: Tombstones::default(),
+            key_format: KeyFormat::default(),
         })
     }
 
+    /// Sets the format that the keys of the entries are parsed in, which is
+    /// that of InfluxDB 2.x unless set.
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    /// Skips the index entries of blocks that all of the values of have been
+    /// deleted by the provided tombstones, which should be those of the TSM
+    /// file.
+    ///
+    /// The values of blocks that are partially deleted are removed when the
+    /// blocks are decoded, see `TsmBlockReader::set_tombstones`.
+    pub fn with_tombstones(mut self, tombstones: Tombstones) -> Self {
+        self.tombstones = tombstones;
+        self
+    }
+
     /// next_index_entry will return either the next index entry in a TSM file's
     /// index or will return an error. `next_index_entry` updates the offset on
     /// the Index, but it's the caller's responsibility to stop reading entries
@@ -109,6 +136,7 @@ where
         let typ = BlockType::try_from(b_type)?;
         Ok(IndexEntry {
             key: key_bytes,
+            key_format: self.key_format,
             block_type: typ,
             count,
             curr_block: 1,
@@ -156,6 +184,27 @@ impl<R: Read + Seek> Iterator for TsmIndexReader<R> {
     type Item = Result<IndexEntry, TsmError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_entry()? {
+                Ok(entry)
+                    if self.tombstones.covers(
+                        &entry.key,
+                        entry.block.min_time,
+                        entry.block.max_time,
+                    ) =>
+                {
+                    continue
+                }
+                res => return Some(res),
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> TsmIndexReader<R> {
+    /// Returns the next index entry, whether or not its block has been
+    /// deleted.
+    fn next_entry(&mut self) -> Option<Result<IndexEntry, TsmError>> {
         if self.curr_offset == self.end_offset {
             // end of entries
             return None;
@@ -196,6 +245,7 @@ impl<R: Read + Seek> Iterator for TsmIndexReader<R> {
 #[derive(Debug, Clone)]
 pub struct IndexEntry {
     key: Vec<u8>,
+    key_format: KeyFormat,
 
     pub block_type: BlockType,
     pub count: u16,
@@ -204,12 +254,19 @@ pub struct IndexEntry {
 }
 
 impl IndexEntry {
-    /// Get the organization ID that this entry belongs to.
+    /// Get the series key of this entry, which includes the field key.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Get the organization ID that this entry belongs to. Only the keys of
+    /// InfluxDB 2.x have one.
     pub fn org_id(&self) -> InfluxId {
         Self::extract_id_from_slice(&self.key[..8])
     }
 
-    /// Get the bucket ID that this entry belongs to.
+    /// Get the bucket ID that this entry belongs to. Only the keys of
+    /// InfluxDB 2.x have one.
     pub fn bucket_id(&self) -> InfluxId {
         Self::extract_id_from_slice(&self.key[8..16])
     }
@@ -221,7 +278,11 @@ impl IndexEntry {
     }
 
     pub fn parse_key(&self) -> Result<ParsedTsmKey, TsmError> {
-        key::parse_tsm_key(&self.key).map_err(|e| TsmError {
+        match self.key_format {
+            KeyFormat::V1 => key::parse_v1_tsm_key(&self.key),
+            KeyFormat::V2 => key::parse_tsm_key(&self.key),
+        }
+        .map_err(|e| TsmError {
             description: e.to_string(),
         })
     }
@@ -453,6 +514,22 @@ impl BlockData {
         }
     }
 
+    /// Removes the values with timestamps in any of the provided inclusive
+    /// time ranges.
+    pub fn remove_ranges(&mut self, ranges: &[(i64, i64)]) {
+        if ranges.is_empty() {
+            return;
+        }
+
+        match self {
+            Self::Float { ts, values, .. } => remove_ranges(ts, values, ranges),
+            Self::Integer { ts, values, .. } => remove_ranges(ts, values, ranges),
+            Self::Bool { ts, values, .. } => remove_ranges(ts, values, ranges),
+            Self::Str { ts, values, .. } => remove_ranges(ts, values, ranges),
+            Self::Unsigned { ts, values, .. } => remove_ranges(ts, values, ranges),
+        }
+    }
+
     /// Merges multiple blocks of data together.
     ///
     /// For values within the block that have identical timestamps, `merge`
@@ -527,6 +604,14 @@ impl BlockData {
     }
 }
 
+fn remove_ranges<T>(ts: &mut Vec<i64>, values: &mut Vec<T>, ranges: &[(i64, i64)]) {
+    let is_deleted = |t: i64| ranges.iter().any(|&(min, max)| min <= t && t <= max);
+
+    let mut deleted = ts.iter().map(|&t| is_deleted(t));
+    values.retain(|_| !deleted.next().unwrap_or(false));
+    ts.retain(|&t| !is_deleted(t));
+}
+
 // ValuePair represents a single timestamp-value pair from a TSM block.
 #[derive(Debug, PartialEq, Clone)]
 pub enum ValuePair {
@@ -558,6 +643,9 @@ where
     R: Read + Seek,
 {
     readers: Vec<R>,
+
+    // the deleted time ranges of the blocks of each reader
+    tombstones: Vec<BlockTombstones>,
 }
 
 impl<R> TsmBlockReader<R>
@@ -565,24 +653,26 @@ where
     R: Read + Seek,
 {
     pub fn new(r: R) -> Self {
-        Self { readers: vec![r] }
+        Self {
+            readers: vec![r],
+            tombstones: vec![BlockTombstones::default()],
+        }
     }
 
     pub fn add_reader(&mut self, r: R) {
         self.readers.push(r);
+        self.tombstones.push(BlockTombstones::default());
     }
-}
 
-impl<R> BlockDecoder for TsmBlockReader<R>
-where
-    R: Read + Seek,
-{
-    /// decode a block whose location is described by the provided
-    /// `Block`.
-    ///
-    /// The components of the returned `BlockData` are guaranteed to have
-    /// identical lengths.
-    fn decode(&mut self, block: &Block) -> Result<BlockData, TsmError> {
+    /// Sets the deleted time ranges of the blocks of a reader, which are
+    /// removed from the block data when those blocks are decoded.
+    pub fn set_tombstones(&mut self, reader_idx: usize, tombstones: BlockTombstones) {
+        if let Some(dst) = self.tombstones.get_mut(reader_idx) {
+            *dst = tombstones;
+        }
+    }
+
+    fn decode_block(&mut self, block: &Block) -> Result<BlockData, TsmError> {
         match self.readers.get_mut(block.reader_idx) {
             Some(r) => {
                 r.seek(SeekFrom::Start(block.offset))?;
@@ -675,6 +765,24 @@ where
     }
 }
 
+impl<R> BlockDecoder for TsmBlockReader<R>
+where
+    R: Read + Seek,
+{
+    /// decode a block whose location is described by the provided
+    /// `Block`, removing the values that have been deleted from it.
+    ///
+    /// The components of the returned `BlockData` are guaranteed to have
+    /// identical lengths.
+    fn decode(&mut self, block: &Block) -> Result<BlockData, TsmError> {
+        let mut data = self.decode_block(block)?;
+        if let Some(tombstones) = self.tombstones.get(block.reader_idx) {
+            data.remove_ranges(tombstones.deleted_ranges(block));
+        }
+        Ok(data)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
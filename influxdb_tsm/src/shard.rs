@@ -0,0 +1,390 @@
+//! Types for reading the TSM files of a shard, along with their tombstones, as
+//! a single source of series data.
+
+use super::*;
+use crate::key::KeyFormat;
+use crate::mapper::{MeasurementTable, TsmMeasurementMapper};
+use crate::reader::{TsmBlockReader, TsmIndexReader};
+use crate::tombstone::{read_tombstones, tombstone_path, Tombstones};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// `TsmShardReader` reads the TSM files in the directory of a shard, applying
+/// the tombstones that InfluxDB writes next to them when data is deleted, so
+/// that deleted values are not read.
+///
+/// The TSM files of a shard are read in the order of their names, which is the
+/// order they were written in, so the values of later files win when blocks
+/// of the same series overlap.
+///
+/// Only the TSM files of a shard are read. Series that are in the TSI index of
+/// a shard but not in its TSM files have either been deleted, or only have
+/// values in the WAL of the shard, which has not been compacted into a TSM
+/// file yet; `wal_files` lists the WAL files that such values are in.
+#[derive(Debug)]
+pub struct TsmShardReader {
+    tsm_files: Vec<TsmFile>,
+    wal_files: Vec<PathBuf>,
+    key_format: KeyFormat,
+}
+
+#[derive(Debug)]
+struct TsmFile {
+    path: PathBuf,
+    tombstones: Tombstones,
+}
+
+impl TsmShardReader {
+    /// Finds the TSM and WAL files in the directory of a shard, and reads the
+    /// tombstones of the TSM files.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, TsmError> {
+        let dir = dir.as_ref();
+        let mut tsm_paths = Vec::new();
+        let mut wal_files = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(|e| io_error(dir, e))? {
+            let path = entry.map_err(|e| io_error(dir, e))?.path();
+            if !path.is_file() {
+                continue;
+            }
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("tsm") => tsm_paths.push(path),
+                Some("wal") => wal_files.push(path),
+                _ => {}
+            }
+        }
+        tsm_paths.sort();
+        wal_files.sort();
+
+        let mut tsm_files = Vec::with_capacity(tsm_paths.len());
+        for path in tsm_paths {
+            let tombstone_path = tombstone_path(&path);
+            let tombstones = match File::open(&tombstone_path) {
+                Ok(file) => Tombstones::new(
+                    read_tombstones(BufReader::new(file))
+                        .map_err(|e| context_error(&tombstone_path, e))?,
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Tombstones::default(),
+                Err(e) => return Err(io_error(&tombstone_path, e)),
+            };
+            tsm_files.push(TsmFile { path, tombstones });
+        }
+
+        Ok(Self {
+            tsm_files,
+            wal_files,
+            key_format: KeyFormat::default(),
+        })
+    }
+
+    /// Sets the format of the keys in the TSM files of the shard, which is
+    /// that of InfluxDB 2.x unless set. The measurements of a shard with
+    /// InfluxDB 1.x keys have zero org and bucket IDs.
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    /// The TSM files of the shard, in the order they are read.
+    pub fn tsm_files(&self) -> impl Iterator<Item = &Path> {
+        self.tsm_files
+            .iter()
+            .map(|tsm_file| tsm_file.path.as_path())
+    }
+
+    /// The WAL files of the shard, the values of which are not read.
+    pub fn wal_files(&self) -> &[PathBuf] {
+        &self.wal_files
+    }
+
+    /// Returns the measurements of the shard, by organization, bucket and
+    /// measurement name, with the blocks of every TSM file of the shard merged
+    /// together. Blocks that all of the values of have been deleted are left
+    /// out.
+    ///
+    /// The data of the measurements can be read with the block reader of the
+    /// shard.
+    pub fn measurement_tables(
+        &self,
+    ) -> Result<BTreeMap<(InfluxId, InfluxId, String), MeasurementTable>, TsmError> {
+        let mut tables: BTreeMap<_, MeasurementTable> = BTreeMap::new();
+        for (reader_idx, tsm_file) in self.tsm_files.iter().enumerate() {
+            let index_reader = self
+                .index_reader(tsm_file)?
+                .with_tombstones(tsm_file.tombstones.clone());
+
+            for table in TsmMeasurementMapper::new(index_reader.peekable(), reader_idx) {
+                let mut table = table.map_err(|e| context_error(&tsm_file.path, e))?;
+                let key = (table.org_id, table.bucket_id, table.name.clone());
+                match tables.get_mut(&key) {
+                    Some(existing) => existing.merge(&mut table)?,
+                    None => {
+                        tables.insert(key, table);
+                    }
+                }
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Returns a block reader for the TSM files of the shard, which removes
+    /// the values that have been deleted from the blocks it decodes.
+    pub fn block_reader(&self) -> Result<Option<TsmBlockReader<BufReader<File>>>, TsmError> {
+        let mut block_reader: Option<TsmBlockReader<_>> = None;
+        for (reader_idx, tsm_file) in self.tsm_files.iter().enumerate() {
+            let reader = BufReader::new(open(&tsm_file.path)?);
+            let block_reader = match &mut block_reader {
+                Some(block_reader) => {
+                    block_reader.add_reader(reader);
+                    block_reader
+                }
+                None => block_reader.insert(TsmBlockReader::new(reader)),
+            };
+
+            if !tsm_file.tombstones.is_empty() {
+                let block_tombstones = tsm_file
+                    .tombstones
+                    .block_tombstones(self.index_reader(tsm_file)?)
+                    .map_err(|e| context_error(&tsm_file.path, e))?;
+                block_reader.set_tombstones(reader_idx, block_tombstones);
+            }
+        }
+        Ok(block_reader)
+    }
+
+    fn index_reader(
+        &self,
+        tsm_file: &TsmFile,
+    ) -> Result<TsmIndexReader<BufReader<File>>, TsmError> {
+        let file = open(&tsm_file.path)?;
+        let len = file
+            .metadata()
+            .map_err(|e| io_error(&tsm_file.path, e))?
+            .len();
+        TsmIndexReader::try_new(BufReader::new(file), len as usize)
+            .map(|index_reader| index_reader.with_key_format(self.key_format))
+            .map_err(|e| context_error(&tsm_file.path, e))
+    }
+}
+
+fn open(path: &Path) -> Result<File, TsmError> {
+    File::open(path).map_err(|e| io_error(path, e))
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> TsmError {
+    TsmError {
+        description: format!("error reading {}: {e}", path.display()),
+    }
+}
+
+fn context_error(path: &Path, e: TsmError) -> TsmError {
+    TsmError {
+        description: format!("error reading {}: {}", path.display(), e.description),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::ParsedTsmKey;
+    use crate::mapper::ColumnData;
+    use crate::reader::BlockData;
+    use crate::writer::TsmWriter;
+
+    fn tsm_key(host: &str, field_key: &str) -> Vec<u8> {
+        ParsedTsmKey {
+            org_id: InfluxId::new_str("05c19117091a1000").unwrap(),
+            bucket_id: InfluxId::new_str("05c19117091a1001").unwrap(),
+            measurement: "cpu".to_string(),
+            tagset: vec![("host".to_string(), host.to_string())],
+            field_key: field_key.to_string(),
+        }
+        .to_tsm_key()
+    }
+
+    fn write_tsm_file(path: &Path, blocks: &[(Vec<u8>, BlockData)]) {
+        let mut writer = TsmWriter::try_new(File::create(path).unwrap()).unwrap();
+        for (key, data) in blocks {
+            writer.write_block(key, data).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn write_v2_tombstones(path: &Path, tombstones: &[(Vec<u8>, i64, i64)]) {
+        let mut buf = 0x1502_u32.to_be_bytes().to_vec();
+        for (key, min_time, max_time) in tombstones {
+            buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&min_time.to_be_bytes());
+            buf.extend_from_slice(&max_time.to_be_bytes());
+        }
+        std::fs::write(path, buf).unwrap();
+    }
+
+    fn float_block(ts: Vec<i64>) -> BlockData {
+        let values = ts.iter().map(|&t| t as f64).collect();
+        BlockData::Float { i: 0, ts, values }
+    }
+
+    #[test]
+    fn read_shard_with_tombstones() {
+        let dir = test_helpers::tmp_dir().unwrap();
+
+        // the first file has series for hosts a and b, the second file
+        // overwrites part of host a
+        let first = dir.path().join("000000001-000000001.tsm");
+        write_tsm_file(
+            &first,
+            &[
+                (tsm_key("a", "usage"), float_block((0..2000).collect())),
+                (tsm_key("b", "usage"), float_block(vec![1, 2, 3])),
+            ],
+        );
+        let second = dir.path().join("000000002-000000001.tsm");
+        write_tsm_file(
+            &second,
+            &[(
+                tsm_key("a", "usage"),
+                BlockData::Float {
+                    i: 0,
+                    ts: vec![5, 2500],
+                    values: vec![-5.0, -2500.0],
+                },
+            )],
+        );
+        std::fs::write(dir.path().join("_00001.wal"), b"").unwrap();
+
+        // every value of host b and the first of the two blocks of host a are
+        // deleted from the first file, along with some values of the second
+        // block of host a
+        write_v2_tombstones(
+            &tombstone_path(&first),
+            &[
+                (tsm_key("b", "usage"), i64::MIN, i64::MAX),
+                (tsm_key("a", "usage"), 0, 999),
+                (tsm_key("a", "usage"), 1500, 1998),
+            ],
+        );
+
+        let shard = TsmShardReader::open(dir.path()).unwrap();
+        assert_eq!(shard.tsm_files().collect::<Vec<_>>(), vec![first, second]);
+        assert_eq!(shard.wal_files(), &[dir.path().join("_00001.wal")]);
+
+        let mut tables = shard.measurement_tables().unwrap();
+        assert_eq!(tables.len(), 1);
+        let cpu = tables.values_mut().next().unwrap();
+
+        let mut sections = vec![];
+        cpu.process(shard.block_reader().unwrap().unwrap(), |section| {
+            sections.push(section);
+            Ok(())
+        })
+        .unwrap();
+        // every value of host b has been deleted
+        assert_eq!(sections.len(), 1);
+        assert_eq!(
+            sections[0].tag_cols,
+            vec![("host".to_string(), "a".to_string())]
+        );
+
+        let mut exp_ts = vec![5];
+        exp_ts.extend(1000..1500);
+        exp_ts.extend([1999, 2500]);
+        assert_eq!(sections[0].ts, exp_ts);
+
+        let mut exp_values = vec![Some(-5.0)];
+        exp_values.extend((1000..1500).map(|v| Some(v as f64)));
+        exp_values.extend([Some(1999.0), Some(-2500.0)]);
+        assert_eq!(
+            sections[0].field_cols["usage"],
+            ColumnData::Float(exp_values)
+        );
+    }
+
+    #[test]
+    fn read_shard_with_point_deletes() {
+        let dir = test_helpers::tmp_dir().unwrap();
+        let path = dir.path().join("000000001-000000001.tsm");
+        write_tsm_file(
+            &path,
+            &[(tsm_key("a", "usage"), float_block((0..1003).collect()))],
+        );
+
+        // the values of the second block are deleted without the tombstone
+        // covering the time range of the block
+        write_v2_tombstones(
+            &tombstone_path(&path),
+            &[
+                (tsm_key("a", "usage"), 1000, 1000),
+                (tsm_key("a", "usage"), 1002, 1002),
+                (tsm_key("a", "usage"), 1001, 1001),
+                (tsm_key("a", "usage"), 500, 998),
+            ],
+        );
+
+        let shard = TsmShardReader::open(dir.path()).unwrap();
+        let mut tables = shard.measurement_tables().unwrap();
+        let cpu = tables.values_mut().next().unwrap();
+
+        let mut sections = vec![];
+        cpu.process(shard.block_reader().unwrap().unwrap(), |section| {
+            sections.push(section);
+            Ok(())
+        })
+        .unwrap();
+
+        let mut exp_ts: Vec<_> = (0..500).collect();
+        exp_ts.push(999);
+        assert_eq!(sections[0].ts, exp_ts);
+    }
+
+    #[test]
+    fn read_shard_with_v1_keys() {
+        let dir = test_helpers::tmp_dir().unwrap();
+        let v1_key = |host: &str| {
+            ParsedTsmKey {
+                org_id: InfluxId(0),
+                bucket_id: InfluxId(0),
+                measurement: "cpu".to_string(),
+                tagset: vec![("host".to_string(), host.to_string())],
+                field_key: "usage".to_string(),
+            }
+            .to_v1_tsm_key()
+        };
+        let path = dir.path().join("000000001-000000001.tsm");
+        write_tsm_file(
+            &path,
+            &[
+                (v1_key("a"), float_block(vec![1, 2])),
+                (v1_key("b"), float_block(vec![3])),
+            ],
+        );
+        write_v2_tombstones(&tombstone_path(&path), &[(v1_key("b"), i64::MIN, i64::MAX)]);
+
+        // the keys can't be parsed as 2.x keys
+        let shard = TsmShardReader::open(dir.path()).unwrap();
+        assert!(shard.measurement_tables().is_err());
+
+        let shard = shard.with_key_format(KeyFormat::V1);
+        let mut tables = shard.measurement_tables().unwrap();
+        assert_eq!(
+            tables.keys().collect::<Vec<_>>(),
+            vec![&(InfluxId(0), InfluxId(0), "cpu".to_string())]
+        );
+        let cpu = tables.values_mut().next().unwrap();
+
+        let mut sections = vec![];
+        cpu.process(shard.block_reader().unwrap().unwrap(), |section| {
+            sections.push(section);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(
+            sections[0].tag_cols,
+            vec![("host".to_string(), "a".to_string())]
+        );
+        assert_eq!(sections[0].ts, vec![1, 2]);
+    }
+}
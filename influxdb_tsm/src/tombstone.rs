@@ -0,0 +1,333 @@
+//! Types for reading the tombstone files of TSM files, which record the time
+//! ranges of the series keys that have been deleted from a TSM file.
+
+use super::*;
+use crate::reader::TsmIndexReader;
+use flate2::read::MultiGzDecoder;
+use observability_deps::tracing::warn;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+// The headers of the versions of the tombstone file format. A file without one
+// of these headers is a version 1 file, which has no header.
+const V2_HEADER: u32 = 0x1502;
+const V3_HEADER: u32 = 0x1503;
+const V4_HEADER: u32 = 0x1504;
+
+/// `Tombstone` records that the values of a series key within a time range,
+/// inclusive of `min_time` and `max_time`, have been deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub key: Vec<u8>,
+    pub min_time: i64,
+    pub max_time: i64,
+}
+
+/// Returns the path of the tombstone file of a TSM file, which InfluxDB writes
+/// next to the TSM file when data is deleted from it.
+pub fn tombstone_path(tsm_path: &Path) -> PathBuf {
+    tsm_path.with_extension("tombstone")
+}
+
+/// Reads the tombstones of a tombstone file, in any of the versions of the
+/// format that InfluxDB writes.
+///
+/// A version 1 file holds the series keys that have been deleted entirely, one
+/// per line. Later versions hold a series key and time range per entry; the
+/// entries of versions 3 and 4 are gzip compressed.
+///
+/// A partially written entry at the end of a file, which InfluxDB leaves
+/// behind if it crashes during a delete, is ignored like InfluxDB ignores it.
+pub fn read_tombstones(mut r: impl Read) -> Result<Vec<Tombstone>, TsmError> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf)?;
+
+    let header = buf
+        .get(..4)
+        .map(|header| u32::from_be_bytes([header[0], header[1], header[2], header[3]]));
+    match header {
+        Some(V2_HEADER) => Ok(read_entries(&buf[4..])),
+        Some(V3_HEADER | V4_HEADER) => {
+            // every delete appends a gzip member to a version 4 file
+            let mut entries = Vec::new();
+            if let Err(e) = MultiGzDecoder::new(&buf[4..]).read_to_end(&mut entries) {
+                warn!(error=%e, "ignoring tombstones that cannot be decompressed");
+            }
+            Ok(read_entries(&entries))
+        }
+        _ => Ok(buf
+            .split(|&b| b == b'\n')
+            .filter(|key| !key.is_empty())
+            .map(|key| Tombstone {
+                key: key.to_vec(),
+                min_time: i64::MIN,
+                max_time: i64::MAX,
+            })
+            .collect()),
+    }
+}
+
+// Reads entries made of a 4 byte key length, the key, and the min and max time
+// of the deleted range.
+fn read_entries(mut buf: &[u8]) -> Vec<Tombstone> {
+    let mut tombstones = Vec::new();
+    while !buf.is_empty() {
+        let Some(key_len) = buf.get(..4) else {
+            break;
+        };
+        let key_len = u32::from_be_bytes([key_len[0], key_len[1], key_len[2], key_len[3]]);
+        let Some(entry) = buf.get(4..4 + key_len as usize + 16) else {
+            break;
+        };
+
+        let (key, times) = entry.split_at(key_len as usize);
+        let mut min_time = [0u8; 8];
+        min_time.copy_from_slice(&times[..8]);
+        let mut max_time = [0u8; 8];
+        max_time.copy_from_slice(&times[8..]);
+        tombstones.push(Tombstone {
+            key: key.to_vec(),
+            min_time: i64::from_be_bytes(min_time),
+            max_time: i64::from_be_bytes(max_time),
+        });
+
+        buf = &buf[4 + entry.len()..];
+    }
+
+    if !buf.is_empty() {
+        warn!(
+            bytes = buf.len(),
+            "ignoring partially written tombstone entry"
+        );
+    }
+    tombstones
+}
+
+/// `Tombstones` holds the deleted time ranges of the series keys of a TSM
+/// file. The ranges of a key are sorted and don't overlap.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Tombstones {
+    deleted: BTreeMap<Vec<u8>, Vec<(i64, i64)>>,
+}
+
+impl Tombstones {
+    pub fn new(tombstones: impl IntoIterator<Item = Tombstone>) -> Self {
+        let mut deleted: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for tombstone in tombstones {
+            deleted
+                .entry(tombstone.key)
+                .or_default()
+                .push((tombstone.min_time, tombstone.max_time));
+        }
+
+        // merge the ranges of each key that overlap or are adjacent
+        for ranges in deleted.values_mut() {
+            ranges.sort_unstable();
+            let mut merged: Vec<(i64, i64)> = Vec::with_capacity(ranges.len());
+            for &(min_time, max_time) in ranges.iter() {
+                match merged.last_mut() {
+                    Some(last) if min_time <= last.1.saturating_add(1) => {
+                        last.1 = last.1.max(max_time);
+                    }
+                    _ => merged.push((min_time, max_time)),
+                }
+            }
+            *ranges = merged;
+        }
+
+        Self { deleted }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty()
+    }
+
+    /// The deleted time ranges of a series key that overlap the time range
+    /// from `min_time` to `max_time`.
+    pub fn deleted_ranges(&self, key: &[u8], min_time: i64, max_time: i64) -> &[(i64, i64)] {
+        let ranges = self.deleted.get(key).map(Vec::as_slice).unwrap_or_default();
+        let start = ranges.partition_point(|&(_, max)| max < min_time);
+        let end = ranges.partition_point(|&(min, _)| min <= max_time);
+        &ranges[start..end.max(start)]
+    }
+
+    /// Determines if every value of a series key in the time range from
+    /// `min_time` to `max_time` has been deleted.
+    pub fn covers(&self, key: &[u8], min_time: i64, max_time: i64) -> bool {
+        self.deleted_ranges(key, min_time, max_time)
+            .first()
+            .map(|&(min, max)| min <= min_time && max >= max_time)
+            .unwrap_or(false)
+    }
+
+    /// Maps the deleted time ranges of the series keys in a TSM index to the
+    /// blocks they delete values from, which is how `TsmBlockReader` applies
+    /// them when it decodes blocks.
+    pub fn block_tombstones<R>(&self, index: TsmIndexReader<R>) -> Result<BlockTombstones, TsmError>
+    where
+        R: Read + Seek,
+    {
+        let mut block_tombstones = BlockTombstones::default();
+        if self.is_empty() {
+            return Ok(block_tombstones);
+        }
+
+        for entry in index {
+            let entry = entry?;
+            let ranges =
+                self.deleted_ranges(entry.key(), entry.block.min_time, entry.block.max_time);
+            if !ranges.is_empty() {
+                block_tombstones
+                    .deleted
+                    .insert(entry.block.offset, ranges.to_vec());
+            }
+        }
+        Ok(block_tombstones)
+    }
+}
+
+/// `BlockTombstones` holds the deleted time ranges of the blocks of a TSM
+/// file, by the offset of the block.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlockTombstones {
+    deleted: BTreeMap<u64, Vec<(i64, i64)>>,
+}
+
+impl BlockTombstones {
+    /// The deleted time ranges of a block, which are sorted and don't overlap.
+    pub fn deleted_ranges(&self, block: &Block) -> &[(i64, i64)] {
+        self.deleted
+            .get(&block.offset)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn entries(tombstones: &[(&str, i64, i64)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (key, min_time, max_time) in tombstones {
+            buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(&min_time.to_be_bytes());
+            buf.extend_from_slice(&max_time.to_be_bytes());
+        }
+        buf
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn tombstone(key: &str, min_time: i64, max_time: i64) -> Tombstone {
+        Tombstone {
+            key: key.as_bytes().to_vec(),
+            min_time,
+            max_time,
+        }
+    }
+
+    #[test]
+    fn read_v1() {
+        let got = read_tombstones(&b"cpu#!~#usage\nmem#!~#free\n"[..]).unwrap();
+        assert_eq!(
+            got,
+            vec![
+                tombstone("cpu#!~#usage", i64::MIN, i64::MAX),
+                tombstone("mem#!~#free", i64::MIN, i64::MAX),
+            ]
+        );
+
+        assert_eq!(read_tombstones(&b""[..]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn read_v2() {
+        let mut buf = V2_HEADER.to_be_bytes().to_vec();
+        buf.extend(entries(&[("cpu#!~#usage", 10, 20), ("mem#!~#free", -5, 5)]));
+
+        let got = read_tombstones(buf.as_slice()).unwrap();
+        assert_eq!(
+            got,
+            vec![
+                tombstone("cpu#!~#usage", 10, 20),
+                tombstone("mem#!~#free", -5, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_v3() {
+        let mut buf = V3_HEADER.to_be_bytes().to_vec();
+        buf.extend(gzip(&entries(&[("cpu#!~#usage", 10, 20)])));
+
+        let got = read_tombstones(buf.as_slice()).unwrap();
+        assert_eq!(got, vec![tombstone("cpu#!~#usage", 10, 20)]);
+    }
+
+    #[test]
+    fn read_v4() {
+        // each delete appends a gzip member
+        let mut buf = V4_HEADER.to_be_bytes().to_vec();
+        buf.extend(gzip(&entries(&[("cpu#!~#usage", 10, 20)])));
+        buf.extend(gzip(&entries(&[("cpu#!~#usage", 30, 40)])));
+
+        let got = read_tombstones(buf.as_slice()).unwrap();
+        assert_eq!(
+            got,
+            vec![
+                tombstone("cpu#!~#usage", 10, 20),
+                tombstone("cpu#!~#usage", 30, 40),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_partially_written_entry() {
+        let mut buf = V2_HEADER.to_be_bytes().to_vec();
+        buf.extend(entries(&[("cpu#!~#usage", 10, 20), ("mem#!~#free", -5, 5)]));
+        buf.truncate(buf.len() - 3);
+
+        let got = read_tombstones(buf.as_slice()).unwrap();
+        assert_eq!(got, vec![tombstone("cpu#!~#usage", 10, 20)]);
+    }
+
+    #[test]
+    fn tombstone_ranges() {
+        let tombstones = Tombstones::new(vec![
+            tombstone("cpu", 30, 40),
+            tombstone("cpu", 10, 20),
+            tombstone("cpu", 15, 25),
+            tombstone("cpu", 26, 28),
+            tombstone("cpu", 100, 200),
+            tombstone("mem", i64::MIN, i64::MAX),
+        ]);
+
+        // overlapping and adjacent ranges are merged
+        assert_eq!(
+            tombstones.deleted_ranges(b"cpu", i64::MIN, i64::MAX),
+            &[(10, 28), (30, 40), (100, 200)]
+        );
+        assert_eq!(tombstones.deleted_ranges(b"cpu", 29, 29), &[]);
+        assert_eq!(
+            tombstones.deleted_ranges(b"cpu", 35, 150),
+            &[(30, 40), (100, 200)]
+        );
+        assert_eq!(tombstones.deleted_ranges(b"disk", i64::MIN, i64::MAX), &[]);
+
+        assert!(tombstones.covers(b"cpu", 12, 27));
+        assert!(!tombstones.covers(b"cpu", 12, 29));
+        assert!(!tombstones.covers(b"cpu", 5, 15));
+        assert!(tombstones.covers(b"mem", 0, 1_000));
+        assert!(!tombstones.covers(b"disk", 0, 1_000));
+    }
+}
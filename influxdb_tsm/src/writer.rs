@@ -0,0 +1,521 @@
+//! Types for writing TSM files that can be read by InfluxDB and by the
+//! readers in this crate.
+
+use super::*;
+use crate::reader::BlockData;
+use integer_encoding::VarInt;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// The magic number at the start of every TSM file.
+const MAGIC_NUMBER: u32 = 0x16D1_16D1;
+
+/// The version of the TSM format that is written.
+const VERSION: u8 = 1;
+
+/// `TsmWriter` writes the blocks of series to a TSM file, followed by the
+/// index of the file once it is finished.
+///
+/// Blocks are encoded with the same encoders that InfluxDB uses, and hold at
+/// most 1,000 values; longer block data is split across several blocks. The
+/// blocks of a series can be written in any order, but the blocks of a
+/// series must all be of the same type.
+///
+/// # Example
+///
+/// Writing a TSM file and reading its index.
+///
+/// ```
+/// # use influxdb_tsm::key::ParsedTsmKey;
+/// # use influxdb_tsm::reader::*;
+/// # use influxdb_tsm::writer::*;
+/// # use influxdb_tsm::InfluxId;
+/// # use std::io::Cursor;
+/// let key = ParsedTsmKey {
+///     org_id: InfluxId::new_str("05c19117091a1000").unwrap(),
+///     bucket_id: InfluxId::new_str("05c19117091a1001").unwrap(),
+///     measurement: "cpu".to_string(),
+///     tagset: vec![("host".to_string(), "a".to_string())],
+///     field_key: "usage_idle".to_string(),
+/// };
+///
+/// let mut writer = TsmWriter::try_new(Vec::new()).unwrap();
+/// writer
+///     .write_block(
+///         &key.to_tsm_key(),
+///         &BlockData::Float {
+///             i: 0,
+///             ts: vec![1, 2, 3],
+///             values: vec![98.5, 99.0, 97.25],
+///         },
+///     )
+///     .unwrap();
+/// let buf = writer.finish().unwrap();
+///
+/// let reader = TsmIndexReader::try_new(Cursor::new(&buf), buf.len()).unwrap();
+/// for index_entry in reader {
+///     let key = index_entry.unwrap().parse_key().unwrap();
+///     assert_eq!(key.measurement, "cpu");
+/// }
+/// ```
+#[derive(Debug)]
+pub struct TsmWriter<W>
+where
+    W: Write,
+{
+    w: W,
+
+    // the offset in the file that the next block is written at
+    offset: u64,
+
+    // the type and blocks of each series, in the order the index is written.
+    index: BTreeMap<Vec<u8>, (BlockType, Vec<Block>)>,
+
+    // buffers that blocks are encoded into
+    ts_buf: Vec<u8>,
+    values_buf: Vec<u8>,
+    block_buf: Vec<u8>,
+}
+
+impl<W> TsmWriter<W>
+where
+    W: Write,
+{
+    /// Creates a writer, writing the header of the TSM file.
+    pub fn try_new(mut w: W) -> Result<Self, TsmError> {
+        w.write_all(&MAGIC_NUMBER.to_be_bytes())?;
+        w.write_all(&[VERSION])?;
+
+        Ok(Self {
+            w,
+            offset: 5,
+            index: BTreeMap::new(),
+            ts_buf: Vec::new(),
+            values_buf: Vec::new(),
+            block_buf: Vec::new(),
+        })
+    }
+
+    /// Writes the values in `data` as blocks of the series with the provided
+    /// key, which is how the series is identified in the index. The
+    /// timestamps of `data` must be in ascending order without duplicates.
+    /// Nothing is written for block data without values.
+    ///
+    /// Keys in the format InfluxDB 2.x uses can be built with
+    /// `ParsedTsmKey::to_tsm_key`.
+    pub fn write_block(&mut self, key: &[u8], data: &BlockData) -> Result<(), TsmError> {
+        if key.len() > u16::MAX as usize {
+            return Err(TsmError {
+                description: format!("series key of {} bytes is too long", key.len()),
+            });
+        }
+
+        let (typ, ts) = match data {
+            BlockData::Float { ts, values, .. } => (BlockType::Float, check_len(ts, values)?),
+            BlockData::Integer { ts, values, .. } => (BlockType::Integer, check_len(ts, values)?),
+            BlockData::Bool { ts, values, .. } => (BlockType::Bool, check_len(ts, values)?),
+            BlockData::Str { ts, values, .. } => (BlockType::Str, check_len(ts, values)?),
+            BlockData::Unsigned { ts, values, .. } => (BlockType::Unsigned, check_len(ts, values)?),
+        };
+        if ts.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(TsmError {
+                description: "block timestamps must be in ascending order without duplicates"
+                    .to_string(),
+            });
+        }
+        if let Some((existing, _)) = self.index.get(key) {
+            if *existing != typ {
+                return Err(TsmError {
+                    description: format!(
+                        "cannot write {typ:?} block to series {} of {existing:?} blocks",
+                        String::from_utf8_lossy(key)
+                    ),
+                });
+            }
+        }
+
+        for start in (0..ts.len()).step_by(MAX_BLOCK_VALUES) {
+            let end = ts.len().min(start + MAX_BLOCK_VALUES);
+            let block = self.write_block_range(typ, data, start..end)?;
+
+            let (_, blocks) = self
+                .index
+                .entry(key.to_vec())
+                .or_insert_with(|| (typ, Vec::new()));
+            if blocks.len() == u16::MAX as usize {
+                return Err(TsmError {
+                    description: format!(
+                        "series {} has too many blocks",
+                        String::from_utf8_lossy(key)
+                    ),
+                });
+            }
+            blocks.push(block);
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the values in `range` of `data` as a block, which is written
+    /// at the current offset, and returns its index entry.
+    fn write_block_range(
+        &mut self,
+        typ: BlockType,
+        data: &BlockData,
+        range: std::ops::Range<usize>,
+    ) -> Result<Block, TsmError> {
+        let to_tsm_error = |e: Box<dyn std::error::Error>| TsmError {
+            description: e.to_string(),
+        };
+
+        let ts = match data {
+            BlockData::Float { ts, values, .. } => {
+                encoders::float::encode_influxdb(&values[range.clone()], &mut self.values_buf)
+                    .map_err(to_tsm_error)?;
+                ts
+            }
+            BlockData::Integer { ts, values, .. } => {
+                encoders::integer::encode(&values[range.clone()], &mut self.values_buf)
+                    .map_err(to_tsm_error)?;
+                ts
+            }
+            BlockData::Bool { ts, values, .. } => {
+                encoders::boolean::encode(&values[range.clone()], &mut self.values_buf)
+                    .map_err(to_tsm_error)?;
+                ts
+            }
+            BlockData::Str { ts, values, .. } => {
+                let values: Vec<_> = values[range.clone()].iter().map(Vec::as_slice).collect();
+                encoders::string::encode(&values, &mut self.values_buf).map_err(to_tsm_error)?;
+                ts
+            }
+            BlockData::Unsigned { ts, values, .. } => {
+                encoders::unsigned::encode(&values[range.clone()], &mut self.values_buf)
+                    .map_err(to_tsm_error)?;
+                ts
+            }
+        };
+        let ts = &ts[range];
+        encoders::timestamp::encode(ts, &mut self.ts_buf).map_err(to_tsm_error)?;
+
+        // a block is a CRC-32 checksum of its data, followed by the block type,
+        // the length of the timestamps, the timestamps and the values.
+        self.block_buf.clear();
+        self.block_buf.push(u8::from(typ));
+        self.block_buf
+            .extend_from_slice(&(self.ts_buf.len() as u64).encode_var_vec());
+        self.block_buf.extend_from_slice(&self.ts_buf);
+        self.block_buf.extend_from_slice(&self.values_buf);
+
+        let checksum = crc32fast::hash(&self.block_buf);
+        self.w.write_all(&checksum.to_be_bytes())?;
+        self.w.write_all(&self.block_buf)?;
+
+        let block = Block {
+            min_time: ts[0],
+            max_time: ts[ts.len() - 1],
+            offset: self.offset,
+            size: (4 + self.block_buf.len()) as u32,
+            typ,
+            reader_idx: 0,
+        };
+        self.offset += block.size as u64;
+
+        Ok(block)
+    }
+
+    /// Writes the index and the footer of the TSM file, returning the
+    /// underlying writer.
+    pub fn finish(mut self) -> Result<W, TsmError> {
+        let index_offset = self.offset;
+
+        for (key, (typ, mut blocks)) in self.index {
+            blocks.sort_by_key(|block| block.min_time);
+
+            self.w.write_all(&(key.len() as u16).to_be_bytes())?;
+            self.w.write_all(&key)?;
+            self.w.write_all(&[u8::from(typ)])?;
+            self.w.write_all(&(blocks.len() as u16).to_be_bytes())?;
+            for block in blocks {
+                self.w.write_all(&block.min_time.to_be_bytes())?;
+                self.w.write_all(&block.max_time.to_be_bytes())?;
+                self.w.write_all(&block.offset.to_be_bytes())?;
+                self.w.write_all(&block.size.to_be_bytes())?;
+            }
+        }
+
+        self.w.write_all(&index_offset.to_be_bytes())?;
+        self.w.flush()?;
+
+        Ok(self.w)
+    }
+}
+
+/// Returns the timestamps of block data after checking that there is a value
+/// for every timestamp.
+fn check_len<'a, T>(ts: &'a [i64], values: &[T]) -> Result<&'a [i64], TsmError> {
+    if ts.len() != values.len() {
+        return Err(TsmError {
+            description: format!(
+                "block has {} timestamps but {} values",
+                ts.len(),
+                values.len()
+            ),
+        });
+    }
+    Ok(ts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::ParsedTsmKey;
+    use crate::mapper::{ColumnData, TsmMeasurementMapper};
+    use crate::reader::{BlockDecoder, TsmBlockReader, TsmIndexReader};
+    use flate2::read::GzDecoder;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+
+    fn tsm_key(measurement: &str, tagset: &[(&str, &str)], field_key: &str) -> Vec<u8> {
+        ParsedTsmKey {
+            org_id: InfluxId::new_str("05c19117091a1000").unwrap(),
+            bucket_id: InfluxId::new_str("05c19117091a1001").unwrap(),
+            measurement: measurement.to_string(),
+            tagset: tagset
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            field_key: field_key.to_string(),
+        }
+        .to_tsm_key()
+    }
+
+    #[test]
+    fn write_and_read_blocks() {
+        let blocks = vec![
+            (
+                tsm_key("cpu", &[("host", "a")], "usage"),
+                BlockData::Float {
+                    i: 0,
+                    ts: (0..2500).collect(),
+                    values: (0..2500).map(|v| v as f64 / 3.0).collect(),
+                },
+            ),
+            (
+                tsm_key("cpu", &[("host", "a")], "count"),
+                BlockData::Integer {
+                    i: 0,
+                    ts: vec![10, 20, 30],
+                    values: vec![-1, 0, i64::MAX],
+                },
+            ),
+            (
+                tsm_key("disk", &[("host", "b"), ("path", "/")], "ok"),
+                BlockData::Bool {
+                    i: 0,
+                    ts: vec![1, 5],
+                    values: vec![true, false],
+                },
+            ),
+            (
+                tsm_key("disk", &[("host", "b"), ("path", "/")], "mode"),
+                BlockData::Str {
+                    i: 0,
+                    ts: vec![7, 8, 9],
+                    values: vec![b"rw".to_vec(), b"".to_vec(), b"ro".to_vec()],
+                },
+            ),
+            (
+                tsm_key("disk", &[("host", "b"), ("path", "/")], "free"),
+                BlockData::Unsigned {
+                    i: 0,
+                    ts: vec![-100, 100],
+                    values: vec![0, u64::MAX],
+                },
+            ),
+        ];
+
+        let mut writer = TsmWriter::try_new(Vec::new()).unwrap();
+        for (key, data) in &blocks {
+            writer.write_block(key, data).unwrap();
+        }
+        let buf = writer.finish().unwrap();
+        assert_eq!(&buf[..5], &[0x16, 0xD1, 0x16, 0xD1, 1]);
+
+        let index_reader = TsmIndexReader::try_new(Cursor::new(&buf), buf.len()).unwrap();
+        let entries: Vec<_> = index_reader.map(Result::unwrap).collect();
+        // the 2,500 float values are split into three blocks
+        assert_eq!(entries.len(), 7);
+
+        // the index is sorted by key
+        let keys: Vec<_> = entries.iter().map(|entry| entry.key().to_vec()).collect();
+        let mut sorted_keys = keys.clone();
+        sorted_keys.sort();
+        assert_eq!(keys, sorted_keys);
+
+        let mut block_reader = TsmBlockReader::new(Cursor::new(&buf));
+        for (key, data) in &blocks {
+            let decoded: Vec<_> = entries
+                .iter()
+                .filter(|entry| entry.key() == key.as_slice())
+                .map(|entry| {
+                    assert!(entry.block.size as usize <= buf.len());
+                    block_reader.decode(&entry.block).unwrap()
+                })
+                .collect();
+            assert_eq!(&BlockData::merge(decoded), data);
+        }
+
+        let float_blocks: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.block_type == BlockType::Float)
+            .map(|entry| (entry.block.min_time, entry.block.max_time))
+            .collect();
+        assert_eq!(float_blocks, vec![(0, 999), (1000, 1999), (2000, 2499)]);
+    }
+
+    #[test]
+    fn write_and_map_measurements() {
+        let mut writer = TsmWriter::try_new(Vec::new()).unwrap();
+        for host in ["a", "b"] {
+            writer
+                .write_block(
+                    &tsm_key("cpu", &[("host", host)], "usage"),
+                    &BlockData::Float {
+                        i: 0,
+                        ts: vec![1, 2],
+                        values: vec![1.0, 2.0],
+                    },
+                )
+                .unwrap();
+        }
+        writer
+            .write_block(
+                &tsm_key("mem", &[], "free"),
+                &BlockData::Integer {
+                    i: 0,
+                    ts: vec![3],
+                    values: vec![30],
+                },
+            )
+            .unwrap();
+        let buf = writer.finish().unwrap();
+
+        let index_reader = TsmIndexReader::try_new(Cursor::new(&buf), buf.len()).unwrap();
+        let mapper = TsmMeasurementMapper::new(index_reader.peekable(), 0);
+        let mut tables: Vec<_> = mapper.map(Result::unwrap).collect();
+        assert_eq!(
+            tables.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(),
+            vec!["cpu", "mem"]
+        );
+
+        let mut sections = vec![];
+        tables[0]
+            .process(TsmBlockReader::new(Cursor::new(&buf)), |section| {
+                sections.push(section);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(
+            sections[1].tag_cols,
+            vec![("host".to_string(), "b".to_string())]
+        );
+        assert_eq!(
+            sections[1].field_cols["usage"],
+            ColumnData::Float(vec![Some(1.0), Some(2.0)])
+        );
+    }
+
+    #[test]
+    fn rewrite_tsm_file() {
+        let file = File::open("../test_fixtures/000000000000005-000000002.tsm.gz");
+        let mut decoder = GzDecoder::new(file.unwrap());
+        let mut buf = Vec::new();
+        decoder.read_to_end(&mut buf).unwrap();
+
+        // copy every block of the fixture into a new file
+        let index_reader = TsmIndexReader::try_new(Cursor::new(&buf), buf.len()).unwrap();
+        let entries: Vec<_> = index_reader.map(Result::unwrap).collect();
+        let mut block_reader = TsmBlockReader::new(Cursor::new(&buf));
+        let mut writer = TsmWriter::try_new(Vec::new()).unwrap();
+        for entry in &entries {
+            let data = block_reader.decode(&entry.block).unwrap();
+            writer.write_block(entry.key(), &data).unwrap();
+        }
+        let rewritten = writer.finish().unwrap();
+
+        let index_reader =
+            TsmIndexReader::try_new(Cursor::new(&rewritten), rewritten.len()).unwrap();
+        let rewritten_entries: Vec<_> = index_reader.map(Result::unwrap).collect();
+        assert_eq!(rewritten_entries.len(), entries.len());
+
+        let mut rewritten_block_reader = TsmBlockReader::new(Cursor::new(&rewritten));
+        for (entry, rewritten_entry) in entries.iter().zip(&rewritten_entries) {
+            assert_eq!(entry.key(), rewritten_entry.key());
+            assert_eq!(entry.block.min_time, rewritten_entry.block.min_time);
+            assert_eq!(entry.block.max_time, rewritten_entry.block.max_time);
+            assert_eq!(
+                block_reader.decode(&entry.block).unwrap(),
+                rewritten_block_reader
+                    .decode(&rewritten_entry.block)
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn write_invalid_blocks() {
+        let mut writer = TsmWriter::try_new(Vec::new()).unwrap();
+        let key = tsm_key("cpu", &[], "usage");
+
+        let err = writer
+            .write_block(
+                &key,
+                &BlockData::Integer {
+                    i: 0,
+                    ts: vec![1, 2],
+                    values: vec![1],
+                },
+            )
+            .unwrap_err();
+        assert_eq!(err.description, "block has 2 timestamps but 1 values");
+
+        let err = writer
+            .write_block(
+                &key,
+                &BlockData::Integer {
+                    i: 0,
+                    ts: vec![2, 1],
+                    values: vec![1, 2],
+                },
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.description,
+            "block timestamps must be in ascending order without duplicates"
+        );
+
+        writer
+            .write_block(
+                &key,
+                &BlockData::Integer {
+                    i: 0,
+                    ts: vec![1],
+                    values: vec![1],
+                },
+            )
+            .unwrap();
+        let err = writer
+            .write_block(
+                &key,
+                &BlockData::Float {
+                    i: 0,
+                    ts: vec![2],
+                    values: vec![1.0],
+                },
+            )
+            .unwrap_err();
+        assert!(err.description.starts_with("cannot write Float block"));
+    }
+}
@@ -227,6 +227,15 @@ impl IOxSessionConfig {
         Self { span_ctx, ..self }
     }
 
+    /// Attach an extension to the session, which can be retrieved while the query is planned
+    /// and executed with [`SessionConfig::get_extension`].
+    pub fn with_extension<T: Send + Sync + 'static>(self, extension: Arc<T>) -> Self {
+        Self {
+            session_config: self.session_config.with_extension(extension),
+            ..self
+        }
+    }
+
     /// Set DataFusion [config option].
     ///
     /// May be used to set [IOx-specific] option as well.
@@ -6,6 +6,7 @@ mod planner;
 mod planner_rewrite_expression;
 mod planner_time_range_expression;
 mod rewriter;
+mod tag_values_cache;
 mod test_utils;
 mod udf;
 mod util;
@@ -13,4 +14,5 @@ mod var_ref;
 
 pub use planner::InfluxQLToLogicalPlan;
 pub use planner::SchemaProvider;
+pub use tag_values_cache::{TagValuesCache, TagValuesCacheExtension};
 pub(crate) use util::parse_regex;
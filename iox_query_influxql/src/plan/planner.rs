@@ -9,6 +9,7 @@ use crate::plan::planner::select::{
 };
 use crate::plan::planner_time_range_expression::time_range_to_df_expr;
 use crate::plan::rewriter::{find_table_names, rewrite_statement, ProjectionType};
+use crate::plan::tag_values_cache::{TagValuesCache, TagValuesCacheExtension};
 use crate::plan::udf::{
     cumulative_sum, derivative, difference, find_window_udfs, moving_average,
     non_negative_derivative, non_negative_difference,
@@ -2274,17 +2275,7 @@ impl<'a> InfluxQLToLogicalPlan<'a> {
         // Add time restriction to logical plan if there isn't any.
         let time_range = if time_range.is_unbounded() {
             TimeRange {
-                lower: Some(match cutoff {
-                    MetadataCutoff::Absolute(dt) => dt
-                        .timestamp_nanos_opt()
-                        .ok_or_else(|| error::map::query("timestamp out of range"))?,
-                    MetadataCutoff::Relative(delta) => {
-                        start_time
-                            .timestamp_nanos_opt()
-                            .ok_or_else(|| error::map::query("timestamp out of range"))?
-                            - delta.as_nanos() as i64
-                    }
-                }),
+                lower: Some(self.metadata_cutoff_time(cutoff)?),
                 upper: None,
             }
         } else {
@@ -2294,6 +2285,24 @@ impl<'a> InfluxQLToLogicalPlan<'a> {
         self.plan_condition_time_range(cond.as_ref(), time_range, plan, schema)
     }
 
+    /// Returns the time, in nanoseconds, from which metadata queries without a time range in
+    /// their `WHERE` clause read data.
+    fn metadata_cutoff_time(&self, cutoff: MetadataCutoff) -> Result<i64> {
+        match cutoff {
+            MetadataCutoff::Absolute(dt) => dt
+                .timestamp_nanos_opt()
+                .ok_or_else(|| error::map::query("timestamp out of range")),
+            MetadataCutoff::Relative(delta) => {
+                let start_time =
+                    Timestamp::from(self.s.execution_props().query_execution_start_time);
+                Ok(start_time
+                    .timestamp_nanos_opt()
+                    .ok_or_else(|| error::map::query("timestamp out of range"))?
+                    - delta.as_nanos() as i64)
+            }
+        }
+    }
+
     /// Generate a logical plan for the specified `DataSource`.
     fn plan_from_data_source(
         &self,
@@ -2703,6 +2712,15 @@ impl<'a> InfluxQLToLogicalPlan<'a> {
         let tables = self.expand_show_from_clause(show_tag_values.from)?;
         let metadata_cutoff = self.metadata_cutoff();
 
+        // without a WHERE clause the values of a tag are read from the tag values cache if it
+        // has all of them from the time the table would be read from
+        let tag_values_cache = match self.tag_values_cache() {
+            Some(cache) if show_tag_values.condition.is_none() => {
+                Some((cache, self.metadata_cutoff_time(metadata_cutoff)?))
+            }
+            _ => None,
+        };
+
         let mut union_plan = None;
         for table in tables {
             let Some(schema) = self.s.table_schema(&table) else {
@@ -2728,14 +2746,46 @@ impl<'a> InfluxQLToLogicalPlan<'a> {
                 self.plan_where_clause(plan, &show_tag_values.condition, metadata_cutoff, &schema)?;
 
             for key in keys {
-                let idx = plan
-                    .schema()
-                    .index_of_column_by_name(None, key)?
-                    .expect("where is the key?");
-
-                let plan = LogicalPlanBuilder::from(plan.clone())
-                    .select([idx])?
-                    .distinct()?
+                let cached_values = tag_values_cache
+                    .as_ref()
+                    .and_then(|(cache, min_time)| cache.tag_values(&table, key, *min_time));
+                let values_plan = match cached_values {
+                    Some(values) => {
+                        debug!(%table, %key, "`SHOW TAG VALUES` from the tag values cache");
+                        let value_schema = Arc::new(ArrowSchema::new(vec![ArrowField::new(
+                            key,
+                            DataType::Dictionary(
+                                Box::new(DataType::Int32),
+                                Box::new(DataType::Utf8),
+                            ),
+                            true,
+                        )]));
+                        let values: DictionaryArray<Int32Type> =
+                            values.iter().map(String::as_str).collect();
+                        LogicalPlanBuilder::scan(
+                            "tag_values",
+                            provider_as_source(Arc::new(MemTable::try_new(
+                                Arc::clone(&value_schema),
+                                vec![vec![RecordBatch::try_new(
+                                    value_schema,
+                                    vec![Arc::new(values)],
+                                )?]],
+                            )?)),
+                            None,
+                        )?
+                    }
+                    None => {
+                        let idx = plan
+                            .schema()
+                            .index_of_column_by_name(None, key)?
+                            .expect("where is the key?");
+                        LogicalPlanBuilder::from(plan.clone())
+                            .select([idx])?
+                            .distinct()?
+                    }
+                };
+
+                let plan = values_plan
                     .project(measurement_expr.iter().cloned().chain([
                         lit_dict(key).alias(key_col),
                         Expr::Column(Column::from_name(key)).alias(value_col),
@@ -2991,6 +3041,15 @@ impl<'a> InfluxQLToLogicalPlan<'a> {
             .unwrap_or_default()
             .influxql_metadata_cutoff
     }
+
+    fn tag_values_cache(&self) -> Option<Arc<dyn TagValuesCache>> {
+        self.iox_ctx
+            .inner()
+            .state()
+            .config()
+            .get_extension::<TagValuesCacheExtension>()
+            .map(|extension| Arc::clone(&extension.0))
+    }
 }
 
 /// Returns a [`LogicalPlan`] that performs gap-filling for the `input` plan.
@@ -0,0 +1,17 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// A cache of the distinct values of tags, which `SHOW TAG VALUES` statements without a `WHERE`
+/// clause are answered from instead of scanning the tables.
+pub trait TagValuesCache: Debug + Send + Sync {
+    /// Returns the distinct values of the tag of the table written at or after `min_time`, in
+    /// nanoseconds, or `None` if the cache doesn't have all of them.
+    fn tag_values(&self, table_name: &str, tag_key: &str, min_time: i64) -> Option<Vec<String>>;
+}
+
+/// The session extension that holds the [`TagValuesCache`] for the namespace being queried,
+/// which is attached with [`IOxSessionConfig::with_extension`].
+///
+/// [`IOxSessionConfig::with_extension`]: iox_query::exec::IOxSessionConfig::with_extension
+#[derive(Debug)]
+pub struct TagValuesCacheExtension(pub Arc<dyn TagValuesCache>);